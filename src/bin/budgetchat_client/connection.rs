@@ -0,0 +1,337 @@
+use std::time::Duration;
+
+use futures::{SinkExt, StreamExt};
+use tokio::net::TcpStream;
+use tokio::sync::mpsc::{UnboundedReceiver, UnboundedSender};
+use tokio_util::codec::{Framed, LinesCodec};
+
+use crate::protocol::{parse_server_line, ServerLine, WRONG_PASSWORD};
+
+#[derive(Clone, Debug)]
+pub struct ConnectionConfig {
+    pub server_address: String,
+    pub user_name: String,
+    /// Password of the user name, only needed for registered names
+    pub password: Option<String>,
+    pub reconnect_delay: Duration,
+}
+
+/// Everything the connection reports to the user interface
+#[derive(Debug, PartialEq)]
+pub enum ConnectionEvent {
+    Connecting,
+    /// Joined the room under the name, together with the members that were there before
+    Joined(String, Vec<String>),
+    /// Line from the server after joining the room
+    Line(String),
+    /// Connection ended, another attempt follows after the reconnect delay
+    Disconnected(String),
+    /// Connection ended for a reason that another attempt cannot fix
+    Failed(String),
+}
+
+/// Why a single connection ended
+enum Disconnect {
+    Retry(String),
+    GiveUp(String),
+}
+
+/// Keeps the client in the room, connecting again whenever the connection is lost. Lines from the
+/// input are sent to the server as they are. Ends once the user interface is gone.
+pub async fn run_connection(
+    config: ConnectionConfig,
+    event_sender: UnboundedSender<ConnectionEvent>,
+    mut input_receiver: UnboundedReceiver<String>,
+) {
+    // Renames carry over to the next connection
+    let mut user_name = config.user_name.clone();
+    loop {
+        let _ = event_sender.send(ConnectionEvent::Connecting);
+        let disconnect = match TcpStream::connect(&config.server_address).await {
+            Ok(tcp_stream) => {
+                chat(
+                    tcp_stream,
+                    &config,
+                    &mut user_name,
+                    &event_sender,
+                    &mut input_receiver,
+                )
+                .await
+            }
+            Err(e) => Disconnect::Retry(format!(
+                "Failed to connect to {}: {e}",
+                config.server_address
+            )),
+        };
+
+        match disconnect {
+            Disconnect::Retry(reason) => {
+                if event_sender
+                    .send(ConnectionEvent::Disconnected(reason))
+                    .is_err()
+                {
+                    return;
+                }
+            }
+            Disconnect::GiveUp(reason) => {
+                let _ = event_sender.send(ConnectionEvent::Failed(reason));
+                return;
+            }
+        }
+        tokio::time::sleep(config.reconnect_delay).await;
+    }
+}
+
+async fn chat(
+    tcp_stream: TcpStream,
+    config: &ConnectionConfig,
+    user_name: &mut String,
+    event_sender: &UnboundedSender<ConnectionEvent>,
+    input_receiver: &mut UnboundedReceiver<String>,
+) -> Disconnect {
+    let mut server = Framed::new(tcp_stream, LinesCodec::new());
+    let members = match join_preamble(&mut server, config, user_name).await {
+        Ok(members) => members,
+        Err(disconnect) => return disconnect,
+    };
+    let _ = event_sender.send(ConnectionEvent::Joined(user_name.clone(), members));
+
+    loop {
+        tokio::select! {
+            server_line = server.next() => match server_line {
+                Some(Ok(server_line)) => {
+                    // Own renames come back as the reply to `/nick`
+                    if let ServerLine::Renamed(old_user_name, new_user_name) =
+                        parse_server_line(&server_line)
+                    {
+                        if old_user_name == *user_name {
+                            *user_name = new_user_name;
+                        }
+                    }
+                    let _ = event_sender.send(ConnectionEvent::Line(server_line));
+                }
+                Some(Err(e)) => return Disconnect::Retry(format!("Connection failed: {e}")),
+                None => return Disconnect::Retry(String::from("Connection closed by the server")),
+            },
+            input_line = input_receiver.recv() => match input_line {
+                Some(input_line) => {
+                    if let Err(e) = server.send(input_line).await {
+                        return Disconnect::Retry(format!("Connection failed: {e}"));
+                    }
+                }
+                // User interface is gone
+                None => return Disconnect::GiveUp(String::from("Closed")),
+            }
+        }
+    }
+}
+
+/// Joins the room as described by the server's greeting. Returns the members that were there
+/// before.
+async fn join_preamble(
+    server: &mut Framed<TcpStream, LinesCodec>,
+    config: &ConnectionConfig,
+    user_name: &str,
+) -> Result<Vec<String>, Disconnect> {
+    match read_line(server).await? {
+        Some(greeting) if parse_server_line(&greeting) == ServerLine::Welcome => {}
+        _ => return Err(Disconnect::Retry(String::from("Unexpected greeting"))),
+    }
+    send_line(server, user_name).await?;
+
+    let mut server_line = read_line(server).await?;
+    if server_line.as_deref().map(parse_server_line) == Some(ServerLine::PasswordPrompt) {
+        let Some(password) = &config.password else {
+            return Err(Disconnect::GiveUp(format!(
+                "Name {user_name} is registered, its password is needed"
+            )));
+        };
+        send_line(server, password).await?;
+        server_line = read_line(server).await?;
+    }
+
+    match server_line {
+        Some(server_line) => match parse_server_line(&server_line) {
+            ServerLine::Members(members) => Ok(members),
+            _ if server_line == WRONG_PASSWORD => Err(Disconnect::GiveUp(format!(
+                "Wrong password for {user_name}"
+            ))),
+            _ => Err(Disconnect::Retry(format!("Unexpected line: {server_line}"))),
+        },
+        // Invalid or used name -> Used names may become free again, e.g. after a lost connection
+        None => Err(Disconnect::Retry(format!(
+            "Name {user_name} was not accepted"
+        ))),
+    }
+}
+
+async fn read_line(
+    server: &mut Framed<TcpStream, LinesCodec>,
+) -> Result<Option<String>, Disconnect> {
+    server
+        .next()
+        .await
+        .transpose()
+        .map_err(|e| Disconnect::Retry(format!("Connection failed: {e}")))
+}
+
+async fn send_line(
+    server: &mut Framed<TcpStream, LinesCodec>,
+    line: &str,
+) -> Result<(), Disconnect> {
+    server
+        .send(line)
+        .await
+        .map_err(|e| Disconnect::Retry(format!("Connection failed: {e}")))
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+    use tokio::sync::mpsc::unbounded_channel;
+    use tokio::time::timeout;
+
+    use super::*;
+    use crate::protocol::WELCOME_PROMPT;
+
+    struct FakeServer {
+        listener: TcpListener,
+    }
+
+    impl FakeServer {
+        async fn start() -> (Self, String) {
+            let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let address = listener.local_addr().unwrap().to_string();
+            (FakeServer { listener }, address)
+        }
+
+        /// Greets the next client and returns the connection and the name the client sent
+        async fn accept(&self) -> (Framed<TcpStream, LinesCodec>, String) {
+            let (tcp_stream, _) = self.listener.accept().await.unwrap();
+            let mut client = Framed::new(tcp_stream, LinesCodec::new());
+            client.send(WELCOME_PROMPT).await.unwrap();
+            let user_name = client.next().await.unwrap().unwrap();
+            (client, user_name)
+        }
+    }
+
+    fn config(server_address: String, password: Option<&str>) -> ConnectionConfig {
+        ConnectionConfig {
+            server_address,
+            user_name: String::from("alice"),
+            password: password.map(String::from),
+            reconnect_delay: Duration::from_millis(10),
+        }
+    }
+
+    async fn next_event(
+        event_receiver: &mut UnboundedReceiver<ConnectionEvent>,
+    ) -> ConnectionEvent {
+        timeout(Duration::from_secs(5), event_receiver.recv())
+            .await
+            .unwrap()
+            .unwrap()
+    }
+
+    #[tokio::test]
+    async fn test_chat_and_reconnect() {
+        let (server, server_address) = FakeServer::start().await;
+        let (event_sender, mut event_receiver) = unbounded_channel();
+        let (input_sender, input_receiver) = unbounded_channel();
+        tokio::spawn(run_connection(
+            config(server_address, None),
+            event_sender,
+            input_receiver,
+        ));
+
+        let (mut client, user_name) = server.accept().await;
+        assert_eq!(user_name, "alice");
+        client.send("* The room contains: bob").await.unwrap();
+        assert_eq!(
+            next_event(&mut event_receiver).await,
+            ConnectionEvent::Connecting
+        );
+        assert_eq!(
+            next_event(&mut event_receiver).await,
+            ConnectionEvent::Joined(String::from("alice"), vec![String::from("bob")])
+        );
+
+        input_sender.send(String::from("hi bob")).unwrap();
+        assert_eq!(client.next().await.unwrap().unwrap(), "hi bob");
+        client.send("* alice is now known as alicia").await.unwrap();
+        assert_eq!(
+            next_event(&mut event_receiver).await,
+            ConnectionEvent::Line(String::from("* alice is now known as alicia"))
+        );
+
+        // Lost connection -> Joins again under the current name
+        drop(client);
+        assert_eq!(
+            next_event(&mut event_receiver).await,
+            ConnectionEvent::Disconnected(String::from("Connection closed by the server"))
+        );
+        let (mut client, user_name) = server.accept().await;
+        assert_eq!(user_name, "alicia");
+        client.send("* The room contains: -").await.unwrap();
+        assert_eq!(
+            next_event(&mut event_receiver).await,
+            ConnectionEvent::Connecting
+        );
+        assert_eq!(
+            next_event(&mut event_receiver).await,
+            ConnectionEvent::Joined(String::from("alicia"), Vec::new())
+        );
+    }
+
+    #[tokio::test]
+    async fn test_registered_name() {
+        let (server, server_address) = FakeServer::start().await;
+        let (event_sender, mut event_receiver) = unbounded_channel();
+        let (_input_sender, input_receiver) = unbounded_channel();
+        tokio::spawn(run_connection(
+            config(server_address.clone(), Some("secret")),
+            event_sender,
+            input_receiver,
+        ));
+
+        let (mut client, _) = server.accept().await;
+        client
+            .send("* Name alice is registered, what is your password?")
+            .await
+            .unwrap();
+        assert_eq!(client.next().await.unwrap().unwrap(), "secret");
+        client.send(WRONG_PASSWORD).await.unwrap();
+        assert_eq!(
+            next_event(&mut event_receiver).await,
+            ConnectionEvent::Connecting
+        );
+        assert_eq!(
+            next_event(&mut event_receiver).await,
+            ConnectionEvent::Failed(String::from("Wrong password for alice"))
+        );
+
+        // Without a password there is nothing to answer
+        let (event_sender, mut event_receiver) = unbounded_channel();
+        let (_input_sender, input_receiver) = unbounded_channel();
+        tokio::spawn(run_connection(
+            config(server_address, None),
+            event_sender,
+            input_receiver,
+        ));
+        let (mut client, _) = server.accept().await;
+        client
+            .send("* Name alice is registered, what is your password?")
+            .await
+            .unwrap();
+        assert_eq!(
+            next_event(&mut event_receiver).await,
+            ConnectionEvent::Connecting
+        );
+        assert_eq!(
+            next_event(&mut event_receiver).await,
+            ConnectionEvent::Failed(String::from(
+                "Name alice is registered, its password is needed"
+            ))
+        );
+    }
+}
@@ -0,0 +1,108 @@
+mod connection;
+mod protocol;
+mod state;
+mod ui;
+
+use std::env;
+use std::io::Result as IO_Result;
+use std::time::Duration;
+
+use crossterm::event::{Event, EventStream, KeyCode, KeyEvent, KeyEventKind, KeyModifiers};
+use futures::StreamExt;
+use ratatui::DefaultTerminal;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
+
+use crate::connection::{run_connection, ConnectionConfig, ConnectionEvent};
+use crate::state::{ChatState, ConnectionStatus};
+
+/// Lines scrolled by Page Up and Page Down
+const PAGE_LINES: usize = 10;
+
+/// Terminal client for the budget chat server:
+/// `budgetchat_client <name> [address]`, registered names take their password from
+/// `BUDGETCHAT_PASSWORD`
+#[tokio::main]
+async fn main() -> IO_Result<()> {
+    let mut arguments = env::args().skip(1);
+    let Some(user_name) = arguments.next() else {
+        eprintln!("Usage: budgetchat_client <name> [address]");
+        std::process::exit(2);
+    };
+    let config = ConnectionConfig {
+        server_address: arguments
+            .next()
+            .unwrap_or_else(|| String::from("127.0.0.1:8080")),
+        user_name,
+        password: env::var("BUDGETCHAT_PASSWORD").ok(),
+        reconnect_delay: Duration::from_secs(3),
+    };
+
+    let (event_sender, event_receiver) = unbounded_channel();
+    let (input_sender, input_receiver) = unbounded_channel();
+    let state = ChatState::new(&config.user_name);
+    tokio::spawn(run_connection(config, event_sender, input_receiver));
+
+    let mut terminal = ratatui::init();
+    let result = run_terminal(&mut terminal, state, event_receiver, input_sender).await;
+    ratatui::restore();
+    result
+}
+
+async fn run_terminal(
+    terminal: &mut DefaultTerminal,
+    mut state: ChatState,
+    mut event_receiver: UnboundedReceiver<ConnectionEvent>,
+    input_sender: UnboundedSender<String>,
+) -> IO_Result<()> {
+    let mut terminal_events = EventStream::new();
+    loop {
+        terminal.draw(|frame| ui::draw(frame, &state))?;
+
+        tokio::select! {
+            terminal_event = terminal_events.next() => match terminal_event {
+                Some(Ok(Event::Key(key_event))) if key_event.kind == KeyEventKind::Press => {
+                    if !handle_key(&mut state, key_event, &input_sender) {
+                        return Ok(());
+                    }
+                }
+                // Resizes only need a redraw
+                Some(Ok(_)) => {}
+                Some(Err(e)) => return Err(e),
+                None => return Ok(()),
+            },
+            Some(connection_event) = event_receiver.recv() => state.apply(connection_event),
+        }
+    }
+}
+
+/// Edits and sends the input line and scrolls the message pane. Returns false to quit.
+fn handle_key(
+    state: &mut ChatState,
+    key_event: KeyEvent,
+    input_sender: &UnboundedSender<String>,
+) -> bool {
+    let is_control = key_event.modifiers.contains(KeyModifiers::CONTROL);
+    match key_event.code {
+        KeyCode::Esc => return false,
+        KeyCode::Char('c' | 'd') if is_control => return false,
+        KeyCode::Char(c) if !is_control => state.input.push(c),
+        KeyCode::Backspace => {
+            state.input.pop();
+        }
+        KeyCode::Enter => {
+            if let Some(input_line) = state.take_input() {
+                if state.status == ConnectionStatus::Connected {
+                    let _ = input_sender.send(input_line);
+                } else {
+                    state.push_line(String::from("* Not connected, nothing was sent"));
+                }
+            }
+        }
+        KeyCode::Up => state.scroll_up(1),
+        KeyCode::Down => state.scroll_down(1),
+        KeyCode::PageUp => state.scroll_up(PAGE_LINES),
+        KeyCode::PageDown => state.scroll_down(PAGE_LINES),
+        _ => {}
+    }
+    true
+}
@@ -0,0 +1,144 @@
+/// First line of the server, answered with the user name
+pub const WELCOME_PROMPT: &str = "Welcome to budgetchat! What shall I call you?";
+/// Server's answer to a wrong password, the connection is closed afterwards
+pub const WRONG_PASSWORD: &str = "* Wrong password";
+
+/// What a line from the budget chat server means to the client
+#[derive(Debug, PartialEq)]
+pub enum ServerLine {
+    Welcome,
+    /// The chosen name is registered, the server waits for its password
+    PasswordPrompt,
+    /// Members that were in the room before joining it
+    Members(Vec<String>),
+    Joined(String),
+    Left(String),
+    Renamed(String, String),
+    Away(String),
+    Back(String),
+    Message(String, String),
+    /// Any other server line, e.g. replies to commands
+    Notice,
+}
+
+pub fn parse_server_line(line: &str) -> ServerLine {
+    if line == WELCOME_PROMPT {
+        return ServerLine::Welcome;
+    }
+    if let Some(message_line) = line.strip_prefix('[') {
+        return match message_line.split_once("] ") {
+            Some((user_name, message)) if is_user_name(user_name) => {
+                ServerLine::Message(String::from(user_name), String::from(message))
+            }
+            _ => ServerLine::Notice,
+        };
+    }
+    let Some(notice) = line.strip_prefix("* ") else {
+        return ServerLine::Notice;
+    };
+
+    if let Some(members) = notice.strip_prefix("The room contains: ") {
+        let members = match members {
+            "-" => Vec::new(),
+            members => members.split(", ").map(String::from).collect(),
+        };
+        return ServerLine::Members(members);
+    }
+    if let Some(user_name) = notice
+        .strip_prefix("Name ")
+        .and_then(|notice| notice.strip_suffix(" is registered, what is your password?"))
+    {
+        if is_user_name(user_name) {
+            return ServerLine::PasswordPrompt;
+        }
+    }
+
+    // Notices about a single user start with the user's name
+    let Some((user_name, announcement)) = notice.split_once(' ') else {
+        return ServerLine::Notice;
+    };
+    if !is_user_name(user_name) {
+        return ServerLine::Notice;
+    }
+    let user_name = String::from(user_name);
+    match announcement {
+        "has entered the room" => ServerLine::Joined(user_name),
+        "has left the room" => ServerLine::Left(user_name),
+        "is away" => ServerLine::Away(user_name),
+        "is back" => ServerLine::Back(user_name),
+        _ => match announcement.strip_prefix("is now known as ") {
+            Some(new_user_name) if is_user_name(new_user_name) => {
+                ServerLine::Renamed(user_name, String::from(new_user_name))
+            }
+            _ => ServerLine::Notice,
+        },
+    }
+}
+
+/// Names never contain spaces or punctuation, whatever the server's name policy is
+fn is_user_name(user_name: &str) -> bool {
+    !user_name.is_empty() && user_name.chars().all(char::is_alphanumeric)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_room_lines() {
+        assert_eq!(parse_server_line(WELCOME_PROMPT), ServerLine::Welcome);
+        assert_eq!(
+            parse_server_line("* Name alice is registered, what is your password?"),
+            ServerLine::PasswordPrompt
+        );
+        assert_eq!(
+            parse_server_line("* The room contains: bob, carol"),
+            ServerLine::Members(vec![String::from("bob"), String::from("carol")])
+        );
+        assert_eq!(
+            parse_server_line("* The room contains: -"),
+            ServerLine::Members(Vec::new())
+        );
+        assert_eq!(
+            parse_server_line("* bob has entered the room"),
+            ServerLine::Joined(String::from("bob"))
+        );
+        assert_eq!(
+            parse_server_line("* bob has left the room"),
+            ServerLine::Left(String::from("bob"))
+        );
+        assert_eq!(
+            parse_server_line("* bob is now known as robert"),
+            ServerLine::Renamed(String::from("bob"), String::from("robert"))
+        );
+        assert_eq!(
+            parse_server_line("* bob is away"),
+            ServerLine::Away(String::from("bob"))
+        );
+        assert_eq!(
+            parse_server_line("* bob is back"),
+            ServerLine::Back(String::from("bob"))
+        );
+        assert_eq!(
+            parse_server_line("[bob] * carol has left the room"),
+            ServerLine::Message(
+                String::from("bob"),
+                String::from("* carol has left the room")
+            )
+        );
+    }
+
+    #[test]
+    fn test_notices() {
+        for line in [
+            "* Name bob is already used",
+            "* 2023-11-14 22:13:20 alice told you: call me",
+            "* bob: connected 5m 3s, idle 2s",
+            "* You are now an operator",
+            "[not a name] hi",
+            "some other line",
+        ] {
+            assert_eq!(parse_server_line(line), ServerLine::Notice, "{line}");
+        }
+    }
+}
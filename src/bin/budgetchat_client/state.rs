@@ -0,0 +1,199 @@
+use std::collections::{BTreeMap, VecDeque};
+
+use crate::connection::ConnectionEvent;
+use crate::protocol::{parse_server_line, ServerLine};
+
+/// Lines kept for scrolling back
+const MAX_LINES: usize = 2000;
+
+#[derive(Debug, PartialEq)]
+pub enum ConnectionStatus {
+    Connecting,
+    Connected,
+    Disconnected,
+    /// No further connection attempts
+    Failed,
+}
+
+/// Everything the terminal shows
+pub struct ChatState {
+    pub status: ConnectionStatus,
+    /// Own name, as last accepted by the server
+    pub user_name: String,
+    pub lines: VecDeque<String>,
+    /// Room members and whether they are away, built from the join and leave notices
+    pub members: BTreeMap<String, bool>,
+    pub input: String,
+    /// Number of lines the message pane is scrolled up from the newest line
+    pub scroll_offset: usize,
+}
+
+impl ChatState {
+    pub fn new(user_name: &str) -> Self {
+        ChatState {
+            status: ConnectionStatus::Connecting,
+            user_name: String::from(user_name),
+            lines: VecDeque::new(),
+            members: BTreeMap::new(),
+            input: String::new(),
+            scroll_offset: 0,
+        }
+    }
+
+    pub fn apply(&mut self, event: ConnectionEvent) {
+        match event {
+            ConnectionEvent::Connecting => self.status = ConnectionStatus::Connecting,
+            ConnectionEvent::Joined(user_name, members) => {
+                self.status = ConnectionStatus::Connected;
+                self.members = members.into_iter().map(|member| (member, false)).collect();
+                self.members.insert(user_name.clone(), false);
+                self.push_line(format!("* Joined the room as {user_name}"));
+                self.user_name = user_name;
+            }
+            ConnectionEvent::Line(server_line) => {
+                self.follow_server_line(&server_line);
+                self.push_line(server_line);
+            }
+            ConnectionEvent::Disconnected(reason) => {
+                self.status = ConnectionStatus::Disconnected;
+                // Members are only known again after joining again
+                self.members.clear();
+                self.push_line(format!("* {reason}, reconnecting"));
+            }
+            ConnectionEvent::Failed(reason) => {
+                self.status = ConnectionStatus::Failed;
+                self.members.clear();
+                self.push_line(format!("* {reason}"));
+            }
+        }
+    }
+
+    fn follow_server_line(&mut self, server_line: &str) {
+        match parse_server_line(server_line) {
+            ServerLine::Joined(user_name) => {
+                self.members.insert(user_name, false);
+            }
+            ServerLine::Left(user_name) => {
+                self.members.remove(&user_name);
+            }
+            ServerLine::Renamed(old_user_name, new_user_name) => {
+                let is_away = self.members.remove(&old_user_name).unwrap_or(false);
+                self.members.insert(new_user_name.clone(), is_away);
+                if old_user_name == self.user_name {
+                    self.user_name = new_user_name;
+                }
+            }
+            ServerLine::Away(user_name) => {
+                self.members.insert(user_name, true);
+            }
+            ServerLine::Back(user_name) => {
+                self.members.insert(user_name, false);
+            }
+            _ => {}
+        }
+    }
+
+    /// Adds a line to the message pane. A scrolled pane keeps showing the same lines.
+    pub fn push_line(&mut self, line: String) {
+        if self.lines.len() == MAX_LINES {
+            self.lines.pop_front();
+        } else if self.scroll_offset > 0 {
+            self.scroll_offset += 1;
+        }
+        self.lines.push_back(line);
+    }
+
+    pub fn scroll_up(&mut self, line_count: usize) {
+        self.scroll_offset =
+            (self.scroll_offset + line_count).min(self.lines.len().saturating_sub(1));
+    }
+
+    pub fn scroll_down(&mut self, line_count: usize) {
+        self.scroll_offset = self.scroll_offset.saturating_sub(line_count);
+    }
+
+    /// Takes the input line for sending it
+    pub fn take_input(&mut self) -> Option<String> {
+        if self.input.trim().is_empty() {
+            return None;
+        }
+        self.scroll_offset = 0;
+        Some(std::mem::take(&mut self.input))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn member_names(state: &ChatState) -> Vec<&str> {
+        state.members.keys().map(String::as_str).collect()
+    }
+
+    #[test]
+    fn test_member_sidebar() {
+        let mut state = ChatState::new("alice");
+        state.apply(ConnectionEvent::Joined(
+            String::from("alice"),
+            vec![String::from("bob")],
+        ));
+        assert_eq!(state.status, ConnectionStatus::Connected);
+        assert_eq!(member_names(&state), ["alice", "bob"]);
+
+        for line in [
+            "* carol has entered the room",
+            "* bob is now known as robert",
+            "* robert is away",
+            "[carol] * dave has entered the room",
+        ] {
+            state.apply(ConnectionEvent::Line(String::from(line)));
+        }
+        assert_eq!(member_names(&state), ["alice", "carol", "robert"]);
+        assert!(state.members["robert"]);
+
+        state.apply(ConnectionEvent::Line(String::from("* robert is back")));
+        state.apply(ConnectionEvent::Line(String::from(
+            "* carol has left the room",
+        )));
+        state.apply(ConnectionEvent::Line(String::from(
+            "* alice is now known as alicia",
+        )));
+        assert_eq!(member_names(&state), ["alicia", "robert"]);
+        assert!(!state.members["robert"]);
+        assert_eq!(state.user_name, "alicia");
+
+        state.apply(ConnectionEvent::Disconnected(String::from(
+            "Connection closed",
+        )));
+        assert_eq!(state.status, ConnectionStatus::Disconnected);
+        assert!(state.members.is_empty());
+        assert_eq!(
+            state.lines.back().unwrap(),
+            "* Connection closed, reconnecting"
+        );
+    }
+
+    #[test]
+    fn test_scrolling() {
+        let mut state = ChatState::new("alice");
+        for number in 0..10 {
+            state.push_line(number.to_string());
+        }
+        state.scroll_up(3);
+        assert_eq!(state.scroll_offset, 3);
+        // New lines do not move a scrolled pane
+        state.push_line(String::from("10"));
+        assert_eq!(state.scroll_offset, 4);
+        state.scroll_up(100);
+        assert_eq!(state.scroll_offset, 10);
+        state.scroll_down(2);
+        assert_eq!(state.scroll_offset, 8);
+
+        state.input = String::from("hello");
+        assert_eq!(state.take_input(), Some(String::from("hello")));
+        assert_eq!(state.scroll_offset, 0);
+        assert_eq!(state.input, "");
+        state.input = String::from("  ");
+        assert_eq!(state.take_input(), None);
+    }
+}
@@ -0,0 +1,130 @@
+use ratatui::layout::{Constraint, Layout, Position, Rect};
+use ratatui::style::{Modifier, Style};
+use ratatui::text::Line;
+use ratatui::widgets::{Block, List, ListItem, Paragraph};
+use ratatui::Frame;
+
+use crate::state::{ChatState, ConnectionStatus};
+
+const SIDEBAR_WIDTH: u16 = 20;
+
+/// Message pane with the member sidebar next to it and the input line below
+pub fn draw(frame: &mut Frame, state: &ChatState) {
+    let [room_area, input_area] =
+        Layout::vertical([Constraint::Min(3), Constraint::Length(3)]).areas(frame.area());
+    let [messages_area, sidebar_area] =
+        Layout::horizontal([Constraint::Min(10), Constraint::Length(SIDEBAR_WIDTH)])
+            .areas(room_area);
+
+    draw_messages(frame, state, messages_area);
+    draw_members(frame, state, sidebar_area);
+    draw_input(frame, state, input_area);
+}
+
+fn draw_messages(frame: &mut Frame, state: &ChatState, area: Rect) {
+    let status = match state.status {
+        ConnectionStatus::Connecting => String::from("connecting"),
+        ConnectionStatus::Connected => format!("connected as {}", state.user_name),
+        ConnectionStatus::Disconnected => String::from("disconnected"),
+        ConnectionStatus::Failed => String::from("not connected"),
+    };
+    let mut block = Block::bordered().title(format!(" budgetchat - {status} "));
+    if state.scroll_offset > 0 {
+        block = block.title_bottom(format!(" {} newer lines below ", state.scroll_offset));
+    }
+
+    // Newest lines at the bottom, scrolling moves the window of visible lines up
+    let visible_count = usize::from(area.height.saturating_sub(2));
+    let last_visible = state.lines.len() - state.scroll_offset.min(state.lines.len());
+    let first_visible = last_visible.saturating_sub(visible_count);
+    let lines: Vec<Line> = state
+        .lines
+        .range(first_visible..last_visible)
+        .map(|line| Line::raw(line.as_str()))
+        .collect();
+    frame.render_widget(Paragraph::new(lines).block(block), area);
+}
+
+fn draw_members(frame: &mut Frame, state: &ChatState, area: Rect) {
+    let members = state.members.iter().map(|(user_name, is_away)| {
+        let style = if *is_away {
+            Style::default().add_modifier(Modifier::DIM)
+        } else if *user_name == state.user_name {
+            Style::default().add_modifier(Modifier::BOLD)
+        } else {
+            Style::default()
+        };
+        ListItem::new(user_name.as_str()).style(style)
+    });
+    frame.render_widget(
+        List::new(members)
+            .block(Block::bordered().title(format!(" members ({}) ", state.members.len()))),
+        area,
+    );
+}
+
+fn draw_input(frame: &mut Frame, state: &ChatState, area: Rect) {
+    // Only the end of long input lines fits
+    let input_width = usize::from(area.width.saturating_sub(2)).max(1);
+    let input_chars = state.input.chars().count();
+    let hidden_chars = (input_chars + 1).saturating_sub(input_width);
+    let visible_input: String = state.input.chars().skip(hidden_chars).collect();
+
+    frame.render_widget(
+        Paragraph::new(visible_input.as_str()).block(Block::bordered()),
+        area,
+    );
+    let cursor_offset = u16::try_from(input_chars - hidden_chars).unwrap_or(u16::MAX);
+    frame.set_cursor_position(Position::new(area.x + 1 + cursor_offset, area.y + 1));
+}
+
+#[cfg(test)]
+mod tests {
+    use ratatui::backend::TestBackend;
+    use ratatui::Terminal;
+
+    use super::*;
+    use crate::connection::ConnectionEvent;
+
+    fn screen_lines(terminal: &Terminal<TestBackend>) -> Vec<String> {
+        let buffer = terminal.backend().buffer();
+        (0..buffer.area.height)
+            .map(|y| {
+                (0..buffer.area.width)
+                    .map(|x| buffer[(x, y)].symbol())
+                    .collect::<String>()
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_chat_screen() {
+        let mut state = ChatState::new("alice");
+        state.apply(ConnectionEvent::Joined(
+            String::from("alice"),
+            vec![String::from("bob")],
+        ));
+        for number in 0..10 {
+            state.push_line(format!("[bob] message {number}"));
+        }
+        state.input = String::from("hello");
+
+        let mut terminal = Terminal::new(TestBackend::new(70, 10)).unwrap();
+        terminal.draw(|frame| draw(frame, &state)).unwrap();
+        let screen = screen_lines(&terminal);
+        assert!(screen[0].contains("connected as alice"));
+        assert!(screen[0].contains("members (2)"));
+        // Only the newest messages fit
+        assert!(screen[1].contains("[bob] message 5"));
+        assert!(screen[1].contains("alice"));
+        assert!(screen[2].contains("bob"));
+        assert!(screen[5].contains("[bob] message 9"));
+        assert!(screen[8].contains("hello"));
+
+        state.scroll_up(2);
+        terminal.draw(|frame| draw(frame, &state)).unwrap();
+        let screen = screen_lines(&terminal);
+        assert!(screen[6].contains("2 newer lines below"));
+        assert!(screen[5].contains("[bob] message 7"));
+    }
+}
@@ -0,0 +1,488 @@
+use std::io::ErrorKind;
+use std::net::{IpAddr, Ipv4Addr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+use futures::StreamExt;
+use tokio::net::UnixListener;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio_util::codec::{FramedRead, LinesCodec};
+
+use crate::event::RoomEvent;
+use crate::moderation::parse_duration;
+use crate::presence::sleep_until;
+use crate::session::{SessionEvent, SessionRegistry};
+
+/// How often the build status file is checked for changes
+const BUILD_STATUS_POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// Pending reminders per user, so that a single user cannot keep the reminder bot busy
+const MAX_REMINDERS_PER_USER: usize = 5;
+
+/// Helper that takes part in the room like a user. A bot only reacts to what happens in the
+/// room, to its feed and to its own wakeups, the lines it returns are posted as its messages.
+pub trait ChatBot: Send {
+    /// User name of the bot in the room
+    fn name(&self) -> &str;
+
+    /// Reacts to a room event. The bot does not see its own messages.
+    fn on_event(&mut self, event: &RoomEvent, now: Instant) -> Vec<String>;
+
+    /// Reacts to a line from the bot's feed, e.g. a file or socket it watches
+    fn on_feed(&mut self, _line: String, _now: Instant) -> Vec<String> {
+        Vec::new()
+    }
+
+    /// Time at which the bot wants to be woken up
+    fn next_wakeup(&self) -> Option<Instant> {
+        None
+    }
+
+    fn on_wakeup(&mut self, _now: Instant) -> Vec<String> {
+        Vec::new()
+    }
+}
+
+/// Built-in bots that can be enabled through the configuration
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BuiltinBot {
+    Echo,
+    Reminders,
+    BuildStatus,
+}
+
+impl FromStr for BuiltinBot {
+    type Err = String;
+
+    fn from_str(bot: &str) -> Result<Self, Self::Err> {
+        match bot.trim().to_lowercase().as_str() {
+            "echo" => Ok(BuiltinBot::Echo),
+            "reminders" => Ok(BuiltinBot::Reminders),
+            "build-status" => Ok(BuiltinBot::BuildStatus),
+            _ => Err(format!("Unknown bot {bot}")),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BotConfig {
+    pub bots: Vec<BuiltinBot>,
+    /// File whose content is the current build status
+    pub build_status_file: Option<PathBuf>,
+    /// Unix socket that accepts build status lines
+    pub build_status_socket: Option<PathBuf>,
+}
+
+/// Starts all configured bots
+pub fn spawn_bots(registry: &SessionRegistry) {
+    let config = registry.config().bots.clone();
+    for bot in config.bots {
+        let (chat_bot, feed): (Box<dyn ChatBot>, _) = match bot {
+            BuiltinBot::Echo => (Box::new(EchoBot), None),
+            BuiltinBot::Reminders => (Box::new(ReminderBot::default()), None),
+            BuiltinBot::BuildStatus => {
+                let feed = if let Some(socket_path) = &config.build_status_socket {
+                    socket_feed(socket_path.clone())
+                } else if let Some(file_path) = &config.build_status_file {
+                    Ok(file_feed(file_path.clone(), BUILD_STATUS_POLL_INTERVAL))
+                } else {
+                    println!("Build status bot needs a build status file or socket");
+                    continue;
+                };
+                match feed {
+                    Ok(feed) => (Box::new(BuildStatusBot::default()), Some(feed)),
+                    Err(e) => {
+                        println!("Failed to open build status socket: {e}");
+                        continue;
+                    }
+                }
+            }
+        };
+        tokio::spawn(run_bot(chat_bot, registry.clone(), feed));
+    }
+}
+
+/// Lets the bot take part in the room until it is removed from it. Bots join and post through
+/// the same sessions as users, so the room treats them like everyone else.
+pub async fn run_bot(
+    mut bot: Box<dyn ChatBot>,
+    registry: SessionRegistry,
+    mut feed: Option<Receiver<String>>,
+) {
+    let connection_id = registry.next_connection_id();
+    let bot_address = IpAddr::V4(Ipv4Addr::LOCALHOST);
+    let Ok((mut session, _)) = registry
+        .try_join(connection_id, bot_address, bot.name(), None)
+        .await
+    else {
+        println!(
+            "[{connection_id}] Bot {} could not join the room",
+            bot.name()
+        );
+        return;
+    };
+    println!("[{connection_id}] Bot joined: {}", bot.name());
+
+    loop {
+        let wakeup = bot.next_wakeup();
+        let replies = tokio::select! {
+            session_event = session.event_receiver.recv() => match session_event {
+                Some(SessionEvent::Room(room_event)) => bot.on_event(&room_event, Instant::now()),
+                // Private notices are meant for humans
+                Some(SessionEvent::Message(_)) => Vec::new(),
+                Some(SessionEvent::Disconnect(reason)) => {
+                    println!("[{connection_id}] Bot disconnected: {reason}");
+                    break;
+                }
+                None => break,
+            },
+            feed_line = next_feed_line(&mut feed) => match feed_line {
+                Some(line) => bot.on_feed(line, Instant::now()),
+                None => {
+                    // Feed is gone -> Only keep reacting to the room
+                    feed = None;
+                    Vec::new()
+                }
+            },
+            _ = sleep_until(wakeup) => bot.on_wakeup(Instant::now()),
+        };
+
+        for reply in replies {
+            session.broadcast_message(reply).await;
+        }
+    }
+}
+
+async fn next_feed_line(feed: &mut Option<Receiver<String>>) -> Option<String> {
+    match feed {
+        Some(feed) => feed.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+/// Feeds the trimmed content of the file whenever it changed. A missing file is treated like an
+/// empty one.
+pub fn file_feed(file_path: PathBuf, poll_interval: Duration) -> Receiver<String> {
+    let (line_sender, line_receiver) = channel(16);
+    tokio::spawn(async move {
+        let mut last_content = String::new();
+        loop {
+            let content = match tokio::fs::read_to_string(&file_path).await {
+                Ok(content) => String::from(content.trim()),
+                Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+                Err(e) => {
+                    println!("Failed to read {}: {e}", file_path.display());
+                    last_content.clone()
+                }
+            };
+            if content != last_content {
+                if !content.is_empty() && line_sender.send(content.clone()).await.is_err() {
+                    // Bot is gone
+                    break;
+                }
+                last_content = content;
+            }
+            tokio::time::sleep(poll_interval).await;
+        }
+    });
+    line_receiver
+}
+
+/// Feeds every line written to the Unix socket, from any number of connections
+pub fn socket_feed(socket_path: PathBuf) -> std::io::Result<Receiver<String>> {
+    // Socket file of a previous run would make binding fail
+    let _ = std::fs::remove_file(&socket_path);
+    let socket_listener = UnixListener::bind(&socket_path)?;
+    let (line_sender, line_receiver) = channel(16);
+    tokio::spawn(async move {
+        while let Ok((socket_stream, _)) = socket_listener.accept().await {
+            tokio::spawn(forward_lines(socket_stream, line_sender.clone()));
+        }
+    });
+    Ok(line_receiver)
+}
+
+async fn forward_lines(socket_stream: tokio::net::UnixStream, line_sender: Sender<String>) {
+    let mut lines = FramedRead::new(socket_stream, LinesCodec::new_with_max_length(1000));
+    while let Some(Ok(line)) = lines.next().await {
+        let line = String::from(line.trim());
+        if !line.is_empty() && line_sender.send(line).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// Repeats messages starting with `!echo`
+pub struct EchoBot;
+
+impl ChatBot for EchoBot {
+    fn name(&self) -> &str {
+        "echobot"
+    }
+
+    fn on_event(&mut self, event: &RoomEvent, _now: Instant) -> Vec<String> {
+        match event {
+            RoomEvent::Message(_, message) => message
+                .strip_prefix("!echo ")
+                .map(|text| vec![String::from(text.trim())])
+                .unwrap_or_default(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+struct Reminder {
+    due: Instant,
+    user_name: String,
+    text: String,
+}
+
+/// Reminds users of something after a while: `!remind <duration> <text>`
+#[derive(Default)]
+pub struct ReminderBot {
+    reminders: Vec<Reminder>,
+}
+
+impl ReminderBot {
+    fn add_reminder(&mut self, user_name: &str, request: &str, now: Instant) -> String {
+        let Some((duration, text)) = request.trim().split_once(' ') else {
+            return format!("{user_name}, usage: !remind <duration> <text>");
+        };
+        let Some(due) = parse_duration(duration).and_then(|duration| now.checked_add(duration))
+        else {
+            return format!("{user_name}, {duration} is not a valid duration");
+        };
+
+        let pending_reminders = self
+            .reminders
+            .iter()
+            .filter(|reminder| reminder.user_name == user_name)
+            .count();
+        if pending_reminders >= MAX_REMINDERS_PER_USER {
+            return format!("{user_name}, you have too many reminders already");
+        }
+
+        self.reminders.push(Reminder {
+            due,
+            user_name: String::from(user_name),
+            text: String::from(text.trim()),
+        });
+        format!("{user_name}, I will remind you in {duration}")
+    }
+}
+
+impl ChatBot for ReminderBot {
+    fn name(&self) -> &str {
+        "remindbot"
+    }
+
+    fn on_event(&mut self, event: &RoomEvent, now: Instant) -> Vec<String> {
+        match event {
+            RoomEvent::Message(user_name, message) => match message.strip_prefix("!remind") {
+                Some(request) => vec![self.add_reminder(user_name, request, now)],
+                None => Vec::new(),
+            },
+            // Reminders follow their users
+            RoomEvent::Renamed(old_user_name, new_user_name) => {
+                self.reminders
+                    .iter_mut()
+                    .filter(|reminder| reminder.user_name == *old_user_name)
+                    .for_each(|reminder| reminder.user_name = new_user_name.clone());
+                Vec::new()
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn next_wakeup(&self) -> Option<Instant> {
+        self.reminders.iter().map(|reminder| reminder.due).min()
+    }
+
+    fn on_wakeup(&mut self, now: Instant) -> Vec<String> {
+        let (due_reminders, pending_reminders) = std::mem::take(&mut self.reminders)
+            .into_iter()
+            .partition(|reminder| reminder.due <= now);
+        self.reminders = pending_reminders;
+        due_reminders
+            .into_iter()
+            .map(|reminder: Reminder| {
+                format!("{}, reminder: {}", reminder.user_name, reminder.text)
+            })
+            .collect()
+    }
+}
+
+/// Announces changes of the build status it is fed. Users can ask for the current status with
+/// `!build`.
+#[derive(Default)]
+pub struct BuildStatusBot {
+    /// Current status and the time it changed to it
+    status: Option<(String, Instant)>,
+}
+
+impl ChatBot for BuildStatusBot {
+    fn name(&self) -> &str {
+        "buildbot"
+    }
+
+    fn on_event(&mut self, event: &RoomEvent, now: Instant) -> Vec<String> {
+        match event {
+            RoomEvent::Message(_, message) if message.trim() == "!build" => {
+                vec![match &self.status {
+                    Some((status, changed_at)) => {
+                        let age = now.duration_since(*changed_at).as_secs();
+                        format!("Build status: {status} (since {age}s)")
+                    }
+                    None => String::from("Build status: unknown"),
+                }]
+            }
+            _ => Vec::new(),
+        }
+    }
+
+    fn on_feed(&mut self, line: String, now: Instant) -> Vec<String> {
+        if self
+            .status
+            .as_ref()
+            .is_some_and(|(status, _)| *status == line)
+        {
+            return Vec::new();
+        }
+        self.status = Some((line.clone(), now));
+        vec![format!("Build status: {line}")]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(user_name: &str, text: &str) -> RoomEvent {
+        RoomEvent::Message(String::from(user_name), String::from(text))
+    }
+
+    #[test]
+    fn test_echo_bot() {
+        let mut bot = EchoBot;
+        let now = Instant::now();
+        assert_eq!(
+            bot.on_event(&message("alice", "!echo hello there"), now),
+            vec![String::from("hello there")]
+        );
+        assert!(bot.on_event(&message("alice", "hello"), now).is_empty());
+        assert!(bot
+            .on_event(&RoomEvent::Joined(String::from("alice")), now)
+            .is_empty());
+    }
+
+    #[test]
+    fn test_reminder_bot() {
+        let mut bot = ReminderBot::default();
+        let now = Instant::now();
+        assert_eq!(bot.next_wakeup(), None);
+
+        assert_eq!(
+            bot.on_event(&message("alice", "!remind 10m tea"), now),
+            vec![String::from("alice, I will remind you in 10m")]
+        );
+        assert_eq!(
+            bot.on_event(&message("bob", "!remind 1m stretch"), now),
+            vec![String::from("bob, I will remind you in 1m")]
+        );
+        assert_eq!(
+            bot.on_event(&message("bob", "!remind soon stretch"), now),
+            vec![String::from("bob, soon is not a valid duration")]
+        );
+        assert_eq!(
+            bot.on_event(&message("bob", "!remind"), now),
+            vec![String::from("bob, usage: !remind <duration> <text>")]
+        );
+        assert_eq!(bot.next_wakeup(), Some(now + Duration::from_secs(60)));
+
+        bot.on_event(
+            &RoomEvent::Renamed(String::from("bob"), String::from("robert")),
+            now,
+        );
+        assert_eq!(
+            bot.on_wakeup(now + Duration::from_secs(60)),
+            vec![String::from("robert, reminder: stretch")]
+        );
+        assert_eq!(bot.next_wakeup(), Some(now + Duration::from_secs(600)));
+        assert_eq!(
+            bot.on_wakeup(now + Duration::from_secs(600)),
+            vec![String::from("alice, reminder: tea")]
+        );
+        assert_eq!(bot.next_wakeup(), None);
+    }
+
+    #[test]
+    fn test_reminder_limit() {
+        let mut bot = ReminderBot::default();
+        let now = Instant::now();
+        for _ in 0..MAX_REMINDERS_PER_USER {
+            bot.on_event(&message("alice", "!remind 1h tea"), now);
+        }
+        assert_eq!(
+            bot.on_event(&message("alice", "!remind 1h tea"), now),
+            vec![String::from("alice, you have too many reminders already")]
+        );
+    }
+
+    #[test]
+    fn test_build_status_bot() {
+        let mut bot = BuildStatusBot::default();
+        let now = Instant::now();
+        assert_eq!(
+            bot.on_event(&message("alice", "!build"), now),
+            vec![String::from("Build status: unknown")]
+        );
+        assert_eq!(
+            bot.on_feed(String::from("passed"), now),
+            vec![String::from("Build status: passed")]
+        );
+        // Unchanged status is not announced again
+        assert!(bot.on_feed(String::from("passed"), now).is_empty());
+        assert_eq!(
+            bot.on_event(&message("alice", "!build"), now + Duration::from_secs(5)),
+            vec![String::from("Build status: passed (since 5s)")]
+        );
+    }
+
+    #[test]
+    fn test_bot_parsing() {
+        assert_eq!("Echo".parse(), Ok(BuiltinBot::Echo));
+        assert_eq!(" build-status ".parse(), Ok(BuiltinBot::BuildStatus));
+        assert!("chess".parse::<BuiltinBot>().is_err());
+    }
+
+    #[tokio::test]
+    async fn test_file_feed() {
+        let file_path =
+            std::env::temp_dir().join(format!("budgetchat-build-{}.txt", std::process::id()));
+        let _ = std::fs::remove_file(&file_path);
+        let mut feed = file_feed(file_path.clone(), Duration::from_millis(10));
+
+        std::fs::write(&file_path, "failed\n").unwrap();
+        assert_eq!(feed.recv().await, Some(String::from("failed")));
+        std::fs::write(&file_path, "passed\n").unwrap();
+        assert_eq!(feed.recv().await, Some(String::from("passed")));
+
+        std::fs::remove_file(&file_path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_socket_feed() {
+        let socket_path =
+            std::env::temp_dir().join(format!("budgetchat-build-{}.sock", std::process::id()));
+        let mut feed = socket_feed(socket_path.clone()).unwrap();
+
+        let mut socket_stream = tokio::net::UnixStream::connect(&socket_path).await.unwrap();
+        tokio::io::AsyncWriteExt::write_all(&mut socket_stream, b"passed\n\nfailed: tests\n")
+            .await
+            .unwrap();
+        assert_eq!(feed.recv().await, Some(String::from("passed")));
+        assert_eq!(feed.recv().await, Some(String::from("failed: tests")));
+
+        std::fs::remove_file(&socket_path).unwrap();
+    }
+}
@@ -0,0 +1,207 @@
+use std::io::{Error as IO_Error, Result as IO_Result};
+use std::ops::ControlFlow;
+use std::time::Instant;
+
+use futures::{SinkExt, StreamExt};
+
+use crate::command::{execute_chat_command, parse_chat_command, redact_chat_command};
+use crate::presence::{sleep_until, IdleTracker};
+use crate::rate_limit::{FloodGuard, FloodVerdict};
+use crate::session::{Session, SessionEvent, SessionRegistry};
+use crate::transport::{ClientLine, LineReader, LineWriter};
+
+pub enum UserPreambleError<D> {
+    Protocol(D),
+    IO(IO_Error),
+}
+
+pub struct ChatRoomClient {
+    pub connection_id: i32,
+    pub session: Option<Session>,
+    pub socket_reader: LineReader,
+    pub socket_writer: LineWriter,
+    pub flood_guard: FloodGuard,
+    pub idle_tracker: IdleTracker,
+    /// Throttled message and when it is due, the user is not read from until it was sent
+    pub throttled_message: Option<(Instant, String)>,
+}
+
+impl ChatRoomClient {
+    pub fn new(
+        connection_id: i32,
+        (socket_reader, socket_writer): (LineReader, LineWriter),
+        registry: &SessionRegistry,
+    ) -> Self {
+        ChatRoomClient {
+            connection_id,
+            session: None,
+            socket_reader,
+            socket_writer,
+            flood_guard: FloodGuard::new(registry.config().flood_limits.clone(), Instant::now()),
+            idle_tracker: IdleTracker::new(registry.config().away_after, Instant::now()),
+            throttled_message: None,
+        }
+    }
+
+    pub async fn send_message_to_user(&mut self, message: String) -> IO_Result<()> {
+        self.socket_writer.send(message).await
+    }
+
+    fn session(&self) -> &Session {
+        self.session.as_ref().unwrap()
+    }
+
+    /// Asks for the user name and, for registered names, for the password
+    pub async fn client_join_preamble(
+        &mut self,
+        registry: &SessionRegistry,
+    ) -> Result<(String, Option<String>), UserPreambleError<String>> {
+        // Send user name input prompt
+        self.send_message_to_user(String::from(
+            "Welcome to budgetchat! What shall I call you?",
+        ))
+        .await
+        .map_err(UserPreambleError::IO)?;
+
+        // Await user name input
+        let name_input = self.read_preamble_line().await?;
+        // Check if input is a valid user name
+        let Some(user_name) = name_input
+            .and_then(|name_input| registry.config().name_policy.check(name_input.trim()))
+        else {
+            return Err(UserPreambleError::Protocol(String::from(
+                "INVALID_USER_NAME",
+            )));
+        };
+        if !registry.is_registered(&user_name).await {
+            return Ok((user_name, None));
+        }
+
+        // Registered name -> Only its owner may join under it
+        self.send_message_to_user(format!(
+            "* Name {user_name} is registered, what is your password?"
+        ))
+        .await
+        .map_err(UserPreambleError::IO)?;
+        match self.read_preamble_line().await? {
+            Some(password) => Ok((user_name, Some(password))),
+            None => Err(UserPreambleError::Protocol(String::from(
+                "MISSING_PASSWORD",
+            ))),
+        }
+    }
+
+    async fn read_preamble_line(&mut self) -> Result<Option<String>, UserPreambleError<String>> {
+        match self.socket_reader.next().await {
+            Some(Ok(ClientLine::Line(line))) => Ok(Some(line)),
+            Some(Ok(ClientLine::TooLong)) => {
+                Err(UserPreambleError::Protocol(String::from("LINE_TOO_LONG")))
+            }
+            Some(Err(e)) => Err(UserPreambleError::IO(e)),
+            None => Ok(None),
+        }
+    }
+
+    /// Applies the flood limits to a line from the user before it is executed or broadcast.
+    /// Breaks if the user has to be disconnected.
+    async fn process_user_line(&mut self, user_line: ClientLine) -> IO_Result<ControlFlow<()>> {
+        self.idle_tracker.record_activity(Instant::now());
+        self.session().record_activity().await;
+
+        let (flood_verdict, user_line) = match user_line {
+            ClientLine::Line(user_line) => (
+                self.flood_guard.check(&user_line, Instant::now()),
+                user_line,
+            ),
+            // Too long lines are never allowed, so their content is not needed
+            ClientLine::TooLong => (self.flood_guard.check_too_long_line(), String::new()),
+        };
+        match flood_verdict {
+            FloodVerdict::Allow => self.process_user_message(user_line).await?,
+            FloodVerdict::Delay(waiting_time) => {
+                // Room events keep being delivered while the message waits
+                self.throttled_message = Some((Instant::now() + waiting_time, user_line));
+            }
+            FloodVerdict::Reject(reason) => {
+                println!("[{}] Rejected message: {reason}", self.connection_id);
+                self.send_message_to_user(format!("* {reason}")).await?;
+            }
+            FloodVerdict::Disconnect(reason) => {
+                println!("[{}] Disconnected: {reason}", self.connection_id);
+                self.send_message_to_user(format!("* {reason}")).await?;
+                return Ok(ControlFlow::Break(()));
+            }
+        }
+        Ok(ControlFlow::Continue(()))
+    }
+
+    async fn process_user_message(&mut self, user_message: String) -> IO_Result<()> {
+        match parse_chat_command(&user_message) {
+            Some(Ok(command)) => {
+                println!(
+                    "[{}] Issued command: {}",
+                    self.connection_id,
+                    redact_chat_command(&user_message)
+                );
+                let reply = execute_chat_command(self.session.as_mut().unwrap(), command).await;
+                for reply_line in reply {
+                    self.send_message_to_user(reply_line).await?;
+                }
+                Ok(())
+            }
+            Some(Err(usage)) => self.send_message_to_user(format!("* {usage}")).await,
+            None if self.session().registry.is_muted(self.session()).await => {
+                self.send_message_to_user(String::from("* You are muted"))
+                    .await
+            }
+            None => {
+                println!("[{}] Wrote message: {user_message}", self.connection_id);
+                self.session().broadcast_message(user_message).await;
+                Ok(())
+            }
+        }
+    }
+
+    pub async fn process_message_interchange(&mut self) -> IO_Result<()> {
+        loop {
+            let away_at = self.idle_tracker.away_at();
+            let throttled_until = self.throttled_message.as_ref().map(|(due_at, _)| *due_at);
+            let event_receiver = &mut self.session.as_mut().unwrap().event_receiver;
+            tokio::select! {
+                user_message_line = self.socket_reader.next(), if throttled_until.is_none() => match user_message_line {
+                    Some(Ok(user_line)) => {
+                        if self.process_user_line(user_line).await?.is_break() {
+                            break;
+                        }
+                    }
+                    _ => break,
+                },
+                _ = sleep_until(throttled_until) => {
+                    let (_, user_message) = self.throttled_message.take().unwrap();
+                    self.process_user_message(user_message).await?;
+                }
+                session_event = event_receiver.recv() => match session_event {
+                    Some(SessionEvent::Room(room_event)) => {
+                        self.session.as_mut().unwrap().follow_room_event(&room_event);
+                        self.send_message_to_user(room_event.to_string()).await?;
+                    }
+                    Some(SessionEvent::Message(message)) => {
+                        self.send_message_to_user(message).await?;
+                    }
+                    Some(SessionEvent::Disconnect(reason)) => {
+                        println!("[{}] Disconnected: {reason}", self.connection_id);
+                        self.send_message_to_user(reason).await?;
+                        break;
+                    }
+                    // Removed from the room for falling behind
+                    None => break,
+                },
+                _ = sleep_until(away_at) => {
+                    self.idle_tracker.mark_away();
+                    self.session().mark_away().await;
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,335 @@
+use std::time::Duration;
+
+use crate::moderation::{parse_duration, ModerationTarget};
+use crate::presence::MemberPresence;
+use crate::session::{
+    Delivery, ModerationError, RegistrationError, RenameError, Session, TellError,
+};
+
+#[derive(Debug, PartialEq)]
+pub enum ChatCommand {
+    Nick(String),
+    Op(String),
+    Kick(String),
+    Mute(String, Option<Duration>),
+    Unmute(String),
+    Ban(ModerationTarget, Option<Duration>),
+    Unban(ModerationTarget),
+    Search(String),
+    Register(String),
+    Tell(String, String),
+    Who,
+}
+
+/// Parses a user message starting with a known command. Returns `None` for regular messages and
+/// the command's usage for malformed commands.
+pub fn parse_chat_command(user_message: &str) -> Option<Result<ChatCommand, String>> {
+    let (command, argument) = user_message.split_once(' ').unwrap_or((user_message, ""));
+    let arguments: Vec<&str> = argument.split_whitespace().collect();
+
+    let parsed_command = match command {
+        "/nick" => Ok(ChatCommand::Nick(String::from(argument.trim()))),
+        "/op" => match arguments[..] {
+            [password] => Ok(ChatCommand::Op(String::from(password))),
+            _ => Err("/op <password>"),
+        },
+        "/kick" => match arguments[..] {
+            [user_name] => Ok(ChatCommand::Kick(String::from(user_name))),
+            _ => Err("/kick <name>"),
+        },
+        "/mute" => match arguments[..] {
+            [user_name] => Ok(ChatCommand::Mute(String::from(user_name), None)),
+            [user_name, duration] => parse_duration(duration)
+                .map(|duration| ChatCommand::Mute(String::from(user_name), Some(duration)))
+                .ok_or("/mute <name> [duration]"),
+            _ => Err("/mute <name> [duration]"),
+        },
+        "/unmute" => match arguments[..] {
+            [user_name] => Ok(ChatCommand::Unmute(String::from(user_name))),
+            _ => Err("/unmute <name>"),
+        },
+        "/ban" => match arguments[..] {
+            [target] => Ok(ChatCommand::Ban(ModerationTarget::parse(target), None)),
+            [target, duration] => parse_duration(duration)
+                .map(|duration| ChatCommand::Ban(ModerationTarget::parse(target), Some(duration)))
+                .ok_or("/ban <name|address> [duration]"),
+            _ => Err("/ban <name|address> [duration]"),
+        },
+        "/unban" => match arguments[..] {
+            [target] => Ok(ChatCommand::Unban(ModerationTarget::parse(target))),
+            _ => Err("/unban <name|address>"),
+        },
+        "/register" => match arguments[..] {
+            [password] => Ok(ChatCommand::Register(String::from(password))),
+            _ => Err("/register <password>"),
+        },
+        "/tell" => match argument.trim().split_once(' ') {
+            Some((recipient, text)) if !text.trim().is_empty() => Ok(ChatCommand::Tell(
+                String::from(recipient),
+                String::from(text.trim()),
+            )),
+            _ => Err("/tell <name> <text>"),
+        },
+        "/who" => match arguments[..] {
+            [] => Ok(ChatCommand::Who),
+            _ => Err("/who"),
+        },
+        "/search" => match argument.trim() {
+            "" => Err("/search <term>"),
+            term => Ok(ChatCommand::Search(String::from(term))),
+        },
+        _ => return None,
+    };
+
+    Some(parsed_command.map_err(|usage| format!("Usage: {usage}")))
+}
+
+/// Executes the command on behalf of the session and returns the reply lines for the issuing user
+pub async fn execute_chat_command(session: &mut Session, command: ChatCommand) -> Vec<String> {
+    let registry = session.registry.clone();
+    let connection_id = session.connection_id;
+
+    let reply = match command {
+        ChatCommand::Nick(new_user_name) => {
+            let old_user_name = session.user_name.clone();
+            match registry.try_rename(session, &new_user_name).await {
+                Ok(()) => {
+                    let new_user_name = &session.user_name;
+                    println!("[{connection_id}] Renamed {old_user_name} to {new_user_name}");
+                    format!("* {old_user_name} is now known as {new_user_name}")
+                }
+                Err(RenameError::InvalidName) => {
+                    format!("* Name {new_user_name} is not a valid user name")
+                }
+                Err(RenameError::NameTaken) => format!("* Name {new_user_name} is already used"),
+                Err(RenameError::NameRegistered) => {
+                    format!(
+                        "* Name {new_user_name} is registered, join with its password to use it"
+                    )
+                }
+            }
+        }
+        ChatCommand::Op(password) => {
+            if registry.try_become_operator(session, &password).await {
+                println!("[{connection_id}] Became operator");
+                String::from("* You are now an operator")
+            } else {
+                String::from("* Invalid operator password")
+            }
+        }
+        ChatCommand::Kick(user_name) => registry
+            .kick(session, &user_name)
+            .await
+            .map(|_| format!("* Kicked {user_name}"))
+            .unwrap_or_else(moderation_error_reply),
+        ChatCommand::Mute(user_name, duration) => registry
+            .mute(session, &user_name, duration)
+            .await
+            .map(|_| format!("* Muted {user_name}"))
+            .unwrap_or_else(moderation_error_reply),
+        ChatCommand::Unmute(user_name) => registry
+            .unmute(session, &user_name)
+            .await
+            .map(|_| format!("* Unmuted {user_name}"))
+            .unwrap_or_else(moderation_error_reply),
+        ChatCommand::Ban(target, duration) => registry
+            .ban(session, &target, duration)
+            .await
+            .map(|address| format!("* Banned {address}"))
+            .unwrap_or_else(moderation_error_reply),
+        ChatCommand::Unban(target) => registry
+            .unban(session, &target)
+            .await
+            .map(|_| String::from("* Ban lifted"))
+            .unwrap_or_else(moderation_error_reply),
+        ChatCommand::Register(password) => match registry.register(session, &password).await {
+            Ok(()) => {
+                println!("[{connection_id}] Registered {}", session.user_name);
+                format!("* Registered name {}", session.user_name)
+            }
+            Err(RegistrationError::Disabled) => String::from("* Registration is disabled"),
+            Err(RegistrationError::NotSaved) => String::from("* Registration failed"),
+        },
+        ChatCommand::Tell(recipient, text) => {
+            match registry.tell(session, &recipient, &text).await {
+                Ok(Delivery::Delivered) => format!("* Told {recipient}"),
+                Ok(Delivery::Queued) => {
+                    format!("* {recipient} will get your message when joining")
+                }
+                Err(TellError::UnknownRecipient) => format!("* Unknown user {recipient}"),
+                Err(TellError::MailboxFull) => format!("* Mailbox of {recipient} is full"),
+                Err(TellError::Muted) => String::from("* You are muted"),
+            }
+        }
+        ChatCommand::Who => {
+            return registry
+                .who()
+                .await
+                .iter()
+                .map(MemberPresence::to_line)
+                .collect();
+        }
+        ChatCommand::Search(term) => {
+            let matching_lines = registry.search_transcript(&term).await;
+            if matching_lines.is_empty() {
+                format!("* No messages found for {term}")
+            } else {
+                return matching_lines;
+            }
+        }
+    };
+    vec![reply]
+}
+
+/// The user message as it may be logged, without passwords
+pub fn redact_chat_command(user_message: &str) -> &str {
+    match user_message.split_once(' ') {
+        Some((command @ ("/op" | "/register"), _)) => command,
+        _ => user_message,
+    }
+}
+
+fn moderation_error_reply(error: ModerationError) -> String {
+    match error {
+        ModerationError::NotAnOperator => String::from("* Only operators can do that"),
+        ModerationError::UnknownUser(user_name) => format!("* There is no user {user_name}"),
+        ModerationError::NotBanned => String::from("* No matching ban found"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_chat_command_parsing() {
+        assert_eq!(
+            parse_chat_command("/nick bob"),
+            Some(Ok(ChatCommand::Nick(String::from("bob"))))
+        );
+        assert_eq!(
+            parse_chat_command("/nick  bob "),
+            Some(Ok(ChatCommand::Nick(String::from("bob"))))
+        );
+        assert_eq!(
+            parse_chat_command("/nick"),
+            Some(Ok(ChatCommand::Nick(String::new())))
+        );
+
+        assert_eq!(
+            parse_chat_command("/search hello world"),
+            Some(Ok(ChatCommand::Search(String::from("hello world"))))
+        );
+        assert_eq!(
+            parse_chat_command("/search "),
+            Some(Err(String::from("Usage: /search <term>")))
+        );
+
+        assert_eq!(
+            parse_chat_command("/register secret"),
+            Some(Ok(ChatCommand::Register(String::from("secret"))))
+        );
+        assert_eq!(
+            parse_chat_command("/register"),
+            Some(Err(String::from("Usage: /register <password>")))
+        );
+
+        assert_eq!(
+            parse_chat_command("/tell bob  see you later "),
+            Some(Ok(ChatCommand::Tell(
+                String::from("bob"),
+                String::from("see you later")
+            )))
+        );
+        assert_eq!(
+            parse_chat_command("/tell bob"),
+            Some(Err(String::from("Usage: /tell <name> <text>")))
+        );
+        assert_eq!(
+            parse_chat_command("/tell bob  "),
+            Some(Err(String::from("Usage: /tell <name> <text>")))
+        );
+
+        assert_eq!(parse_chat_command("/who"), Some(Ok(ChatCommand::Who)));
+        assert_eq!(
+            parse_chat_command("/who bob"),
+            Some(Err(String::from("Usage: /who")))
+        );
+
+        assert_eq!(parse_chat_command("nick bob"), None);
+        assert_eq!(parse_chat_command("/nickname bob"), None);
+        assert_eq!(parse_chat_command("hello /nick bob"), None);
+    }
+
+    #[test]
+    fn test_moderation_command_parsing() {
+        assert_eq!(
+            parse_chat_command("/op secret"),
+            Some(Ok(ChatCommand::Op(String::from("secret"))))
+        );
+        assert_eq!(
+            parse_chat_command("/kick bob"),
+            Some(Ok(ChatCommand::Kick(String::from("bob"))))
+        );
+        assert_eq!(
+            parse_chat_command("/mute bob"),
+            Some(Ok(ChatCommand::Mute(String::from("bob"), None)))
+        );
+        assert_eq!(
+            parse_chat_command("/mute bob 5m"),
+            Some(Ok(ChatCommand::Mute(
+                String::from("bob"),
+                Some(Duration::from_secs(300))
+            )))
+        );
+        assert_eq!(
+            parse_chat_command("/unmute bob"),
+            Some(Ok(ChatCommand::Unmute(String::from("bob"))))
+        );
+        assert_eq!(
+            parse_chat_command("/ban bob 1h"),
+            Some(Ok(ChatCommand::Ban(
+                ModerationTarget::UserName(String::from("bob")),
+                Some(Duration::from_secs(3600))
+            )))
+        );
+        assert_eq!(
+            parse_chat_command("/ban 10.0.0.1"),
+            Some(Ok(ChatCommand::Ban(
+                ModerationTarget::Address("10.0.0.1".parse().unwrap()),
+                None
+            )))
+        );
+        assert_eq!(
+            parse_chat_command("/unban 10.0.0.1"),
+            Some(Ok(ChatCommand::Unban(ModerationTarget::Address(
+                "10.0.0.1".parse().unwrap()
+            ))))
+        );
+
+        assert_eq!(
+            parse_chat_command("/op"),
+            Some(Err(String::from("Usage: /op <password>")))
+        );
+        assert_eq!(
+            parse_chat_command("/kick bob alice"),
+            Some(Err(String::from("Usage: /kick <name>")))
+        );
+        assert_eq!(
+            parse_chat_command("/mute bob forever"),
+            Some(Err(String::from("Usage: /mute <name> [duration]")))
+        );
+        assert_eq!(
+            parse_chat_command("/ban"),
+            Some(Err(String::from("Usage: /ban <name|address> [duration]")))
+        );
+    }
+
+    #[test]
+    fn test_password_redaction() {
+        assert_eq!(redact_chat_command("/register secret"), "/register");
+        assert_eq!(redact_chat_command("/op secret"), "/op");
+        assert_eq!(redact_chat_command("/kick bob"), "/kick bob");
+        assert_eq!(redact_chat_command("/register"), "/register");
+    }
+}
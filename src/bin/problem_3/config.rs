@@ -0,0 +1,257 @@
+use std::collections::HashSet;
+use std::env;
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::bot::BotConfig;
+use crate::federation::FederationConfig;
+use crate::filter::FilterConfig;
+use crate::mailbox::MailboxConfig;
+use crate::moderation::parse_duration;
+use crate::name_policy::NamePolicy;
+use crate::rate_limit::{FloodLimits, RateLimit};
+use crate::transcript::TranscriptConfig;
+
+/// Optional server settings, read from `BUDGETCHAT_*` environment variables
+#[derive(Clone, Debug)]
+pub struct ChatServerConfig {
+    /// Password that grants operator rights through `/op <password>`
+    pub operator_password: Option<String>,
+    /// User names that are granted operator rights when joining
+    pub operator_names: HashSet<String>,
+    /// Per-user rate limits and maximum message length
+    pub flood_limits: FloodLimits,
+    /// Port of the optional WebSocket listener
+    pub websocket_port: Option<u16>,
+    /// Port of the optional IRC listener
+    pub irc_port: Option<u16>,
+    /// Location, rotation and searchable history of the chat transcript
+    pub transcript: TranscriptConfig,
+    /// Number of room events that may be waiting for a session before it is removed from the room
+    pub session_queue_capacity: usize,
+    /// Number of commands that may be waiting for the room before sessions have to wait
+    pub room_queue_capacity: usize,
+    /// File with the password hashes of registered names, registration is disabled without it
+    pub credentials_file: Option<PathBuf>,
+    /// Location and per-recipient capacity of the `/tell` mailboxes
+    pub mailbox: MailboxConfig,
+    /// Filters applied to chat messages before they are broadcast
+    pub message_filters: FilterConfig,
+    /// Bots that take part in the room
+    pub bots: BotConfig,
+    /// Link to another server that shares the room
+    pub federation: FederationConfig,
+    /// Rules for user names
+    pub name_policy: NamePolicy,
+    /// Users are announced as away after this long without sending anything, never without it
+    pub away_after: Option<Duration>,
+}
+
+impl Default for ChatServerConfig {
+    fn default() -> Self {
+        ChatServerConfig {
+            operator_password: None,
+            operator_names: HashSet::new(),
+            flood_limits: FloodLimits::default(),
+            websocket_port: None,
+            irc_port: None,
+            transcript: TranscriptConfig::default(),
+            session_queue_capacity: 1024,
+            room_queue_capacity: 1024,
+            credentials_file: None,
+            mailbox: MailboxConfig::default(),
+            message_filters: FilterConfig::default(),
+            bots: BotConfig::default(),
+            federation: FederationConfig::default(),
+            name_policy: NamePolicy::default(),
+            away_after: None,
+        }
+    }
+}
+
+impl ChatServerConfig {
+    pub fn from_env() -> Self {
+        ChatServerConfig {
+            operator_password: env_var("BUDGETCHAT_OPERATOR_PASSWORD"),
+            operator_names: env_var("BUDGETCHAT_OPERATORS")
+                .map(|names| parse_list(&names))
+                .unwrap_or_default(),
+            flood_limits: flood_limits_from_env(),
+            websocket_port: parsed_env_var("BUDGETCHAT_WEBSOCKET_PORT"),
+            irc_port: parsed_env_var("BUDGETCHAT_IRC_PORT"),
+            transcript: transcript_config_from_env(),
+            session_queue_capacity: parsed_env_var("BUDGETCHAT_SESSION_QUEUE")
+                .filter(|capacity| *capacity > 0)
+                .unwrap_or(ChatServerConfig::default().session_queue_capacity),
+            room_queue_capacity: parsed_env_var("BUDGETCHAT_ROOM_QUEUE")
+                .filter(|capacity| *capacity > 0)
+                .unwrap_or(ChatServerConfig::default().room_queue_capacity),
+            credentials_file: env_var("BUDGETCHAT_CREDENTIALS_FILE").map(PathBuf::from),
+            mailbox: MailboxConfig {
+                file: env_var("BUDGETCHAT_MAILBOX_FILE").map(PathBuf::from),
+                capacity: parsed_env_var("BUDGETCHAT_MAILBOX_CAPACITY")
+                    .filter(|capacity| *capacity > 0)
+                    .unwrap_or(MailboxConfig::default().capacity),
+            },
+            message_filters: filter_config_from_env(),
+            bots: BotConfig {
+                bots: parsed_list_env_var("BUDGETCHAT_BOTS"),
+                build_status_file: env_var("BUDGETCHAT_BUILD_STATUS_FILE").map(PathBuf::from),
+                build_status_socket: env_var("BUDGETCHAT_BUILD_STATUS_SOCKET").map(PathBuf::from),
+            },
+            federation: federation_config_from_env(),
+            name_policy: name_policy_from_env(),
+            away_after: duration_env_var("BUDGETCHAT_AWAY_AFTER"),
+        }
+    }
+}
+
+fn transcript_config_from_env() -> TranscriptConfig {
+    let default_config = TranscriptConfig::default();
+    TranscriptConfig {
+        directory: env_var("BUDGETCHAT_TRANSCRIPT_DIR").map(PathBuf::from),
+        max_file_bytes: parsed_env_var("BUDGETCHAT_TRANSCRIPT_MAX_BYTES")
+            .unwrap_or(default_config.max_file_bytes),
+        max_file_age: duration_env_var("BUDGETCHAT_TRANSCRIPT_MAX_AGE")
+            .unwrap_or(default_config.max_file_age),
+        search_history: parsed_env_var("BUDGETCHAT_SEARCH_HISTORY")
+            .unwrap_or(default_config.search_history),
+    }
+}
+
+fn federation_config_from_env() -> FederationConfig {
+    let default_config = FederationConfig::default();
+    FederationConfig {
+        server_name: env_var("BUDGETCHAT_SERVER_NAME").unwrap_or(default_config.server_name),
+        port: parsed_env_var("BUDGETCHAT_FEDERATION_PORT"),
+        peer_address: env_var("BUDGETCHAT_FEDERATION_PEER"),
+        reconnect_delay: duration_env_var("BUDGETCHAT_FEDERATION_RECONNECT")
+            .unwrap_or(default_config.reconnect_delay),
+    }
+}
+
+fn name_policy_from_env() -> NamePolicy {
+    let default_policy = NamePolicy::default();
+    NamePolicy {
+        max_length: parsed_env_var("BUDGETCHAT_NAME_MAX_LENGTH")
+            .filter(|max_length| *max_length > 0)
+            .unwrap_or(default_policy.max_length),
+        length_unit: parsed_env_var("BUDGETCHAT_NAME_LENGTH_UNIT")
+            .unwrap_or(default_policy.length_unit),
+        charset: parsed_env_var("BUDGETCHAT_NAME_CHARSET").unwrap_or(default_policy.charset),
+        normalize: parsed_env_var("BUDGETCHAT_NAME_NORMALIZE").unwrap_or(default_policy.normalize),
+        case_insensitive: parsed_env_var("BUDGETCHAT_NAME_CASE_INSENSITIVE")
+            .unwrap_or(default_policy.case_insensitive),
+    }
+}
+
+fn filter_config_from_env() -> FilterConfig {
+    let default_config = FilterConfig::default();
+    let ordered_list = |list: String| -> Vec<String> {
+        list.split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+            .map(String::from)
+            .collect()
+    };
+    FilterConfig {
+        filters: parsed_list_env_var("BUDGETCHAT_MESSAGE_FILTERS"),
+        profanity_words: env_var("BUDGETCHAT_PROFANITY_WORDS")
+            .map(ordered_list)
+            .unwrap_or(default_config.profanity_words),
+        flag_words: env_var("BUDGETCHAT_FLAG_WORDS")
+            .map(ordered_list)
+            .unwrap_or_default(),
+    }
+}
+
+fn flood_limits_from_env() -> FloodLimits {
+    let default_limits = FloodLimits::default();
+    FloodLimits {
+        messages: rate_limit_from_env("BUDGETCHAT_MESSAGES_PER_SECOND", "BUDGETCHAT_MESSAGE_BURST"),
+        bytes: rate_limit_from_env("BUDGETCHAT_BYTES_PER_SECOND", "BUDGETCHAT_BYTE_BURST"),
+        max_message_length: parsed_env_var("BUDGETCHAT_MAX_MESSAGE_LENGTH")
+            .unwrap_or(default_limits.max_message_length),
+        policy: parsed_env_var("BUDGETCHAT_FLOOD_POLICY").unwrap_or(default_limits.policy),
+    }
+}
+
+/// Rate limits are only active if a rate is configured. Without a configured burst, one
+/// second's worth of the rate may be used at once.
+fn rate_limit_from_env(rate_variable: &str, burst_variable: &str) -> Option<RateLimit> {
+    let per_second: f64 = parsed_env_var(rate_variable).filter(|rate: &f64| *rate > 0.0)?;
+    let burst = parsed_env_var(burst_variable).unwrap_or(per_second);
+    Some(RateLimit {
+        per_second,
+        burst: burst.max(1.0),
+    })
+}
+
+fn env_var(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|value| !value.trim().is_empty())
+}
+
+fn parsed_env_var<T: FromStr>(name: &str) -> Option<T> {
+    let value = env_var(name)?;
+    let parsed_value = value.trim().parse().ok();
+    if parsed_value.is_none() {
+        println!("Ignoring invalid value {value} for {name}");
+    }
+    parsed_value
+}
+
+/// Durations like `30s` or `2h`
+fn duration_env_var(name: &str) -> Option<Duration> {
+    let value = env_var(name)?;
+    let parsed_value = parse_duration(value.trim());
+    if parsed_value.is_none() {
+        println!("Ignoring invalid value {value} for {name}");
+    }
+    parsed_value
+}
+
+/// Comma separated list of values in their given order, invalid values are left out
+fn parsed_list_env_var<T: FromStr<Err = String>>(name: &str) -> Vec<T> {
+    let Some(list) = env_var(name) else {
+        return Vec::new();
+    };
+    list.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .filter_map(|entry| match entry.parse() {
+            Ok(value) => Some(value),
+            Err(e) => {
+                println!("Ignoring invalid value for {name}: {e}");
+                None
+            }
+        })
+        .collect()
+}
+
+fn parse_list(list: &str) -> HashSet<String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .map(String::from)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_list_parsing() {
+        assert_eq!(parse_list(""), HashSet::new());
+        assert_eq!(parse_list("alice"), HashSet::from([String::from("alice")]));
+        assert_eq!(
+            parse_list(" alice, bob,,carol "),
+            HashSet::from([
+                String::from("alice"),
+                String::from("bob"),
+                String::from("carol")
+            ])
+        );
+    }
+}
@@ -0,0 +1,207 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Error as IO_Error, ErrorKind, Result as IO_Result};
+use std::path::PathBuf;
+
+use argon2::password_hash::rand_core::OsRng;
+use argon2::password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString};
+use argon2::Argon2;
+use tokio::sync::oneshot;
+
+use crate::file_writer::{replace_file, FileWriter};
+
+/// Hashes the password with a random salt. Hashing is slow on purpose, so it should not run on
+/// the room task.
+pub fn hash_password(password: &str) -> String {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(password.as_bytes(), &salt)
+        .expect("Default Argon2 parameters accept any password")
+        .to_string()
+}
+
+pub fn verify_password(password: &str, password_hash: &str) -> bool {
+    PasswordHash::new(password_hash).is_ok_and(|password_hash| {
+        Argon2::default()
+            .verify_password(password.as_bytes(), &password_hash)
+            .is_ok()
+    })
+}
+
+/// Contents of the credentials file, and where to report whether it was saved
+type CredentialsSave = (String, oneshot::Sender<IO_Result<()>>);
+
+/// Password hashes of registered user names. With a credentials file, every registration is
+/// written to it by a [`FileWriter`], one `name hash` pair per line.
+#[derive(Default)]
+pub struct CredentialStore {
+    file_writer: Option<FileWriter<CredentialsSave>>,
+    password_hashes: HashMap<String, String>,
+}
+
+/// Registration whose credentials are being written to the file
+pub struct SavingRegistration {
+    pub user_name: String,
+    /// Hash to restore if the file cannot be written
+    previous_hash: Option<String>,
+    saved: oneshot::Receiver<IO_Result<()>>,
+}
+
+impl SavingRegistration {
+    /// Waits until the credentials file is written
+    pub async fn saved(&mut self) -> IO_Result<()> {
+        (&mut self.saved)
+            .await
+            .unwrap_or_else(|_| Err(IO_Error::other("Credentials writer stopped")))
+    }
+}
+
+impl CredentialStore {
+    pub fn open(file_path: Option<PathBuf>) -> IO_Result<Self> {
+        let Some(file_path) = file_path else {
+            return Ok(CredentialStore::default());
+        };
+
+        let credentials = match fs::read_to_string(&file_path) {
+            Ok(credentials) => credentials,
+            // No registrations yet
+            Err(e) if e.kind() == ErrorKind::NotFound => String::new(),
+            Err(e) => return Err(e),
+        };
+        let password_hashes = credentials
+            .lines()
+            .filter_map(|line| line.split_once(' '))
+            .map(|(user_name, password_hash)| {
+                (String::from(user_name), String::from(password_hash))
+            })
+            .collect();
+
+        let file_writer = FileWriter::start(
+            "credentials",
+            move |(credentials, saved): CredentialsSave| {
+                let _ = saved.send(replace_file(&file_path, &credentials));
+            },
+        );
+        Ok(CredentialStore {
+            file_writer: Some(file_writer),
+            password_hashes,
+        })
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.file_writer.is_some()
+    }
+
+    pub fn password_hash(&self, user_name: &str) -> Option<&str> {
+        self.password_hashes.get(user_name).map(String::as_str)
+    }
+
+    pub fn is_registered(&self, user_name: &str) -> bool {
+        self.password_hashes.contains_key(user_name)
+    }
+
+    /// Registers the name or replaces its password and starts saving all credentials. If saving
+    /// fails, [`CredentialStore::undo`] keeps memory and file in sync.
+    pub fn register(
+        &mut self,
+        user_name: &str,
+        password_hash: String,
+    ) -> IO_Result<SavingRegistration> {
+        let Some(file_writer) = &self.file_writer else {
+            return Err(ErrorKind::Unsupported.into());
+        };
+
+        let previous_hash = self
+            .password_hashes
+            .insert(String::from(user_name), password_hash);
+        let credentials: String = self
+            .password_hashes
+            .iter()
+            .map(|(user_name, password_hash)| format!("{user_name} {password_hash}\n"))
+            .collect();
+
+        let (saved_sender, saved) = oneshot::channel();
+        let registration = SavingRegistration {
+            user_name: String::from(user_name),
+            previous_hash,
+            saved,
+        };
+        if !file_writer.write((credentials, saved_sender)) {
+            self.undo(registration);
+            return Err(IO_Error::other(
+                "Too many registrations waiting for the disk",
+            ));
+        }
+        Ok(registration)
+    }
+
+    /// Restores the password hash from before the registration
+    pub fn undo(&mut self, registration: SavingRegistration) {
+        match registration.previous_hash {
+            Some(previous_hash) => self
+                .password_hashes
+                .insert(registration.user_name, previous_hash),
+            None => self.password_hashes.remove(&registration.user_name),
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_password_hashing() {
+        let password_hash = hash_password("secret");
+        assert!(password_hash.starts_with("$argon2"));
+        assert!(!password_hash.contains("secret"));
+        assert_ne!(password_hash, hash_password("secret"));
+
+        assert!(verify_password("secret", &password_hash));
+        assert!(!verify_password("Secret", &password_hash));
+        assert!(!verify_password("secret", "not a hash"));
+    }
+
+    #[tokio::test]
+    async fn test_credential_store() {
+        let file_path =
+            std::env::temp_dir().join(format!("budgetchat-credentials-{}.txt", std::process::id()));
+        let _ = fs::remove_file(&file_path);
+
+        let mut store = CredentialStore::open(Some(file_path.clone())).unwrap();
+        assert!(store.is_enabled());
+        assert!(!store.is_registered("alice"));
+        for (user_name, password_hash) in
+            [("alice", "$hash1"), ("bob", "$hash2"), ("alice", "$hash3")]
+        {
+            let mut registration = store
+                .register(user_name, String::from(password_hash))
+                .unwrap();
+            registration.saved().await.unwrap();
+        }
+
+        let mut store = CredentialStore::open(Some(file_path.clone())).unwrap();
+        assert_eq!(store.password_hash("alice"), Some("$hash3"));
+        assert_eq!(store.password_hash("bob"), Some("$hash2"));
+        assert_eq!(store.password_hash("carol"), None);
+
+        // Undoing restores the previous state
+        let registration = store.register("alice", String::from("$hash4")).unwrap();
+        store.undo(registration);
+        let registration = store.register("carol", String::from("$hash5")).unwrap();
+        store.undo(registration);
+        assert_eq!(store.password_hash("alice"), Some("$hash3"));
+        assert!(!store.is_registered("carol"));
+        drop(store);
+
+        fs::remove_file(&file_path).unwrap();
+    }
+
+    #[test]
+    fn test_disabled_credential_store() {
+        let mut store = CredentialStore::open(None).unwrap();
+        assert!(!store.is_enabled());
+        assert!(store.register("alice", String::from("$hash")).is_err());
+        assert!(!store.is_registered("alice"));
+    }
+}
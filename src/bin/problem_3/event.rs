@@ -0,0 +1,80 @@
+use std::fmt::{Display, Formatter, Result as FmtResult};
+
+use serde::{Deserialize, Serialize};
+
+/// Everything that is broadcast to the whole room. Its display form is the budget chat line the
+/// event is shown as, other protocols translate the event on their own.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RoomEvent {
+    Joined(String),
+    Left(String),
+    Renamed(String, String),
+    Message(String, String),
+    /// User has not sent anything for a while
+    Away(String),
+    /// User that was away sent something again
+    Back(String),
+}
+
+impl RoomEvent {
+    /// Name of the user the event is about, the old name for renames
+    pub fn user_name(&self) -> &str {
+        match self {
+            RoomEvent::Joined(user_name)
+            | RoomEvent::Left(user_name)
+            | RoomEvent::Renamed(user_name, _)
+            | RoomEvent::Message(user_name, _)
+            | RoomEvent::Away(user_name)
+            | RoomEvent::Back(user_name) => user_name,
+        }
+    }
+}
+
+impl Display for RoomEvent {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            RoomEvent::Joined(user_name) => write!(f, "* {user_name} has entered the room"),
+            RoomEvent::Left(user_name) => write!(f, "* {user_name} has left the room"),
+            RoomEvent::Renamed(old_user_name, new_user_name) => {
+                write!(f, "* {old_user_name} is now known as {new_user_name}")
+            }
+            RoomEvent::Message(user_name, message) => write!(f, "[{user_name}] {message}"),
+            RoomEvent::Away(user_name) => write!(f, "* {user_name} is away"),
+            RoomEvent::Back(user_name) => write!(f, "* {user_name} is back"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_room_event_lines() {
+        assert_eq!(
+            RoomEvent::Joined(String::from("alice")).to_string(),
+            "* alice has entered the room"
+        );
+        assert_eq!(
+            RoomEvent::Left(String::from("alice")).to_string(),
+            "* alice has left the room"
+        );
+        assert_eq!(
+            RoomEvent::Renamed(String::from("alice"), String::from("bob")).to_string(),
+            "* alice is now known as bob"
+        );
+        assert_eq!(
+            RoomEvent::Message(String::from("alice"), String::from("hi there")).to_string(),
+            "[alice] hi there"
+        );
+        assert_eq!(
+            RoomEvent::Away(String::from("alice")).to_string(),
+            "* alice is away"
+        );
+        assert_eq!(
+            RoomEvent::Back(String::from("alice")).to_string(),
+            "* alice is back"
+        );
+    }
+}
@@ -0,0 +1,204 @@
+use std::collections::HashMap;
+use std::io::Result as IO_Result;
+use std::time::Duration;
+
+use futures::{SinkExt, StreamExt};
+use serde::{Deserialize, Serialize};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::mpsc::{channel, Sender};
+use tokio_util::codec::{FramedRead, FramedWrite, LinesCodec};
+
+use crate::event::RoomEvent;
+use crate::session::SessionRegistry;
+use crate::transport::to_io_error;
+
+/// Hellos list all members of a server, so link lines may be much longer than chat lines
+const MAX_LINK_LINE_LENGTH: usize = 4 * 1024 * 1024;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FederationConfig {
+    /// Name of this server, linked servers must have different names
+    pub server_name: String,
+    /// Port on which a linked server may connect
+    pub port: Option<u16>,
+    /// Address of the server to link to
+    pub peer_address: Option<String>,
+    /// Waiting time before connecting to the peer again after the link was lost
+    pub reconnect_delay: Duration,
+}
+
+impl Default for FederationConfig {
+    fn default() -> Self {
+        FederationConfig {
+            server_name: String::from("budgetchat"),
+            port: None,
+            peer_address: None,
+            reconnect_delay: Duration::from_secs(5),
+        }
+    }
+}
+
+/// One line of the server-to-server protocol, sent as JSON. Both servers start with a hello
+/// listing their own members and then relay the events of their own members.
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LinkMessage {
+    Hello {
+        server_name: String,
+        members: Vec<String>,
+    },
+    Event(RoomEvent),
+    /// The name is registered on the sending server, the receiving server renames its
+    /// participant
+    NameTaken(String),
+}
+
+/// The room's side of a link to another server
+pub struct FederationLink {
+    pub link_id: i32,
+    pub message_sender: Sender<LinkMessage>,
+    /// Name of the linked server, known once its hello arrived
+    pub peer_name: Option<String>,
+    /// Members of the room that are connected to the linked server, by the key of their name
+    pub remote_members: HashMap<String, String>,
+}
+
+/// Accepts links from other servers. The room only keeps a single link, further links are
+/// closed right away.
+pub async fn run_federation_listener(
+    federation_listener: TcpListener,
+    registry: SessionRegistry,
+) -> IO_Result<()> {
+    loop {
+        let (tcp_stream, peer_address) = federation_listener.accept().await?;
+        println!("Federation link from {peer_address}");
+        tokio::spawn(run_link(tcp_stream, registry.clone()));
+    }
+}
+
+/// Keeps the room linked to the peer, connecting again whenever the link is lost
+pub async fn connect_to_peer(peer_address: String, registry: SessionRegistry) {
+    loop {
+        match TcpStream::connect(&peer_address).await {
+            Ok(tcp_stream) => {
+                println!("Federation link to {peer_address} established");
+                run_link(tcp_stream, registry.clone()).await;
+                println!("Federation link to {peer_address} lost");
+            }
+            Err(e) => println!("Failed to connect to federation peer {peer_address}: {e}"),
+        }
+        tokio::time::sleep(registry.config().federation.reconnect_delay).await;
+    }
+}
+
+/// Relays link messages between the connection and the room until either side ends the link
+pub async fn run_link(tcp_stream: TcpStream, registry: SessionRegistry) {
+    let link_id = registry.next_connection_id();
+    let (message_sender, mut message_receiver) =
+        channel(registry.config().session_queue_capacity.max(1));
+    let Some(_link_guard) = LinkGuard::attach(link_id, message_sender, &registry).await else {
+        println!("[{link_id}] Refused federation link, the room is already linked");
+        return;
+    };
+
+    let (tcp_socket_reader, tcp_socket_writer) = tcp_stream.into_split();
+    let mut link_reader = FramedRead::new(
+        tcp_socket_reader,
+        LinesCodec::new_with_max_length(MAX_LINK_LINE_LENGTH),
+    );
+    let mut link_writer = FramedWrite::new(tcp_socket_writer, LinesCodec::new());
+
+    loop {
+        tokio::select! {
+            link_line = link_reader.next() => match link_line {
+                Some(Ok(link_line)) => match serde_json::from_str(&link_line) {
+                    Ok(link_message) => registry.receive_link_message(link_id, link_message).await,
+                    Err(e) => {
+                        println!("[{link_id}] Invalid federation message: {e}");
+                        break;
+                    }
+                },
+                Some(Err(e)) => {
+                    println!("[{link_id}] Federation link failed: {}", to_io_error(e));
+                    break;
+                }
+                None => break,
+            },
+            link_message = message_receiver.recv() => match link_message {
+                Some(link_message) => {
+                    let link_line = serde_json::to_string(&link_message)
+                        .expect("Link messages are always serializable");
+                    if let Err(e) = link_writer.send(link_line).await {
+                        println!("[{link_id}] Federation link failed: {}", to_io_error(e));
+                        break;
+                    }
+                }
+                // Room dropped the link
+                None => break,
+            }
+        }
+    }
+}
+
+/// Detaches the link from the room on every exit path, so that the members of the linked
+/// server leave the room
+struct LinkGuard {
+    link_id: i32,
+    registry: SessionRegistry,
+}
+
+impl LinkGuard {
+    async fn attach(
+        link_id: i32,
+        message_sender: Sender<LinkMessage>,
+        registry: &SessionRegistry,
+    ) -> Option<Self> {
+        registry
+            .attach_link(link_id, message_sender)
+            .await
+            .then(|| LinkGuard {
+                link_id,
+                registry: registry.clone(),
+            })
+    }
+}
+
+impl Drop for LinkGuard {
+    fn drop(&mut self) {
+        self.registry.detach_link(self.link_id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_link_message_format() {
+        let hello = LinkMessage::Hello {
+            server_name: String::from("alpha"),
+            members: vec![String::from("alice")],
+        };
+        assert_eq!(
+            serde_json::to_string(&hello).unwrap(),
+            r#"{"hello":{"server_name":"alpha","members":["alice"]}}"#
+        );
+
+        let event = LinkMessage::Event(RoomEvent::Renamed(
+            String::from("alice"),
+            String::from("alicia"),
+        ));
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"event":{"renamed":["alice","alicia"]}}"#
+        );
+        assert_eq!(
+            serde_json::from_str::<LinkMessage>(r#"{"event":{"left":"bob"}}"#).unwrap(),
+            LinkMessage::Event(RoomEvent::Left(String::from("bob")))
+        );
+        assert_eq!(
+            serde_json::to_string(&LinkMessage::NameTaken(String::from("alice"))).unwrap(),
+            r#"{"name_taken":"alice"}"#
+        );
+    }
+}
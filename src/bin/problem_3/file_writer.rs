@@ -0,0 +1,58 @@
+use std::fs;
+use std::io::Result as IO_Result;
+use std::path::Path;
+use std::thread::{self, JoinHandle};
+
+use tokio::sync::mpsc::{channel, Sender};
+
+/// Writes that may be waiting for a file writer. Beyond that, writes are refused, so that a
+/// stuck disk cannot fill up the memory.
+const WRITE_QUEUE_CAPACITY: usize = 1024;
+
+/// Thread that applies writes to a file one after another, so that a slow disk holds up neither
+/// the room nor the runtime. Dropping the writer waits for the writes that are still queued.
+pub struct FileWriter<W> {
+    write_sender: Option<Sender<W>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl<W: Send + 'static> FileWriter<W> {
+    pub fn start(name: &str, mut apply: impl FnMut(W) + Send + 'static) -> Self {
+        let (write_sender, mut write_receiver) = channel(WRITE_QUEUE_CAPACITY);
+        let thread = thread::Builder::new()
+            .name(format!("{name} writer"))
+            .spawn(move || {
+                while let Some(write) = write_receiver.blocking_recv() {
+                    apply(write);
+                }
+            })
+            .expect("Failed to start file writer thread");
+        FileWriter {
+            write_sender: Some(write_sender),
+            thread: Some(thread),
+        }
+    }
+
+    /// Queues the write. Returns false if too many writes are waiting already.
+    pub fn write(&self, write: W) -> bool {
+        self.write_sender
+            .as_ref()
+            .is_some_and(|write_sender| write_sender.try_send(write).is_ok())
+    }
+}
+
+impl<W> Drop for FileWriter<W> {
+    fn drop(&mut self) {
+        // Closing the queue ends the thread once it is empty
+        self.write_sender.take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Replaces the file at once, so that a crash cannot leave a half written file behind
+pub fn replace_file(file_path: &Path, contents: &str) -> IO_Result<()> {
+    let temporary_path = file_path.with_extension("tmp");
+    fs::write(&temporary_path, contents).and_then(|_| fs::rename(&temporary_path, file_path))
+}
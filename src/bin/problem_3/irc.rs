@@ -0,0 +1,606 @@
+use std::io::{Error as IO_Error, Result as IO_Result};
+use std::net::SocketAddr;
+use std::ops::ControlFlow;
+use std::time::Instant;
+
+use futures::{SinkExt, StreamExt, TryStreamExt};
+use itertools::Itertools;
+use tokio::net::tcp::OwnedWriteHalf;
+use tokio::net::TcpStream;
+use tokio_util::codec::{AnyDelimiterCodec, AnyDelimiterCodecError, FramedRead, FramedWrite};
+
+use crate::command::{execute_chat_command, parse_chat_command, redact_chat_command};
+use crate::event::RoomEvent;
+use crate::presence::{sleep_until, IdleTracker};
+use crate::rate_limit::{FloodGuard, FloodVerdict};
+use crate::session::{JoinError, RenameError, Session, SessionEvent, SessionRegistry};
+use crate::transport::{to_io_error, ClientLine, ClientLinesCodec, LineReader};
+
+const SERVER_NAME: &str = "budgetchat";
+/// The budget chat room as seen by IRC clients
+const CHANNEL: &str = "#budgetchat";
+
+#[derive(Debug, PartialEq)]
+struct IrcMessage {
+    command: String,
+    params: Vec<String>,
+}
+
+/// Parses `[:prefix] COMMAND [params...] [:trailing]`. The prefix of client messages is ignored.
+fn parse_irc_message(line: &str) -> Option<IrcMessage> {
+    let mut message = line.trim_start();
+    if message.starts_with(':') {
+        message = message.split_once(' ')?.1.trim_start();
+    }
+
+    let (middle, trailing) = match message.split_once(" :") {
+        Some((middle, trailing)) => (middle, Some(trailing)),
+        None => (message, None),
+    };
+    let mut parts = middle.split_whitespace();
+    let command = parts.next()?.to_uppercase();
+    let params = parts
+        .map(String::from)
+        .chain(trailing.map(String::from))
+        .collect();
+
+    Some(IrcMessage { command, params })
+}
+
+fn user_prefix(user_name: &str) -> String {
+    format!("{user_name}!{user_name}@{SERVER_NAME}")
+}
+
+/// Translates a room event into the IRC message an IRC client expects for it
+fn room_event_to_irc(event: &RoomEvent) -> String {
+    match event {
+        RoomEvent::Joined(user_name) => format!(":{} JOIN {CHANNEL}", user_prefix(user_name)),
+        RoomEvent::Left(user_name) => format!(":{} PART {CHANNEL}", user_prefix(user_name)),
+        RoomEvent::Renamed(old_user_name, new_user_name) => {
+            format!(":{} NICK :{new_user_name}", user_prefix(old_user_name))
+        }
+        RoomEvent::Message(user_name, message) => {
+            format!(":{} PRIVMSG {CHANNEL} :{message}", user_prefix(user_name))
+        }
+        // IRC has no presence announcements -> Server notice to the channel
+        RoomEvent::Away(_) | RoomEvent::Back(_) => {
+            format!(":{SERVER_NAME} NOTICE {CHANNEL} :{event}")
+        }
+    }
+}
+
+fn is_room_channel(channel: &str) -> bool {
+    channel.eq_ignore_ascii_case(CHANNEL)
+}
+
+/// IRC connection that takes part in the budget chat room as the single channel `#budgetchat`
+pub struct IrcClient {
+    connection_id: i32,
+    client_address: SocketAddr,
+    registry: SessionRegistry,
+    nick: Option<String>,
+    user: Option<String>,
+    /// Password from PASS for joining under a registered nick
+    password: Option<String>,
+    is_registered: bool,
+    session: Option<Session>,
+    flood_guard: FloodGuard,
+    idle_tracker: IdleTracker,
+    /// Throttled room message and when it is due, the client is not read from until it was sent
+    throttled_message: Option<(Instant, String)>,
+    socket_reader: LineReader,
+    socket_writer: FramedWrite<OwnedWriteHalf, AnyDelimiterCodec>,
+}
+
+impl IrcClient {
+    pub fn new(
+        connection_id: i32,
+        client_address: SocketAddr,
+        tcp_stream: TcpStream,
+        registry: SessionRegistry,
+    ) -> Self {
+        let (tcp_socket_reader, tcp_socket_writer) = tcp_stream.into_split();
+        // IRC messages are terminated by CR LF, but clients are read leniently like budget chat
+        let max_line_length = registry.config().flood_limits.max_line_length();
+        let socket_reader = Box::pin(
+            FramedRead::new(tcp_socket_reader, ClientLinesCodec::new(max_line_length))
+                .map_err(to_io_error),
+        );
+        let socket_writer = FramedWrite::new(
+            tcp_socket_writer,
+            AnyDelimiterCodec::new(b"\n".to_vec(), b"\r\n".to_vec()),
+        );
+        IrcClient {
+            connection_id,
+            client_address,
+            flood_guard: FloodGuard::new(registry.config().flood_limits.clone(), Instant::now()),
+            idle_tracker: IdleTracker::new(registry.config().away_after, Instant::now()),
+            registry,
+            nick: None,
+            user: None,
+            password: None,
+            is_registered: false,
+            session: None,
+            throttled_message: None,
+            socket_reader,
+            socket_writer,
+        }
+    }
+
+    async fn send_line(&mut self, line: String) -> IO_Result<()> {
+        self.socket_writer
+            .send(line)
+            .await
+            .map_err(|error| match error {
+                AnyDelimiterCodecError::Io(io_error) => io_error,
+                codec_error => IO_Error::other(codec_error),
+            })
+    }
+
+    async fn send_numeric(&mut self, code: u16, params: &str) -> IO_Result<()> {
+        let target = self.nick.clone().unwrap_or_else(|| String::from("*"));
+        self.send_line(format!(":{SERVER_NAME} {code:03} {target} {params}"))
+            .await
+    }
+
+    async fn send_notice(&mut self, notice: &str) -> IO_Result<()> {
+        let target = self.nick.clone().unwrap_or_else(|| String::from("*"));
+        self.send_line(format!(":{SERVER_NAME} NOTICE {target} :{notice}"))
+            .await
+    }
+
+    async fn send_names(&mut self) -> IO_Result<()> {
+        let names = self
+            .registry
+            .participant_names()
+            .await
+            .iter()
+            .sorted()
+            .join(" ");
+        self.send_numeric(353, &format!("= {CHANNEL} :{names}"))
+            .await?;
+        self.send_numeric(366, &format!("{CHANNEL} :End of /NAMES list"))
+            .await
+    }
+
+    /// Joins the room under the current nick, if the nick is still free
+    async fn join_room(&mut self) -> IO_Result<()> {
+        match self.try_join_room().await {
+            Ok(session) => self.enter_room(session).await,
+            Err(join_error) => self.send_join_error(join_error).await,
+        }
+    }
+
+    async fn try_join_room(&mut self) -> Result<Session, JoinError> {
+        let nick = self.nick.as_deref().unwrap();
+        let client_ip = self.client_address.ip();
+        self.registry
+            .try_join(
+                self.connection_id,
+                client_ip,
+                nick,
+                self.password.as_deref(),
+            )
+            .await
+            .map(|(session, _)| session)
+    }
+
+    async fn enter_room(&mut self, session: Session) -> IO_Result<()> {
+        println!(
+            "[{}] IRC user joined: {}",
+            self.connection_id, session.user_name
+        );
+        let join_line = format!(":{} JOIN {CHANNEL}", user_prefix(&session.user_name));
+        self.idle_tracker.record_activity(Instant::now());
+        self.session = Some(session);
+        self.send_line(join_line).await?;
+        self.send_names().await
+    }
+
+    /// Tells the client why its nick could not join, the client is expected to pick another nick
+    /// or to send the right password
+    async fn send_join_error(&mut self, join_error: JoinError) -> IO_Result<()> {
+        let nick = self.nick.clone().unwrap();
+        match join_error {
+            JoinError::InvalidName => {
+                self.send_numeric(432, &format!("{nick} :Erroneous nickname"))
+                    .await
+            }
+            JoinError::NameTaken => {
+                self.send_numeric(433, &format!("{nick} :Nickname is already in use"))
+                    .await
+            }
+            JoinError::WrongPassword => {
+                println!("[{}] Wrong password for {nick}", self.connection_id);
+                self.send_numeric(464, ":Password incorrect").await
+            }
+        }
+    }
+
+    fn leave_room(&mut self) {
+        // Dropping the session announces the departure to the room
+        self.session = None;
+    }
+
+    /// Registration completes once NICK and USER are known and the nick could join the room
+    async fn try_complete_registration(&mut self) -> IO_Result<()> {
+        if self.is_registered || self.nick.is_none() || self.user.is_none() {
+            return Ok(());
+        }
+
+        let session = match self.try_join_room().await {
+            Ok(session) => session,
+            Err(join_error) => return self.send_join_error(join_error).await,
+        };
+
+        self.is_registered = true;
+        let nick = session.user_name.clone();
+        self.send_numeric(1, &format!(":Welcome to budgetchat, {nick}"))
+            .await?;
+        self.enter_room(session).await
+    }
+
+    async fn change_nick(&mut self, requested_nick: String) -> IO_Result<()> {
+        let Some(new_nick) = self.registry.config().name_policy.check(&requested_nick) else {
+            return self
+                .send_numeric(432, &format!("{requested_nick} :Erroneous nickname"))
+                .await;
+        };
+
+        if let Some(session) = self.session.as_mut() {
+            let old_nick = session.user_name.clone();
+            match self.registry.try_rename(session, &new_nick).await {
+                Ok(()) => {
+                    self.nick = Some(new_nick.clone());
+                    self.send_line(format!(":{} NICK :{new_nick}", user_prefix(&old_nick)))
+                        .await
+                }
+                Err(RenameError::InvalidName) => {
+                    self.send_numeric(432, &format!("{new_nick} :Erroneous nickname"))
+                        .await
+                }
+                Err(RenameError::NameTaken) => {
+                    self.send_numeric(433, &format!("{new_nick} :Nickname is already in use"))
+                        .await
+                }
+                Err(RenameError::NameRegistered) => {
+                    self.send_numeric(433, &format!("{new_nick} :Nickname is registered"))
+                        .await
+                }
+            }
+        } else if !self.is_registered {
+            self.nick = Some(new_nick);
+            self.try_complete_registration().await
+        } else if self.registry.participant_names().await.contains(&new_nick) {
+            self.send_numeric(433, &format!("{new_nick} :Nickname is already in use"))
+                .await
+        } else {
+            // Registered but not in the room -> Nick is only reserved when joining again
+            let old_nick = self.nick.replace(new_nick.clone()).unwrap();
+            self.send_line(format!(":{} NICK :{new_nick}", user_prefix(&old_nick)))
+                .await
+        }
+    }
+
+    /// Treats a PRIVMSG to the room exactly like a line from a budget chat user
+    async fn send_room_message(&mut self, message: String) -> IO_Result<ControlFlow<()>> {
+        // Only messages to the channel count as activity, like the idle time of IRC servers
+        self.idle_tracker.record_activity(Instant::now());
+        self.session.as_ref().unwrap().record_activity().await;
+
+        match self.flood_guard.check(&message, Instant::now()) {
+            FloodVerdict::Allow => self.execute_room_message(message).await?,
+            FloodVerdict::Delay(waiting_time) => {
+                // Room events keep being delivered while the message waits
+                self.throttled_message = Some((Instant::now() + waiting_time, message));
+            }
+            FloodVerdict::Reject(reason) => self.send_notice(&format!("* {reason}")).await?,
+            FloodVerdict::Disconnect(reason) => {
+                println!("[{}] Disconnected: {reason}", self.connection_id);
+                self.send_line(format!("ERROR :{reason}")).await?;
+                return Ok(ControlFlow::Break(()));
+            }
+        }
+        Ok(ControlFlow::Continue(()))
+    }
+
+    async fn execute_room_message(&mut self, message: String) -> IO_Result<()> {
+        let session = self.session.as_mut().unwrap();
+        match parse_chat_command(&message) {
+            Some(Ok(command)) => {
+                println!(
+                    "[{}] Issued command: {}",
+                    self.connection_id,
+                    redact_chat_command(&message)
+                );
+                let old_nick = session.user_name.clone();
+                let reply = execute_chat_command(session, command).await;
+                let new_nick = session.user_name.clone();
+                if old_nick != new_nick {
+                    // Renamed through /nick -> The IRC client has to learn about its new nick
+                    self.nick = Some(new_nick.clone());
+                    self.send_line(format!(":{} NICK :{new_nick}", user_prefix(&old_nick)))
+                        .await?;
+                }
+                for reply_line in reply {
+                    self.send_notice(&reply_line).await?;
+                }
+            }
+            Some(Err(usage)) => self.send_notice(&format!("* {usage}")).await?,
+            None if self.registry.is_muted(session).await => {
+                self.send_notice("* You are muted").await?;
+            }
+            None => {
+                println!("[{}] Wrote message: {message}", self.connection_id);
+                session.broadcast_message(message).await;
+            }
+        }
+        Ok(())
+    }
+
+    /// Lines too long to be read are treated like too long messages to the room
+    async fn reject_too_long_line(&mut self) -> IO_Result<ControlFlow<()>> {
+        match self.flood_guard.check_too_long_line() {
+            FloodVerdict::Disconnect(reason) => {
+                println!("[{}] Disconnected: {reason}", self.connection_id);
+                self.send_line(format!("ERROR :{reason}")).await?;
+                Ok(ControlFlow::Break(()))
+            }
+            FloodVerdict::Reject(reason) => {
+                self.send_notice(&format!("* {reason}")).await?;
+                Ok(ControlFlow::Continue(()))
+            }
+            FloodVerdict::Allow | FloodVerdict::Delay(_) => Ok(ControlFlow::Continue(())),
+        }
+    }
+
+    async fn process_irc_message(&mut self, message: IrcMessage) -> IO_Result<ControlFlow<()>> {
+        let IrcMessage {
+            command,
+            mut params,
+        } = message;
+
+        match command.as_str() {
+            "NICK" => match params.into_iter().next() {
+                Some(new_nick) => self.change_nick(new_nick).await?,
+                None => self.send_numeric(431, ":No nickname given").await?,
+            },
+            "USER" if self.is_registered => {
+                self.send_numeric(462, ":You may not reregister").await?
+            }
+            "USER" => match params.into_iter().next() {
+                Some(user) => {
+                    self.user = Some(user);
+                    self.try_complete_registration().await?;
+                }
+                None => {
+                    self.send_numeric(461, "USER :Not enough parameters")
+                        .await?
+                }
+            },
+            "PASS" if self.is_registered => {
+                self.send_numeric(462, ":You may not reregister").await?
+            }
+            "PASS" => match params.into_iter().next() {
+                Some(password) => self.password = Some(password),
+                None => {
+                    self.send_numeric(461, "PASS :Not enough parameters")
+                        .await?
+                }
+            },
+            "PING" => match params.into_iter().next() {
+                Some(token) => {
+                    self.send_line(format!(":{SERVER_NAME} PONG {SERVER_NAME} :{token}"))
+                        .await?
+                }
+                None => self.send_numeric(409, ":No origin specified").await?,
+            },
+            "PONG" => {}
+            "QUIT" => {
+                self.send_line(String::from("ERROR :Closing link")).await?;
+                return Ok(ControlFlow::Break(()));
+            }
+            _ if !self.is_registered => self.send_numeric(451, ":You have not registered").await?,
+            "JOIN" => {
+                let channels = params.into_iter().next().unwrap_or_default();
+                if channels == "0" {
+                    // Leave all channels
+                    self.leave_room();
+                }
+                for channel in channels.split(',').filter(|channel| *channel != "0") {
+                    if !is_room_channel(channel) {
+                        self.send_numeric(403, &format!("{channel} :No such channel"))
+                            .await?;
+                    } else if self.session.is_none() {
+                        self.join_room().await?;
+                    }
+                }
+            }
+            "PART" => {
+                let channels = params.into_iter().next().unwrap_or_default();
+                for channel in channels.split(',') {
+                    if !is_room_channel(channel) {
+                        self.send_numeric(403, &format!("{channel} :No such channel"))
+                            .await?;
+                    } else if self.session.is_none() {
+                        self.send_numeric(442, &format!("{channel} :You're not on that channel"))
+                            .await?;
+                    } else {
+                        let nick = self.nick.clone().unwrap();
+                        self.send_line(format!(":{} PART {CHANNEL}", user_prefix(&nick)))
+                            .await?;
+                        self.leave_room();
+                    }
+                }
+            }
+            "PRIVMSG" if params.len() < 2 => self.send_numeric(412, ":No text to send").await?,
+            "PRIVMSG" => {
+                let message = params.pop().unwrap();
+                let target = params.swap_remove(0);
+                if !is_room_channel(&target) {
+                    self.send_numeric(401, &format!("{target} :No such nick/channel"))
+                        .await?;
+                } else if self.session.is_none() {
+                    self.send_numeric(404, &format!("{target} :Cannot send to channel"))
+                        .await?;
+                } else {
+                    return self.send_room_message(message).await;
+                }
+            }
+            "NAMES" => self.send_names().await?,
+            _ => {
+                self.send_numeric(421, &format!("{command} :Unknown command"))
+                    .await?
+            }
+        }
+        Ok(ControlFlow::Continue(()))
+    }
+
+    pub async fn process_irc_interchange(&mut self) -> IO_Result<()> {
+        loop {
+            // Only users in the room can be away
+            let away_at = self
+                .session
+                .as_ref()
+                .and_then(|_| self.idle_tracker.away_at());
+            let throttled_until = self.throttled_message.as_ref().map(|(due_at, _)| *due_at);
+            tokio::select! {
+                irc_line = self.socket_reader.next(), if throttled_until.is_none() => match irc_line {
+                    Some(Ok(ClientLine::Line(irc_line))) => {
+                        let Some(irc_message) = parse_irc_message(&irc_line) else {
+                            continue;
+                        };
+                        if self.process_irc_message(irc_message).await?.is_break() {
+                            break;
+                        }
+                    }
+                    Some(Ok(ClientLine::TooLong)) => {
+                        if self.reject_too_long_line().await?.is_break() {
+                            break;
+                        }
+                    }
+                    _ => break,
+                },
+                _ = sleep_until(throttled_until) => {
+                    let (_, message) = self.throttled_message.take().unwrap();
+                    self.execute_room_message(message).await?;
+                }
+                // Only users in the room see what happens there
+                session_event = next_session_event(&mut self.session) => match session_event {
+                    Some(SessionEvent::Room(room_event)) => {
+                        // The room may rename the user to resolve a name collision
+                        if let Some(session) = self.session.as_mut() {
+                            session.follow_room_event(&room_event);
+                            self.nick = Some(session.user_name.clone());
+                        }
+                        self.send_line(room_event_to_irc(&room_event)).await?;
+                    }
+                    Some(SessionEvent::Message(message)) => self.send_notice(&message).await?,
+                    Some(SessionEvent::Disconnect(reason)) => {
+                        println!("[{}] Disconnected: {reason}", self.connection_id);
+                        self.send_notice(&reason).await?;
+                        self.send_line(format!("ERROR :{reason}")).await?;
+                        break;
+                    }
+                    // Removed from the room for falling behind
+                    None => break,
+                },
+                _ = sleep_until(away_at) => {
+                    self.idle_tracker.mark_away();
+                    if let Some(session) = self.session.as_ref() {
+                        session.mark_away().await;
+                    }
+                }
+            }
+        }
+
+        let _ = SinkExt::<String>::close(&mut self.socket_writer).await;
+        Ok(())
+    }
+}
+
+async fn next_session_event(session: &mut Option<Session>) -> Option<SessionEvent> {
+    match session {
+        Some(session) => session.event_receiver.recv().await,
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn irc_message(command: &str, params: &[&str]) -> Option<IrcMessage> {
+        Some(IrcMessage {
+            command: String::from(command),
+            params: params.iter().map(|param| String::from(*param)).collect(),
+        })
+    }
+
+    #[test]
+    fn test_irc_message_parsing() {
+        assert_eq!(
+            parse_irc_message("NICK alice"),
+            irc_message("NICK", &["alice"])
+        );
+        assert_eq!(
+            parse_irc_message("USER alice 0 * :Alice Liddell"),
+            irc_message("USER", &["alice", "0", "*", "Alice Liddell"])
+        );
+        assert_eq!(
+            parse_irc_message("privmsg #budgetchat :hello :) there"),
+            irc_message("PRIVMSG", &["#budgetchat", "hello :) there"])
+        );
+        assert_eq!(
+            parse_irc_message(":alice!alice@host PRIVMSG #budgetchat :hi"),
+            irc_message("PRIVMSG", &["#budgetchat", "hi"])
+        );
+        assert_eq!(
+            parse_irc_message("PRIVMSG #budgetchat :"),
+            irc_message("PRIVMSG", &["#budgetchat", ""])
+        );
+        assert_eq!(
+            parse_irc_message("PING :token"),
+            irc_message("PING", &["token"])
+        );
+        assert_eq!(parse_irc_message("QUIT"), irc_message("QUIT", &[]));
+        assert_eq!(
+            parse_irc_message("JOIN  #a,#b "),
+            irc_message("JOIN", &["#a,#b"])
+        );
+
+        assert_eq!(parse_irc_message(""), None);
+        assert_eq!(parse_irc_message(":prefix"), None);
+        assert_eq!(parse_irc_message(":prefix "), None);
+    }
+
+    #[test]
+    fn test_room_event_translation() {
+        assert_eq!(
+            room_event_to_irc(&RoomEvent::Joined(String::from("alice"))),
+            ":alice!alice@budgetchat JOIN #budgetchat"
+        );
+        assert_eq!(
+            room_event_to_irc(&RoomEvent::Left(String::from("alice"))),
+            ":alice!alice@budgetchat PART #budgetchat"
+        );
+        assert_eq!(
+            room_event_to_irc(&RoomEvent::Renamed(
+                String::from("alice"),
+                String::from("bob")
+            )),
+            ":alice!alice@budgetchat NICK :bob"
+        );
+        assert_eq!(
+            room_event_to_irc(&RoomEvent::Message(
+                String::from("alice"),
+                String::from("hi there")
+            )),
+            ":alice!alice@budgetchat PRIVMSG #budgetchat :hi there"
+        );
+        assert_eq!(
+            room_event_to_irc(&RoomEvent::Away(String::from("alice"))),
+            ":budgetchat NOTICE #budgetchat :* alice is away"
+        );
+    }
+}
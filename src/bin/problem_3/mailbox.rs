@@ -0,0 +1,266 @@
+use std::collections::{BTreeMap, BTreeSet, VecDeque};
+use std::fs;
+use std::io::{Error as IO_Error, ErrorKind, Result as IO_Result};
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use serde::{Deserialize, Serialize};
+
+use crate::file_writer::{replace_file, FileWriter};
+use crate::transcript::format_timestamp;
+
+/// Changes are collected for this long and then saved at once
+const SAVE_DELAY: Duration = Duration::from_secs(1);
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct MailboxConfig {
+    /// File the mailboxes are saved to, they are only kept in memory without it
+    pub file: Option<PathBuf>,
+    /// Number of messages that may wait for a single recipient
+    pub capacity: usize,
+}
+
+impl Default for MailboxConfig {
+    fn default() -> Self {
+        MailboxConfig {
+            file: None,
+            capacity: 20,
+        }
+    }
+}
+
+/// Message left with `/tell` for a user that was not in the room
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct MailboxMessage {
+    /// Seconds since the Unix epoch
+    pub timestamp: u64,
+    pub sender: String,
+    pub text: String,
+}
+
+impl MailboxMessage {
+    /// Budget chat line shown to the recipient
+    pub fn to_line(&self) -> String {
+        format!(
+            "* {} {} told you: {}",
+            format_timestamp(self.timestamp),
+            self.sender,
+            self.text
+        )
+    }
+}
+
+/// Everything that is saved to the mailbox file
+#[derive(Debug, Default, PartialEq, Serialize, Deserialize)]
+struct MailboxState {
+    /// Names that were used in the room at some point, messages can be left for them
+    seen_names: BTreeSet<String>,
+    messages: BTreeMap<String, VecDeque<MailboxMessage>>,
+}
+
+/// Messages waiting for their recipients to join. With a mailbox file, changes are written to it
+/// shortly after they happened, see [`Mailbox::save_at`].
+pub struct Mailbox {
+    config: MailboxConfig,
+    state: MailboxState,
+    file_writer: Option<FileWriter<String>>,
+    /// When the first change that is not saved yet happened
+    changed_at: Option<Instant>,
+}
+
+impl Mailbox {
+    pub fn in_memory(config: MailboxConfig) -> Self {
+        Mailbox {
+            config: MailboxConfig {
+                file: None,
+                ..config
+            },
+            state: MailboxState::default(),
+            file_writer: None,
+            changed_at: None,
+        }
+    }
+
+    pub fn open(config: MailboxConfig) -> IO_Result<Self> {
+        let Some(file_path) = &config.file else {
+            return Ok(Mailbox::in_memory(config));
+        };
+
+        let state = match fs::read_to_string(file_path) {
+            Ok(saved_state) => serde_json::from_str(&saved_state)
+                .map_err(|e| IO_Error::new(ErrorKind::InvalidData, e))?,
+            // Nothing saved yet
+            Err(e) if e.kind() == ErrorKind::NotFound => MailboxState::default(),
+            Err(e) => return Err(e),
+        };
+        let file_path = file_path.clone();
+        let file_writer = FileWriter::start("mailbox", move |saved_state: String| {
+            if let Err(e) = replace_file(&file_path, &saved_state) {
+                println!("Failed to save mailboxes: {e}");
+            }
+        });
+        Ok(Mailbox {
+            config,
+            state,
+            file_writer: Some(file_writer),
+            changed_at: None,
+        })
+    }
+
+    pub fn has_seen(&self, user_name: &str) -> bool {
+        self.state.seen_names.contains(user_name)
+    }
+
+    pub fn record_seen(&mut self, user_name: &str) {
+        if self.state.seen_names.insert(String::from(user_name)) {
+            self.record_change();
+        }
+    }
+
+    /// Queues the message for the recipient. Returns false if the recipient's mailbox is full.
+    pub fn queue(&mut self, recipient: &str, message: MailboxMessage) -> bool {
+        let messages = self
+            .state
+            .messages
+            .entry(String::from(recipient))
+            .or_default();
+        if messages.len() >= self.config.capacity {
+            return false;
+        }
+        messages.push_back(message);
+        self.record_change();
+        true
+    }
+
+    /// Removes up to `limit` of the oldest messages for the recipient
+    pub fn take(&mut self, recipient: &str, limit: usize) -> Vec<MailboxMessage> {
+        let Some(messages) = self.state.messages.get_mut(recipient) else {
+            return Vec::new();
+        };
+
+        let taken_messages: Vec<MailboxMessage> =
+            messages.drain(..limit.min(messages.len())).collect();
+        if messages.is_empty() {
+            self.state.messages.remove(recipient);
+        }
+        if !taken_messages.is_empty() {
+            self.record_change();
+        }
+        taken_messages
+    }
+
+    fn record_change(&mut self) {
+        if self.file_writer.is_some() {
+            self.changed_at.get_or_insert_with(Instant::now);
+        }
+    }
+
+    /// When the changes have to be saved, never without changes
+    pub fn save_at(&self) -> Option<Instant> {
+        self.changed_at.map(|changed_at| changed_at + SAVE_DELAY)
+    }
+
+    /// Hands the changes to the file writer. Failing to save only puts the messages at risk on a
+    /// restart -> Keep going.
+    pub fn save(&mut self) {
+        let Some(file_writer) = &self.file_writer else {
+            return;
+        };
+        if self.changed_at.is_none() {
+            return;
+        }
+
+        match serde_json::to_string(&self.state) {
+            Ok(saved_state) => {
+                if file_writer.write(saved_state) {
+                    self.changed_at = None;
+                } else {
+                    // Try again later
+                    println!("Failed to save mailboxes: Too many saves waiting for the disk");
+                    self.changed_at = Some(Instant::now());
+                }
+            }
+            Err(e) => {
+                println!("Failed to save mailboxes: {e}");
+                self.changed_at = None;
+            }
+        }
+    }
+}
+
+impl Drop for Mailbox {
+    fn drop(&mut self) {
+        self.save();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(sender: &str, text: &str) -> MailboxMessage {
+        MailboxMessage {
+            timestamp: 1700000000,
+            sender: String::from(sender),
+            text: String::from(text),
+        }
+    }
+
+    #[test]
+    fn test_message_line() {
+        assert_eq!(
+            message("alice", "call me").to_line(),
+            "* 2023-11-14 22:13:20 alice told you: call me"
+        );
+    }
+
+    #[test]
+    fn test_mailbox_capacity() {
+        let mut mailbox = Mailbox::in_memory(MailboxConfig {
+            capacity: 2,
+            ..MailboxConfig::default()
+        });
+        assert!(mailbox.queue("bob", message("alice", "1")));
+        assert!(mailbox.queue("bob", message("alice", "2")));
+        assert!(!mailbox.queue("bob", message("alice", "3")));
+        assert!(mailbox.queue("carol", message("alice", "4")));
+
+        assert_eq!(mailbox.take("bob", 1), vec![message("alice", "1")]);
+        assert!(mailbox.queue("bob", message("alice", "5")));
+        assert_eq!(
+            mailbox.take("bob", 10),
+            vec![message("alice", "2"), message("alice", "5")]
+        );
+        assert_eq!(mailbox.take("bob", 10), vec![]);
+    }
+
+    #[test]
+    fn test_mailbox_survives_restart() {
+        let file_path =
+            std::env::temp_dir().join(format!("budgetchat-mailbox-{}.json", std::process::id()));
+        let _ = fs::remove_file(&file_path);
+        let config = MailboxConfig {
+            file: Some(file_path.clone()),
+            ..MailboxConfig::default()
+        };
+
+        let mut mailbox = Mailbox::open(config.clone()).unwrap();
+        mailbox.record_seen("bob");
+        assert!(mailbox.queue("bob", message("alice", "hi")));
+        assert!(mailbox.queue("carol", message("alice", "hey")));
+        assert_eq!(mailbox.take("carol", 10).len(), 1);
+        // Nothing is written before the changes are due, but dropping saves them
+        assert!(!file_path.exists());
+        assert!(mailbox.save_at().is_some());
+        drop(mailbox);
+
+        let mut mailbox = Mailbox::open(config).unwrap();
+        assert!(mailbox.has_seen("bob"));
+        assert!(!mailbox.has_seen("carol"));
+        assert_eq!(mailbox.take("bob", 10), vec![message("alice", "hi")]);
+        assert_eq!(mailbox.take("carol", 10), vec![]);
+        drop(mailbox);
+
+        fs::remove_file(&file_path).unwrap();
+    }
+}
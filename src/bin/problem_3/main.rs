@@ -0,0 +1,1508 @@
+mod bot;
+mod client;
+mod command;
+mod config;
+mod credentials;
+mod event;
+mod federation;
+mod file_writer;
+mod filter;
+mod irc;
+mod mailbox;
+mod moderation;
+mod name_policy;
+mod presence;
+mod rate_limit;
+mod room;
+mod session;
+mod transcript;
+mod transport;
+
+use std::io::Result as IO_Result;
+use std::net::SocketAddr;
+
+use futures::SinkExt;
+use tokio::net::TcpListener;
+
+use crate::bot::spawn_bots;
+use crate::client::{ChatRoomClient, UserPreambleError};
+use crate::config::ChatServerConfig;
+use crate::federation::{connect_to_peer, run_federation_listener};
+use crate::irc::IrcClient;
+use crate::session::{JoinError, SessionRegistry};
+use crate::transport::{tcp_lines, websocket_lines, websocket_to_io_error};
+
+#[tokio::main]
+async fn main() -> IO_Result<()> {
+    let tcp_listener = TcpListener::bind("0.0.0.0:8080").await?;
+
+    println!("Running server for Problem 3 on port 8080");
+
+    let registry = SessionRegistry::new(ChatServerConfig::from_env())?;
+
+    if let Some(websocket_port) = registry.config().websocket_port {
+        let websocket_listener = TcpListener::bind(("0.0.0.0", websocket_port)).await?;
+        println!("Accepting WebSocket connections on port {websocket_port}");
+        tokio::spawn(run_websocket_server(websocket_listener, registry.clone()));
+    }
+
+    if let Some(irc_port) = registry.config().irc_port {
+        let irc_listener = TcpListener::bind(("0.0.0.0", irc_port)).await?;
+        println!("Accepting IRC connections on port {irc_port}");
+        tokio::spawn(run_irc_server(irc_listener, registry.clone()));
+    }
+
+    let federation_config = registry.config().federation.clone();
+    if let Some(federation_port) = federation_config.port {
+        let federation_listener = TcpListener::bind(("0.0.0.0", federation_port)).await?;
+        println!("Accepting federation links on port {federation_port}");
+        tokio::spawn(run_federation_listener(
+            federation_listener,
+            registry.clone(),
+        ));
+    }
+    if let Some(peer_address) = federation_config.peer_address {
+        tokio::spawn(connect_to_peer(peer_address, registry.clone()));
+    }
+
+    spawn_bots(&registry);
+
+    run_chat_server(tcp_listener, registry).await
+}
+
+async fn run_chat_server(tcp_listener: TcpListener, registry: SessionRegistry) -> IO_Result<()> {
+    loop {
+        let (tcp_socket_stream, client_address) = tcp_listener.accept().await?;
+        let Some(current_connection) = accept_connection(&registry, client_address).await else {
+            continue;
+        };
+
+        // Create chat room client for user socket
+        let max_line_length = registry.config().flood_limits.max_line_length();
+        let chat_room_client = ChatRoomClient::new(
+            current_connection,
+            tcp_lines(tcp_socket_stream, max_line_length),
+            &registry,
+        );
+        tokio::spawn(handle_chat_client(
+            chat_room_client,
+            registry.clone(),
+            client_address,
+        ));
+    }
+}
+
+async fn run_websocket_server(
+    websocket_listener: TcpListener,
+    registry: SessionRegistry,
+) -> IO_Result<()> {
+    loop {
+        let (tcp_socket_stream, client_address) = websocket_listener.accept().await?;
+        let Some(current_connection) = accept_connection(&registry, client_address).await else {
+            continue;
+        };
+
+        let registry = registry.clone();
+        tokio::spawn(async move {
+            // Complete WebSocket handshake before treating the connection like any other client
+            let websocket_stream = tokio_tungstenite::accept_async(tcp_socket_stream)
+                .await
+                .map_err(|e| {
+                    println!("[{current_connection}] WebSocket handshake failed: {e}");
+                    websocket_to_io_error(e)
+                })?;
+            let max_line_length = registry.config().flood_limits.max_line_length();
+            let chat_room_client = ChatRoomClient::new(
+                current_connection,
+                websocket_lines(websocket_stream, max_line_length),
+                &registry,
+            );
+            handle_chat_client(chat_room_client, registry, client_address).await
+        });
+    }
+}
+
+async fn run_irc_server(irc_listener: TcpListener, registry: SessionRegistry) -> IO_Result<()> {
+    loop {
+        let (tcp_socket_stream, client_address) = irc_listener.accept().await?;
+        let Some(current_connection) = accept_connection(&registry, client_address).await else {
+            continue;
+        };
+
+        let mut irc_client = IrcClient::new(
+            current_connection,
+            client_address,
+            tcp_socket_stream,
+            registry.clone(),
+        );
+        tokio::spawn(async move { irc_client.process_irc_interchange().await });
+    }
+}
+
+/// Refuses connections from banned addresses and assigns an id to all other connections
+async fn accept_connection(registry: &SessionRegistry, client_address: SocketAddr) -> Option<i32> {
+    if registry.is_banned(&client_address.ip()).await {
+        // Banned address -> Close connection right away
+        println!("Refused connection from banned address {client_address}");
+        return None;
+    }
+
+    let current_connection = registry.next_connection_id();
+    println!("Established connection {current_connection} from {client_address}");
+    Some(current_connection)
+}
+
+async fn handle_chat_client(
+    mut chat_room_client: ChatRoomClient,
+    registry: SessionRegistry,
+    client_address: SocketAddr,
+) -> IO_Result<()> {
+    let chat_result = chat(&mut chat_room_client, &registry, client_address).await;
+
+    // Close connection gracefully, e.g. with a WebSocket close frame
+    let _ = chat_room_client.socket_writer.close().await;
+    chat_result
+}
+
+async fn chat(
+    chat_room_client: &mut ChatRoomClient,
+    registry: &SessionRegistry,
+    client_address: SocketAddr,
+) -> IO_Result<()> {
+    let current_connection = chat_room_client.connection_id;
+
+    // Handle new user join protocol
+    match chat_room_client.client_join_preamble(registry).await {
+        Ok((user_name, password)) => {
+            println!("[{current_connection}] New user joined: {user_name}");
+            // Check if name is already used
+            match registry
+                .try_join(
+                    current_connection,
+                    client_address.ip(),
+                    &user_name,
+                    password.as_deref(),
+                )
+                .await
+            {
+                Ok((session, participant_names_list)) => {
+                    // Valid name -> From here on the session announces the departure on every
+                    // exit path, so it is safe to use `?` below
+                    chat_room_client.session = Some(session);
+
+                    // Send list of current participant names to new user
+                    chat_room_client
+                        .send_message_to_user(format!(
+                            "* The room contains: {participant_names_list}"
+                        ))
+                        .await?;
+                }
+                Err(JoinError::InvalidName) => {
+                    // Name does not follow the name policy -> Close connection
+                    println!(
+                        "[{current_connection}] Name {user_name} is not allowed. Connection will be closed."
+                    );
+                    return Ok(());
+                }
+                Err(JoinError::NameTaken) => {
+                    // Duplicate user name -> Close connection
+                    println!(
+                        "[{current_connection}] Name {user_name} is already used. Connection will be closed."
+                    );
+                    return Ok(());
+                }
+                Err(JoinError::WrongPassword) => {
+                    println!(
+                        "[{current_connection}] Wrong password for {user_name}. Connection will be closed."
+                    );
+                    chat_room_client
+                        .send_message_to_user(String::from("* Wrong password"))
+                        .await?;
+                    return Ok(());
+                }
+            }
+        }
+        Err(UserPreambleError::Protocol(error_type)) => {
+            println!("[{current_connection}] Error {error_type}: Close Connection");
+            return Ok(());
+        }
+        Err(UserPreambleError::IO(e)) => {
+            println!("[{current_connection}] Error {e}: Close Connection");
+            return Err(e);
+        }
+    }
+
+    // Handle message interchange for connected user client
+    chat_room_client.process_message_interchange().await?;
+
+    // User disconnected -> Dropping the session broadcasts the exit message
+    println!("[{current_connection}] User disconnected");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use futures::{SinkExt, StreamExt};
+    use tokio::io::{copy_bidirectional, AsyncBufReadExt, AsyncWriteExt, BufReader, Lines};
+    use tokio::net::tcp::{OwnedReadHalf, OwnedWriteHalf};
+    use tokio::net::{TcpSocket, TcpStream};
+    use tokio::sync::Semaphore;
+    use tokio::task::JoinHandle;
+    use tokio::time::timeout;
+    use tokio_tungstenite::tungstenite::Message;
+    use tokio_tungstenite::{connect_async, MaybeTlsStream, WebSocketStream};
+
+    use super::*;
+    use crate::bot::{run_bot, BuildStatusBot, EchoBot};
+    use crate::federation::FederationConfig;
+    use crate::filter::{BuiltinFilter, FilterConfig};
+    use crate::mailbox::MailboxConfig;
+    use crate::name_policy::NamePolicy;
+    use crate::rate_limit::{FloodLimits, FloodPolicy, RateLimit};
+
+    struct TestClient {
+        lines: Lines<BufReader<OwnedReadHalf>>,
+        writer: OwnedWriteHalf,
+    }
+
+    impl TestClient {
+        async fn connect(server_address: SocketAddr) -> Self {
+            let tcp_stream = TcpStream::connect(server_address).await.unwrap();
+            Self::from_stream(tcp_stream)
+        }
+
+        /// Connects from another loopback address, so that the client can be banned separately
+        async fn connect_from(server_address: SocketAddr, local_address: &str) -> Self {
+            let tcp_socket = TcpSocket::new_v4().unwrap();
+            tcp_socket
+                .bind(format!("{local_address}:0").parse().unwrap())
+                .unwrap();
+            Self::from_stream(tcp_socket.connect(server_address).await.unwrap())
+        }
+
+        fn from_stream(tcp_stream: TcpStream) -> Self {
+            let (reader, writer) = tcp_stream.into_split();
+            TestClient {
+                lines: BufReader::new(reader).lines(),
+                writer,
+            }
+        }
+
+        async fn join(server_address: SocketAddr, user_name: &str) -> Self {
+            Self::connect(server_address)
+                .await
+                .send_name(user_name)
+                .await
+        }
+
+        async fn send_name(mut self, user_name: &str) -> Self {
+            self.read_line().await;
+            self.write_line(user_name).await;
+            self.read_line().await;
+            self
+        }
+
+        async fn read_line(&mut self) -> String {
+            self.try_read_line().await.unwrap()
+        }
+
+        async fn try_read_line(&mut self) -> Option<String> {
+            timeout(Duration::from_secs(5), self.lines.next_line())
+                .await
+                .unwrap()
+                .unwrap_or(None)
+        }
+
+        async fn write_line(&mut self, line: &str) {
+            self.writer
+                .write_all(format!("{line}\n").as_bytes())
+                .await
+                .unwrap();
+        }
+    }
+
+    async fn start_server() -> (SocketAddr, SessionRegistry) {
+        start_server_with_config(ChatServerConfig::default()).await
+    }
+
+    async fn start_server_with_config(config: ChatServerConfig) -> (SocketAddr, SessionRegistry) {
+        let tcp_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_address = tcp_listener.local_addr().unwrap();
+        let registry = SessionRegistry::new(config).unwrap();
+        tokio::spawn(run_chat_server(tcp_listener, registry.clone()));
+        (server_address, registry)
+    }
+
+    async fn wait_for_names(registry: &SessionRegistry, expected_names: &[&str]) {
+        let expected_names: HashSet<String> = expected_names
+            .iter()
+            .map(|name| String::from(*name))
+            .collect();
+        timeout(Duration::from_secs(5), async {
+            while registry.participant_names().await != expected_names {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_nick_command() {
+        let (server_address, registry) = start_server().await;
+        let mut alice = TestClient::join(server_address, "alice").await;
+        let mut bob = TestClient::join(server_address, "bob").await;
+        assert_eq!(alice.read_line().await, "* bob has entered the room");
+
+        bob.write_line("/nick alice").await;
+        assert_eq!(bob.read_line().await, "* Name alice is already used");
+        bob.write_line("/nick b-o-b").await;
+        assert_eq!(
+            bob.read_line().await,
+            "* Name b-o-b is not a valid user name"
+        );
+
+        bob.write_line("/nick robert").await;
+        assert_eq!(bob.read_line().await, "* bob is now known as robert");
+        assert_eq!(alice.read_line().await, "* bob is now known as robert");
+        wait_for_names(&registry, &["alice", "robert"]).await;
+
+        bob.write_line("hi").await;
+        assert_eq!(alice.read_line().await, "[robert] hi");
+
+        // Old name is free again
+        TestClient::join(server_address, "bob").await;
+        assert_eq!(alice.read_line().await, "* bob has entered the room");
+    }
+
+    #[tokio::test]
+    async fn test_search_command() {
+        let (server_address, _) = start_server().await;
+        let mut alice = TestClient::join(server_address, "alice").await;
+        let mut bob = TestClient::join(server_address, "bob").await;
+        assert_eq!(alice.read_line().await, "* bob has entered the room");
+
+        alice.write_line("The build is green").await;
+        assert_eq!(bob.read_line().await, "[alice] The build is green");
+        alice.write_line("lunch?").await;
+        assert_eq!(bob.read_line().await, "[alice] lunch?");
+
+        // Results go to the requesting user only
+        bob.write_line("/search BUILD").await;
+        let search_result = bob.read_line().await;
+        assert!(search_result.starts_with("* "));
+        assert!(search_result.ends_with(" [alice] The build is green"));
+        bob.write_line("/search bob").await;
+        assert!(bob
+            .read_line()
+            .await
+            .ends_with(" * bob has entered the room"));
+        bob.write_line("/search dinner").await;
+        assert_eq!(bob.read_line().await, "* No messages found for dinner");
+
+        bob.write_line("done").await;
+        assert_eq!(alice.read_line().await, "[bob] done");
+    }
+
+    #[tokio::test]
+    async fn test_socket_killed_before_name_is_sent() {
+        let (server_address, registry) = start_server().await;
+        let mut alice = TestClient::join(server_address, "alice").await;
+
+        let mut quitter = TestClient::connect(server_address).await;
+        quitter.read_line().await;
+        drop(quitter);
+
+        let bob = TestClient::join(server_address, "bob").await;
+        assert_eq!(alice.read_line().await, "* bob has entered the room");
+        wait_for_names(&registry, &["alice", "bob"]).await;
+        drop(bob);
+    }
+
+    #[tokio::test]
+    async fn test_socket_killed_before_room_list_is_read() {
+        let (server_address, registry) = start_server().await;
+        let mut alice = TestClient::join(server_address, "alice").await;
+
+        // Closing right after sending the name might make the room list write fail
+        let mut quitter = TestClient::connect(server_address).await;
+        quitter.read_line().await;
+        quitter.write_line("bob").await;
+        drop(quitter);
+
+        assert_eq!(alice.read_line().await, "* bob has entered the room");
+        assert_eq!(alice.read_line().await, "* bob has left the room");
+        wait_for_names(&registry, &["alice"]).await;
+
+        // Name can be used again
+        TestClient::join(server_address, "bob").await;
+    }
+
+    #[tokio::test]
+    async fn test_socket_killed_during_message_interchange() {
+        let (server_address, registry) = start_server().await;
+        let mut alice = TestClient::join(server_address, "alice").await;
+        let mut bob = TestClient::join(server_address, "bob").await;
+        assert_eq!(alice.read_line().await, "* bob has entered the room");
+
+        bob.write_line("hello").await;
+        assert_eq!(alice.read_line().await, "[bob] hello");
+        drop(bob);
+
+        assert_eq!(alice.read_line().await, "* bob has left the room");
+        wait_for_names(&registry, &["alice"]).await;
+    }
+
+    #[tokio::test]
+    async fn test_socket_killed_with_pending_writes() {
+        let (server_address, registry) = start_server().await;
+        let mut alice = TestClient::join(server_address, "alice").await;
+        let bob = TestClient::join(server_address, "bob").await;
+        assert_eq!(alice.read_line().await, "* bob has entered the room");
+
+        // Bob's socket is gone while messages are still being written to it
+        drop(bob);
+        for i in 0..10 {
+            alice.write_line(&format!("message {i}")).await;
+        }
+
+        assert_eq!(alice.read_line().await, "* bob has left the room");
+        wait_for_names(&registry, &["alice"]).await;
+    }
+
+    fn operator_config() -> ChatServerConfig {
+        ChatServerConfig {
+            operator_password: Some(String::from("secret")),
+            operator_names: HashSet::from([String::from("alice")]),
+            ..ChatServerConfig::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_name_policy() {
+        let (server_address, registry) = start_server_with_config(ChatServerConfig {
+            name_policy: NamePolicy {
+                case_insensitive: true,
+                ..NamePolicy::default()
+            },
+            ..ChatServerConfig::default()
+        })
+        .await;
+        // Longer than 16 bytes, but only 10 characters
+        let mut alexandra = TestClient::join(server_address, "Александра").await;
+
+        for user_name in ["александра", "Ａlice", "al ice"] {
+            let mut client = TestClient::connect(server_address).await;
+            client.read_line().await;
+            client.write_line(user_name).await;
+            assert_eq!(client.try_read_line().await, None);
+        }
+
+        alexandra.write_line("/nick Ｓasha").await;
+        assert_eq!(
+            alexandra.read_line().await,
+            "* Name Ｓasha is not a valid user name"
+        );
+        alexandra.write_line("/nick Sasha").await;
+        assert_eq!(
+            alexandra.read_line().await,
+            "* Александра is now known as Sasha"
+        );
+        wait_for_names(&registry, &["Sasha"]).await;
+    }
+
+    #[tokio::test]
+    async fn test_presence() {
+        let (server_address, _) = start_server_with_config(ChatServerConfig {
+            away_after: Some(Duration::from_millis(300)),
+            ..ChatServerConfig::default()
+        })
+        .await;
+        let mut alice = TestClient::join(server_address, "alice").await;
+        let mut bob = TestClient::join(server_address, "bob").await;
+        assert_eq!(alice.read_line().await, "* bob has entered the room");
+
+        alice.write_line("/who").await;
+        assert!(alice
+            .read_line()
+            .await
+            .starts_with("* alice: connected 0s, idle "));
+        assert!(alice
+            .read_line()
+            .await
+            .starts_with("* bob: connected 0s, idle "));
+
+        // Both users go away, which only the other one is told about
+        assert_eq!(alice.read_line().await, "* bob is away");
+        assert_eq!(bob.read_line().await, "* alice is away");
+        bob.write_line("/who").await;
+        assert_eq!(alice.read_line().await, "* bob is back");
+        assert!(bob
+            .read_line()
+            .await
+            .starts_with("* alice (away): connected "));
+        assert!(bob.read_line().await.starts_with("* bob: connected "));
+
+        alice.write_line("hi").await;
+        assert_eq!(bob.read_line().await, "* alice is back");
+        assert_eq!(bob.read_line().await, "[alice] hi");
+    }
+
+    #[tokio::test]
+    async fn test_kick_command() {
+        let (server_address, registry) = start_server_with_config(operator_config()).await;
+        let mut alice = TestClient::join(server_address, "alice").await;
+        let mut bob = TestClient::join(server_address, "bob").await;
+        assert_eq!(alice.read_line().await, "* bob has entered the room");
+
+        bob.write_line("/kick alice").await;
+        assert_eq!(bob.read_line().await, "* Only operators can do that");
+
+        alice.write_line("/kick carol").await;
+        assert_eq!(alice.read_line().await, "* There is no user carol");
+        alice.write_line("/kick bob").await;
+        assert_eq!(alice.read_line().await, "* Kicked bob");
+        assert_eq!(alice.read_line().await, "* bob has left the room");
+
+        assert_eq!(bob.read_line().await, "* You have been kicked by alice");
+        assert_eq!(bob.try_read_line().await, None);
+        wait_for_names(&registry, &["alice"]).await;
+
+        // Kicked users may reconnect
+        TestClient::join(server_address, "bob").await;
+    }
+
+    #[tokio::test]
+    async fn test_operator_password() {
+        let (server_address, _) = start_server_with_config(operator_config()).await;
+        let mut alice = TestClient::join(server_address, "alice").await;
+        let mut bob = TestClient::join(server_address, "bob").await;
+        assert_eq!(alice.read_line().await, "* bob has entered the room");
+
+        bob.write_line("/op guess").await;
+        assert_eq!(bob.read_line().await, "* Invalid operator password");
+        bob.write_line("/op secret").await;
+        assert_eq!(bob.read_line().await, "* You are now an operator");
+        bob.write_line("/kick alice").await;
+        assert_eq!(bob.read_line().await, "* Kicked alice");
+        assert_eq!(alice.read_line().await, "* You have been kicked by bob");
+    }
+
+    #[tokio::test]
+    async fn test_mute_command() {
+        let (server_address, _) = start_server_with_config(operator_config()).await;
+        let mut alice = TestClient::join(server_address, "alice").await;
+        let mut bob = TestClient::join(server_address, "bob").await;
+        assert_eq!(alice.read_line().await, "* bob has entered the room");
+
+        alice.write_line("/mute bob").await;
+        assert_eq!(alice.read_line().await, "* Muted bob");
+        assert_eq!(bob.read_line().await, "* You have been muted by alice");
+
+        bob.write_line("hello").await;
+        assert_eq!(bob.read_line().await, "* You are muted");
+        bob.write_line("/tell alice hello").await;
+        assert_eq!(bob.read_line().await, "* You are muted");
+
+        alice.write_line("/unmute bob").await;
+        assert_eq!(alice.read_line().await, "* Unmuted bob");
+        assert_eq!(bob.read_line().await, "* You have been unmuted by alice");
+
+        bob.write_line("hello again").await;
+        assert_eq!(alice.read_line().await, "[bob] hello again");
+    }
+
+    #[tokio::test]
+    async fn test_ban_command() {
+        let (server_address, registry) = start_server_with_config(operator_config()).await;
+        let mut alice = TestClient::join(server_address, "alice").await;
+        let mut bob = TestClient::connect_from(server_address, "127.0.0.2")
+            .await
+            .send_name("bob")
+            .await;
+        assert_eq!(alice.read_line().await, "* bob has entered the room");
+
+        alice.write_line("/ban bob").await;
+        assert_eq!(alice.read_line().await, "* Banned 127.0.0.2");
+        assert_eq!(alice.read_line().await, "* bob has left the room");
+        assert_eq!(bob.read_line().await, "* You have been banned by alice");
+        assert_eq!(bob.try_read_line().await, None);
+        wait_for_names(&registry, &["alice"]).await;
+
+        // Banned address is refused at accept time
+        let mut banned_client = TestClient::connect_from(server_address, "127.0.0.2").await;
+        assert_eq!(banned_client.try_read_line().await, None);
+
+        // Other addresses can still connect
+        let _carol = TestClient::connect_from(server_address, "127.0.0.3")
+            .await
+            .send_name("carol")
+            .await;
+        assert_eq!(alice.read_line().await, "* carol has entered the room");
+
+        alice.write_line("/unban bob").await;
+        assert_eq!(alice.read_line().await, "* Ban lifted");
+        TestClient::connect_from(server_address, "127.0.0.2")
+            .await
+            .send_name("bob")
+            .await;
+        assert_eq!(alice.read_line().await, "* bob has entered the room");
+    }
+
+    fn flood_config(policy: FloodPolicy) -> ChatServerConfig {
+        ChatServerConfig {
+            flood_limits: FloodLimits {
+                messages: Some(RateLimit {
+                    per_second: 0.1,
+                    burst: 2.0,
+                }),
+                bytes: None,
+                max_message_length: 20,
+                policy,
+            },
+            ..ChatServerConfig::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_flood_protection_warns() {
+        let (server_address, _) = start_server_with_config(flood_config(FloodPolicy::Warn)).await;
+        let mut alice = TestClient::join(server_address, "alice").await;
+        let mut bob = TestClient::join(server_address, "bob").await;
+        assert_eq!(alice.read_line().await, "* bob has entered the room");
+
+        bob.write_line(&"a".repeat(21)).await;
+        assert_eq!(
+            bob.read_line().await,
+            "* Messages must not be longer than 20 characters"
+        );
+
+        for i in 0..3 {
+            bob.write_line(&format!("message {i}")).await;
+        }
+        assert_eq!(bob.read_line().await, "* You are sending messages too fast");
+
+        // Only the messages within the limits reach the room
+        assert_eq!(alice.read_line().await, "[bob] message 0");
+        assert_eq!(alice.read_line().await, "[bob] message 1");
+        alice.write_line("done").await;
+        assert_eq!(bob.read_line().await, "[alice] done");
+    }
+
+    #[tokio::test]
+    async fn test_flood_protection_disconnects() {
+        let (server_address, registry) =
+            start_server_with_config(flood_config(FloodPolicy::Disconnect)).await;
+        let mut alice = TestClient::join(server_address, "alice").await;
+        let mut bob = TestClient::join(server_address, "bob").await;
+        assert_eq!(alice.read_line().await, "* bob has entered the room");
+
+        for i in 0..3 {
+            bob.write_line(&format!("message {i}")).await;
+        }
+        assert_eq!(bob.read_line().await, "* You are sending messages too fast");
+        assert_eq!(bob.try_read_line().await, None);
+
+        assert_eq!(alice.read_line().await, "[bob] message 0");
+        assert_eq!(alice.read_line().await, "[bob] message 1");
+        assert_eq!(alice.read_line().await, "* bob has left the room");
+        wait_for_names(&registry, &["alice"]).await;
+    }
+
+    #[tokio::test]
+    async fn test_flood_protection_throttles() {
+        let config = ChatServerConfig {
+            flood_limits: FloodLimits {
+                messages: None,
+                bytes: Some(RateLimit {
+                    per_second: 20.0,
+                    burst: 20.0,
+                }),
+                ..flood_config(FloodPolicy::Throttle).flood_limits
+            },
+            session_queue_capacity: 4,
+            ..ChatServerConfig::default()
+        };
+        let (server_address, registry) = start_server_with_config(config).await;
+        let mut alice = TestClient::join(server_address, "alice").await;
+        let mut bob = TestClient::join(server_address, "bob").await;
+        assert_eq!(alice.read_line().await, "* bob has entered the room");
+
+        let long_message = "a".repeat(20);
+        for _ in 0..3 {
+            bob.write_line(&long_message).await;
+        }
+        // More room events than fit into the queue arrive while bob is throttled
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        for i in 0..10 {
+            alice.write_line(&i.to_string()).await;
+        }
+        for i in 0..10 {
+            assert_eq!(bob.read_line().await, format!("[alice] {i}"));
+        }
+
+        for _ in 0..3 {
+            assert_eq!(alice.read_line().await, format!("[bob] {long_message}"));
+        }
+        wait_for_names(&registry, &["alice", "bob"]).await;
+    }
+
+    #[tokio::test]
+    async fn test_too_long_lines() {
+        let (server_address, registry) =
+            start_server_with_config(flood_config(FloodPolicy::Warn)).await;
+        let mut alice = TestClient::join(server_address, "alice").await;
+        let mut bob = TestClient::join(server_address, "bob").await;
+        assert_eq!(alice.read_line().await, "* bob has entered the room");
+
+        // Lines are dropped while they are read, long before the end of the line arrives
+        let too_long_line = "a".repeat(registry.config().flood_limits.max_line_length() + 1);
+        bob.writer
+            .write_all(too_long_line.as_bytes())
+            .await
+            .unwrap();
+        assert_eq!(
+            bob.read_line().await,
+            "* Messages must not be longer than 20 characters"
+        );
+        // The rest of the line is skipped as well
+        bob.write_line("end of the line").await;
+        bob.write_line("still here").await;
+        assert_eq!(alice.read_line().await, "[bob] still here");
+
+        let (server_address, registry) =
+            start_server_with_config(flood_config(FloodPolicy::Disconnect)).await;
+        let mut alice = TestClient::join(server_address, "alice").await;
+        let mut bob = TestClient::join(server_address, "bob").await;
+        assert_eq!(alice.read_line().await, "* bob has entered the room");
+        bob.write_line(&too_long_line).await;
+        assert_eq!(
+            bob.read_line().await,
+            "* Messages must not be longer than 20 characters"
+        );
+        assert_eq!(bob.try_read_line().await, None);
+        wait_for_names(&registry, &["alice"]).await;
+    }
+
+    struct WebSocketTestClient {
+        websocket_stream: WebSocketStream<MaybeTlsStream<TcpStream>>,
+    }
+
+    impl WebSocketTestClient {
+        async fn join(websocket_address: SocketAddr, user_name: &str) -> Self {
+            let (websocket_stream, _) = connect_async(format!("ws://{websocket_address}"))
+                .await
+                .unwrap();
+            let mut client = WebSocketTestClient { websocket_stream };
+            client.read_line().await;
+            client.write_line(user_name).await;
+            client.read_line().await;
+            client
+        }
+
+        async fn read_message(&mut self) -> Message {
+            timeout(Duration::from_secs(5), self.websocket_stream.next())
+                .await
+                .unwrap()
+                .unwrap()
+                .unwrap()
+        }
+
+        async fn read_line(&mut self) -> String {
+            self.read_message().await.into_text().unwrap()
+        }
+
+        async fn write_line(&mut self, line: &str) {
+            self.websocket_stream
+                .send(Message::Text(String::from(line)))
+                .await
+                .unwrap();
+        }
+    }
+
+    async fn start_websocket_listener(registry: &SessionRegistry) -> SocketAddr {
+        let websocket_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let websocket_address = websocket_listener.local_addr().unwrap();
+        tokio::spawn(run_websocket_server(websocket_listener, registry.clone()));
+        websocket_address
+    }
+
+    #[tokio::test]
+    async fn test_websocket_and_tcp_users_share_room() {
+        let (server_address, registry) = start_server().await;
+        let websocket_address = start_websocket_listener(&registry).await;
+
+        let mut alice = TestClient::join(server_address, "alice").await;
+
+        let (websocket_stream, _) = connect_async(format!("ws://{websocket_address}"))
+            .await
+            .unwrap();
+        let mut bob = WebSocketTestClient { websocket_stream };
+        assert_eq!(
+            bob.read_line().await,
+            "Welcome to budgetchat! What shall I call you?"
+        );
+        bob.write_line("alice").await;
+        assert!(bob.read_message().await.is_close());
+
+        let mut bob = WebSocketTestClient::join(websocket_address, "bob").await;
+        assert_eq!(alice.read_line().await, "* bob has entered the room");
+        wait_for_names(&registry, &["alice", "bob"]).await;
+
+        bob.write_line("hi").await;
+        assert_eq!(alice.read_line().await, "[bob] hi");
+        alice.write_line("hello").await;
+        assert_eq!(bob.read_line().await, "[alice] hello");
+
+        // Several lines in a single WebSocket message are separate chat messages
+        bob.write_line("first\nsecond\n").await;
+        assert_eq!(alice.read_line().await, "[bob] first");
+        assert_eq!(alice.read_line().await, "[bob] second");
+
+        let mut carol = TestClient::join(server_address, "carol").await;
+        assert_eq!(bob.read_line().await, "* carol has entered the room");
+        assert_eq!(alice.read_line().await, "* carol has entered the room");
+
+        bob.websocket_stream.close(None).await.unwrap();
+        assert_eq!(alice.read_line().await, "* bob has left the room");
+        assert_eq!(carol.read_line().await, "* bob has left the room");
+        wait_for_names(&registry, &["alice", "carol"]).await;
+    }
+
+    struct IrcTestClient {
+        reader: BufReader<OwnedReadHalf>,
+        writer: OwnedWriteHalf,
+    }
+
+    impl IrcTestClient {
+        async fn connect(irc_address: SocketAddr) -> Self {
+            let (reader, writer) = TcpStream::connect(irc_address).await.unwrap().into_split();
+            IrcTestClient {
+                reader: BufReader::new(reader),
+                writer,
+            }
+        }
+
+        async fn register(&mut self, nick: &str) {
+            self.write_line(&format!("NICK {nick}")).await;
+            self.write_line(&format!("USER {nick} 0 * :{nick}")).await;
+        }
+
+        async fn read_line(&mut self) -> String {
+            let mut line = String::new();
+            timeout(Duration::from_secs(5), self.reader.read_line(&mut line))
+                .await
+                .unwrap()
+                .unwrap();
+            String::from(line.strip_suffix("\r\n").expect("IRC lines end with CR LF"))
+        }
+
+        async fn is_closed(&mut self) -> bool {
+            let mut line = String::new();
+            timeout(Duration::from_secs(5), self.reader.read_line(&mut line))
+                .await
+                .unwrap()
+                .is_ok_and(|read_bytes| read_bytes == 0)
+        }
+
+        async fn write_line(&mut self, line: &str) {
+            self.writer
+                .write_all(format!("{line}\r\n").as_bytes())
+                .await
+                .unwrap();
+        }
+    }
+
+    async fn start_irc_listener(registry: &SessionRegistry) -> SocketAddr {
+        let irc_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let irc_address = irc_listener.local_addr().unwrap();
+        tokio::spawn(run_irc_server(irc_listener, registry.clone()));
+        irc_address
+    }
+
+    #[tokio::test]
+    async fn test_irc_and_tcp_users_share_room() {
+        let (server_address, registry) = start_server().await;
+        let irc_address = start_irc_listener(&registry).await;
+
+        let mut alice = TestClient::join(server_address, "alice").await;
+
+        let mut bob = IrcTestClient::connect(irc_address).await;
+        bob.write_line("PING :early").await;
+        assert_eq!(bob.read_line().await, ":budgetchat PONG budgetchat :early");
+        bob.write_line("NAMES").await;
+        assert_eq!(
+            bob.read_line().await,
+            ":budgetchat 451 * :You have not registered"
+        );
+
+        // Taken nick -> Registration completes with another nick
+        bob.register("alice").await;
+        assert_eq!(
+            bob.read_line().await,
+            ":budgetchat 433 alice alice :Nickname is already in use"
+        );
+        bob.write_line("NICK bob").await;
+        assert_eq!(
+            bob.read_line().await,
+            ":budgetchat 001 bob :Welcome to budgetchat, bob"
+        );
+        assert_eq!(
+            bob.read_line().await,
+            ":bob!bob@budgetchat JOIN #budgetchat"
+        );
+        assert_eq!(
+            bob.read_line().await,
+            ":budgetchat 353 bob = #budgetchat :alice bob"
+        );
+        assert_eq!(
+            bob.read_line().await,
+            ":budgetchat 366 bob #budgetchat :End of /NAMES list"
+        );
+        assert_eq!(alice.read_line().await, "* bob has entered the room");
+        wait_for_names(&registry, &["alice", "bob"]).await;
+
+        bob.write_line("PRIVMSG #budgetchat :hi :)").await;
+        assert_eq!(alice.read_line().await, "[bob] hi :)");
+        alice.write_line("hello").await;
+        assert_eq!(
+            bob.read_line().await,
+            ":alice!alice@budgetchat PRIVMSG #budgetchat :hello"
+        );
+
+        bob.write_line("PRIVMSG #elsewhere :hi").await;
+        assert_eq!(
+            bob.read_line().await,
+            ":budgetchat 401 bob #elsewhere :No such nick/channel"
+        );
+
+        // Chat commands work through PRIVMSG as well
+        bob.write_line("PRIVMSG #budgetchat :/nick robert").await;
+        assert_eq!(bob.read_line().await, ":bob!bob@budgetchat NICK :robert");
+        assert_eq!(
+            bob.read_line().await,
+            ":budgetchat NOTICE robert :* bob is now known as robert"
+        );
+        assert_eq!(alice.read_line().await, "* bob is now known as robert");
+
+        alice.write_line("/nick alicia").await;
+        alice.read_line().await;
+        assert_eq!(
+            bob.read_line().await,
+            ":alice!alice@budgetchat NICK :alicia"
+        );
+
+        bob.write_line("PART #budgetchat").await;
+        assert_eq!(
+            bob.read_line().await,
+            ":robert!robert@budgetchat PART #budgetchat"
+        );
+        assert_eq!(alice.read_line().await, "* robert has left the room");
+        wait_for_names(&registry, &["alicia"]).await;
+
+        bob.write_line("JOIN #budgetchat").await;
+        assert_eq!(
+            bob.read_line().await,
+            ":robert!robert@budgetchat JOIN #budgetchat"
+        );
+        bob.read_line().await;
+        bob.read_line().await;
+        assert_eq!(alice.read_line().await, "* robert has entered the room");
+
+        let _carol = TestClient::join(server_address, "carol").await;
+        assert_eq!(
+            bob.read_line().await,
+            ":carol!carol@budgetchat JOIN #budgetchat"
+        );
+
+        bob.write_line("QUIT :bye").await;
+        assert_eq!(bob.read_line().await, "ERROR :Closing link");
+        assert!(bob.is_closed().await);
+        assert_eq!(alice.read_line().await, "* carol has entered the room");
+        assert_eq!(alice.read_line().await, "* robert has left the room");
+        wait_for_names(&registry, &["alicia", "carol"]).await;
+    }
+
+    fn credentials_config(test_name: &str) -> ChatServerConfig {
+        let credentials_file =
+            std::env::temp_dir().join(format!("budgetchat-{test_name}-{}.txt", std::process::id()));
+        let _ = std::fs::remove_file(&credentials_file);
+        ChatServerConfig {
+            credentials_file: Some(credentials_file),
+            ..ChatServerConfig::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_register_command() {
+        let config = credentials_config("register-command");
+        let credentials_file = config.credentials_file.clone().unwrap();
+        let (server_address, registry) = start_server_with_config(config).await;
+
+        let mut alice = TestClient::join(server_address, "alice").await;
+        alice.write_line("/register").await;
+        assert_eq!(alice.read_line().await, "* Usage: /register <password>");
+        alice.write_line("/register secret").await;
+        assert_eq!(alice.read_line().await, "* Registered name alice");
+        drop(alice);
+        wait_for_names(&registry, &[]).await;
+
+        // Registered name -> Password is asked for before joining
+        let mut alice = TestClient::connect(server_address).await;
+        alice.read_line().await;
+        alice.write_line("alice").await;
+        assert_eq!(
+            alice.read_line().await,
+            "* Name alice is registered, what is your password?"
+        );
+        alice.write_line("guess").await;
+        assert_eq!(alice.read_line().await, "* Wrong password");
+        assert_eq!(alice.try_read_line().await, None);
+
+        let mut alice = TestClient::connect(server_address).await;
+        alice.read_line().await;
+        alice.write_line("alice").await;
+        alice.read_line().await;
+        alice.write_line("secret").await;
+        assert_eq!(alice.read_line().await, "* The room contains: -");
+
+        // Other names still join without a password, but cannot take the registered name
+        let mut bob = TestClient::join(server_address, "bob").await;
+        assert_eq!(alice.read_line().await, "* bob has entered the room");
+        drop(alice);
+        assert_eq!(bob.read_line().await, "* alice has left the room");
+        bob.write_line("/nick alice").await;
+        assert_eq!(
+            bob.read_line().await,
+            "* Name alice is registered, join with its password to use it"
+        );
+
+        std::fs::remove_file(&credentials_file).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_irc_password() {
+        let config = credentials_config("irc-password");
+        let credentials_file = config.credentials_file.clone().unwrap();
+        let (server_address, registry) = start_server_with_config(config).await;
+        let irc_address = start_irc_listener(&registry).await;
+
+        let mut alice = TestClient::join(server_address, "alice").await;
+        alice.write_line("/register secret").await;
+        alice.read_line().await;
+        drop(alice);
+        wait_for_names(&registry, &[]).await;
+
+        let mut alice = IrcTestClient::connect(irc_address).await;
+        alice.register("alice").await;
+        assert_eq!(
+            alice.read_line().await,
+            ":budgetchat 464 alice :Password incorrect"
+        );
+        alice.write_line("PASS secret").await;
+        alice.write_line("NICK alice").await;
+        assert_eq!(
+            alice.read_line().await,
+            ":budgetchat 001 alice :Welcome to budgetchat, alice"
+        );
+        assert_eq!(
+            alice.read_line().await,
+            ":alice!alice@budgetchat JOIN #budgetchat"
+        );
+        alice.read_line().await;
+        alice.read_line().await;
+        alice.write_line("PASS other").await;
+        assert_eq!(
+            alice.read_line().await,
+            ":budgetchat 462 alice :You may not reregister"
+        );
+
+        std::fs::remove_file(&credentials_file).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_tell_command() {
+        let mailbox_file =
+            std::env::temp_dir().join(format!("budgetchat-tell-{}.json", std::process::id()));
+        let _ = std::fs::remove_file(&mailbox_file);
+        let config = ChatServerConfig {
+            mailbox: MailboxConfig {
+                file: Some(mailbox_file.clone()),
+                capacity: 2,
+            },
+            ..ChatServerConfig::default()
+        };
+        let (server_address, registry) = start_server_with_config(config.clone()).await;
+
+        let mut alice = TestClient::join(server_address, "alice").await;
+        let bob = TestClient::join(server_address, "bob").await;
+        alice.read_line().await;
+        drop(bob);
+        assert_eq!(alice.read_line().await, "* bob has left the room");
+
+        alice.write_line("/tell carol hi").await;
+        assert_eq!(alice.read_line().await, "* Unknown user carol");
+        for message in ["hi bob", "call me"] {
+            alice.write_line(&format!("/tell bob {message}")).await;
+            assert_eq!(
+                alice.read_line().await,
+                "* bob will get your message when joining"
+            );
+        }
+        alice.write_line("/tell bob are you there?").await;
+        assert_eq!(alice.read_line().await, "* Mailbox of bob is full");
+        drop(alice);
+        wait_for_names(&registry, &[]).await;
+        // Changes are saved shortly after they happened
+        timeout(Duration::from_secs(5), async {
+            while !std::fs::read_to_string(&mailbox_file)
+                .is_ok_and(|saved| saved.contains("call me"))
+            {
+                tokio::time::sleep(Duration::from_millis(50)).await;
+            }
+        })
+        .await
+        .unwrap();
+
+        // Mailboxes survive a restart and are delivered after the room list
+        let (server_address, _) = start_server_with_config(config).await;
+        let mut bob = TestClient::connect(server_address).await;
+        bob.read_line().await;
+        bob.write_line("bob").await;
+        assert_eq!(bob.read_line().await, "* The room contains: -");
+        assert!(bob.read_line().await.ends_with(" alice told you: hi bob"));
+        assert!(bob.read_line().await.ends_with(" alice told you: call me"));
+
+        // Users in the room are told right away
+        let mut alice = TestClient::join(server_address, "alice").await;
+        bob.read_line().await;
+        alice.write_line("/tell bob lunch?").await;
+        assert_eq!(alice.read_line().await, "* Told bob");
+        assert!(bob.read_line().await.ends_with(" alice told you: lunch?"));
+
+        std::fs::remove_file(&mailbox_file).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_message_filters() {
+        let (server_address, _) = start_server_with_config(ChatServerConfig {
+            message_filters: FilterConfig {
+                filters: vec![
+                    BuiltinFilter::Secrets,
+                    BuiltinFilter::Links,
+                    BuiltinFilter::Keywords,
+                ],
+                flag_words: vec![String::from("refund")],
+                ..FilterConfig::default()
+            },
+            ..operator_config()
+        })
+        .await;
+        let mut alice = TestClient::join(server_address, "alice").await;
+        let mut bob = TestClient::join(server_address, "bob").await;
+        alice.read_line().await;
+
+        bob.write_line("see https://example.com").await;
+        assert_eq!(
+            bob.read_line().await,
+            "* Message not sent: Links are not allowed"
+        );
+        bob.write_line("my password=hunter2").await;
+        assert_eq!(alice.read_line().await, "[bob] my password=[redacted]");
+
+        // Flagged messages are still broadcast, operators learn about them first
+        bob.write_line("I want a refund").await;
+        assert_eq!(
+            alice.read_line().await,
+            "* Flagged message from bob: mentions refund"
+        );
+        assert_eq!(alice.read_line().await, "[bob] I want a refund");
+    }
+
+    #[tokio::test]
+    async fn test_bots_take_part_in_room() {
+        let (server_address, registry) = start_server().await;
+        tokio::spawn(run_bot(Box::new(EchoBot), registry.clone(), None));
+        let (status_sender, status_receiver) = tokio::sync::mpsc::channel(1);
+        tokio::spawn(run_bot(
+            Box::new(BuildStatusBot::default()),
+            registry.clone(),
+            Some(status_receiver),
+        ));
+        wait_for_names(&registry, &["echobot", "buildbot"]).await;
+
+        let mut alice = TestClient::connect(server_address).await;
+        alice.read_line().await;
+        alice.write_line("alice").await;
+        let room_list = alice.read_line().await;
+        assert!(room_list.contains("echobot") && room_list.contains("buildbot"));
+
+        // Bot names are taken like any other name
+        alice.write_line("/nick echobot").await;
+        assert_eq!(alice.read_line().await, "* Name echobot is already used");
+
+        alice.write_line("!echo hello").await;
+        assert_eq!(alice.read_line().await, "[echobot] hello");
+
+        status_sender.send(String::from("failed")).await.unwrap();
+        assert_eq!(alice.read_line().await, "[buildbot] Build status: failed");
+    }
+
+    /// Forwards connections to the target and can cut them, to lose the federation link
+    struct LinkProxy {
+        address: SocketAddr,
+        is_open: Arc<AtomicBool>,
+        connections: Arc<Mutex<Vec<JoinHandle<()>>>>,
+    }
+
+    impl LinkProxy {
+        async fn start(target_address: SocketAddr) -> Self {
+            let proxy_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let proxy = LinkProxy {
+                address: proxy_listener.local_addr().unwrap(),
+                is_open: Arc::new(AtomicBool::new(true)),
+                connections: Arc::new(Mutex::new(Vec::new())),
+            };
+
+            let is_open = proxy.is_open.clone();
+            let connections = proxy.connections.clone();
+            tokio::spawn(async move {
+                loop {
+                    let (mut client_stream, _) = proxy_listener.accept().await.unwrap();
+                    if !is_open.load(Ordering::SeqCst) {
+                        continue;
+                    }
+                    let mut target_stream = TcpStream::connect(target_address).await.unwrap();
+                    let connection = tokio::spawn(async move {
+                        let _ = copy_bidirectional(&mut client_stream, &mut target_stream).await;
+                    });
+                    connections.lock().unwrap().push(connection);
+                }
+            });
+            proxy
+        }
+
+        fn cut(&self) {
+            self.is_open.store(false, Ordering::SeqCst);
+            for connection in self.connections.lock().unwrap().drain(..) {
+                connection.abort();
+            }
+        }
+
+        fn reopen(&self) {
+            self.is_open.store(true, Ordering::SeqCst);
+        }
+    }
+
+    fn federation_config(server_name: &str) -> ChatServerConfig {
+        ChatServerConfig {
+            federation: FederationConfig {
+                server_name: String::from(server_name),
+                reconnect_delay: Duration::from_millis(50),
+                ..FederationConfig::default()
+            },
+            ..ChatServerConfig::default()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_federated_servers_share_room() {
+        let (alpha_address, alpha_registry) =
+            start_server_with_config(federation_config("alpha")).await;
+        let (beta_address, beta_registry) =
+            start_server_with_config(federation_config("beta")).await;
+        let federation_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let proxy = LinkProxy::start(federation_listener.local_addr().unwrap()).await;
+        tokio::spawn(run_federation_listener(
+            federation_listener,
+            beta_registry.clone(),
+        ));
+
+        let mut alice = TestClient::join(alpha_address, "alice").await;
+        let mut bob = TestClient::join(beta_address, "bob").await;
+        tokio::spawn(connect_to_peer(
+            proxy.address.to_string(),
+            alpha_registry.clone(),
+        ));
+        assert_eq!(alice.read_line().await, "* bob has entered the room");
+        assert_eq!(bob.read_line().await, "* alice has entered the room");
+        wait_for_names(&alpha_registry, &["alice", "bob"]).await;
+        wait_for_names(&beta_registry, &["alice", "bob"]).await;
+
+        alice.write_line("hi bob").await;
+        assert_eq!(bob.read_line().await, "[alice] hi bob");
+        bob.write_line("hi alice").await;
+        assert_eq!(alice.read_line().await, "[bob] hi alice");
+
+        // Names are unique across both servers
+        let mut carol = TestClient::connect(beta_address).await;
+        carol.read_line().await;
+        carol.write_line("alice").await;
+        assert_eq!(carol.try_read_line().await, None);
+        bob.write_line("/nick alice").await;
+        assert_eq!(bob.read_line().await, "* Name alice is already used");
+
+        // Lost link -> Members of the other server leave, names can be taken on both servers
+        proxy.cut();
+        assert_eq!(alice.read_line().await, "* bob has left the room");
+        assert_eq!(bob.read_line().await, "* alice has left the room");
+        let mut alpha_dave = TestClient::join(alpha_address, "dave").await;
+        let mut beta_dave = TestClient::join(beta_address, "dave").await;
+        assert_eq!(alice.read_line().await, "* dave has entered the room");
+        assert_eq!(bob.read_line().await, "* dave has entered the room");
+
+        // Reconnected link -> The server with the lower name keeps the name
+        proxy.reopen();
+        assert_eq!(alice.read_line().await, "* bob has entered the room");
+        assert_eq!(alice.read_line().await, "* dave2 has entered the room");
+        assert_eq!(alpha_dave.read_line().await, "* bob has entered the room");
+        assert_eq!(alpha_dave.read_line().await, "* dave2 has entered the room");
+        wait_for_names(&alpha_registry, &["alice", "bob", "dave", "dave2"]).await;
+        wait_for_names(&beta_registry, &["alice", "bob", "dave", "dave2"]).await;
+
+        let mut beta_dave_lines = Vec::new();
+        while beta_dave_lines.len() < 4 {
+            beta_dave_lines.push(beta_dave.read_line().await);
+        }
+        beta_dave_lines.sort();
+        assert_eq!(
+            beta_dave_lines,
+            [
+                "* Name dave is used on server alpha too",
+                "* alice has entered the room",
+                "* dave has entered the room",
+                "* dave is now known as dave2",
+            ]
+        );
+        beta_dave.write_line("renamed").await;
+        assert_eq!(alice.read_line().await, "[dave2] renamed");
+        assert_eq!(alpha_dave.read_line().await, "[dave2] renamed");
+    }
+
+    #[tokio::test]
+    async fn test_federation_keeps_registered_names() {
+        let beta_config = ChatServerConfig {
+            federation: federation_config("beta").federation,
+            ..credentials_config("federation-registered")
+        };
+        let credentials_file = beta_config.credentials_file.clone().unwrap();
+        let (alpha_address, alpha_registry) =
+            start_server_with_config(federation_config("alpha")).await;
+        let (beta_address, beta_registry) = start_server_with_config(beta_config).await;
+        let federation_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let federation_address = federation_listener.local_addr().unwrap();
+        tokio::spawn(run_federation_listener(
+            federation_listener,
+            beta_registry.clone(),
+        ));
+
+        let mut beta_alice = TestClient::join(beta_address, "alice").await;
+        beta_alice.write_line("/register secret").await;
+        assert_eq!(beta_alice.read_line().await, "* Registered name alice");
+        let mut alpha_alice = TestClient::join(alpha_address, "alice").await;
+
+        // Alpha has the lower name, but the name is registered on beta -> Alpha renames its user
+        tokio::spawn(connect_to_peer(
+            federation_address.to_string(),
+            alpha_registry.clone(),
+        ));
+        assert_eq!(
+            alpha_alice.read_line().await,
+            "* Name alice is used on server beta too"
+        );
+        assert_eq!(
+            alpha_alice.read_line().await,
+            "* alice is now known as alice2"
+        );
+        assert_eq!(
+            alpha_alice.read_line().await,
+            "* alice has entered the room"
+        );
+        assert_eq!(
+            beta_alice.read_line().await,
+            "* alice2 has entered the room"
+        );
+        wait_for_names(&alpha_registry, &["alice", "alice2"]).await;
+        wait_for_names(&beta_registry, &["alice", "alice2"]).await;
+
+        beta_alice.write_line("still mine").await;
+        assert_eq!(alpha_alice.read_line().await, "[alice] still mine");
+
+        std::fs::remove_file(&credentials_file).unwrap();
+    }
+
+    /// Every client joins concurrently over TCP, waits until it hears the host's question and
+    /// leaves again. With the default config, nobody may fall behind and no event may get lost.
+    async fn run_load_test(client_count: usize) {
+        let (server_address, registry) = start_server().await;
+        let mut host = TestClient::join(server_address, "host").await;
+        // Connections beyond the listen backlog are dropped by the kernel, while the client
+        // already considers them established and waits for the welcome forever
+        let connecting = Arc::new(Semaphore::new(512));
+
+        let client_tasks: Vec<JoinHandle<()>> = (1..=client_count)
+            .map(|i| {
+                let connecting = connecting.clone();
+                tokio::spawn(async move {
+                    let user_name = format!("user{i}");
+                    // Lines are read without a timeout, a busy room may take a while to answer
+                    let connect_permit = connecting.acquire_owned().await.unwrap();
+                    let mut client = TestClient::connect(server_address).await;
+                    client.lines.next_line().await.unwrap();
+                    drop(connect_permit);
+                    client.write_line(&user_name).await;
+                    loop {
+                        match client.lines.next_line().await.unwrap() {
+                            Some(line) if line == "[host] who is there?" => break,
+                            Some(_) => {}
+                            None => panic!("{user_name} was removed from the room"),
+                        }
+                    }
+                })
+            })
+            .collect();
+
+        let mut joined_names = HashSet::new();
+        while joined_names.len() < client_count {
+            let line = host.lines.next_line().await.unwrap().unwrap();
+            let user_name = line
+                .strip_prefix("* ")
+                .and_then(|line| line.strip_suffix(" has entered the room"))
+                .unwrap_or_else(|| panic!("Unexpected line {line}"));
+            joined_names.insert(String::from(user_name));
+        }
+
+        host.write_line("who is there?").await;
+        let mut left_names = HashSet::new();
+        while left_names.len() < client_count {
+            let line = host.lines.next_line().await.unwrap().unwrap();
+            let user_name = line
+                .strip_prefix("* ")
+                .and_then(|line| line.strip_suffix(" has left the room"))
+                .unwrap_or_else(|| panic!("Unexpected line {line}"));
+            left_names.insert(String::from(user_name));
+        }
+        assert_eq!(joined_names, left_names);
+
+        for client_task in client_tasks {
+            client_task.await.unwrap();
+        }
+        wait_for_names(&registry, &["host"]).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_room_load() {
+        run_load_test(500).await;
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "takes minutes and more than 20000 open files, run with --release --ignored"]
+    async fn test_room_load_with_10000_clients() {
+        run_load_test(10_000).await;
+    }
+}
@@ -0,0 +1,225 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum ModerationTarget {
+    UserName(String),
+    Address(IpAddr),
+}
+
+impl ModerationTarget {
+    pub fn parse(target: &str) -> Self {
+        target
+            .parse()
+            .map(ModerationTarget::Address)
+            .unwrap_or_else(|_| ModerationTarget::UserName(String::from(target)))
+    }
+}
+
+struct Ban {
+    /// Key of the name, see [`crate::name_policy::NamePolicy::key`]
+    name_key: Option<String>,
+    expires_at: Option<Instant>,
+}
+
+impl Ban {
+    fn is_active(&self, now: Instant) -> bool {
+        self.expires_at.is_none_or(|expires_at| now < expires_at)
+    }
+}
+
+/// Banned client addresses, optionally remembering the key of the user name they were banned under
+#[derive(Default)]
+pub struct BanList {
+    bans: HashMap<IpAddr, Ban>,
+}
+
+impl BanList {
+    /// Banning an address again never shortens its ban or forgets the name it was banned under
+    pub fn ban(
+        &mut self,
+        address: IpAddr,
+        name_key: Option<String>,
+        duration: Option<Duration>,
+        now: Instant,
+    ) {
+        let expires_at = duration.map(|duration| now + duration);
+        match self.bans.get_mut(&address) {
+            Some(ban) if ban.is_active(now) => {
+                // No expiry is the latest one
+                ban.expires_at = ban.expires_at.zip(expires_at).map(|(a, b)| a.max(b));
+                if ban.name_key.is_none() {
+                    ban.name_key = name_key;
+                }
+            }
+            _ => {
+                self.bans.insert(
+                    address,
+                    Ban {
+                        name_key,
+                        expires_at,
+                    },
+                );
+            }
+        }
+    }
+
+    /// Lifts all bans matching the target and returns whether there were any. Names have to be
+    /// given as their key.
+    pub fn unban(&mut self, target: &ModerationTarget) -> bool {
+        let number_of_bans = self.bans.len();
+        match target {
+            ModerationTarget::Address(address) => {
+                self.bans.remove(address);
+            }
+            ModerationTarget::UserName(name_key) => self
+                .bans
+                .retain(|_, ban| ban.name_key.as_ref() != Some(name_key)),
+        }
+        self.bans.len() < number_of_bans
+    }
+
+    pub fn is_banned(&mut self, address: &IpAddr, now: Instant) -> bool {
+        match self.bans.get(address) {
+            Some(ban) if ban.is_active(now) => true,
+            Some(_) => {
+                // Ban has expired -> Forget about it
+                self.bans.remove(address);
+                false
+            }
+            None => false,
+        }
+    }
+}
+
+/// Compares without returning early at the first difference, so that the time it takes does not
+/// reveal how much of a guessed secret is right
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    let mut difference = a.len() ^ b.len();
+    for i in 0..a.len().max(b.len()) {
+        let a_byte = a.get(i).copied().unwrap_or(0);
+        let b_byte = b.get(i).copied().unwrap_or(0);
+        difference |= usize::from(a_byte ^ b_byte);
+    }
+    difference == 0
+}
+
+/// Parses durations like `90`, `30s`, `10m`, `2h` or `1d`. Plain numbers are seconds.
+pub fn parse_duration(duration: &str) -> Option<Duration> {
+    let unit_start = duration
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(duration.len());
+    let (amount, unit) = duration.split_at(unit_start);
+    let amount: u64 = amount.parse().ok()?;
+    let unit_seconds = match unit {
+        "" | "s" => 1,
+        "m" => 60,
+        "h" => 60 * 60,
+        "d" => 24 * 60 * 60,
+        _ => return None,
+    };
+    amount.checked_mul(unit_seconds).map(Duration::from_secs)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use super::*;
+
+    #[test]
+    fn test_target_parsing() {
+        assert_eq!(
+            ModerationTarget::parse("alice"),
+            ModerationTarget::UserName(String::from("alice"))
+        );
+        assert_eq!(
+            ModerationTarget::parse("10.0.0.1"),
+            ModerationTarget::Address(IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1)))
+        );
+        assert_eq!(
+            ModerationTarget::parse("::1"),
+            ModerationTarget::Address("::1".parse().unwrap())
+        );
+    }
+
+    #[test]
+    fn test_duration_parsing() {
+        assert_eq!(parse_duration("90"), Some(Duration::from_secs(90)));
+        assert_eq!(parse_duration("30s"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_duration("10m"), Some(Duration::from_secs(600)));
+        assert_eq!(parse_duration("2h"), Some(Duration::from_secs(7200)));
+        assert_eq!(parse_duration("1d"), Some(Duration::from_secs(86400)));
+
+        assert_eq!(parse_duration(""), None);
+        assert_eq!(parse_duration("m"), None);
+        assert_eq!(parse_duration("10x"), None);
+        assert_eq!(parse_duration("-10m"), None);
+        assert_eq!(parse_duration("1h30m"), None);
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"secret", b"secret"));
+        assert!(constant_time_eq(b"", b""));
+
+        assert!(!constant_time_eq(b"secret", b"secreT"));
+        assert!(!constant_time_eq(b"secret", b"secret\0"));
+        assert!(!constant_time_eq(b"secret", b""));
+    }
+
+    #[test]
+    fn test_ban_list() {
+        let mut ban_list = BanList::default();
+        let now = Instant::now();
+        let alice_address = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let bob_address = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+
+        ban_list.ban(alice_address, Some(String::from("alice")), None, now);
+        ban_list.ban(bob_address, None, Some(Duration::from_secs(60)), now);
+
+        assert!(ban_list.is_banned(&alice_address, now + Duration::from_secs(3600)));
+        assert!(ban_list.is_banned(&bob_address, now + Duration::from_secs(59)));
+        assert!(!ban_list.is_banned(&bob_address, now + Duration::from_secs(60)));
+
+        // Lifting bans by user name or address
+        assert!(!ban_list.unban(&ModerationTarget::UserName(String::from("bob"))));
+        assert!(ban_list.unban(&ModerationTarget::UserName(String::from("alice"))));
+        assert!(!ban_list.is_banned(&alice_address, now));
+
+        ban_list.ban(bob_address, None, None, now);
+        assert!(ban_list.unban(&ModerationTarget::Address(bob_address)));
+        assert!(!ban_list.unban(&ModerationTarget::Address(bob_address)));
+        assert!(!ban_list.is_banned(&bob_address, now));
+    }
+
+    #[test]
+    fn test_repeated_ban() {
+        let mut ban_list = BanList::default();
+        let now = Instant::now();
+        let address = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let minute = Some(Duration::from_secs(60));
+        let hour = Some(Duration::from_secs(3600));
+
+        // A temporary ban does not shorten a permanent one or lose its name
+        ban_list.ban(address, Some(String::from("alice")), None, now);
+        ban_list.ban(address, Some(String::from("bob")), minute, now);
+        assert!(ban_list.is_banned(&address, now + Duration::from_secs(3600)));
+        assert!(ban_list.unban(&ModerationTarget::UserName(String::from("alice"))));
+
+        // The later expiry wins, and a name is added to a ban without one
+        ban_list.ban(address, None, hour, now);
+        ban_list.ban(address, Some(String::from("alice")), minute, now);
+        assert!(ban_list.is_banned(&address, now + Duration::from_secs(120)));
+        ban_list.ban(address, None, None, now);
+        assert!(ban_list.is_banned(&address, now + Duration::from_secs(7200)));
+        assert!(ban_list.unban(&ModerationTarget::UserName(String::from("alice"))));
+
+        // Expired bans are replaced
+        ban_list.ban(address, Some(String::from("alice")), minute, now);
+        ban_list.ban(address, None, minute, now + Duration::from_secs(120));
+        assert!(!ban_list.unban(&ModerationTarget::UserName(String::from("alice"))));
+        assert!(ban_list.is_banned(&address, now + Duration::from_secs(150)));
+    }
+}
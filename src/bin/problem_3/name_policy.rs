@@ -0,0 +1,226 @@
+use std::str::FromStr;
+
+use unicode_normalization::char::is_combining_mark;
+use unicode_normalization::UnicodeNormalization;
+use unicode_segmentation::UnicodeSegmentation;
+
+/// What the maximum name length counts
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum LengthUnit {
+    /// Unicode scalar values, e.g. `é` written as `e` and a combining accent counts twice
+    Chars,
+    /// User-perceived characters, e.g. `é` counts once however it is written
+    Graphemes,
+}
+
+impl FromStr for LengthUnit {
+    type Err = String;
+
+    fn from_str(unit: &str) -> Result<Self, Self::Err> {
+        match unit.trim().to_lowercase().as_str() {
+            "chars" | "characters" => Ok(LengthUnit::Chars),
+            "graphemes" => Ok(LengthUnit::Graphemes),
+            _ => Err(format!("Unknown name length unit {unit}")),
+        }
+    }
+}
+
+/// Characters names may consist of
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum NameCharset {
+    /// `a-z`, `A-Z` and `0-9`
+    Ascii,
+    /// Letters and digits of any script. Compatibility characters like fullwidth or mathematical
+    /// letters are refused, they only imitate other letters.
+    Unicode,
+}
+
+impl FromStr for NameCharset {
+    type Err = String;
+
+    fn from_str(charset: &str) -> Result<Self, Self::Err> {
+        match charset.trim().to_lowercase().as_str() {
+            "ascii" => Ok(NameCharset::Ascii),
+            "unicode" => Ok(NameCharset::Unicode),
+            _ => Err(format!("Unknown name charset {charset}")),
+        }
+    }
+}
+
+/// Rules for user names, applied when joining and when renaming
+#[derive(Clone, Debug, PartialEq)]
+pub struct NamePolicy {
+    pub max_length: usize,
+    pub length_unit: LengthUnit,
+    pub charset: NameCharset,
+    /// Names are converted to NFC, so that differently encoded but equal names are the same name
+    pub normalize: bool,
+    /// Names that only differ in case are the same name, e.g. `Alice` and `alice`
+    pub case_insensitive: bool,
+}
+
+impl Default for NamePolicy {
+    fn default() -> Self {
+        NamePolicy {
+            max_length: 16,
+            length_unit: LengthUnit::Chars,
+            charset: NameCharset::Unicode,
+            normalize: true,
+            case_insensitive: false,
+        }
+    }
+}
+
+impl NamePolicy {
+    /// Returns the name as it is used in the room, or nothing if the name is not allowed
+    pub fn check(&self, name: &str) -> Option<String> {
+        let name = self.normalized(name);
+        let length = match self.length_unit {
+            LengthUnit::Chars => name.chars().count(),
+            LengthUnit::Graphemes => name.graphemes(true).count(),
+        };
+        if !(1..=self.max_length).contains(&length) {
+            return None;
+        }
+
+        let is_valid_charset = match self.charset {
+            NameCharset::Ascii => name.chars().all(|c| c.is_ascii_alphanumeric()),
+            NameCharset::Unicode => {
+                // Combining marks have to belong to a letter
+                !name.starts_with(is_combining_mark)
+                    && name
+                        .chars()
+                        .all(|c| is_combining_mark(c) || is_unicode_alphanumeric(c))
+            }
+        };
+        is_valid_charset.then_some(name)
+    }
+
+    /// Form of the name under which it is unique in the room
+    pub fn key(&self, name: &str) -> String {
+        let name = self.normalized(name);
+        if self.case_insensitive {
+            name.to_lowercase()
+        } else {
+            name
+        }
+    }
+
+    fn normalized(&self, name: &str) -> String {
+        if self.normalize {
+            name.nfc().collect()
+        } else {
+            String::from(name)
+        }
+    }
+}
+
+/// Letters and digits that are not just another form of a letter or digit, e.g. `Ａ` or `①`
+fn is_unicode_alphanumeric(c: char) -> bool {
+    c.is_alphanumeric() && c.to_string().nfkc().eq([c])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_valid_name(name: &str) -> bool {
+        NamePolicy::default().check(name).is_some()
+    }
+
+    #[test]
+    fn test_valid_name_check() {
+        assert!(is_valid_name("a"));
+        assert!(is_valid_name("1"));
+        assert!(is_valid_name("abc123"));
+        assert!(is_valid_name("abcdefghijklmnop"));
+
+        assert!(!is_valid_name(""));
+        assert!(!is_valid_name(" "));
+        assert!(!is_valid_name("-"));
+        assert!(!is_valid_name("abc+123"));
+        assert!(!is_valid_name("abcdefghijklmnopq"));
+    }
+
+    #[test]
+    fn test_unicode_names() {
+        // Length counts characters, not bytes
+        assert!(is_valid_name("Александра"));
+        assert!(is_valid_name("ÄöüßÄöüßÄöüßÄöüß"));
+        assert!(is_valid_name("名前"));
+
+        // Imitations of other letters and digits
+        assert!(!is_valid_name("Ａlice"));
+        assert!(!is_valid_name("𝐚lice"));
+        assert!(!is_valid_name("alice①"));
+        assert!(!is_valid_name("Ⅻ"));
+        // Combining mark without a letter
+        assert!(!is_valid_name("\u{301}alice"));
+    }
+
+    #[test]
+    fn test_ascii_names() {
+        let policy = NamePolicy {
+            charset: NameCharset::Ascii,
+            ..NamePolicy::default()
+        };
+        assert_eq!(policy.check("Alice42"), Some(String::from("Alice42")));
+        assert_eq!(policy.check("Zoë"), None);
+        assert_eq!(policy.check("Дима"), None);
+    }
+
+    #[test]
+    fn test_name_normalization() {
+        let policy = NamePolicy::default();
+        assert_eq!(policy.check("Zoe\u{308}"), Some(String::from("Zoë")));
+        assert_eq!(policy.key("Zoe\u{308}"), policy.key("Zoë"));
+
+        let policy = NamePolicy {
+            normalize: false,
+            ..NamePolicy::default()
+        };
+        assert_eq!(policy.check("Zoe\u{308}"), Some(String::from("Zoe\u{308}")));
+        assert_ne!(policy.key("Zoe\u{308}"), policy.key("Zoë"));
+    }
+
+    #[test]
+    fn test_name_length_units() {
+        let chars_policy = NamePolicy {
+            max_length: 3,
+            normalize: false,
+            ..NamePolicy::default()
+        };
+        assert_eq!(chars_policy.check("Zoe\u{308}"), None);
+
+        let graphemes_policy = NamePolicy {
+            length_unit: LengthUnit::Graphemes,
+            ..chars_policy
+        };
+        assert!(graphemes_policy.check("Zoe\u{308}").is_some());
+        assert_eq!(graphemes_policy.check("Zoey"), None);
+    }
+
+    #[test]
+    fn test_case_insensitive_keys() {
+        let policy = NamePolicy::default();
+        assert_ne!(policy.key("Alice"), policy.key("alice"));
+
+        let policy = NamePolicy {
+            case_insensitive: true,
+            ..NamePolicy::default()
+        };
+        assert_eq!(policy.key("Alice"), policy.key("aLICE"));
+        assert_eq!(policy.key("ÄNNE"), policy.key("änne"));
+        // Only the key changes, names keep their case
+        assert_eq!(policy.check("Alice"), Some(String::from("Alice")));
+    }
+
+    #[test]
+    fn test_policy_parsing() {
+        assert_eq!("Graphemes".parse(), Ok(LengthUnit::Graphemes));
+        assert_eq!(" chars ".parse(), Ok(LengthUnit::Chars));
+        assert_eq!("ascii".parse(), Ok(NameCharset::Ascii));
+        assert!("bytes".parse::<LengthUnit>().is_err());
+        assert!("latin1".parse::<NameCharset>().is_err());
+    }
+}
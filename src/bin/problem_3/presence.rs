@@ -0,0 +1,147 @@
+use std::time::{Duration, Instant};
+
+/// Tracks the activity of a single connection to tell when its user went away
+pub struct IdleTracker {
+    /// Users count as away after this long without sending anything, never without it
+    away_after: Option<Duration>,
+    last_activity: Instant,
+    is_away: bool,
+}
+
+impl IdleTracker {
+    pub fn new(away_after: Option<Duration>, now: Instant) -> Self {
+        IdleTracker {
+            away_after,
+            last_activity: now,
+            is_away: false,
+        }
+    }
+
+    pub fn record_activity(&mut self, now: Instant) {
+        self.last_activity = now;
+        self.is_away = false;
+    }
+
+    /// Point in time at which the user goes away, unless the user is away already
+    pub fn away_at(&self) -> Option<Instant> {
+        match self.away_after {
+            Some(away_after) if !self.is_away => Some(self.last_activity + away_after),
+            _ => None,
+        }
+    }
+
+    pub fn mark_away(&mut self) {
+        self.is_away = true;
+    }
+}
+
+/// Waits until the point in time, forever without one
+pub async fn sleep_until(wakeup: Option<Instant>) {
+    match wakeup {
+        Some(wakeup) => tokio::time::sleep_until(wakeup.into()).await,
+        None => std::future::pending().await,
+    }
+}
+
+/// A member of the room as listed by `/who`
+#[derive(Clone, Debug, PartialEq)]
+pub struct MemberPresence {
+    pub user_name: String,
+    /// Time since joining and since the last activity, only known for members of this server
+    pub connected_for: Option<Duration>,
+    pub idle_for: Option<Duration>,
+    pub is_away: bool,
+}
+
+impl MemberPresence {
+    /// Budget chat line shown for the member
+    pub fn to_line(&self) -> String {
+        let away_marker = if self.is_away { " (away)" } else { "" };
+        match (self.connected_for, self.idle_for) {
+            (Some(connected_for), Some(idle_for)) => format!(
+                "* {}{away_marker}: connected {}, idle {}",
+                self.user_name,
+                format_elapsed(connected_for),
+                format_elapsed(idle_for)
+            ),
+            _ => format!("* {}{away_marker}: on linked server", self.user_name),
+        }
+    }
+}
+
+/// Elapsed time in its two largest units, e.g. `1h 5m` or `42s`
+pub fn format_elapsed(elapsed: Duration) -> String {
+    let seconds = elapsed.as_secs();
+    let units = [
+        (seconds / 86400, "d"),
+        (seconds / 3600 % 24, "h"),
+        (seconds / 60 % 60, "m"),
+        (seconds % 60, "s"),
+    ];
+    let first_unit = units
+        .iter()
+        .position(|(amount, _)| *amount > 0)
+        .unwrap_or(units.len() - 1);
+    units[first_unit..]
+        .iter()
+        .take(2)
+        .map(|(amount, unit)| format!("{amount}{unit}"))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_idle_tracker() {
+        let start = Instant::now();
+        let mut tracker = IdleTracker::new(Some(Duration::from_secs(60)), start);
+        assert_eq!(tracker.away_at(), Some(start + Duration::from_secs(60)));
+
+        tracker.record_activity(start + Duration::from_secs(30));
+        assert_eq!(tracker.away_at(), Some(start + Duration::from_secs(90)));
+
+        tracker.mark_away();
+        assert_eq!(tracker.away_at(), None);
+        tracker.record_activity(start + Duration::from_secs(200));
+        assert_eq!(tracker.away_at(), Some(start + Duration::from_secs(260)));
+
+        assert_eq!(IdleTracker::new(None, start).away_at(), None);
+    }
+
+    #[test]
+    fn test_elapsed_format() {
+        assert_eq!(format_elapsed(Duration::from_millis(300)), "0s");
+        assert_eq!(format_elapsed(Duration::from_secs(42)), "42s");
+        assert_eq!(format_elapsed(Duration::from_secs(125)), "2m 5s");
+        assert_eq!(format_elapsed(Duration::from_secs(3600)), "1h 0m");
+        assert_eq!(
+            format_elapsed(Duration::from_secs(3 * 86400 + 7300)),
+            "3d 2h"
+        );
+    }
+
+    #[test]
+    fn test_member_lines() {
+        let mut presence = MemberPresence {
+            user_name: String::from("alice"),
+            connected_for: Some(Duration::from_secs(3700)),
+            idle_for: Some(Duration::from_secs(12)),
+            is_away: false,
+        };
+        assert_eq!(presence.to_line(), "* alice: connected 1h 1m, idle 12s");
+
+        presence.is_away = true;
+        assert_eq!(
+            presence.to_line(),
+            "* alice (away): connected 1h 1m, idle 12s"
+        );
+
+        presence.is_away = false;
+        presence.connected_for = None;
+        presence.idle_for = None;
+        assert_eq!(presence.to_line(), "* alice: on linked server");
+    }
+}
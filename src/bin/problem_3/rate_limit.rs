@@ -0,0 +1,369 @@
+use std::str::FromStr;
+use std::time::{Duration, Instant};
+
+/// Room for chat commands and IRC framing around a message of the maximum length
+const LINE_OVERHEAD: usize = 512;
+
+/// How to deal with users exceeding their message or byte rate
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FloodPolicy {
+    /// Drop the message and tell the user
+    Warn,
+    /// Delay the message until the user is within the limits again, and close the connection
+    /// once the user owes more than a burst
+    Throttle,
+    /// Drop the message and close the connection
+    Disconnect,
+}
+
+impl FromStr for FloodPolicy {
+    type Err = String;
+
+    fn from_str(policy: &str) -> Result<Self, Self::Err> {
+        match policy.trim().to_lowercase().as_str() {
+            "warn" => Ok(FloodPolicy::Warn),
+            "throttle" => Ok(FloodPolicy::Throttle),
+            "disconnect" => Ok(FloodPolicy::Disconnect),
+            _ => Err(format!("Unknown flood policy {policy}")),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RateLimit {
+    pub per_second: f64,
+    pub burst: f64,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct FloodLimits {
+    pub messages: Option<RateLimit>,
+    pub bytes: Option<RateLimit>,
+    pub max_message_length: usize,
+    pub policy: FloodPolicy,
+}
+
+impl Default for FloodLimits {
+    fn default() -> Self {
+        FloodLimits {
+            messages: None,
+            bytes: None,
+            // Budget chat clients must be able to send at least 1000 characters per message
+            max_message_length: 1000,
+            policy: FloodPolicy::Warn,
+        }
+    }
+}
+
+impl FloodLimits {
+    /// Longest line in bytes that is read from a client. Every character of a message may take
+    /// up to four bytes in UTF-8.
+    pub fn max_line_length(&self) -> usize {
+        self.max_message_length
+            .saturating_mul(4)
+            .saturating_add(LINE_OVERHEAD)
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum FloodVerdict {
+    Allow,
+    Delay(Duration),
+    Reject(String),
+    Disconnect(String),
+}
+
+struct TokenBucket {
+    rate_limit: RateLimit,
+    tokens: f64,
+    last_refill: Instant,
+}
+
+impl TokenBucket {
+    fn new(rate_limit: RateLimit, now: Instant) -> Self {
+        TokenBucket {
+            rate_limit,
+            tokens: rate_limit.burst,
+            last_refill: now,
+        }
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed_seconds = now
+            .saturating_duration_since(self.last_refill)
+            .as_secs_f64();
+        self.tokens =
+            (self.tokens + elapsed_seconds * self.rate_limit.per_second).min(self.rate_limit.burst);
+        self.last_refill = now;
+    }
+
+    /// Time until the bucket holds the given amount of tokens
+    fn time_until_available(&mut self, amount: f64, now: Instant) -> Duration {
+        self.refill(now);
+        let missing_tokens = amount.min(self.rate_limit.burst) - self.tokens;
+        if missing_tokens <= 0.0 {
+            Duration::ZERO
+        } else {
+            Duration::from_secs_f64(missing_tokens / self.rate_limit.per_second)
+        }
+    }
+
+    /// Takes the tokens, going into debt if there are not enough of them
+    fn take(&mut self, amount: f64) {
+        self.tokens -= amount;
+    }
+
+    /// Whether taking the tokens would leave a debt of more than a burst. A single amount larger
+    /// than a burst may always be owed, otherwise a long message could never be sent.
+    fn exceeds_debt_limit(&self, amount: f64) -> bool {
+        self.tokens - amount < -self.rate_limit.burst.max(amount)
+    }
+}
+
+/// Per-connection token buckets for the message and byte rate of a single user
+pub struct FloodGuard {
+    limits: FloodLimits,
+    message_bucket: Option<TokenBucket>,
+    byte_bucket: Option<TokenBucket>,
+}
+
+impl FloodGuard {
+    pub fn new(limits: FloodLimits, now: Instant) -> Self {
+        FloodGuard {
+            message_bucket: limits.messages.map(|limit| TokenBucket::new(limit, now)),
+            byte_bucket: limits.bytes.map(|limit| TokenBucket::new(limit, now)),
+            limits,
+        }
+    }
+
+    pub fn check(&mut self, message: &str, now: Instant) -> FloodVerdict {
+        if message.chars().count() > self.limits.max_message_length {
+            return self.check_too_long_line();
+        }
+
+        let message_bytes = message.len() as f64;
+        let waiting_time = [
+            self.message_bucket
+                .as_mut()
+                .map(|bucket| bucket.time_until_available(1.0, now)),
+            self.byte_bucket
+                .as_mut()
+                .map(|bucket| bucket.time_until_available(message_bytes, now)),
+        ]
+        .into_iter()
+        .flatten()
+        .max()
+        .unwrap_or(Duration::ZERO);
+
+        if !waiting_time.is_zero() {
+            let reason = String::from("You are sending messages too fast");
+            match self.limits.policy {
+                FloodPolicy::Warn => return FloodVerdict::Reject(reason),
+                FloodPolicy::Disconnect => return FloodVerdict::Disconnect(reason),
+                // Delays must not grow without limit
+                FloodPolicy::Throttle if self.exceeds_debt_limit(message_bytes) => {
+                    return FloodVerdict::Disconnect(reason)
+                }
+                FloodPolicy::Throttle => {}
+            }
+        }
+
+        // Message is accepted now or after the delay -> Charge it to the buckets
+        if let Some(bucket) = self.message_bucket.as_mut() {
+            bucket.take(1.0);
+        }
+        if let Some(bucket) = self.byte_bucket.as_mut() {
+            bucket.take(message_bytes);
+        }
+
+        if waiting_time.is_zero() {
+            FloodVerdict::Allow
+        } else {
+            FloodVerdict::Delay(waiting_time)
+        }
+    }
+
+    fn exceeds_debt_limit(&self, message_bytes: f64) -> bool {
+        let message_debt = self
+            .message_bucket
+            .as_ref()
+            .is_some_and(|bucket| bucket.exceeds_debt_limit(1.0));
+        let byte_debt = self
+            .byte_bucket
+            .as_ref()
+            .is_some_and(|bucket| bucket.exceeds_debt_limit(message_bytes));
+        message_debt || byte_debt
+    }
+
+    /// Verdict on a message that is too long, including lines that were too long to be read
+    pub fn check_too_long_line(&self) -> FloodVerdict {
+        let reason = format!(
+            "Messages must not be longer than {} characters",
+            self.limits.max_message_length
+        );
+        match self.limits.policy {
+            FloodPolicy::Disconnect => FloodVerdict::Disconnect(reason),
+            // Waiting does not make an oversized message acceptable
+            FloodPolicy::Warn | FloodPolicy::Throttle => FloodVerdict::Reject(reason),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn limits(policy: FloodPolicy) -> FloodLimits {
+        FloodLimits {
+            messages: Some(RateLimit {
+                per_second: 1.0,
+                burst: 2.0,
+            }),
+            bytes: Some(RateLimit {
+                per_second: 10.0,
+                burst: 20.0,
+            }),
+            max_message_length: 10,
+            policy,
+        }
+    }
+
+    #[test]
+    fn test_flood_policy_parsing() {
+        assert_eq!("warn".parse(), Ok(FloodPolicy::Warn));
+        assert_eq!("Throttle".parse(), Ok(FloodPolicy::Throttle));
+        assert_eq!(" disconnect ".parse(), Ok(FloodPolicy::Disconnect));
+        assert!("ignore".parse::<FloodPolicy>().is_err());
+    }
+
+    #[test]
+    fn test_unlimited_guard() {
+        let now = Instant::now();
+        let mut guard = FloodGuard::new(FloodLimits::default(), now);
+
+        for _ in 0..1000 {
+            assert_eq!(guard.check(&"a".repeat(1000), now), FloodVerdict::Allow);
+        }
+        assert!(matches!(
+            guard.check(&"a".repeat(1001), now),
+            FloodVerdict::Reject(_)
+        ));
+    }
+
+    #[test]
+    fn test_max_message_length_counts_characters() {
+        let now = Instant::now();
+        let mut guard = FloodGuard::new(limits(FloodPolicy::Warn), now);
+
+        assert_eq!(guard.check("ääääääääää", now), FloodVerdict::Allow);
+        assert_eq!(
+            guard.check("abcdefghijk", now),
+            FloodVerdict::Reject(String::from(
+                "Messages must not be longer than 10 characters"
+            ))
+        );
+
+        let mut guard = FloodGuard::new(limits(FloodPolicy::Disconnect), now);
+        assert!(matches!(
+            guard.check("abcdefghijk", now),
+            FloodVerdict::Disconnect(_)
+        ));
+    }
+
+    #[test]
+    fn test_warn_policy() {
+        let now = Instant::now();
+        let mut guard = FloodGuard::new(limits(FloodPolicy::Warn), now);
+
+        // Message burst
+        assert_eq!(guard.check("a", now), FloodVerdict::Allow);
+        assert_eq!(guard.check("b", now), FloodVerdict::Allow);
+        assert_eq!(
+            guard.check("c", now),
+            FloodVerdict::Reject(String::from("You are sending messages too fast"))
+        );
+
+        // Refill after a second
+        let now = now + Duration::from_secs(1);
+        assert_eq!(guard.check("c", now), FloodVerdict::Allow);
+        assert!(matches!(guard.check("d", now), FloodVerdict::Reject(_)));
+    }
+
+    #[test]
+    fn test_byte_rate_limit() {
+        let now = Instant::now();
+        let byte_limits = FloodLimits {
+            messages: None,
+            ..limits(FloodPolicy::Warn)
+        };
+        let mut guard = FloodGuard::new(byte_limits, now);
+
+        // Byte burst
+        assert_eq!(guard.check("abcdefghij", now), FloodVerdict::Allow);
+        assert_eq!(guard.check("abcdefghij", now), FloodVerdict::Allow);
+        assert!(matches!(guard.check("a", now), FloodVerdict::Reject(_)));
+
+        // Refill for a single byte
+        let now = now + Duration::from_millis(100);
+        assert_eq!(guard.check("a", now), FloodVerdict::Allow);
+        assert!(matches!(guard.check("a", now), FloodVerdict::Reject(_)));
+    }
+
+    #[test]
+    fn test_throttle_policy() {
+        let now = Instant::now();
+        let mut guard = FloodGuard::new(limits(FloodPolicy::Throttle), now);
+
+        assert_eq!(guard.check("a", now), FloodVerdict::Allow);
+        assert_eq!(guard.check("b", now), FloodVerdict::Allow);
+        assert_eq!(
+            guard.check("c", now),
+            FloodVerdict::Delay(Duration::from_secs(1))
+        );
+        assert_eq!(
+            guard.check("d", now),
+            FloodVerdict::Delay(Duration::from_secs(2))
+        );
+        assert_eq!(
+            guard.check("e", now + Duration::from_secs(2)),
+            FloodVerdict::Delay(Duration::from_secs(1))
+        );
+
+        // Owing more than a burst ends the connection
+        let later = now + Duration::from_secs(2);
+        assert_eq!(
+            guard.check("f", later),
+            FloodVerdict::Delay(Duration::from_secs(2))
+        );
+        assert_eq!(
+            guard.check("g", later),
+            FloodVerdict::Disconnect(String::from("You are sending messages too fast"))
+        );
+
+        // A single message larger than a burst may still be sent
+        let byte_limits = FloodLimits {
+            messages: None,
+            max_message_length: 100,
+            ..limits(FloodPolicy::Throttle)
+        };
+        let mut guard = FloodGuard::new(byte_limits, now);
+        assert_eq!(guard.check(&"a".repeat(50), now), FloodVerdict::Allow);
+        assert_eq!(
+            guard.check(&"a".repeat(50), now + Duration::from_secs(3)),
+            FloodVerdict::Delay(Duration::from_secs(2))
+        );
+    }
+
+    #[test]
+    fn test_disconnect_policy() {
+        let now = Instant::now();
+        let mut guard = FloodGuard::new(limits(FloodPolicy::Disconnect), now);
+
+        assert_eq!(guard.check("a", now), FloodVerdict::Allow);
+        assert_eq!(guard.check("b", now), FloodVerdict::Allow);
+        assert_eq!(
+            guard.check("c", now),
+            FloodVerdict::Disconnect(String::from("You are sending messages too fast"))
+        );
+    }
+}
@@ -0,0 +1,1213 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{Error as IO_Error, Result as IO_Result};
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant, SystemTime};
+
+use futures::future::BoxFuture;
+use futures::stream::FuturesUnordered;
+use futures::StreamExt;
+use itertools::Itertools;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::oneshot;
+
+use crate::config::ChatServerConfig;
+use crate::credentials::{CredentialStore, SavingRegistration};
+use crate::event::RoomEvent;
+use crate::federation::{FederationLink, LinkMessage};
+use crate::filter::{FilterChain, FilterOutcome};
+use crate::mailbox::{Mailbox, MailboxMessage};
+use crate::moderation::{constant_time_eq, BanList, ModerationTarget};
+use crate::presence::{sleep_until, MemberPresence};
+use crate::session::{
+    Delivery, ModerationError, RegistrationError, RenameError, SessionEvent, TellError,
+};
+use crate::transcript::{unix_timestamp, Transcript};
+
+/// Reply channel of a room command
+pub type Reply<T> = oneshot::Sender<T>;
+
+/// Origin of events the server causes on its own. They are delivered to every participant.
+const SERVER_ORIGIN: i32 = 0;
+/// Origin of events relayed from the linked server. They are not relayed back.
+const FEDERATION_ORIGIN: i32 = -1;
+/// Pause of the room after a command while a session is falling behind
+const CATCH_UP_PAUSE: Duration = Duration::from_millis(1);
+/// Invalid operator passwords after which a connection is disconnected
+const MAX_OPERATOR_ATTEMPTS: u32 = 3;
+/// Invalid operator passwords after which all connections of an address are banned for a while
+const MAX_OPERATOR_ATTEMPTS_PER_ADDRESS: u32 = 10;
+const OPERATOR_GUESSING_BAN: Duration = Duration::from_secs(60 * 60);
+
+/// Everything a joining session gets from the room
+pub struct JoinedRoom {
+    pub event_receiver: Receiver<SessionEvent>,
+    /// Comma separated names of the users that were in the room before, `-` for an empty room
+    pub participants_list: String,
+}
+
+/// Requests to the room actor. Sessions are identified by their connection id.
+pub enum RoomCommand {
+    Join {
+        connection_id: i32,
+        address: IpAddr,
+        user_name: String,
+        /// Whether the session proved that it knows the password of the registered name
+        is_authenticated: bool,
+        reply: Reply<Result<JoinedRoom, ()>>,
+    },
+    Rename {
+        connection_id: i32,
+        new_user_name: String,
+        reply: Reply<Result<(), RenameError>>,
+    },
+    Leave {
+        connection_id: i32,
+    },
+    Message {
+        connection_id: i32,
+        message: String,
+    },
+    ParticipantNames {
+        reply: Reply<HashSet<String>>,
+    },
+    /// User of the session sent something
+    Activity {
+        connection_id: i32,
+    },
+    /// User of the session has not sent anything for a while
+    Away {
+        connection_id: i32,
+    },
+    Who {
+        reply: Reply<Vec<MemberPresence>>,
+    },
+    PasswordHash {
+        user_name: String,
+        reply: Reply<Option<String>>,
+    },
+    Register {
+        connection_id: i32,
+        password_hash: String,
+        reply: Reply<Result<(), RegistrationError>>,
+    },
+    Tell {
+        connection_id: i32,
+        recipient: String,
+        text: String,
+        reply: Reply<Result<Delivery, TellError>>,
+    },
+    SearchTranscript {
+        term: String,
+        reply: Reply<Vec<String>>,
+    },
+    BecomeOperator {
+        connection_id: i32,
+        password: String,
+        reply: Reply<bool>,
+    },
+    IsMuted {
+        connection_id: i32,
+        reply: Reply<bool>,
+    },
+    Kick {
+        moderator_id: i32,
+        user_name: String,
+        reply: Reply<Result<(), ModerationError>>,
+    },
+    Mute {
+        moderator_id: i32,
+        user_name: String,
+        duration: Option<Duration>,
+        reply: Reply<Result<(), ModerationError>>,
+    },
+    Unmute {
+        moderator_id: i32,
+        user_name: String,
+        reply: Reply<Result<(), ModerationError>>,
+    },
+    Ban {
+        moderator_id: i32,
+        target: ModerationTarget,
+        duration: Option<Duration>,
+        reply: Reply<Result<IpAddr, ModerationError>>,
+    },
+    Unban {
+        moderator_id: i32,
+        target: ModerationTarget,
+        reply: Reply<Result<(), ModerationError>>,
+    },
+    IsBanned {
+        address: IpAddr,
+        reply: Reply<bool>,
+    },
+    AttachLink {
+        link_id: i32,
+        message_sender: Sender<LinkMessage>,
+        reply: Reply<bool>,
+    },
+    DetachLink {
+        link_id: i32,
+    },
+    LinkMessage {
+        link_id: i32,
+        message: LinkMessage,
+    },
+}
+
+struct Participant {
+    user_name: String,
+    address: IpAddr,
+    /// Kept across renames, the rights belong to the connection
+    is_operator: bool,
+    failed_operator_attempts: u32,
+    is_muted: bool,
+    muted_until: Option<Instant>,
+    /// Registered names the session may use
+    authenticated_names: HashSet<String>,
+    joined_at: Instant,
+    last_activity: Instant,
+    is_away: bool,
+    event_sender: Sender<SessionEvent>,
+}
+
+/// Registration whose credentials file write finished
+struct SavedRegistration {
+    connection_id: i32,
+    registration: SavingRegistration,
+    result: IO_Result<()>,
+    reply: Reply<Result<(), RegistrationError>>,
+}
+
+/// State of the chat room. It is owned by a single task that executes the room commands one
+/// after another, so membership changes and the announcements about them can never interleave.
+pub struct Room {
+    config: Arc<ChatServerConfig>,
+    participants: HashMap<i32, Participant>,
+    /// Participants by the key of their name, see [`Room::name_key`]
+    connection_ids: HashMap<String, i32>,
+    bans: BanList,
+    /// Invalid operator passwords per address, over all of its connections
+    failed_operator_attempts: HashMap<IpAddr, u32>,
+    transcript: Transcript,
+    credentials: CredentialStore,
+    /// Registrations waiting for their credentials to be saved
+    saving_registrations: FuturesUnordered<BoxFuture<'static, SavedRegistration>>,
+    mailbox: Mailbox,
+    filters: FilterChain,
+    link: Option<FederationLink>,
+}
+
+impl Room {
+    /// Opens the files of the room. Only unreadable credentials are an error, the transcript and
+    /// the mailboxes fall back to memory.
+    pub fn open(config: Arc<ChatServerConfig>) -> IO_Result<Self> {
+        let transcript = Transcript::open(config.transcript.clone()).unwrap_or_else(|e| {
+            println!("Keeping transcript in memory only, failed to open transcript file: {e}");
+            Transcript::in_memory(config.transcript.clone())
+        });
+        // Without the registrations anyone could take a registered name -> Do not start at all
+        let credentials = CredentialStore::open(config.credentials_file.clone()).map_err(|e| {
+            IO_Error::new(e.kind(), format!("Failed to read credentials file: {e}"))
+        })?;
+        let mailbox = Mailbox::open(config.mailbox.clone()).unwrap_or_else(|e| {
+            println!("Keeping mailboxes in memory only, failed to open mailbox file: {e}");
+            Mailbox::in_memory(config.mailbox.clone())
+        });
+        let filters = FilterChain::from_config(&config.message_filters);
+        Ok(Room {
+            config,
+            participants: HashMap::new(),
+            connection_ids: HashMap::new(),
+            bans: BanList::default(),
+            failed_operator_attempts: HashMap::new(),
+            transcript,
+            credentials,
+            saving_registrations: FuturesUnordered::new(),
+            filters,
+            mailbox,
+            link: None,
+        })
+    }
+
+    /// Executes commands until the last handle to the room is dropped
+    pub async fn run(mut self, mut command_receiver: Receiver<RoomCommand>) {
+        loop {
+            tokio::select! {
+                command = command_receiver.recv() => match command {
+                    Some(command) => self.execute(command),
+                    None => break,
+                },
+                Some(saved_registration) = self.saving_registrations.next() => {
+                    self.finish_registration(saved_registration);
+                }
+                _ = sleep_until(self.mailbox.save_at()) => self.mailbox.save(),
+            }
+            // Give the sessions a chance to catch up with the events of the command, otherwise a
+            // burst of commands easily fills their queues. Merely yielding is not enough once
+            // thousands of sessions are waiting to run -> Pause as long as lagging sessions catch
+            // up. A stuck session does not hold up the room, it is removed once its queue is full.
+            tokio::task::yield_now().await;
+            let mut lagging_events = self.lagging_events();
+            while lagging_events > 0 {
+                tokio::time::sleep(CATCH_UP_PAUSE).await;
+                let still_lagging_events = self.lagging_events();
+                if still_lagging_events >= lagging_events {
+                    break;
+                }
+                lagging_events = still_lagging_events;
+            }
+        }
+    }
+
+    /// Number of events waiting for sessions whose queue is at least half full
+    fn lagging_events(&self) -> usize {
+        self.participants
+            .values()
+            .map(|participant| {
+                let event_sender = &participant.event_sender;
+                let queued_events = event_sender.max_capacity() - event_sender.capacity();
+                if queued_events * 2 >= event_sender.max_capacity() {
+                    queued_events
+                } else {
+                    0
+                }
+            })
+            .sum()
+    }
+
+    fn execute(&mut self, command: RoomCommand) {
+        // Requesting sessions may have gone away in the meantime -> Replies may fail
+        match command {
+            RoomCommand::Join {
+                connection_id,
+                address,
+                user_name,
+                is_authenticated,
+                reply,
+            } => {
+                let joined_room = self.join(connection_id, address, user_name, is_authenticated);
+                let _ = reply.send(joined_room);
+            }
+            RoomCommand::Rename {
+                connection_id,
+                new_user_name,
+                reply,
+            } => {
+                let _ = reply.send(self.rename(connection_id, new_user_name));
+            }
+            RoomCommand::Leave { connection_id } => self.leave(connection_id),
+            RoomCommand::Message {
+                connection_id,
+                message,
+            } => {
+                self.broadcast_user_message(connection_id, message);
+            }
+            RoomCommand::ParticipantNames { reply } => {
+                let _ = reply.send(self.member_names().cloned().collect());
+            }
+            RoomCommand::Activity { connection_id } => self.record_activity(connection_id),
+            RoomCommand::Away { connection_id } => self.mark_away(connection_id),
+            RoomCommand::Who { reply } => {
+                let _ = reply.send(self.who());
+            }
+            RoomCommand::PasswordHash { user_name, reply } => {
+                let password_hash = self
+                    .credentials
+                    .password_hash(&self.name_key(&user_name))
+                    .map(String::from);
+                let _ = reply.send(password_hash);
+            }
+            RoomCommand::Register {
+                connection_id,
+                password_hash,
+                reply,
+            } => match self.register(connection_id, password_hash) {
+                Ok(mut registration) => self.saving_registrations.push(Box::pin(async move {
+                    let result = registration.saved().await;
+                    SavedRegistration {
+                        connection_id,
+                        registration,
+                        result,
+                        reply,
+                    }
+                })),
+                Err(error) => {
+                    let _ = reply.send(Err(error));
+                }
+            },
+            RoomCommand::Tell {
+                connection_id,
+                recipient,
+                text,
+                reply,
+            } => {
+                let _ = reply.send(self.tell(connection_id, recipient, text));
+            }
+            RoomCommand::SearchTranscript { term, reply } => {
+                let matching_lines = self
+                    .transcript
+                    .search(&term)
+                    .into_iter()
+                    .map(|entry| entry.to_line())
+                    .collect();
+                let _ = reply.send(matching_lines);
+            }
+            RoomCommand::BecomeOperator {
+                connection_id,
+                password,
+                reply,
+            } => {
+                let _ = reply.send(self.become_operator(connection_id, &password));
+            }
+            RoomCommand::IsMuted {
+                connection_id,
+                reply,
+            } => {
+                let _ = reply.send(self.is_muted(connection_id));
+            }
+            RoomCommand::Kick {
+                moderator_id,
+                user_name,
+                reply,
+            } => {
+                let _ = reply.send(self.kick(moderator_id, &user_name));
+            }
+            RoomCommand::Mute {
+                moderator_id,
+                user_name,
+                duration,
+                reply,
+            } => {
+                let _ = reply.send(self.mute(moderator_id, &user_name, duration));
+            }
+            RoomCommand::Unmute {
+                moderator_id,
+                user_name,
+                reply,
+            } => {
+                let _ = reply.send(self.unmute(moderator_id, &user_name));
+            }
+            RoomCommand::Ban {
+                moderator_id,
+                target,
+                duration,
+                reply,
+            } => {
+                let _ = reply.send(self.ban(moderator_id, &target, duration));
+            }
+            RoomCommand::Unban {
+                moderator_id,
+                target,
+                reply,
+            } => {
+                let _ = reply.send(self.unban(moderator_id, &target));
+            }
+            RoomCommand::IsBanned { address, reply } => {
+                let _ = reply.send(self.bans.is_banned(&address, Instant::now()));
+            }
+            RoomCommand::AttachLink {
+                link_id,
+                message_sender,
+                reply,
+            } => {
+                let _ = reply.send(self.attach_link(link_id, message_sender));
+            }
+            RoomCommand::DetachLink { link_id } => {
+                if self
+                    .link
+                    .as_ref()
+                    .is_some_and(|link| link.link_id == link_id)
+                {
+                    for event in self.drop_link() {
+                        self.broadcast(FEDERATION_ORIGIN, event);
+                    }
+                }
+            }
+            RoomCommand::LinkMessage { link_id, message } => {
+                if self
+                    .link
+                    .as_ref()
+                    .is_some_and(|link| link.link_id == link_id)
+                {
+                    self.receive_link_message(message);
+                }
+            }
+        }
+    }
+
+    /// Form of the name under which it is unique in the room. Names that only differ in their
+    /// encoding or, with a case-insensitive name policy, in case have the same key. Registrations
+    /// and mailboxes are kept under the key as well.
+    fn name_key(&self, user_name: &str) -> String {
+        self.config.name_policy.key(user_name)
+    }
+
+    fn local_names(&self) -> impl Iterator<Item = &String> {
+        self.participants
+            .values()
+            .map(|participant| &participant.user_name)
+    }
+
+    /// Names of everyone in the room, including the members of the linked server
+    fn member_names(&self) -> impl Iterator<Item = &String> {
+        let remote_members = self
+            .link
+            .iter()
+            .flat_map(|link| link.remote_members.values());
+        self.local_names().chain(remote_members)
+    }
+
+    fn is_remote_member(&self, user_name: &str) -> bool {
+        self.link
+            .as_ref()
+            .is_some_and(|link| link.remote_members.contains_key(&self.name_key(user_name)))
+    }
+
+    fn is_name_taken(&self, user_name: &str) -> bool {
+        self.connection_ids.contains_key(&self.name_key(user_name))
+            || self.is_remote_member(user_name)
+    }
+
+    fn is_registered(&self, user_name: &str) -> bool {
+        self.credentials.is_registered(&self.name_key(user_name))
+    }
+
+    /// Records the event and delivers it to everyone but its origin. Participants whose queue is
+    /// full are too slow for the room -> They are removed and their departure is announced. The
+    /// same goes for the linked server, which gets all events that did not come from it.
+    fn broadcast(&mut self, origin_id: i32, event: RoomEvent) {
+        let mut pending_events = VecDeque::from([(origin_id, event)]);
+        while let Some((origin_id, event)) = pending_events.pop_front() {
+            self.transcript
+                .record(event.clone(), unix_timestamp(SystemTime::now()));
+
+            let mut slow_participants = Vec::new();
+            for (connection_id, participant) in &self.participants {
+                if *connection_id == origin_id {
+                    continue;
+                }
+                let room_event = SessionEvent::Room(event.clone());
+                if let Err(TrySendError::Full(_)) = participant.event_sender.try_send(room_event) {
+                    slow_participants.push(*connection_id);
+                }
+            }
+
+            for connection_id in slow_participants {
+                if let Some(user_name) = self.remove(connection_id) {
+                    println!("[{connection_id}] User {user_name} fell behind the room");
+                    pending_events.push_back((connection_id, RoomEvent::Left(user_name)));
+                }
+            }
+
+            let is_link_lost = origin_id != FEDERATION_ORIGIN
+                && self.link.as_ref().is_some_and(|link| {
+                    let link_message = LinkMessage::Event(event.clone());
+                    link.message_sender.try_send(link_message).is_err()
+                });
+            if is_link_lost {
+                println!("Federation link fell behind the room");
+                let remote_departures = self.drop_link().into_iter();
+                pending_events.extend(remote_departures.map(|event| (FEDERATION_ORIGIN, event)));
+            }
+        }
+    }
+
+    /// Runs the message through the filters before broadcasting it. Flagged messages are
+    /// reported to the operators in the room.
+    fn broadcast_user_message(&mut self, connection_id: i32, message: String) {
+        let Some(participant) = self.participants.get(&connection_id) else {
+            return;
+        };
+        let user_name = participant.user_name.clone();
+
+        match self.filters.apply(message) {
+            FilterOutcome::Broadcast { message, flags } => {
+                for reason in flags {
+                    println!("[{connection_id}] Flagged message from {user_name}: {reason}");
+                    let operator_ids: Vec<i32> = self
+                        .participants
+                        .iter()
+                        .filter(|(_, participant)| participant.is_operator)
+                        .map(|(operator_id, _)| *operator_id)
+                        .collect();
+                    for operator_id in operator_ids {
+                        self.notify(
+                            operator_id,
+                            SessionEvent::Message(format!(
+                                "* Flagged message from {user_name}: {reason}"
+                            )),
+                        );
+                    }
+                }
+                self.broadcast(connection_id, RoomEvent::Message(user_name, message));
+            }
+            FilterOutcome::Dropped(reason) => {
+                println!("[{connection_id}] Dropped message from {user_name}: {reason}");
+                self.notify(
+                    connection_id,
+                    SessionEvent::Message(format!("* Message not sent: {reason}")),
+                );
+            }
+        }
+    }
+
+    /// Sends an event to a single participant. A participant that cannot even take this event
+    /// is removed like any other slow participant.
+    fn notify(&mut self, connection_id: i32, event: SessionEvent) {
+        let Some(participant) = self.participants.get(&connection_id) else {
+            return;
+        };
+        if let Err(TrySendError::Full(_)) = participant.event_sender.try_send(event) {
+            if let Some(user_name) = self.remove(connection_id) {
+                println!("[{connection_id}] User {user_name} fell behind the room");
+                self.broadcast(connection_id, RoomEvent::Left(user_name));
+            }
+        }
+    }
+
+    /// Removes the participant and returns its name. Dropping its event sender ends the session.
+    fn remove(&mut self, connection_id: i32) -> Option<String> {
+        let participant = self.participants.remove(&connection_id)?;
+        self.connection_ids
+            .remove(&self.name_key(&participant.user_name));
+        Some(participant.user_name)
+    }
+
+    fn join(
+        &mut self,
+        connection_id: i32,
+        address: IpAddr,
+        user_name: String,
+        is_authenticated: bool,
+    ) -> Result<JoinedRoom, ()> {
+        if self.is_registered(&user_name) && !is_authenticated {
+            return Err(());
+        }
+
+        let participants_list = if self.member_names().next().is_none() {
+            // New user is first joining user -> Valid & Return empty participants list
+            String::from("-")
+        } else if self.is_name_taken(&user_name) {
+            // Name is already in use, possibly on the linked server -> Invalid
+            return Err(());
+        } else {
+            // New user has an unused name -> Valid & Return comma separated participants list
+            self.member_names().join(", ")
+        };
+
+        let (event_sender, event_receiver) = channel(self.config.session_queue_capacity.max(1));
+        let name_key = self.name_key(&user_name);
+        // With registration, the name has to be proven, or anyone claiming it first moderates
+        let is_operator = self
+            .config
+            .operator_names
+            .iter()
+            .any(|operator_name| self.name_key(operator_name) == name_key)
+            && (is_authenticated || !self.credentials.is_enabled());
+        self.connection_ids.insert(name_key.clone(), connection_id);
+        self.participants.insert(
+            connection_id,
+            Participant {
+                user_name: user_name.clone(),
+                address,
+                is_operator,
+                failed_operator_attempts: 0,
+                is_muted: false,
+                muted_until: None,
+                authenticated_names: HashSet::from_iter(
+                    is_authenticated.then_some(name_key.clone()),
+                ),
+                joined_at: Instant::now(),
+                last_activity: Instant::now(),
+                is_away: false,
+                event_sender,
+            },
+        );
+        self.broadcast(connection_id, RoomEvent::Joined(user_name.clone()));
+        self.mailbox.record_seen(&name_key);
+        self.deliver_mailbox(connection_id);
+
+        Ok(JoinedRoom {
+            event_receiver,
+            participants_list,
+        })
+    }
+
+    fn rename(&mut self, connection_id: i32, new_user_name: String) -> Result<(), RenameError> {
+        let name_key = self.name_key(&new_user_name);
+        // Changing only the case of the own name is fine with a case-insensitive name policy
+        let is_own_name_key = self.connection_ids.get(&name_key) == Some(&connection_id);
+        if self.is_name_taken(&new_user_name) && !is_own_name_key {
+            return Err(RenameError::NameTaken);
+        }
+        let Some(participant) = self.participants.get_mut(&connection_id) else {
+            // Session already fell behind and is about to end
+            return Err(RenameError::NameTaken);
+        };
+        if participant.user_name == new_user_name {
+            return Err(RenameError::NameTaken);
+        }
+        if self.credentials.is_registered(&name_key)
+            && !participant.authenticated_names.contains(&name_key)
+        {
+            return Err(RenameError::NameRegistered);
+        }
+
+        self.rename_participant(connection_id, new_user_name, connection_id);
+        Ok(())
+    }
+
+    fn rename_participant(&mut self, connection_id: i32, new_user_name: String, origin_id: i32) {
+        let Some(participant) = self.participants.get_mut(&connection_id) else {
+            return;
+        };
+
+        let old_user_name = std::mem::replace(&mut participant.user_name, new_user_name.clone());
+        self.connection_ids.remove(&self.name_key(&old_user_name));
+        self.connection_ids
+            .insert(self.name_key(&new_user_name), connection_id);
+        self.broadcast(
+            origin_id,
+            RoomEvent::Renamed(old_user_name, new_user_name.clone()),
+        );
+        self.mailbox.record_seen(&self.name_key(&new_user_name));
+        self.deliver_mailbox(connection_id);
+    }
+
+    fn attach_link(&mut self, link_id: i32, message_sender: Sender<LinkMessage>) -> bool {
+        if self.link.is_some() {
+            return false;
+        }
+
+        // Hello goes out before any event, so the linked server learns about all members
+        let hello = LinkMessage::Hello {
+            server_name: self.config.federation.server_name.clone(),
+            members: self.local_names().cloned().collect(),
+        };
+        if message_sender.try_send(hello).is_err() {
+            return false;
+        }
+        self.link = Some(FederationLink {
+            link_id,
+            message_sender,
+            peer_name: None,
+            remote_members: HashMap::new(),
+        });
+        true
+    }
+
+    /// Forgets the link and returns the departures of its members. Dropping the message sender
+    /// closes the connection to the linked server.
+    fn drop_link(&mut self) -> Vec<RoomEvent> {
+        let Some(link) = self.link.take() else {
+            return Vec::new();
+        };
+        let peer_name = link.peer_name.as_deref().unwrap_or("unknown server");
+        println!("Federation link to {peer_name} closed");
+        link.remote_members
+            .into_values()
+            .sorted()
+            .map(RoomEvent::Left)
+            .collect()
+    }
+
+    fn receive_link_message(&mut self, message: LinkMessage) {
+        let config = self.config.clone();
+        let name_key = |user_name: &str| config.name_policy.key(user_name);
+        let Some(link) = self.link.as_mut() else {
+            return;
+        };
+
+        match message {
+            LinkMessage::Hello {
+                server_name,
+                members,
+            } => {
+                if server_name == self.config.federation.server_name {
+                    // Name collisions could not be resolved
+                    println!("Refusing federation link to {server_name}, it has the same name");
+                    self.drop_link();
+                    return;
+                }
+                println!("Federation link to {server_name} is ready");
+                link.peer_name = Some(server_name);
+                for user_name in members {
+                    if self.admit_remote_member(user_name.as_str()) {
+                        self.broadcast(FEDERATION_ORIGIN, RoomEvent::Joined(user_name));
+                    }
+                }
+            }
+            // Linked server has to introduce itself first
+            LinkMessage::Event(_) if link.peer_name.is_none() => {}
+            LinkMessage::Event(RoomEvent::Joined(user_name)) => {
+                if self.admit_remote_member(&user_name) {
+                    self.broadcast(FEDERATION_ORIGIN, RoomEvent::Joined(user_name));
+                }
+            }
+            LinkMessage::Event(RoomEvent::Left(user_name)) => {
+                if link.remote_members.remove(&name_key(&user_name)).is_some() {
+                    self.broadcast(FEDERATION_ORIGIN, RoomEvent::Left(user_name));
+                }
+            }
+            LinkMessage::Event(RoomEvent::Renamed(old_user_name, new_user_name)) => {
+                let event = if link
+                    .remote_members
+                    .remove(&name_key(&old_user_name))
+                    .is_none()
+                {
+                    // Unknown name, e.g. the linked server lost a name collision -> New member
+                    self.admit_remote_member(&new_user_name)
+                        .then_some(RoomEvent::Joined(new_user_name))
+                } else if self.admit_remote_member(&new_user_name) {
+                    Some(RoomEvent::Renamed(old_user_name, new_user_name))
+                } else {
+                    Some(RoomEvent::Left(old_user_name))
+                };
+                if let Some(event) = event {
+                    self.broadcast(FEDERATION_ORIGIN, event);
+                }
+            }
+            LinkMessage::Event(
+                event @ (RoomEvent::Message(..) | RoomEvent::Away(_) | RoomEvent::Back(_)),
+            ) => {
+                if link
+                    .remote_members
+                    .contains_key(&name_key(event.user_name()))
+                {
+                    self.broadcast(FEDERATION_ORIGIN, event);
+                }
+            }
+            LinkMessage::NameTaken(user_name) => {
+                let peer_name = link.peer_name.clone().unwrap_or_default();
+                let Some(&connection_id) = self.connection_ids.get(&name_key(&user_name)) else {
+                    return;
+                };
+                // Registered on both servers -> The server with the lower name keeps it
+                if self.is_registered(&user_name) && self.config.federation.server_name < peer_name
+                {
+                    return;
+                }
+                self.rename_conflicting_participant(connection_id, &user_name, &peer_name);
+                if self.insert_remote_member(&user_name) {
+                    self.broadcast(FEDERATION_ORIGIN, RoomEvent::Joined(user_name));
+                }
+            }
+        }
+    }
+
+    /// Adds a member of the linked server to the room, unless it is known already. A name that
+    /// is registered on this server stays here, the linked server is told to rename its
+    /// participant. Otherwise, if a local participant has the same name, the server with the
+    /// lower name keeps it. The other server renames its participant and announces that, which
+    /// the winner takes as a new member.
+    fn admit_remote_member(&mut self, user_name: &str) -> bool {
+        let Some(link) = self.link.as_ref() else {
+            return false;
+        };
+        let name_key = self.name_key(user_name);
+        if link.remote_members.contains_key(&name_key) {
+            return false;
+        }
+
+        let peer_name = link.peer_name.clone().unwrap_or_default();
+        if self.is_registered(user_name) {
+            println!("Name {user_name} of {peer_name} is registered here, asking for a rename");
+            self.send_link_message(LinkMessage::NameTaken(String::from(user_name)));
+            return false;
+        }
+        if let Some(&connection_id) = self.connection_ids.get(&name_key) {
+            if self.config.federation.server_name < peer_name {
+                return false;
+            }
+            self.rename_conflicting_participant(connection_id, user_name, &peer_name);
+        }
+        self.insert_remote_member(user_name)
+    }
+
+    /// Moves the local participant out of the way of the linked server's member
+    fn rename_conflicting_participant(
+        &mut self,
+        connection_id: i32,
+        user_name: &str,
+        peer_name: &str,
+    ) {
+        let free_user_name = self.free_user_name(user_name);
+        println!(
+            "[{connection_id}] Name {user_name} is used on {peer_name} too, renaming to {free_user_name}"
+        );
+        self.notify(
+            connection_id,
+            SessionEvent::Message(format!(
+                "* Name {user_name} is used on server {peer_name} too"
+            )),
+        );
+        self.rename_participant(connection_id, free_user_name, SERVER_ORIGIN);
+    }
+
+    fn insert_remote_member(&mut self, user_name: &str) -> bool {
+        let name_key = self.name_key(user_name);
+        // Renaming may have lost the link if it fell behind
+        match self.link.as_mut() {
+            Some(link) => link
+                .remote_members
+                .insert(name_key, String::from(user_name))
+                .is_none(),
+            None => false,
+        }
+    }
+
+    /// Sends the message to the linked server, dropping the link if it fell behind
+    fn send_link_message(&mut self, message: LinkMessage) {
+        let is_link_lost = self
+            .link
+            .as_ref()
+            .is_some_and(|link| link.message_sender.try_send(message).is_err());
+        if is_link_lost {
+            println!("Federation link fell behind the room");
+            for event in self.drop_link() {
+                self.broadcast(FEDERATION_ORIGIN, event);
+            }
+        }
+    }
+
+    /// Unused name derived from the given name, e.g. `alice2`
+    fn free_user_name(&self, user_name: &str) -> String {
+        let base_name: String = user_name.chars().take(12).collect();
+        (2..)
+            .map(|number| format!("{base_name}{number}"))
+            .find(|candidate| !self.is_name_taken(candidate) && !self.is_registered(candidate))
+            .unwrap()
+    }
+
+    /// Hands the messages waiting for the participant's name over to the participant. Messages
+    /// that do not fit into the participant's queue stay in the mailbox for the next time.
+    fn deliver_mailbox(&mut self, connection_id: i32) {
+        let Some(participant) = self.participants.get(&connection_id) else {
+            return;
+        };
+
+        let messages = self.mailbox.take(
+            &self.config.name_policy.key(&participant.user_name),
+            participant.event_sender.capacity(),
+        );
+        for message in messages {
+            let _ = participant
+                .event_sender
+                .try_send(SessionEvent::Message(message.to_line()));
+        }
+    }
+
+    fn tell(
+        &mut self,
+        connection_id: i32,
+        recipient: String,
+        text: String,
+    ) -> Result<Delivery, TellError> {
+        if self.is_muted(connection_id) {
+            return Err(TellError::Muted);
+        }
+        let Some(sender) = self.participants.get(&connection_id) else {
+            return Err(TellError::UnknownRecipient);
+        };
+        let message = MailboxMessage {
+            timestamp: unix_timestamp(SystemTime::now()),
+            sender: sender.user_name.clone(),
+            text,
+        };
+
+        let recipient_key = self.name_key(&recipient);
+        if let Some(recipient_id) = self.connection_ids.get(&recipient_key) {
+            self.notify(*recipient_id, SessionEvent::Message(message.to_line()));
+            Ok(Delivery::Delivered)
+        } else if !self.mailbox.has_seen(&recipient_key)
+            && !self.credentials.is_registered(&recipient_key)
+        {
+            Err(TellError::UnknownRecipient)
+        } else if self.mailbox.queue(&recipient_key, message) {
+            Ok(Delivery::Queued)
+        } else {
+            Err(TellError::MailboxFull)
+        }
+    }
+
+    /// Registers the current name of the session. Holding the name is proof enough to change the
+    /// password of a registered name, as registered names can only be taken with their password.
+    fn register(
+        &mut self,
+        connection_id: i32,
+        password_hash: String,
+    ) -> Result<SavingRegistration, RegistrationError> {
+        if !self.credentials.is_enabled() {
+            return Err(RegistrationError::Disabled);
+        }
+        let Some(participant) = self.participants.get(&connection_id) else {
+            return Err(RegistrationError::NotSaved);
+        };
+
+        let name_key = self.config.name_policy.key(&participant.user_name);
+        self.credentials
+            .register(&name_key, password_hash)
+            .map_err(|e| {
+                println!("[{connection_id}] Failed to save credentials: {e}");
+                RegistrationError::NotSaved
+            })
+    }
+
+    /// The session may use the registered name once the credentials are saved
+    fn finish_registration(&mut self, saved_registration: SavedRegistration) {
+        let SavedRegistration {
+            connection_id,
+            registration,
+            result,
+            reply,
+        } = saved_registration;
+        if let Err(e) = result {
+            println!("[{connection_id}] Failed to save credentials: {e}");
+            self.credentials.undo(registration);
+            let _ = reply.send(Err(RegistrationError::NotSaved));
+            return;
+        }
+
+        if let Some(participant) = self.participants.get_mut(&connection_id) {
+            participant
+                .authenticated_names
+                .insert(registration.user_name);
+        }
+        let _ = reply.send(Ok(()));
+    }
+
+    /// Announces the return of participants that were away
+    fn record_activity(&mut self, connection_id: i32) {
+        let Some(participant) = self.participants.get_mut(&connection_id) else {
+            return;
+        };
+        participant.last_activity = Instant::now();
+        if participant.is_away {
+            participant.is_away = false;
+            let user_name = participant.user_name.clone();
+            self.broadcast(connection_id, RoomEvent::Back(user_name));
+        }
+    }
+
+    fn mark_away(&mut self, connection_id: i32) {
+        let Some(participant) = self.participants.get_mut(&connection_id) else {
+            return;
+        };
+        if !participant.is_away {
+            participant.is_away = true;
+            let user_name = participant.user_name.clone();
+            self.broadcast(connection_id, RoomEvent::Away(user_name));
+        }
+    }
+
+    /// All members sorted by name, the linked server does not share the times of its members
+    fn who(&self) -> Vec<MemberPresence> {
+        let now = Instant::now();
+        let local_members = self
+            .participants
+            .values()
+            .map(|participant| MemberPresence {
+                user_name: participant.user_name.clone(),
+                connected_for: Some(now - participant.joined_at),
+                idle_for: Some(now - participant.last_activity),
+                is_away: participant.is_away,
+            });
+        let remote_members = self
+            .link
+            .iter()
+            .flat_map(|link| link.remote_members.values())
+            .map(|user_name| MemberPresence {
+                user_name: user_name.clone(),
+                connected_for: None,
+                idle_for: None,
+                is_away: false,
+            });
+        local_members
+            .chain(remote_members)
+            .sorted_by(|a, b| a.user_name.cmp(&b.user_name))
+            .collect()
+    }
+
+    /// Only announces the departure of actual participants, sessions removed for falling behind
+    /// have already been announced
+    fn leave(&mut self, connection_id: i32) {
+        if let Some(user_name) = self.remove(connection_id) {
+            self.broadcast(connection_id, RoomEvent::Left(user_name));
+        }
+    }
+
+    /// Guessing the password gets the connection disconnected and eventually its address banned
+    fn become_operator(&mut self, connection_id: i32, password: &str) -> bool {
+        let Some(operator_password) = &self.config.operator_password else {
+            return false;
+        };
+        let is_valid_password = constant_time_eq(operator_password.as_bytes(), password.as_bytes());
+        let Some(participant) = self.participants.get_mut(&connection_id) else {
+            return false;
+        };
+        let address = participant.address;
+        if is_valid_password {
+            participant.is_operator = true;
+            participant.failed_operator_attempts = 0;
+            self.failed_operator_attempts.remove(&address);
+            return true;
+        }
+
+        participant.failed_operator_attempts += 1;
+        let connection_attempts = participant.failed_operator_attempts;
+        let address_attempts = self.failed_operator_attempts.entry(address).or_default();
+        *address_attempts += 1;
+        if *address_attempts >= MAX_OPERATOR_ATTEMPTS_PER_ADDRESS {
+            println!("Banning {address} after {address_attempts} invalid operator passwords");
+            self.failed_operator_attempts.remove(&address);
+            let now = Instant::now();
+            self.bans
+                .ban(address, None, Some(OPERATOR_GUESSING_BAN), now);
+            self.disconnect_address(address, "* Too many invalid operator passwords");
+        } else if connection_attempts >= MAX_OPERATOR_ATTEMPTS {
+            self.notify(
+                connection_id,
+                SessionEvent::Disconnect(String::from("* Too many invalid operator passwords")),
+            );
+        }
+        false
+    }
+
+    fn is_muted(&mut self, connection_id: i32) -> bool {
+        let Some(participant) = self.participants.get_mut(&connection_id) else {
+            return false;
+        };
+        if participant
+            .muted_until
+            .is_some_and(|muted_until| muted_until <= Instant::now())
+        {
+            // Mute has expired -> Lift it
+            participant.is_muted = false;
+            participant.muted_until = None;
+        }
+        participant.is_muted
+    }
+
+    /// Returns the name of the moderator if it is an operator
+    fn check_operator(&self, moderator_id: i32) -> Result<String, ModerationError> {
+        match self.participants.get(&moderator_id) {
+            Some(participant) if participant.is_operator => Ok(participant.user_name.clone()),
+            _ => Err(ModerationError::NotAnOperator),
+        }
+    }
+
+    fn find_participant(&self, user_name: &str) -> Result<i32, ModerationError> {
+        self.connection_ids
+            .get(&self.name_key(user_name))
+            .copied()
+            .ok_or_else(|| ModerationError::UnknownUser(String::from(user_name)))
+    }
+
+    fn kick(&mut self, moderator_id: i32, user_name: &str) -> Result<(), ModerationError> {
+        let moderator_name = self.check_operator(moderator_id)?;
+        let connection_id = self.find_participant(user_name)?;
+        self.notify(
+            connection_id,
+            SessionEvent::Disconnect(format!("* You have been kicked by {moderator_name}")),
+        );
+        Ok(())
+    }
+
+    fn mute(
+        &mut self,
+        moderator_id: i32,
+        user_name: &str,
+        duration: Option<Duration>,
+    ) -> Result<(), ModerationError> {
+        let moderator_name = self.check_operator(moderator_id)?;
+        let connection_id = self.find_participant(user_name)?;
+        let participant = self.participants.get_mut(&connection_id).unwrap();
+        participant.is_muted = true;
+        participant.muted_until = duration.map(|duration| Instant::now() + duration);
+        self.notify(
+            connection_id,
+            SessionEvent::Message(format!("* You have been muted by {moderator_name}")),
+        );
+        Ok(())
+    }
+
+    fn unmute(&mut self, moderator_id: i32, user_name: &str) -> Result<(), ModerationError> {
+        let moderator_name = self.check_operator(moderator_id)?;
+        let connection_id = self.find_participant(user_name)?;
+        let participant = self.participants.get_mut(&connection_id).unwrap();
+        participant.is_muted = false;
+        participant.muted_until = None;
+        self.notify(
+            connection_id,
+            SessionEvent::Message(format!("* You have been unmuted by {moderator_name}")),
+        );
+        Ok(())
+    }
+
+    /// Bans the address of the target and disconnects all of its sessions except for operators.
+    /// Returns the banned address.
+    fn ban(
+        &mut self,
+        moderator_id: i32,
+        target: &ModerationTarget,
+        duration: Option<Duration>,
+    ) -> Result<IpAddr, ModerationError> {
+        let moderator_name = self.check_operator(moderator_id)?;
+
+        let (address, user_name) = match target {
+            ModerationTarget::Address(address) => (*address, None),
+            ModerationTarget::UserName(user_name) => {
+                let connection_id = self.find_participant(user_name)?;
+                (
+                    self.participants[&connection_id].address,
+                    Some(self.name_key(user_name)),
+                )
+            }
+        };
+        self.bans.ban(address, user_name, duration, Instant::now());
+        self.disconnect_address(
+            address,
+            &format!("* You have been banned by {moderator_name}"),
+        );
+        Ok(address)
+    }
+
+    /// Disconnects all sessions of the address except for operators
+    fn disconnect_address(&mut self, address: IpAddr, message: &str) {
+        let banned_participants = self
+            .participants
+            .iter()
+            .filter(|(_, participant)| participant.address == address && !participant.is_operator)
+            .map(|(connection_id, _)| *connection_id)
+            .collect_vec();
+        for connection_id in banned_participants {
+            self.notify(
+                connection_id,
+                SessionEvent::Disconnect(String::from(message)),
+            );
+        }
+    }
+
+    fn unban(
+        &mut self,
+        moderator_id: i32,
+        target: &ModerationTarget,
+    ) -> Result<(), ModerationError> {
+        self.check_operator(moderator_id)?;
+
+        // Bans by name are kept under the name key, so that any form of the name lifts them
+        let target = match target {
+            ModerationTarget::UserName(user_name) => {
+                ModerationTarget::UserName(self.name_key(user_name))
+            }
+            ModerationTarget::Address(address) => ModerationTarget::Address(*address),
+        };
+        if self.bans.unban(&target) {
+            Ok(())
+        } else {
+            Err(ModerationError::NotBanned)
+        }
+    }
+}
@@ -0,0 +1,1186 @@
+use std::collections::HashSet;
+use std::io::Result as IO_Result;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicI32, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::mpsc::{channel, Receiver, Sender};
+use tokio::sync::oneshot;
+
+use crate::config::ChatServerConfig;
+use crate::credentials::{hash_password, verify_password};
+use crate::event::RoomEvent;
+use crate::federation::LinkMessage;
+use crate::moderation::ModerationTarget;
+use crate::presence::MemberPresence;
+use crate::room::{JoinedRoom, Reply, Room, RoomCommand};
+
+#[derive(Debug, PartialEq)]
+pub enum JoinError {
+    InvalidName,
+    NameTaken,
+    WrongPassword,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RenameError {
+    InvalidName,
+    NameTaken,
+    /// Registered names can only be taken by joining with their password
+    NameRegistered,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum RegistrationError {
+    Disabled,
+    NotSaved,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum TellError {
+    /// Messages can only be left for registered names and names that were used before
+    UnknownRecipient,
+    MailboxFull,
+    /// Muted participants cannot send private messages either
+    Muted,
+}
+
+/// How a message from `/tell` reached its recipient
+#[derive(Debug, PartialEq)]
+pub enum Delivery {
+    /// Recipient is in the room and got the message right away
+    Delivered,
+    /// Recipient gets the message when joining the next time
+    Queued,
+}
+
+#[derive(Debug, PartialEq)]
+pub enum ModerationError {
+    NotAnOperator,
+    UnknownUser(String),
+    NotBanned,
+}
+
+/// Everything the room delivers to a single session
+#[derive(Debug, PartialEq)]
+pub enum SessionEvent {
+    Room(RoomEvent),
+    Message(String),
+    Disconnect(String),
+}
+
+/// Handle to the room actor, shared by all listeners and sessions
+#[derive(Clone)]
+pub struct SessionRegistry {
+    command_sender: Sender<RoomCommand>,
+    config: Arc<ChatServerConfig>,
+    connection_counter: Arc<AtomicI32>,
+}
+
+impl SessionRegistry {
+    /// Opens the files of the room and starts the room actor, which runs until the last handle
+    /// to it is dropped
+    pub fn new(config: ChatServerConfig) -> IO_Result<Self> {
+        let config = Arc::new(config);
+        let room = Room::open(config.clone())?;
+        let (command_sender, command_receiver) = channel(config.room_queue_capacity.max(1));
+        tokio::spawn(room.run(command_receiver));
+        Ok(SessionRegistry {
+            command_sender,
+            config,
+            connection_counter: Arc::new(AtomicI32::new(0)),
+        })
+    }
+
+    pub fn config(&self) -> &ChatServerConfig {
+        &self.config
+    }
+
+    /// Ids are unique across all listeners, so that the room can tell all sessions apart
+    pub fn next_connection_id(&self) -> i32 {
+        self.connection_counter.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    /// Waits while the room is busy, so that fast sessions cannot pile up commands without limit
+    async fn send_command(&self, command: RoomCommand) {
+        // The room only stops once all handles are gone, so this cannot fail
+        let _ = self.command_sender.send(command).await;
+    }
+
+    /// Sends the command without waiting, for `drop`. A busy room gets it from a task of its own,
+    /// so that it is not lost.
+    fn send_command_later(&self, command: RoomCommand) {
+        if let Err(TrySendError::Full(command)) = self.command_sender.try_send(command) {
+            let command_sender = self.command_sender.clone();
+            tokio::spawn(async move {
+                let _ = command_sender.send(command).await;
+            });
+        }
+    }
+
+    async fn request<T>(&self, command: impl FnOnce(Reply<T>) -> RoomCommand) -> T {
+        let (reply_sender, reply_receiver) = oneshot::channel();
+        self.send_command(command(reply_sender)).await;
+        reply_receiver.await.expect("Room stopped unexpectedly")
+    }
+
+    pub async fn participant_names(&self) -> HashSet<String> {
+        self.request(|reply| RoomCommand::ParticipantNames { reply })
+            .await
+    }
+
+    /// Everyone in the room with their connected and idle times, sorted by name
+    pub async fn who(&self) -> Vec<MemberPresence> {
+        self.request(|reply| RoomCommand::Who { reply }).await
+    }
+
+    /// Connects the room to a linked server. Returns false if the room is linked already.
+    pub async fn attach_link(&self, link_id: i32, message_sender: Sender<LinkMessage>) -> bool {
+        self.request(|reply| RoomCommand::AttachLink {
+            link_id,
+            message_sender,
+            reply,
+        })
+        .await
+    }
+
+    pub fn detach_link(&self, link_id: i32) {
+        self.send_command_later(RoomCommand::DetachLink { link_id });
+    }
+
+    pub async fn receive_link_message(&self, link_id: i32, message: LinkMessage) {
+        self.send_command(RoomCommand::LinkMessage { link_id, message })
+            .await;
+    }
+
+    /// Transcript lines of recent events matching the term
+    pub async fn search_transcript(&self, term: &str) -> Vec<String> {
+        let term = String::from(term);
+        self.request(|reply| RoomCommand::SearchTranscript { term, reply })
+            .await
+    }
+
+    pub async fn is_registered(&self, user_name: &str) -> bool {
+        let user_name = String::from(user_name);
+        self.request(|reply| RoomCommand::PasswordHash { user_name, reply })
+            .await
+            .is_some()
+    }
+
+    /// Reserves the user name and announces the join to the room. The returned session releases
+    /// the name and announces the departure again once it is dropped, regardless of how the
+    /// connection ended. Registered names can only be joined with their password, other names do
+    /// not need one. The session's name is the name as allowed by the name policy.
+    pub async fn try_join(
+        &self,
+        connection_id: i32,
+        address: IpAddr,
+        new_user_name: &str,
+        password: Option<&str>,
+    ) -> Result<(Session, String), JoinError> {
+        let new_user_name = self
+            .config
+            .name_policy
+            .check(new_user_name)
+            .ok_or(JoinError::InvalidName)?;
+        let user_name = new_user_name.clone();
+        let password_hash = self
+            .request(|reply| RoomCommand::PasswordHash { user_name, reply })
+            .await;
+
+        let is_authenticated = match (password_hash, password) {
+            (None, _) => false,
+            (Some(password_hash), Some(password)) => {
+                // Verifying takes a while on purpose -> Keep it off the async workers
+                let password = String::from(password);
+                let is_valid_password =
+                    tokio::task::spawn_blocking(move || verify_password(&password, &password_hash))
+                        .await
+                        .unwrap_or(false);
+                if !is_valid_password {
+                    return Err(JoinError::WrongPassword);
+                }
+                true
+            }
+            (Some(_), None) => return Err(JoinError::WrongPassword),
+        };
+
+        self.join(connection_id, address, &new_user_name, is_authenticated)
+            .await
+            .map_err(|()| JoinError::NameTaken)
+    }
+
+    async fn join(
+        &self,
+        connection_id: i32,
+        address: IpAddr,
+        new_user_name: &str,
+        is_authenticated: bool,
+    ) -> Result<(Session, String), ()> {
+        let user_name = String::from(new_user_name);
+        let JoinedRoom {
+            event_receiver,
+            participants_list,
+        } = self
+            .request(|reply| RoomCommand::Join {
+                connection_id,
+                address,
+                user_name,
+                is_authenticated,
+                reply,
+            })
+            .await?;
+
+        let session = Session {
+            connection_id,
+            user_name: String::from(new_user_name),
+            event_receiver,
+            registry: self.clone(),
+        };
+        Ok((session, participants_list))
+    }
+
+    /// Registers the session's current name with the password
+    pub async fn register(
+        &self,
+        session: &Session,
+        password: &str,
+    ) -> Result<(), RegistrationError> {
+        let password = String::from(password);
+        let password_hash = tokio::task::spawn_blocking(move || hash_password(&password))
+            .await
+            .map_err(|_| RegistrationError::NotSaved)?;
+
+        let connection_id = session.connection_id;
+        self.request(|reply| RoomCommand::Register {
+            connection_id,
+            password_hash,
+            reply,
+        })
+        .await
+    }
+
+    /// Sends a private message to the recipient, or leaves it in the recipient's mailbox if the
+    /// recipient is not in the room
+    pub async fn tell(
+        &self,
+        session: &Session,
+        recipient: &str,
+        text: &str,
+    ) -> Result<Delivery, TellError> {
+        let connection_id = session.connection_id;
+        let recipient = String::from(recipient);
+        let text = String::from(text);
+        self.request(|reply| RoomCommand::Tell {
+            connection_id,
+            recipient,
+            text,
+            reply,
+        })
+        .await
+    }
+
+    /// Swaps the session's reserved name for the new one. The room handles renames one after
+    /// another, so that of several sessions racing for the same name exactly one wins.
+    pub async fn try_rename(
+        &self,
+        session: &mut Session,
+        new_user_name: &str,
+    ) -> Result<(), RenameError> {
+        let new_user_name = self
+            .config
+            .name_policy
+            .check(new_user_name)
+            .ok_or(RenameError::InvalidName)?;
+
+        let connection_id = session.connection_id;
+        self.request(|reply| RoomCommand::Rename {
+            connection_id,
+            new_user_name: new_user_name.clone(),
+            reply,
+        })
+        .await?;
+        session.user_name = new_user_name;
+        Ok(())
+    }
+
+    /// Grants operator rights if the password matches the configured operator password
+    pub async fn try_become_operator(&self, session: &Session, password: &str) -> bool {
+        let connection_id = session.connection_id;
+        let password = String::from(password);
+        self.request(|reply| RoomCommand::BecomeOperator {
+            connection_id,
+            password,
+            reply,
+        })
+        .await
+    }
+
+    pub async fn is_muted(&self, session: &Session) -> bool {
+        let connection_id = session.connection_id;
+        self.request(|reply| RoomCommand::IsMuted {
+            connection_id,
+            reply,
+        })
+        .await
+    }
+
+    pub async fn kick(&self, moderator: &Session, user_name: &str) -> Result<(), ModerationError> {
+        let moderator_id = moderator.connection_id;
+        let user_name = String::from(user_name);
+        self.request(|reply| RoomCommand::Kick {
+            moderator_id,
+            user_name,
+            reply,
+        })
+        .await
+    }
+
+    pub async fn mute(
+        &self,
+        moderator: &Session,
+        user_name: &str,
+        duration: Option<Duration>,
+    ) -> Result<(), ModerationError> {
+        let moderator_id = moderator.connection_id;
+        let user_name = String::from(user_name);
+        self.request(|reply| RoomCommand::Mute {
+            moderator_id,
+            user_name,
+            duration,
+            reply,
+        })
+        .await
+    }
+
+    pub async fn unmute(
+        &self,
+        moderator: &Session,
+        user_name: &str,
+    ) -> Result<(), ModerationError> {
+        let moderator_id = moderator.connection_id;
+        let user_name = String::from(user_name);
+        self.request(|reply| RoomCommand::Unmute {
+            moderator_id,
+            user_name,
+            reply,
+        })
+        .await
+    }
+
+    /// Bans the address of the target and disconnects all of its sessions except for operators.
+    /// Returns the banned address.
+    pub async fn ban(
+        &self,
+        moderator: &Session,
+        target: &ModerationTarget,
+        duration: Option<Duration>,
+    ) -> Result<IpAddr, ModerationError> {
+        let moderator_id = moderator.connection_id;
+        let target = target.clone();
+        self.request(|reply| RoomCommand::Ban {
+            moderator_id,
+            target,
+            duration,
+            reply,
+        })
+        .await
+    }
+
+    pub async fn unban(
+        &self,
+        moderator: &Session,
+        target: &ModerationTarget,
+    ) -> Result<(), ModerationError> {
+        let moderator_id = moderator.connection_id;
+        let target = target.clone();
+        self.request(|reply| RoomCommand::Unban {
+            moderator_id,
+            target,
+            reply,
+        })
+        .await
+    }
+
+    pub async fn is_banned(&self, address: &IpAddr) -> bool {
+        let address = *address;
+        self.request(|reply| RoomCommand::IsBanned { address, reply })
+            .await
+    }
+}
+
+pub struct Session {
+    pub connection_id: i32,
+    pub user_name: String,
+    /// Room events, notices and disconnects for this session. It ends if the session fell behind
+    /// the room and was removed from it.
+    pub event_receiver: Receiver<SessionEvent>,
+    pub registry: SessionRegistry,
+}
+
+impl Session {
+    pub async fn broadcast_message(&self, message: String) {
+        self.registry
+            .send_command(RoomCommand::Message {
+                connection_id: self.connection_id,
+                message,
+            })
+            .await;
+    }
+
+    /// Resets the idle time of the session and ends its absence
+    pub async fn record_activity(&self) {
+        self.registry
+            .send_command(RoomCommand::Activity {
+                connection_id: self.connection_id,
+            })
+            .await;
+    }
+
+    /// Announces that the user of the session is away, until the next activity
+    pub async fn mark_away(&self) {
+        self.registry
+            .send_command(RoomCommand::Away {
+                connection_id: self.connection_id,
+            })
+            .await;
+    }
+
+    /// Keeps the name of the session up to date when the room renamed the session on its own,
+    /// e.g. to resolve a name collision with a linked server
+    pub fn follow_room_event(&mut self, event: &RoomEvent) {
+        if let RoomEvent::Renamed(old_user_name, new_user_name) = event {
+            if *old_user_name == self.user_name {
+                self.user_name = new_user_name.clone();
+            }
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        println!("[{}] User {} left", self.connection_id, self.user_name);
+        self.registry.send_command_later(RoomCommand::Leave {
+            connection_id: self.connection_id,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::Ipv4Addr;
+
+    use itertools::Itertools;
+
+    use super::*;
+    use crate::name_policy::NamePolicy;
+
+    const LOCALHOST: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    fn names(names: &[&str]) -> HashSet<String> {
+        names.iter().map(|name| String::from(*name)).collect()
+    }
+
+    async fn wait_until_empty(registry: &SessionRegistry) {
+        while !registry.participant_names().await.is_empty() {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    fn room_event(event: RoomEvent) -> Option<SessionEvent> {
+        Some(SessionEvent::Room(event))
+    }
+
+    /// All events that have been delivered to the session so far
+    fn drain_events(session: &mut Session) -> Vec<SessionEvent> {
+        let mut events = Vec::new();
+        while let Ok(event) = session.event_receiver.try_recv() {
+            events.push(event);
+        }
+        events
+    }
+
+    #[tokio::test]
+    async fn test_session_registry_join_and_leave() {
+        let registry = SessionRegistry::new(ChatServerConfig::default()).unwrap();
+
+        let (mut alice, alice_participants) = registry
+            .try_join(1, LOCALHOST, "alice", None)
+            .await
+            .unwrap();
+        assert_eq!(alice_participants, "-");
+        assert!(registry
+            .try_join(2, LOCALHOST, "alice", None)
+            .await
+            .is_err());
+
+        let (bob, bob_participants) = registry.try_join(2, LOCALHOST, "bob", None).await.unwrap();
+        assert_eq!(bob_participants, "alice");
+
+        drop(bob);
+        assert_eq!(
+            alice.event_receiver.recv().await,
+            room_event(RoomEvent::Joined(String::from("bob")))
+        );
+        assert_eq!(
+            alice.event_receiver.recv().await,
+            room_event(RoomEvent::Left(String::from("bob")))
+        );
+
+        // Sessions do not get their own events
+        let (mut bob, _) = registry.try_join(3, LOCALHOST, "bob", None).await.unwrap();
+        alice.broadcast_message(String::from("hi")).await;
+        assert_eq!(
+            bob.event_receiver.recv().await,
+            room_event(RoomEvent::Message(
+                String::from("alice"),
+                String::from("hi")
+            ))
+        );
+        drop(bob);
+        drop(alice);
+        assert!(registry.participant_names().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_busy_room() {
+        let registry = SessionRegistry::new(ChatServerConfig {
+            room_queue_capacity: 1,
+            ..ChatServerConfig::default()
+        })
+        .unwrap();
+        let (alice, _) = registry
+            .try_join(1, LOCALHOST, "alice", None)
+            .await
+            .unwrap();
+        let (mut bob, _) = registry.try_join(2, LOCALHOST, "bob", None).await.unwrap();
+
+        // Sessions wait for the room instead of losing their commands
+        for i in 0..100 {
+            alice.broadcast_message(format!("message {i}")).await;
+        }
+        for i in 0..100 {
+            assert_eq!(
+                bob.event_receiver.recv().await,
+                room_event(RoomEvent::Message(
+                    String::from("alice"),
+                    format!("message {i}")
+                ))
+            );
+        }
+
+        // Leaving cannot wait, but still reaches the room
+        let mut sessions = vec![alice, bob];
+        for connection_id in 3..13 {
+            let user_name = format!("user{connection_id}");
+            let (session, _) = registry
+                .try_join(connection_id, LOCALHOST, &user_name, None)
+                .await
+                .unwrap();
+            sessions.push(session);
+        }
+        drop(sessions);
+        wait_until_empty(&registry).await;
+    }
+
+    #[tokio::test]
+    async fn test_session_released_on_panic() {
+        let registry = SessionRegistry::new(ChatServerConfig::default()).unwrap();
+        let (mut observer, _) = registry
+            .try_join(1, LOCALHOST, "observer", None)
+            .await
+            .unwrap();
+
+        let task_registry = registry.clone();
+        let task_result = tokio::spawn(async move {
+            let _session = task_registry
+                .try_join(2, LOCALHOST, "alice", None)
+                .await
+                .unwrap();
+            panic!("Session task failed");
+        })
+        .await;
+
+        assert!(task_result.unwrap_err().is_panic());
+        assert_eq!(registry.participant_names().await, names(&["observer"]));
+        assert_eq!(
+            observer.event_receiver.recv().await,
+            room_event(RoomEvent::Joined(String::from("alice")))
+        );
+        assert_eq!(
+            observer.event_receiver.recv().await,
+            room_event(RoomEvent::Left(String::from("alice")))
+        );
+
+        // Name is free again
+        assert!(registry.try_join(3, LOCALHOST, "alice", None).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn test_session_rename() {
+        let registry = SessionRegistry::new(ChatServerConfig::default()).unwrap();
+
+        let (mut alice, _) = registry
+            .try_join(1, LOCALHOST, "alice", None)
+            .await
+            .unwrap();
+        let (mut bob, _) = registry.try_join(2, LOCALHOST, "bob", None).await.unwrap();
+
+        assert_eq!(
+            registry.try_rename(&mut alice, "bob").await,
+            Err(RenameError::NameTaken)
+        );
+        assert_eq!(
+            registry.try_rename(&mut alice, "al ice").await,
+            Err(RenameError::InvalidName)
+        );
+        assert_eq!(registry.try_rename(&mut alice, "carol").await, Ok(()));
+        assert_eq!(alice.user_name, "carol");
+        assert_eq!(registry.participant_names().await, names(&["carol", "bob"]));
+
+        // Messages carry the new name
+        alice.broadcast_message(String::from("hi")).await;
+
+        // Dropping the renamed session releases the new name
+        drop(alice);
+        assert_eq!(registry.participant_names().await, names(&["bob"]));
+
+        assert_eq!(
+            drain_events(&mut bob),
+            vec![
+                SessionEvent::Room(RoomEvent::Renamed(
+                    String::from("alice"),
+                    String::from("carol")
+                )),
+                SessionEvent::Room(RoomEvent::Message(
+                    String::from("carol"),
+                    String::from("hi")
+                )),
+                SessionEvent::Room(RoomEvent::Left(String::from("carol"))),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_name_policy() {
+        let registry = SessionRegistry::new(ChatServerConfig {
+            name_policy: NamePolicy {
+                case_insensitive: true,
+                ..NamePolicy::default()
+            },
+            ..ChatServerConfig::default()
+        })
+        .unwrap();
+
+        // Names are joined in NFC
+        let (mut zoe, _) = registry
+            .try_join(1, LOCALHOST, "Zoe\u{308}", None)
+            .await
+            .unwrap();
+        assert_eq!(zoe.user_name, "Zoë");
+        assert_eq!(
+            registry.try_join(2, LOCALHOST, "ZOË", None).await.err(),
+            Some(JoinError::NameTaken)
+        );
+        assert_eq!(
+            registry.try_join(2, LOCALHOST, "zo-e", None).await.err(),
+            Some(JoinError::InvalidName)
+        );
+
+        let (mut bob, _) = registry.try_join(2, LOCALHOST, "bob", None).await.unwrap();
+        assert_eq!(
+            registry.try_rename(&mut bob, "zoe\u{308}").await,
+            Err(RenameError::NameTaken)
+        );
+        assert_eq!(
+            registry.try_rename(&mut bob, "Ｂob").await,
+            Err(RenameError::InvalidName)
+        );
+        // Changing the case of the own name is fine
+        assert_eq!(registry.try_rename(&mut zoe, "ZOË").await, Ok(()));
+        assert_eq!(
+            registry.try_rename(&mut zoe, "ZOË").await,
+            Err(RenameError::NameTaken)
+        );
+        assert_eq!(registry.participant_names().await, names(&["ZOË", "bob"]));
+    }
+
+    #[tokio::test]
+    async fn test_registered_names() {
+        let credentials_file = std::env::temp_dir().join(format!(
+            "budgetchat-registered-names-{}.txt",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&credentials_file);
+        let registry = SessionRegistry::new(ChatServerConfig {
+            credentials_file: Some(credentials_file.clone()),
+            ..ChatServerConfig::default()
+        })
+        .unwrap();
+
+        let (alice, _) = registry
+            .try_join(1, LOCALHOST, "alice", None)
+            .await
+            .unwrap();
+        assert_eq!(registry.register(&alice, "secret").await, Ok(()));
+        assert!(registry.is_registered("alice").await);
+        drop(alice);
+        wait_until_empty(&registry).await;
+
+        assert_eq!(
+            registry.try_join(2, LOCALHOST, "alice", None).await.err(),
+            Some(JoinError::WrongPassword)
+        );
+        assert_eq!(
+            registry
+                .try_join(2, LOCALHOST, "alice", Some("guess"))
+                .await
+                .err(),
+            Some(JoinError::WrongPassword)
+        );
+        let (mut bob, _) = registry
+            .try_join(3, LOCALHOST, "bob", Some("any"))
+            .await
+            .unwrap();
+        assert_eq!(
+            registry.try_rename(&mut bob, "alice").await,
+            Err(RenameError::NameRegistered)
+        );
+        let (alice, _) = registry
+            .try_join(4, LOCALHOST, "alice", Some("secret"))
+            .await
+            .unwrap();
+        drop(alice);
+        drop(bob);
+
+        // Registrations survive a restart
+        wait_until_empty(&registry).await;
+        let registry = SessionRegistry::new(ChatServerConfig {
+            credentials_file: Some(credentials_file.clone()),
+            ..ChatServerConfig::default()
+        })
+        .unwrap();
+        assert!(registry.is_registered("alice").await);
+        assert!(!registry.is_registered("bob").await);
+
+        std::fs::remove_file(&credentials_file).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_unreadable_credentials() {
+        let error = SessionRegistry::new(ChatServerConfig {
+            credentials_file: Some(std::env::temp_dir()),
+            ..ChatServerConfig::default()
+        })
+        .err()
+        .unwrap();
+        assert!(error
+            .to_string()
+            .starts_with("Failed to read credentials file: "));
+    }
+
+    #[tokio::test]
+    async fn test_registration_disabled() {
+        let registry = SessionRegistry::new(ChatServerConfig::default()).unwrap();
+        let (alice, _) = registry
+            .try_join(1, LOCALHOST, "alice", None)
+            .await
+            .unwrap();
+        assert_eq!(
+            registry.register(&alice, "secret").await,
+            Err(RegistrationError::Disabled)
+        );
+        assert!(!registry.is_registered("alice").await);
+    }
+
+    #[tokio::test]
+    async fn test_registration_not_saved() {
+        let credentials_file = std::env::temp_dir()
+            .join(format!("budgetchat-missing-{}", std::process::id()))
+            .join("credentials.txt");
+        let registry = SessionRegistry::new(ChatServerConfig {
+            credentials_file: Some(credentials_file),
+            ..ChatServerConfig::default()
+        })
+        .unwrap();
+        let (alice, _) = registry
+            .try_join(1, LOCALHOST, "alice", None)
+            .await
+            .unwrap();
+        assert_eq!(
+            registry.register(&alice, "secret").await,
+            Err(RegistrationError::NotSaved)
+        );
+        assert!(!registry.is_registered("alice").await);
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_concurrent_renames_to_same_name() {
+        let registry = SessionRegistry::new(ChatServerConfig::default()).unwrap();
+
+        let mut rename_tasks = Vec::new();
+        for i in 0..16 {
+            let (mut session, _) = registry
+                .try_join(i, LOCALHOST, &format!("user{i}"), None)
+                .await
+                .unwrap();
+            let registry = registry.clone();
+            rename_tasks.push(tokio::spawn(async move {
+                let rename_result = registry.try_rename(&mut session, "winner").await;
+                (rename_result, session)
+            }));
+        }
+        let mut rename_results = Vec::new();
+        for rename_task in rename_tasks {
+            rename_results.push(rename_task.await.unwrap());
+        }
+
+        let winners = rename_results
+            .iter()
+            .filter(|(rename_result, _)| rename_result.is_ok())
+            .collect_vec();
+        assert_eq!(winners.len(), 1);
+        assert_eq!(winners[0].1.user_name, "winner");
+
+        let participant_names = registry.participant_names().await;
+        assert_eq!(participant_names.len(), 16);
+        assert!(participant_names.contains("winner"));
+        for (_, session) in &rename_results {
+            assert!(participant_names.contains(&session.user_name));
+        }
+    }
+
+    #[tokio::test]
+    async fn test_operator_role() {
+        let config = ChatServerConfig {
+            operator_password: Some(String::from("secret")),
+            operator_names: names(&["alice"]),
+            ..ChatServerConfig::default()
+        };
+        let registry = SessionRegistry::new(config).unwrap();
+
+        let (alice, _) = registry
+            .try_join(1, LOCALHOST, "alice", None)
+            .await
+            .unwrap();
+        let (mut bob, _) = registry.try_join(2, LOCALHOST, "bob", None).await.unwrap();
+        let (mut carol, _) = registry
+            .try_join(3, LOCALHOST, "carol", None)
+            .await
+            .unwrap();
+
+        // Allowlisted operator
+        assert_eq!(registry.kick(&alice, "carol").await, Ok(()));
+
+        // Renaming to an allowlisted name does not grant operator rights
+        assert_eq!(
+            registry.kick(&carol, "bob").await,
+            Err(ModerationError::NotAnOperator)
+        );
+        drop(alice);
+        registry.try_rename(&mut bob, "alice").await.unwrap();
+        assert_eq!(
+            registry.kick(&bob, "carol").await,
+            Err(ModerationError::NotAnOperator)
+        );
+
+        // Password operator
+        assert!(!registry.try_become_operator(&carol, "guess").await);
+        assert!(registry.try_become_operator(&carol, "secret").await);
+        assert_eq!(registry.kick(&carol, "alice").await, Ok(()));
+        assert_eq!(
+            registry.kick(&carol, "dave").await,
+            Err(ModerationError::UnknownUser(String::from("dave")))
+        );
+
+        // Operator rights belong to the connection and are kept across renames
+        registry.try_rename(&mut carol, "caroline").await.unwrap();
+        assert_eq!(registry.kick(&carol, "alice").await, Ok(()));
+    }
+
+    #[tokio::test]
+    async fn test_operator_names_with_registration() {
+        let credentials_file = std::env::temp_dir().join(format!(
+            "budgetchat-operator-names-{}.txt",
+            std::process::id()
+        ));
+        let _ = std::fs::remove_file(&credentials_file);
+        let registry = SessionRegistry::new(ChatServerConfig {
+            operator_names: names(&["alice"]),
+            credentials_file: Some(credentials_file.clone()),
+            ..ChatServerConfig::default()
+        })
+        .unwrap();
+        let (_bob, _) = registry.try_join(1, LOCALHOST, "bob", None).await.unwrap();
+
+        // Claiming the name is not enough, not even registering it afterwards
+        let (alice, _) = registry
+            .try_join(2, LOCALHOST, "alice", None)
+            .await
+            .unwrap();
+        assert_eq!(
+            registry.kick(&alice, "bob").await,
+            Err(ModerationError::NotAnOperator)
+        );
+        registry.register(&alice, "secret").await.unwrap();
+        assert_eq!(
+            registry.kick(&alice, "bob").await,
+            Err(ModerationError::NotAnOperator)
+        );
+        drop(alice);
+        while registry.participant_names().await != names(&["bob"]) {
+            tokio::task::yield_now().await;
+        }
+
+        let (alice, _) = registry
+            .try_join(3, LOCALHOST, "alice", Some("secret"))
+            .await
+            .unwrap();
+        assert_eq!(registry.kick(&alice, "bob").await, Ok(()));
+
+        std::fs::remove_file(&credentials_file).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_operator_password_guessing() {
+        let config = ChatServerConfig {
+            operator_password: Some(String::from("secret")),
+            ..ChatServerConfig::default()
+        };
+        let registry = SessionRegistry::new(config).unwrap();
+        let guesser_address = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        let too_many_guesses =
+            SessionEvent::Disconnect(String::from("* Too many invalid operator passwords"));
+
+        // Each connection is disconnected after a few guesses
+        for connection_id in 1..=3 {
+            let (mut guesser, _) = registry
+                .try_join(connection_id, guesser_address, "guesser", None)
+                .await
+                .unwrap();
+            for _ in 0..3 {
+                assert!(!registry.try_become_operator(&guesser, "guess").await);
+            }
+            assert!(drain_events(&mut guesser).contains(&too_many_guesses));
+        }
+        assert!(!registry.is_banned(&guesser_address).await);
+
+        // The address is banned after further guesses over new connections
+        let (mut guesser, _) = registry
+            .try_join(4, guesser_address, "guesser", None)
+            .await
+            .unwrap();
+        assert!(!registry.try_become_operator(&guesser, "guess").await);
+        assert!(drain_events(&mut guesser).contains(&too_many_guesses));
+        assert!(registry.is_banned(&guesser_address).await);
+        assert!(!registry.is_banned(&LOCALHOST).await);
+
+        // The right password is accepted from other addresses
+        let (alice, _) = registry
+            .try_join(5, LOCALHOST, "alice", None)
+            .await
+            .unwrap();
+        assert!(!registry.try_become_operator(&alice, "guess").await);
+        assert!(registry.try_become_operator(&alice, "secret").await);
+    }
+
+    #[tokio::test]
+    async fn test_moderation_actions() {
+        let config = ChatServerConfig {
+            operator_names: names(&["alice"]),
+            ..ChatServerConfig::default()
+        };
+        let registry = SessionRegistry::new(config).unwrap();
+        let bob_address = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+
+        let (mut alice, _) = registry
+            .try_join(1, LOCALHOST, "alice", None)
+            .await
+            .unwrap();
+        let (mut bob, _) = registry
+            .try_join(2, bob_address, "bob", None)
+            .await
+            .unwrap();
+        let (mut carol, _) = registry
+            .try_join(3, LOCALHOST, "carol", None)
+            .await
+            .unwrap();
+        drain_events(&mut alice);
+        drain_events(&mut bob);
+
+        // Kick
+        registry.kick(&alice, "bob").await.unwrap();
+        assert_eq!(
+            drain_events(&mut bob),
+            vec![SessionEvent::Disconnect(String::from(
+                "* You have been kicked by alice"
+            ))]
+        );
+
+        // Mute
+        registry.mute(&alice, "carol", None).await.unwrap();
+        assert!(registry.is_muted(&carol).await);
+        assert!(!registry.is_muted(&alice).await);
+        registry.unmute(&alice, "carol").await.unwrap();
+        assert!(!registry.is_muted(&carol).await);
+        registry
+            .mute(&alice, "carol", Some(Duration::ZERO))
+            .await
+            .unwrap();
+        assert!(!registry.is_muted(&carol).await);
+        assert_eq!(
+            carol.event_receiver.try_recv(),
+            Ok(SessionEvent::Message(String::from(
+                "* You have been muted by alice"
+            )))
+        );
+
+        // Ban by name bans the address
+        assert_eq!(
+            registry
+                .ban(
+                    &alice,
+                    &ModerationTarget::UserName(String::from("bob")),
+                    None
+                )
+                .await,
+            Ok(bob_address)
+        );
+        assert!(registry.is_banned(&bob_address).await);
+        assert!(!registry.is_banned(&LOCALHOST).await);
+        assert_eq!(
+            drain_events(&mut bob),
+            vec![SessionEvent::Disconnect(String::from(
+                "* You have been banned by alice"
+            ))]
+        );
+        assert_eq!(
+            registry
+                .unban(&alice, &ModerationTarget::UserName(String::from("bob")))
+                .await,
+            Ok(())
+        );
+        assert!(!registry.is_banned(&bob_address).await);
+        assert_eq!(
+            registry
+                .unban(&alice, &ModerationTarget::Address(bob_address))
+                .await,
+            Err(ModerationError::NotBanned)
+        );
+
+        // Banning an address spares operators
+        registry
+            .ban(&alice, &ModerationTarget::Address(LOCALHOST), None)
+            .await
+            .unwrap();
+        assert!(matches!(
+            drain_events(&mut carol).last(),
+            Some(SessionEvent::Disconnect(_))
+        ));
+        assert!(alice.event_receiver.try_recv().is_err());
+        assert!(registry.is_banned(&LOCALHOST).await);
+    }
+
+    #[tokio::test]
+    async fn test_ban_by_name_variant() {
+        let registry = SessionRegistry::new(ChatServerConfig {
+            operator_names: names(&["alice"]),
+            name_policy: NamePolicy {
+                case_insensitive: true,
+                ..NamePolicy::default()
+            },
+            ..ChatServerConfig::default()
+        })
+        .unwrap();
+        let zoe_address = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+
+        let (alice, _) = registry
+            .try_join(1, LOCALHOST, "alice", None)
+            .await
+            .unwrap();
+        let (_zoe, _) = registry
+            .try_join(2, zoe_address, "Zoë", None)
+            .await
+            .unwrap();
+
+        // Bans are kept under the name key, so other forms of the name match them
+        registry
+            .ban(
+                &alice,
+                &ModerationTarget::UserName(String::from("ZOË")),
+                None,
+            )
+            .await
+            .unwrap();
+        assert!(registry.is_banned(&zoe_address).await);
+        assert_eq!(
+            registry
+                .unban(
+                    &alice,
+                    &ModerationTarget::UserName(String::from("zoe\u{308}"))
+                )
+                .await,
+            Ok(())
+        );
+        assert!(!registry.is_banned(&zoe_address).await);
+    }
+
+    #[tokio::test]
+    async fn test_slow_session_is_removed() {
+        let config = ChatServerConfig {
+            session_queue_capacity: 4,
+            ..ChatServerConfig::default()
+        };
+        let registry = SessionRegistry::new(config).unwrap();
+
+        let (alice, _) = registry
+            .try_join(1, LOCALHOST, "alice", None)
+            .await
+            .unwrap();
+        let (mut slowpoke, _) = registry
+            .try_join(2, LOCALHOST, "slowpoke", None)
+            .await
+            .unwrap();
+        let (mut bob, _) = registry.try_join(3, LOCALHOST, "bob", None).await.unwrap();
+
+        for i in 0..3 {
+            alice.broadcast_message(format!("message {i}")).await;
+        }
+        assert_eq!(
+            registry.participant_names().await,
+            names(&["alice", "slowpoke", "bob"])
+        );
+        assert_eq!(drain_events(&mut bob).len(), 3);
+
+        // Slowpoke's queue is full with the join of bob and the messages
+        alice.broadcast_message(String::from("message 3")).await;
+        assert_eq!(registry.participant_names().await, names(&["alice", "bob"]));
+
+        // Bob keeps up and learns about the departure, the slow session ends after its backlog
+        assert_eq!(
+            drain_events(&mut bob),
+            vec![
+                SessionEvent::Room(RoomEvent::Message(
+                    String::from("alice"),
+                    String::from("message 3")
+                )),
+                SessionEvent::Room(RoomEvent::Left(String::from("slowpoke"))),
+            ]
+        );
+        assert_eq!(drain_events(&mut slowpoke).len(), 4);
+        assert_eq!(slowpoke.event_receiver.recv().await, None);
+
+        // Leaving afterwards is not announced again
+        drop(slowpoke);
+        alice.broadcast_message(String::from("done")).await;
+        assert_eq!(
+            bob.event_receiver.recv().await,
+            room_event(RoomEvent::Message(
+                String::from("alice"),
+                String::from("done")
+            ))
+        );
+    }
+}
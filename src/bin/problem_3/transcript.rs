@@ -0,0 +1,430 @@
+use std::collections::VecDeque;
+use std::fs::{self, File, OpenOptions};
+use std::io::{BufRead, BufReader, Result as IO_Result, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+
+use crate::event::RoomEvent;
+use crate::file_writer::FileWriter;
+
+const TRANSCRIPT_FILE_NAME: &str = "transcript.jsonl";
+/// Searches only return the most recent matches, so that a reply cannot flood the user
+const MAX_SEARCH_RESULTS: usize = 20;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct TranscriptConfig {
+    /// Directory of the transcript files, the transcript is only kept in memory without it
+    pub directory: Option<PathBuf>,
+    /// The transcript file is rotated once it is larger than this
+    pub max_file_bytes: u64,
+    /// The transcript file is rotated once its first entry is older than this
+    pub max_file_age: Duration,
+    /// Number of recent entries `/search` looks at
+    pub search_history: usize,
+}
+
+impl Default for TranscriptConfig {
+    fn default() -> Self {
+        TranscriptConfig {
+            directory: None,
+            max_file_bytes: 10 * 1024 * 1024,
+            max_file_age: Duration::from_secs(24 * 60 * 60),
+            search_history: 1000,
+        }
+    }
+}
+
+/// One line of the transcript file
+#[derive(Clone, Debug, PartialEq, Serialize, Deserialize)]
+pub struct TranscriptEntry {
+    /// Seconds since the Unix epoch
+    pub timestamp: u64,
+    pub event: RoomEvent,
+}
+
+impl TranscriptEntry {
+    /// Budget chat line of the entry, prefixed with its UTC time
+    pub fn to_line(&self) -> String {
+        format!("* {} {}", format_timestamp(self.timestamp), self.event)
+    }
+}
+
+struct TranscriptFile {
+    directory: PathBuf,
+    file: File,
+    size: u64,
+    first_timestamp: Option<u64>,
+}
+
+impl TranscriptFile {
+    fn current_path(directory: &Path) -> PathBuf {
+        directory.join(TRANSCRIPT_FILE_NAME)
+    }
+
+    fn open(directory: &Path) -> IO_Result<Self> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(Self::current_path(directory))?;
+        Ok(TranscriptFile {
+            directory: directory.to_path_buf(),
+            size: file.metadata()?.len(),
+            file,
+            first_timestamp: None,
+        })
+    }
+
+    fn needs_rotation(&self, config: &TranscriptConfig, timestamp: u64) -> bool {
+        let Some(first_timestamp) = self.first_timestamp else {
+            return false;
+        };
+        self.size >= config.max_file_bytes
+            || timestamp.saturating_sub(first_timestamp) >= config.max_file_age.as_secs()
+    }
+
+    /// Moves the current file aside as `transcript-<first timestamp>.jsonl` and starts a new one
+    fn rotate(&mut self) -> IO_Result<()> {
+        let first_timestamp = self.first_timestamp.unwrap_or_default();
+        let mut rotated_path = self
+            .directory
+            .join(format!("transcript-{first_timestamp}.jsonl"));
+        let mut suffix = 1;
+        while rotated_path.exists() {
+            rotated_path = self
+                .directory
+                .join(format!("transcript-{first_timestamp}-{suffix}.jsonl"));
+            suffix += 1;
+        }
+
+        fs::rename(Self::current_path(&self.directory), rotated_path)?;
+        *self = Self::open(&self.directory)?;
+        Ok(())
+    }
+
+    /// Appends the entry, rotating the file first if necessary
+    fn write(&mut self, config: &TranscriptConfig, entry: &TranscriptEntry) -> IO_Result<()> {
+        if self.needs_rotation(config, entry.timestamp) {
+            self.rotate()?;
+        }
+        self.append(entry)
+    }
+
+    fn append(&mut self, entry: &TranscriptEntry) -> IO_Result<()> {
+        let mut line = serde_json::to_string(entry)?;
+        line.push('\n');
+        self.file.write_all(line.as_bytes())?;
+        self.file.flush()?;
+        self.size += line.len() as u64;
+        self.first_timestamp.get_or_insert(entry.timestamp);
+        Ok(())
+    }
+}
+
+/// Record of everything that happened in the room. Recent entries are kept in memory for
+/// searching, all entries are appended to a JSON lines file by a [`FileWriter`] if a directory
+/// is configured.
+pub struct Transcript {
+    config: TranscriptConfig,
+    history: VecDeque<TranscriptEntry>,
+    file_writer: Option<FileWriter<TranscriptEntry>>,
+}
+
+impl Transcript {
+    pub fn in_memory(config: TranscriptConfig) -> Self {
+        Transcript {
+            config,
+            history: VecDeque::new(),
+            file_writer: None,
+        }
+    }
+
+    /// Continues the transcript file in the configured directory, so that the history of the
+    /// previous run can still be searched
+    pub fn open(config: TranscriptConfig) -> IO_Result<Self> {
+        let mut transcript = Self::in_memory(config);
+        let Some(directory) = transcript.config.directory.clone() else {
+            return Ok(transcript);
+        };
+
+        fs::create_dir_all(&directory)?;
+        let mut transcript_file = TranscriptFile::open(&directory)?;
+        let previous_file = File::open(TranscriptFile::current_path(&directory))?;
+        for line in BufReader::new(previous_file).lines() {
+            // Skip lines that could not be written completely
+            let Ok(entry) = serde_json::from_str::<TranscriptEntry>(&line?) else {
+                continue;
+            };
+            transcript_file
+                .first_timestamp
+                .get_or_insert(entry.timestamp);
+            transcript.remember(entry);
+        }
+
+        let config = transcript.config.clone();
+        let file_writer = FileWriter::start("transcript", move |entry: TranscriptEntry| {
+            if let Err(e) = transcript_file.write(&config, &entry) {
+                // A full disk must not take the chat down with it
+                println!("Failed to write transcript: {e}");
+            }
+        });
+        transcript.file_writer = Some(file_writer);
+        Ok(transcript)
+    }
+
+    fn remember(&mut self, entry: TranscriptEntry) {
+        if self.history.len() >= self.config.search_history {
+            self.history.pop_front();
+        }
+        if self.config.search_history > 0 {
+            self.history.push_back(entry);
+        }
+    }
+
+    pub fn record(&mut self, event: RoomEvent, timestamp: u64) {
+        let entry = TranscriptEntry { timestamp, event };
+
+        if let Some(file_writer) = &self.file_writer {
+            if !file_writer.write(entry.clone()) {
+                println!("Failed to write transcript: Too many entries waiting for the disk");
+            }
+        }
+
+        self.remember(entry);
+    }
+
+    /// Most recent entries containing the term, ignoring case, oldest first
+    pub fn search(&self, term: &str) -> Vec<&TranscriptEntry> {
+        let term = term.to_lowercase();
+        let mut matches: Vec<&TranscriptEntry> = self
+            .history
+            .iter()
+            .rev()
+            .filter(|entry| entry.event.to_string().to_lowercase().contains(&term))
+            .take(MAX_SEARCH_RESULTS)
+            .collect();
+        matches.reverse();
+        matches
+    }
+}
+
+pub fn unix_timestamp(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .map(|duration| duration.as_secs())
+        .unwrap_or_default()
+}
+
+/// Formats seconds since the Unix epoch as `YYYY-MM-DD HH:MM:SS`
+pub fn format_timestamp(timestamp: u64) -> String {
+    let days = (timestamp / 86400) as i64;
+    let seconds_of_day = timestamp % 86400;
+
+    // Civil date from days since the epoch, see http://howardhinnant.github.io/date_algorithms.html
+    let shifted_days = days + 719468;
+    let era = shifted_days.div_euclid(146097);
+    let day_of_era = shifted_days.rem_euclid(146097);
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let shifted_month = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * shifted_month + 2) / 5 + 1;
+    let month = if shifted_month < 10 {
+        shifted_month + 3
+    } else {
+        shifted_month - 9
+    };
+    let year = year_of_era + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{year:04}-{month:02}-{day:02} {:02}:{:02}:{:02}",
+        seconds_of_day / 3600,
+        seconds_of_day / 60 % 60,
+        seconds_of_day % 60
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(user_name: &str, message: &str) -> RoomEvent {
+        RoomEvent::Message(String::from(user_name), String::from(message))
+    }
+
+    fn temporary_directory(name: &str) -> PathBuf {
+        let directory = std::env::temp_dir().join(format!(
+            "budgetchat-transcript-{name}-{}",
+            std::process::id()
+        ));
+        let _ = fs::remove_dir_all(&directory);
+        directory
+    }
+
+    fn file_config(directory: &Path) -> TranscriptConfig {
+        TranscriptConfig {
+            directory: Some(directory.to_path_buf()),
+            ..TranscriptConfig::default()
+        }
+    }
+
+    fn transcript_files(directory: &Path) -> Vec<String> {
+        let mut file_names: Vec<String> = fs::read_dir(directory)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect();
+        file_names.sort();
+        file_names
+    }
+
+    #[test]
+    fn test_timestamp_formatting() {
+        assert_eq!(format_timestamp(0), "1970-01-01 00:00:00");
+        assert_eq!(format_timestamp(951782400), "2000-02-29 00:00:00");
+        assert_eq!(format_timestamp(1700000000), "2023-11-14 22:13:20");
+    }
+
+    #[test]
+    fn test_entry_lines() {
+        let entry = TranscriptEntry {
+            timestamp: 1700000000,
+            event: message("alice", "hi"),
+        };
+        assert_eq!(entry.to_line(), "* 2023-11-14 22:13:20 [alice] hi");
+        assert_eq!(
+            serde_json::to_string(&entry).unwrap(),
+            r#"{"timestamp":1700000000,"event":{"message":["alice","hi"]}}"#
+        );
+    }
+
+    #[test]
+    fn test_search() {
+        let mut transcript = Transcript::in_memory(TranscriptConfig {
+            search_history: 3,
+            ..TranscriptConfig::default()
+        });
+        transcript.record(message("alice", "Hello there"), 1);
+        transcript.record(RoomEvent::Joined(String::from("bob")), 2);
+        transcript.record(message("bob", "hello"), 3);
+        transcript.record(message("alice", "bye"), 4);
+
+        let matches = |term| {
+            transcript
+                .search(term)
+                .iter()
+                .map(|entry| entry.event.clone())
+                .collect::<Vec<_>>()
+        };
+        // Oldest entry fell out of the history
+        assert_eq!(matches("HELLO"), vec![message("bob", "hello")]);
+        assert_eq!(
+            matches("bob"),
+            vec![
+                RoomEvent::Joined(String::from("bob")),
+                message("bob", "hello")
+            ]
+        );
+        assert_eq!(matches("nothing"), vec![]);
+
+        for timestamp in 0..30 {
+            transcript.record(message("bob", "spam"), timestamp);
+        }
+        assert_eq!(transcript.search("spam").len(), 3);
+    }
+
+    #[test]
+    fn test_transcript_survives_restart() {
+        let directory = temporary_directory("restart");
+
+        let mut transcript = Transcript::open(file_config(&directory)).unwrap();
+        transcript.record(RoomEvent::Joined(String::from("alice")), 1);
+        transcript.record(message("alice", "remember me"), 2);
+        drop(transcript);
+
+        // Partially written line of a crashed server
+        let mut transcript_file = OpenOptions::new()
+            .append(true)
+            .open(directory.join(TRANSCRIPT_FILE_NAME))
+            .unwrap();
+        transcript_file.write_all(b"{\"timest").unwrap();
+        drop(transcript_file);
+
+        let transcript = Transcript::open(file_config(&directory)).unwrap();
+        assert_eq!(
+            transcript.search("remember")[0].event,
+            message("alice", "remember me")
+        );
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_size_rotation() {
+        let directory = temporary_directory("size");
+        let mut transcript = Transcript::open(TranscriptConfig {
+            max_file_bytes: 100,
+            ..file_config(&directory)
+        })
+        .unwrap();
+
+        transcript.record(message("alice", "first message of the transcript"), 10);
+        transcript.record(message("alice", "second message of the transcript"), 11);
+        // Dropping waits for the writes
+        drop(transcript);
+        assert_eq!(transcript_files(&directory), vec!["transcript.jsonl"]);
+
+        let mut transcript = Transcript::open(TranscriptConfig {
+            max_file_bytes: 100,
+            ..file_config(&directory)
+        })
+        .unwrap();
+        transcript.record(message("alice", "third"), 12);
+        // Rotated entries can still be searched
+        assert_eq!(transcript.search("message").len(), 2);
+        drop(transcript);
+        assert_eq!(
+            transcript_files(&directory),
+            vec!["transcript-10.jsonl", "transcript.jsonl"]
+        );
+        let current_file = fs::read_to_string(directory.join(TRANSCRIPT_FILE_NAME)).unwrap();
+        assert_eq!(current_file.lines().count(), 1);
+        assert!(current_file.contains("third"));
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_time_rotation() {
+        let directory = temporary_directory("time");
+        let mut transcript = Transcript::open(TranscriptConfig {
+            max_file_age: Duration::from_secs(60),
+            ..file_config(&directory)
+        })
+        .unwrap();
+
+        transcript.record(message("alice", "a"), 100);
+        transcript.record(message("alice", "b"), 159);
+        drop(transcript);
+        assert_eq!(transcript_files(&directory), vec!["transcript.jsonl"]);
+
+        let mut transcript = Transcript::open(TranscriptConfig {
+            max_file_age: Duration::from_secs(60),
+            ..file_config(&directory)
+        })
+        .unwrap();
+        transcript.record(message("alice", "c"), 160);
+        transcript.record(message("alice", "d"), 219);
+        transcript.record(message("alice", "e"), 220);
+        drop(transcript);
+        assert_eq!(
+            transcript_files(&directory),
+            vec![
+                "transcript-100.jsonl",
+                "transcript-160.jsonl",
+                "transcript.jsonl"
+            ]
+        );
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,206 @@
+use std::io::{Error as IO_Error, ErrorKind};
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use bytes::BytesMut;
+use futures::{future, stream, Sink, SinkExt, Stream, StreamExt, TryStreamExt};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::{Error as WebSocketError, Message};
+use tokio_tungstenite::WebSocketStream;
+use tokio_util::codec::{Decoder, FramedRead, FramedWrite, LinesCodec, LinesCodecError};
+
+/// Line received from a client
+#[derive(Debug, PartialEq)]
+pub enum ClientLine {
+    Line(String),
+    /// Line longer than the maximum line length, which was skipped up to the next newline
+    TooLong,
+}
+
+/// Incoming chat lines of a client, independent of how the client is connected
+pub type LineReader = Pin<Box<dyn Stream<Item = Result<ClientLine, IO_Error>> + Send>>;
+/// Outgoing chat lines to a client, independent of how the client is connected
+pub type LineWriter = Pin<Box<dyn Sink<String, Error = IO_Error> + Send>>;
+
+/// Newline delimited lines of a limited length, so that a client cannot make the server buffer
+/// an endless line
+pub struct ClientLinesCodec(LinesCodec);
+
+impl ClientLinesCodec {
+    pub fn new(max_line_length: usize) -> Self {
+        ClientLinesCodec(LinesCodec::new_with_max_length(max_line_length))
+    }
+}
+
+impl Decoder for ClientLinesCodec {
+    type Item = ClientLine;
+    type Error = LinesCodecError;
+
+    fn decode(&mut self, buffer: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        to_client_line(self.0.decode(buffer))
+    }
+
+    fn decode_eof(&mut self, buffer: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        to_client_line(self.0.decode_eof(buffer))
+    }
+}
+
+/// Too long lines are not an error of the connection, the codec discards them by itself
+fn to_client_line(
+    line: Result<Option<String>, LinesCodecError>,
+) -> Result<Option<ClientLine>, LinesCodecError> {
+    match line {
+        Ok(line) => Ok(line.map(ClientLine::Line)),
+        Err(LinesCodecError::MaxLineLengthExceeded) => Ok(Some(ClientLine::TooLong)),
+        Err(e) => Err(e),
+    }
+}
+
+/// Newline delimited lines over a plain TCP connection
+pub fn tcp_lines(tcp_stream: TcpStream, max_line_length: usize) -> (LineReader, LineWriter) {
+    let (tcp_socket_reader, tcp_socket_writer) = tcp_stream.into_split();
+    let line_reader = FramedRead::new(tcp_socket_reader, ClientLinesCodec::new(max_line_length))
+        .map_err(to_io_error);
+    let line_writer = IoErrorSink::new(
+        FramedWrite::new(tcp_socket_writer, LinesCodec::new()),
+        to_io_error,
+    );
+    (Box::pin(line_reader), Box::pin(line_writer))
+}
+
+/// One text message per line over a WebSocket connection. Text messages containing several lines
+/// are split up, so that WebSocket users cannot send anything TCP users could not send.
+pub fn websocket_lines<S>(
+    websocket_stream: WebSocketStream<S>,
+    max_line_length: usize,
+) -> (LineReader, LineWriter)
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (websocket_sink, websocket_stream) = websocket_stream.split();
+
+    let line_reader = websocket_stream
+        .map_err(websocket_to_io_error)
+        .try_filter_map(move |message| {
+            future::ready(Ok(match message {
+                Message::Text(text) => {
+                    let text = text.strip_suffix('\n').unwrap_or(&text);
+                    let lines = text
+                        .split('\n')
+                        .map(|line| line.strip_suffix('\r').unwrap_or(line))
+                        .map(|line| {
+                            Ok(if line.len() > max_line_length {
+                                ClientLine::TooLong
+                            } else {
+                                ClientLine::Line(String::from(line))
+                            })
+                        })
+                        .collect::<Vec<_>>();
+                    Some(stream::iter(lines))
+                }
+                // Pings are answered by tungstenite, close frames end the stream
+                _ => None,
+            }))
+        })
+        .try_flatten();
+    let line_writer = IoErrorSink::new(websocket_sink, websocket_to_io_error)
+        .with(|line: String| future::ready(Ok::<_, IO_Error>(Message::Text(line))));
+
+    (Box::pin(line_reader), Box::pin(line_writer))
+}
+
+/// Converts the errors of a sink into IO errors. Unlike `SinkExt::sink_map_err`, it can still be
+/// closed after a failed write, which happens whenever a client drops the connection.
+struct IoErrorSink<S, F> {
+    sink: S,
+    to_io_error: F,
+}
+
+impl<S, F> IoErrorSink<S, F> {
+    fn new(sink: S, to_io_error: F) -> Self {
+        IoErrorSink { sink, to_io_error }
+    }
+
+    fn map_poll<E>(&self, poll: Poll<Result<(), E>>) -> Poll<Result<(), IO_Error>>
+    where
+        F: Fn(E) -> IO_Error,
+    {
+        poll.map_err(&self.to_io_error)
+    }
+}
+
+impl<S, F, Item> Sink<Item> for IoErrorSink<S, F>
+where
+    S: Sink<Item> + Unpin,
+    F: Fn(S::Error) -> IO_Error + Unpin,
+{
+    type Error = IO_Error;
+
+    fn poll_ready(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), IO_Error>> {
+        let poll = Pin::new(&mut self.sink).poll_ready(cx);
+        self.map_poll(poll)
+    }
+
+    fn start_send(mut self: Pin<&mut Self>, item: Item) -> Result<(), IO_Error> {
+        Pin::new(&mut self.sink)
+            .start_send(item)
+            .map_err(&self.to_io_error)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), IO_Error>> {
+        let poll = Pin::new(&mut self.sink).poll_flush(cx);
+        self.map_poll(poll)
+    }
+
+    fn poll_close(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<(), IO_Error>> {
+        let poll = Pin::new(&mut self.sink).poll_close(cx);
+        self.map_poll(poll)
+    }
+}
+
+pub fn to_io_error(error: LinesCodecError) -> IO_Error {
+    match error {
+        LinesCodecError::Io(io_error) => io_error,
+        LinesCodecError::MaxLineLengthExceeded => {
+            IO_Error::new(ErrorKind::InvalidData, "Maximum line length exceeded")
+        }
+    }
+}
+
+pub fn websocket_to_io_error(error: WebSocketError) -> IO_Error {
+    match error {
+        WebSocketError::Io(io_error) => io_error,
+        websocket_error => IO_Error::other(websocket_error),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn test_close_after_failed_write() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let client = TcpStream::connect(listener.local_addr().unwrap())
+            .await
+            .unwrap();
+        let (server_stream, _) = listener.accept().await.unwrap();
+        drop(client);
+
+        let (_, mut line_writer) = tcp_lines(server_stream, 1000);
+        let mut write_result = Ok(());
+        for _ in 0..100 {
+            write_result = line_writer.send(String::from("hello")).await;
+            if write_result.is_err() {
+                break;
+            }
+            tokio::task::yield_now().await;
+        }
+        assert!(write_result.is_err());
+        // The writer is closed after the connection is gone
+        let _ = line_writer.close().await;
+    }
+}
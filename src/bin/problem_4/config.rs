@@ -0,0 +1,240 @@
+use std::collections::HashSet;
+use std::env;
+use std::fmt::Display;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::query::Extension;
+use crate::store::StoreConfig;
+use crate::wal::SyncPolicy;
+
+/// Optional server settings, read from `UNUSUALDB_*` environment variables
+#[derive(Clone, Debug)]
+pub struct DatabaseConfig {
+    /// Kind of map that holds the entries
+    pub store: StoreConfig,
+    /// Write-ahead log and snapshots of the inserts
+    pub persistence: PersistenceConfig,
+    /// Commands beyond the spec that clients may use
+    pub extensions: HashSet<Extension>,
+    /// Port for clients that send newline delimited requests over TCP
+    pub tcp_port: Option<u16>,
+    pub replication: ReplicationConfig,
+    pub limits: LimitsConfig,
+    /// Every request and response is logged, which slows down busy servers
+    pub log_requests: bool,
+}
+
+impl Default for DatabaseConfig {
+    fn default() -> Self {
+        DatabaseConfig {
+            store: StoreConfig::default(),
+            persistence: PersistenceConfig::default(),
+            extensions: HashSet::new(),
+            tcp_port: None,
+            replication: ReplicationConfig::default(),
+            limits: LimitsConfig::default(),
+            log_requests: true,
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct PersistenceConfig {
+    /// Directory of the log and the snapshots, entries are only kept in memory without it
+    pub directory: Option<PathBuf>,
+    pub sync_policy: SyncPolicy,
+    /// Time between compacting the log into a snapshot
+    pub snapshot_interval: Duration,
+}
+
+impl Default for PersistenceConfig {
+    fn default() -> Self {
+        PersistenceConfig {
+            directory: None,
+            sync_policy: SyncPolicy::Interval(Duration::from_secs(1)),
+            snapshot_interval: Duration::from_secs(5 * 60),
+        }
+    }
+}
+
+/// A database without a replication port or primary is standalone
+#[derive(Clone, Debug, PartialEq)]
+pub struct ReplicationConfig {
+    /// Port that replicas connect to, which makes the database a primary
+    pub listen_port: Option<u16>,
+    /// Addresses that replicas may connect from, other connections to the port are refused
+    pub replica_addresses: Vec<IpAddr>,
+    /// `<host>:<port>` of the primary, which makes the database a replica
+    pub primary_address: Option<String>,
+}
+
+impl Default for ReplicationConfig {
+    fn default() -> Self {
+        ReplicationConfig {
+            listen_port: None,
+            replica_addresses: vec![
+                IpAddr::V4(Ipv4Addr::LOCALHOST),
+                IpAddr::V6(Ipv6Addr::LOCALHOST),
+            ],
+            primary_address: None,
+        }
+    }
+}
+
+/// Protection against floods and against being used to reflect traffic to spoofed sources
+#[derive(Clone, Debug, PartialEq)]
+pub struct LimitsConfig {
+    /// Datagrams per second of each source IP address, with bursts of the same size
+    pub rate_limit: Option<u32>,
+    /// Requests that are handled at the same time, further datagrams are dropped
+    pub max_in_flight: usize,
+    /// Datagrams that wait for their turn or are handled, further datagrams are dropped
+    pub max_queued: usize,
+    /// Responses to a datagram may be at most this many times larger than the datagram
+    pub max_response_ratio: Option<u32>,
+    /// TCP clients connected at the same time, further connections are closed right away
+    pub max_tcp_connections: usize,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            rate_limit: None,
+            max_in_flight: 1024,
+            max_queued: 4096,
+            max_response_ratio: None,
+            max_tcp_connections: 1024,
+        }
+    }
+}
+
+impl DatabaseConfig {
+    pub fn from_env() -> Self {
+        let default_config = DatabaseConfig::default();
+        let default_persistence = PersistenceConfig::default();
+        let default_limits = LimitsConfig::default();
+        let replica_addresses = parsed_list_env_var("UNUSUALDB_REPLICA_ADDRESSES");
+        DatabaseConfig {
+            store: StoreConfig {
+                kind: parsed_env_var("UNUSUALDB_STORE").unwrap_or(default_config.store.kind),
+                shard_count: parsed_env_var("UNUSUALDB_SHARDS")
+                    .filter(|shard_count| *shard_count > 0)
+                    .unwrap_or(default_config.store.shard_count),
+            },
+            persistence: PersistenceConfig {
+                directory: env_var("UNUSUALDB_DATA_DIR").map(PathBuf::from),
+                sync_policy: parsed_env_var("UNUSUALDB_FSYNC")
+                    .unwrap_or(default_persistence.sync_policy),
+                snapshot_interval: duration_env_var("UNUSUALDB_SNAPSHOT_INTERVAL")
+                    .filter(|interval| !interval.is_zero())
+                    .unwrap_or(default_persistence.snapshot_interval),
+            },
+            extensions: parsed_list_env_var("UNUSUALDB_EXTENSIONS")
+                .into_iter()
+                .collect(),
+            tcp_port: parsed_env_var("UNUSUALDB_TCP_PORT"),
+            replication: ReplicationConfig {
+                listen_port: parsed_env_var("UNUSUALDB_REPLICATION_PORT"),
+                replica_addresses: if replica_addresses.is_empty() {
+                    default_config.replication.replica_addresses
+                } else {
+                    replica_addresses
+                },
+                primary_address: env_var("UNUSUALDB_PRIMARY"),
+            },
+            limits: LimitsConfig {
+                rate_limit: parsed_env_var("UNUSUALDB_RATE_LIMIT").filter(|rate| *rate > 0),
+                max_in_flight: parsed_env_var("UNUSUALDB_MAX_IN_FLIGHT")
+                    .filter(|max_in_flight| *max_in_flight > 0)
+                    .unwrap_or(default_limits.max_in_flight),
+                max_queued: parsed_env_var("UNUSUALDB_MAX_QUEUED")
+                    .filter(|max_queued| *max_queued > 0)
+                    .unwrap_or(default_limits.max_queued),
+                max_response_ratio: parsed_env_var("UNUSUALDB_MAX_RESPONSE_RATIO")
+                    .filter(|ratio| *ratio > 0),
+                max_tcp_connections: parsed_env_var("UNUSUALDB_MAX_TCP_CONNECTIONS")
+                    .filter(|max_tcp_connections| *max_tcp_connections > 0)
+                    .unwrap_or(default_limits.max_tcp_connections),
+            },
+            log_requests: parsed_env_var("UNUSUALDB_LOG_REQUESTS")
+                .unwrap_or(default_config.log_requests),
+        }
+    }
+}
+
+/// Durations like `500ms`, `30s` or `2h`
+pub fn parse_duration(duration: &str) -> Option<Duration> {
+    let unit_start = duration
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(duration.len());
+    let (amount, unit) = duration.split_at(unit_start);
+    let amount: u64 = amount.parse().ok()?;
+    let unit_millis = match unit {
+        "ms" => 1,
+        "" | "s" => 1000,
+        "m" => 60 * 1000,
+        "h" => 60 * 60 * 1000,
+        _ => return None,
+    };
+    amount.checked_mul(unit_millis).map(Duration::from_millis)
+}
+
+fn env_var(name: &str) -> Option<String> {
+    env::var(name).ok().filter(|value| !value.trim().is_empty())
+}
+
+fn parsed_env_var<T: FromStr>(name: &str) -> Option<T> {
+    let value = env_var(name)?;
+    let parsed_value = value.trim().parse().ok();
+    if parsed_value.is_none() {
+        println!("Ignoring invalid value {value} for {name}");
+    }
+    parsed_value
+}
+
+fn duration_env_var(name: &str) -> Option<Duration> {
+    let value = env_var(name)?;
+    let parsed_value = parse_duration(value.trim());
+    if parsed_value.is_none() {
+        println!("Ignoring invalid value {value} for {name}");
+    }
+    parsed_value
+}
+
+/// Comma separated list of values, invalid values are left out
+fn parsed_list_env_var<T: FromStr<Err: Display>>(name: &str) -> Vec<T> {
+    let Some(list) = env_var(name) else {
+        return Vec::new();
+    };
+    list.split(',')
+        .map(str::trim)
+        .filter(|entry| !entry.is_empty())
+        .filter_map(|entry| match entry.parse() {
+            Ok(value) => Some(value),
+            Err(e) => {
+                println!("Ignoring invalid value for {name}: {e}");
+                None
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_duration_parsing() {
+        assert_eq!(parse_duration("250ms"), Some(Duration::from_millis(250)));
+        assert_eq!(parse_duration("30"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_duration("30s"), Some(Duration::from_secs(30)));
+        assert_eq!(parse_duration("5m"), Some(Duration::from_secs(300)));
+        assert_eq!(parse_duration("2h"), Some(Duration::from_secs(7200)));
+        assert_eq!(parse_duration(""), None);
+        assert_eq!(parse_duration("s"), None);
+        assert_eq!(parse_duration("3 days"), None);
+    }
+}
@@ -0,0 +1,510 @@
+use std::collections::hash_map::RandomState;
+use std::hash::BuildHasher;
+use std::io::{Error as IO_Error, Result as IO_Result};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime};
+
+use tokio::sync::broadcast;
+use tokio::time::{interval, MissedTickBehavior};
+
+use crate::config::{DatabaseConfig, PersistenceConfig};
+use crate::store::{create_store, Change, Entries, Entry, KeyValueStore, StoreConfig, Update};
+use crate::wal::{write_snapshot, LogWriter, WriteAheadLog};
+
+/// Time between removing expired entries that were not retrieved
+const EXPIRY_INTERVAL: Duration = Duration::from_secs(1);
+/// Changes a replica may fall behind before it has to resynchronise
+const REPLICATION_BUFFER_SIZE: usize = 4096;
+
+/// Entries of the database, with an optional write-ahead log of the changes and optional replicas
+pub struct Database {
+    store: Box<dyn KeyValueStore>,
+    /// Only set if changes are logged or replicated
+    change_log: Option<ChangeLog>,
+}
+
+/// Change of a request in the write-ahead log, see [`Database::wait_for_log`]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct LogTicket(Option<u64>);
+
+/// Receivers of every change. They get the changes of each key in the order of the store.
+struct ChangeLog {
+    /// One of them is held while a change is applied and passed on. The keys are spread over the
+    /// locks like over the shards of the store, so that changes of different keys rarely wait for
+    /// each other.
+    key_locks: Vec<Mutex<()>>,
+    hasher: RandomState,
+    log_writer: Option<LogWriter>,
+    replicas: Option<broadcast::Sender<Change>>,
+}
+
+impl ChangeLog {
+    fn lock_key(&self, key: &[u8]) -> MutexGuard<'_, ()> {
+        let lock_index = self.hasher.hash_one(key) % self.key_locks.len() as u64;
+        self.key_locks[lock_index as usize].lock().unwrap()
+    }
+
+    fn pass_on(&self, change: Change) -> LogTicket {
+        let change_number = self
+            .log_writer
+            .as_ref()
+            .map(|log_writer| log_writer.append(&change));
+        if let Some(replicas) = &self.replicas {
+            // Fails only without connected replicas
+            let _ = replicas.send(change);
+        }
+        LogTicket(change_number)
+    }
+
+    /// Waits until no change is in progress and keeps further changes waiting
+    fn lock_all_keys(&self) -> Vec<MutexGuard<'_, ()>> {
+        // Changes only ever hold a single lock -> No deadlock despite the many locks
+        self.key_locks
+            .iter()
+            .map(|key_lock| key_lock.lock().unwrap())
+            .collect()
+    }
+}
+
+impl Database {
+    pub fn in_memory(config: &StoreConfig, entries: Entries) -> Self {
+        Database {
+            store: create_store(config, entries, SystemTime::now()),
+            change_log: None,
+        }
+    }
+
+    /// Restores the entries of the previous run if a data directory is configured
+    pub fn open(config: &DatabaseConfig) -> IO_Result<Self> {
+        let (write_ahead_log, entries) = match &config.persistence.directory {
+            Some(directory) => {
+                let (write_ahead_log, entries) =
+                    WriteAheadLog::open(directory, config.persistence.sync_policy)?;
+                println!(
+                    "[DB] Restored {} entries from {}",
+                    entries.len(),
+                    directory.display()
+                );
+                (Some(write_ahead_log), entries)
+            }
+            None => (None, Entries::new()),
+        };
+        let replicas = config
+            .replication
+            .listen_port
+            .map(|_| broadcast::channel(REPLICATION_BUFFER_SIZE).0);
+
+        let mut db = Self::in_memory(&config.store, entries);
+        if write_ahead_log.is_some() || replicas.is_some() {
+            db.change_log = Some(ChangeLog {
+                key_locks: (0..config.store.shard_count.max(1))
+                    .map(|_| Mutex::new(()))
+                    .collect(),
+                hasher: RandomState::new(),
+                log_writer: write_ahead_log.map(LogWriter::start),
+                replicas,
+            });
+        }
+        Ok(db)
+    }
+
+    pub fn get(&self, key: &[u8]) -> Option<Vec<u8>> {
+        self.store.get(key, SystemTime::now())
+    }
+
+    pub fn insert(&self, key: Vec<u8>, entry: Entry) -> LogTicket {
+        self.write(&key, |store| {
+            store.insert(key.clone(), entry.clone());
+            ((), Some(Change::Insert(key.clone(), entry)))
+        })
+        .1
+    }
+
+    /// Returns whether the key had a value
+    pub fn delete(&self, key: &[u8]) -> (bool, LogTicket) {
+        self.write(key, |store| {
+            let is_deleted = store.remove(key, SystemTime::now());
+            (is_deleted, is_deleted.then(|| Change::Delete(key.to_vec())))
+        })
+    }
+
+    /// Replaces the value of the key with the result of the update for its current value, without
+    /// another request changing the key in between. Returns the new value.
+    pub fn update(&self, key: &[u8], update: Update) -> (Option<Vec<u8>>, LogTicket) {
+        self.write(key, |store| {
+            let entry = store.update(key, SystemTime::now(), update);
+            let change = entry
+                .clone()
+                .map(|entry| Change::Insert(key.to_vec(), entry));
+            (entry.map(|entry| entry.value), change)
+        })
+    }
+
+    pub fn apply(&self, change: Change) {
+        match change {
+            Change::Insert(key, entry) => {
+                self.insert(key, entry);
+            }
+            Change::Delete(key) => {
+                self.delete(&key);
+            }
+        }
+    }
+
+    /// Replaces all entries at once, e.g. with the entries of the primary. Readers see either the
+    /// previous or the new entries.
+    pub fn replace_entries(&self, entries: Entries) {
+        let Some(change_log) = &self.change_log else {
+            self.store.replace_entries(entries, SystemTime::now());
+            return;
+        };
+
+        let _key_locks = change_log.lock_all_keys();
+        let mut previous_entries = self
+            .store
+            .replace_entries(entries.clone(), SystemTime::now());
+        for (key, entry) in entries {
+            if previous_entries.remove(&key).as_ref() != Some(&entry) {
+                change_log.pass_on(Change::Insert(key, entry));
+            }
+        }
+        for key in previous_entries.into_keys() {
+            change_log.pass_on(Change::Delete(key));
+        }
+    }
+
+    /// Current entries and the changes after them, for a replica. `None` unless the database is
+    /// configured as a primary.
+    pub fn subscribe(&self) -> Option<(Entries, broadcast::Receiver<Change>)> {
+        let change_log = self.change_log.as_ref()?;
+        let replicas = change_log.replicas.as_ref()?;
+        let _key_locks = change_log.lock_all_keys();
+        Some((self.store.entries(), replicas.subscribe()))
+    }
+
+    /// Sorted keys that start with the prefix
+    pub fn keys_with_prefix(&self, prefix: &[u8]) -> Vec<Vec<u8>> {
+        let mut keys = self.store.keys_with_prefix(prefix, SystemTime::now());
+        keys.sort_unstable();
+        keys
+    }
+
+    /// Changes the store and passes on the change it returns. The key stays locked until the
+    /// change is passed on, so that the log and the replicas get the changes of the key in the
+    /// order of the store and snapshots do not miss changes. The log writer only queues the
+    /// change, see [`Self::wait_for_log`].
+    fn write<T>(
+        &self,
+        key: &[u8],
+        change_store: impl FnOnce(&dyn KeyValueStore) -> (T, Option<Change>),
+    ) -> (T, LogTicket) {
+        let Some(change_log) = &self.change_log else {
+            return (change_store(self.store.as_ref()).0, LogTicket::default());
+        };
+
+        let _key_lock = change_log.lock_key(key);
+        let (result, change) = change_store(self.store.as_ref());
+        let Some(change) = change else {
+            return (result, LogTicket::default());
+        };
+        (result, change_log.pass_on(change))
+    }
+
+    /// Waits until the change of the ticket and the changes before it are in the write-ahead log,
+    /// flushed to the disk if the sync policy asks for it. Requests that changed entries are
+    /// answered afterwards. Returns `false` if the change could not be logged.
+    pub async fn wait_for_log(&self, log_ticket: LogTicket) -> bool {
+        let log_writer = self
+            .change_log
+            .as_ref()
+            .and_then(|change_log| change_log.log_writer.as_ref());
+        match (log_writer, log_ticket) {
+            (Some(log_writer), LogTicket(Some(change_number))) => {
+                log_writer.logged(change_number).await
+            }
+            _ => true,
+        }
+    }
+
+    fn has_write_ahead_log(&self) -> bool {
+        self.change_log
+            .as_ref()
+            .is_some_and(|change_log| change_log.log_writer.is_some())
+    }
+
+    /// Copy of the values of all entries that did not expire
+    #[cfg(test)]
+    pub fn entries(&self) -> std::collections::HashMap<Vec<u8>, Vec<u8>> {
+        self.store.remove_expired(SystemTime::now());
+        self.store
+            .entries()
+            .into_iter()
+            .map(|(key, entry)| (key, entry.value))
+            .collect()
+    }
+
+    fn remove_expired(&self) -> usize {
+        self.store.remove_expired(SystemTime::now())
+    }
+
+    /// Compacts the log into a snapshot of the current entries
+    pub fn snapshot(&self) -> IO_Result<()> {
+        let Some(change_log) = &self.change_log else {
+            return Ok(());
+        };
+        let Some(log_writer) = &change_log.log_writer else {
+            return Ok(());
+        };
+        let (directory, generation, entries) = {
+            let _key_locks = change_log.lock_all_keys();
+            (
+                log_writer.directory().to_path_buf(),
+                log_writer.start_generation(),
+                self.store.entries(),
+            )
+        };
+        // Changes continue in the new log while the snapshot is written
+        let generation = generation
+            .recv()
+            .map_err(|_| IO_Error::other("Log writer stopped"))??;
+        write_snapshot(&directory, generation, &entries)
+    }
+}
+
+/// Periodically removes expired entries, so that entries that are never retrieved again do not
+/// stay in memory
+pub fn spawn_expiry_task(db: &Arc<Database>) {
+    let db = Arc::clone(db);
+    tokio::spawn(async move {
+        let mut expiry_timer = interval(EXPIRY_INTERVAL);
+        expiry_timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        loop {
+            expiry_timer.tick().await;
+            let expired_count = db.remove_expired();
+            if expired_count > 0 {
+                println!("[DB] Removed {expired_count} expired entries");
+            }
+        }
+    });
+}
+
+/// Periodically compacts the log into snapshots. The log writer flushes the log by itself.
+pub fn spawn_persistence_tasks(db: &Arc<Database>, config: &PersistenceConfig) {
+    if !db.has_write_ahead_log() {
+        return;
+    }
+
+    let db = Arc::clone(db);
+    let snapshot_interval = config.snapshot_interval;
+    tokio::spawn(async move {
+        let mut snapshot_timer = interval(snapshot_interval);
+        snapshot_timer.set_missed_tick_behavior(MissedTickBehavior::Delay);
+        // The first tick is immediate
+        snapshot_timer.tick().await;
+        loop {
+            snapshot_timer.tick().await;
+            let db = Arc::clone(&db);
+            match tokio::task::spawn_blocking(move || db.snapshot()).await {
+                Ok(Ok(())) => println!("[DB] Wrote snapshot"),
+                Ok(Err(e)) => println!("[DB] Failed to write snapshot: {e}"),
+                Err(e) => println!("[DB] Failed to write snapshot: {e}"),
+            }
+        }
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+    use std::path::PathBuf;
+    use std::time::Duration;
+
+    use super::*;
+    use crate::wal::SyncPolicy;
+
+    fn persistence_config(name: &str) -> DatabaseConfig {
+        let directory =
+            std::env::temp_dir().join(format!("unusualdb-database-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        DatabaseConfig {
+            persistence: PersistenceConfig {
+                directory: Some(directory),
+                sync_policy: SyncPolicy::Interval(Duration::from_millis(10)),
+                snapshot_interval: Duration::from_millis(50),
+            },
+            ..DatabaseConfig::default()
+        }
+    }
+
+    fn data_files(config: &DatabaseConfig) -> Vec<PathBuf> {
+        let mut data_files: Vec<PathBuf> =
+            fs::read_dir(config.persistence.directory.as_ref().unwrap())
+                .unwrap()
+                .map(|dir_entry| PathBuf::from(dir_entry.unwrap().file_name()))
+                .collect();
+        data_files.sort();
+        data_files
+    }
+
+    #[test]
+    fn test_restart() {
+        let config = persistence_config("restart");
+        let db = Database::open(&config).unwrap();
+        db.insert(b"foo".to_vec(), Entry::permanent(b"bar".to_vec()));
+        db.snapshot().unwrap();
+        db.insert(b"foo".to_vec(), Entry::permanent(b"baz".to_vec()));
+        db.insert(b"abc".to_vec(), Entry::permanent(b"42".to_vec()));
+        drop(db);
+
+        let db = Database::open(&config).unwrap();
+        assert_eq!(db.get(b"foo"), Some(b"baz".to_vec()));
+        assert_eq!(db.get(b"abc"), Some(b"42".to_vec()));
+        assert_eq!(db.entries().len(), 2);
+
+        fs::remove_dir_all(config.persistence.directory.unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_concurrent_changes() {
+        let config = persistence_config("concurrent");
+        let db = Database::open(&config).unwrap();
+        std::thread::scope(|scope| {
+            for thread_number in 0..4u8 {
+                let db = &db;
+                scope.spawn(move || {
+                    for i in 0..500u16 {
+                        // The threads share keys
+                        let key = format!("key{}", i % 50).into_bytes();
+                        if i % 7 == 0 {
+                            db.delete(&key);
+                        } else {
+                            db.insert(key, Entry::permanent(vec![thread_number]));
+                        }
+                    }
+                });
+            }
+            scope.spawn(|| {
+                for _ in 0..5 {
+                    db.snapshot().unwrap();
+                }
+            });
+        });
+        let entries = db.entries();
+        drop(db);
+
+        // The log has the changes of every key in the order of the store
+        let db = Database::open(&config).unwrap();
+        assert_eq!(db.entries(), entries);
+
+        fs::remove_dir_all(config.persistence.directory.unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_replaced_entries_after_restart() {
+        let config = persistence_config("replaced");
+        let db = Database::open(&config).unwrap();
+        db.insert(b"foo".to_vec(), Entry::permanent(b"bar".to_vec()));
+        db.insert(b"abc".to_vec(), Entry::permanent(b"42".to_vec()));
+        db.replace_entries(Entries::from([
+            (b"abc".to_vec(), Entry::permanent(b"42".to_vec())),
+            (b"def".to_vec(), Entry::permanent(b"7".to_vec())),
+        ]));
+        drop(db);
+
+        let db = Database::open(&config).unwrap();
+        assert_eq!(db.get(b"foo"), None);
+        assert_eq!(db.get(b"abc"), Some(b"42".to_vec()));
+        assert_eq!(db.get(b"def"), Some(b"7".to_vec()));
+
+        fs::remove_dir_all(config.persistence.directory.unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_changes_after_restart() {
+        let config = persistence_config("changes");
+        let db = Database::open(&config).unwrap();
+        db.insert(b"foo".to_vec(), Entry::permanent(b"bar".to_vec()));
+        db.insert(b"count".to_vec(), Entry::permanent(b"1".to_vec()));
+        db.snapshot().unwrap();
+        assert!(db.delete(b"foo").0);
+        assert!(!db.delete(b"missing").0);
+        assert_eq!(
+            db.update(b"count", &mut |value| Some([value.unwrap(), b"0"].concat()))
+                .0,
+            Some(b"10".to_vec())
+        );
+        assert_eq!(db.update(b"count", &mut |_| None).0, None);
+        drop(db);
+
+        let db = Database::open(&config).unwrap();
+        assert_eq!(db.get(b"foo"), None);
+        assert_eq!(db.get(b"count"), Some(b"10".to_vec()));
+        assert_eq!(db.keys_with_prefix(b""), [b"count".to_vec()]);
+
+        fs::remove_dir_all(config.persistence.directory.unwrap()).unwrap();
+    }
+
+    #[test]
+    fn test_expiry_after_restart() {
+        let config = persistence_config("expiry");
+        let db = Database::open(&config).unwrap();
+        let now = SystemTime::now();
+        db.insert(
+            b"short".to_vec(),
+            Entry {
+                value: b"1".to_vec(),
+                expires_at: Some(now + Duration::from_millis(50)),
+            },
+        );
+        db.insert(
+            b"long".to_vec(),
+            Entry {
+                value: b"2".to_vec(),
+                expires_at: Some(now + Duration::from_secs(3600)),
+            },
+        );
+        db.snapshot().unwrap();
+        db.insert(
+            b"logged".to_vec(),
+            Entry {
+                value: b"3".to_vec(),
+                expires_at: Some(now + Duration::from_millis(50)),
+            },
+        );
+        drop(db);
+
+        std::thread::sleep(Duration::from_millis(100));
+        let db = Database::open(&config).unwrap();
+        assert_eq!(db.get(b"short"), None);
+        assert_eq!(db.get(b"logged"), None);
+        assert_eq!(db.get(b"long"), Some(b"2".to_vec()));
+
+        fs::remove_dir_all(config.persistence.directory.unwrap()).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_periodic_snapshots() {
+        let config = persistence_config("periodic");
+        let db = Arc::new(Database::open(&config).unwrap());
+        spawn_persistence_tasks(&db, &config.persistence);
+        db.insert(b"foo".to_vec(), Entry::permanent(b"bar".to_vec()));
+
+        // The log is compacted into a snapshot
+        let mut files = data_files(&config);
+        for _ in 0..100 {
+            if files.len() == 2 && files[0].to_string_lossy().starts_with("snapshot-") {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            files = data_files(&config);
+        }
+        assert_eq!(files.len(), 2, "{files:?}");
+        assert!(files[0].to_string_lossy().starts_with("snapshot-"));
+        assert!(files[1].to_string_lossy().starts_with("wal-"));
+
+        let restored = Database::open(&config).unwrap();
+        assert_eq!(restored.entries(), db.entries());
+
+        fs::remove_dir_all(config.persistence.directory.unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,1243 @@
+mod config;
+mod database;
+mod query;
+mod rate_limit;
+mod replication;
+mod source_queue;
+mod store;
+mod tcp;
+mod wal;
+
+use std::io::{Error as IO_Error, Result as IO_Result};
+use std::net::SocketAddr;
+use std::str;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Instant, SystemTime};
+
+use bytes::BytesMut;
+use futures::StreamExt;
+use tokio::net::{TcpListener, UdpSocket};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+use tokio_util::codec::Decoder;
+use tokio_util::udp::UdpFramed;
+
+use crate::config::{DatabaseConfig, LimitsConfig};
+use crate::database::{spawn_expiry_task, spawn_persistence_tasks, Database, LogTicket};
+use crate::query::{parse_extended_query, QueryType};
+use crate::rate_limit::{RateLimit, RateLimiter};
+use crate::replication::{run_primary, start_replica, PrimaryLink};
+use crate::source_queue::SourceQueues;
+use crate::store::Entry;
+use crate::tcp::run_tcp_server;
+
+/// Requests and responses must be shorter than this many bytes
+const MAX_DATAGRAM_SIZE: usize = 1000;
+
+#[derive(Debug, PartialEq)]
+enum Datagram {
+    Request(Vec<u8>),
+    /// Request that is too long to be answered, with its size
+    Oversized(usize),
+}
+
+/// Decodes every received datagram as a single request
+struct DatagramCodec {
+    clear_buffer: bool,
+}
+
+impl DatagramCodec {
+    fn new() -> Self {
+        DatagramCodec {
+            clear_buffer: false,
+        }
+    }
+}
+
+impl Decoder for DatagramCodec {
+    type Item = Datagram;
+    type Error = IO_Error;
+
+    fn decode(&mut self, buffer: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        if self.clear_buffer {
+            self.clear_buffer = false;
+            return Ok(None);
+        }
+
+        // Every datagram is a single frame, even an empty one
+        self.clear_buffer = true;
+        let frame_buffer = buffer.split_to(buffer.len());
+        if frame_buffer.len() >= MAX_DATAGRAM_SIZE {
+            Ok(Some(Datagram::Oversized(frame_buffer.len())))
+        } else {
+            Ok(Some(Datagram::Request(frame_buffer.to_vec())))
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> IO_Result<()> {
+    let udp_socket = UdpSocket::bind("0.0.0.0:8080").await?;
+
+    println!("Running server for Problem 4 on port 8080");
+
+    let config = DatabaseConfig::from_env();
+    let db = Arc::new(Database::open(&config)?);
+    spawn_persistence_tasks(&db, &config.persistence);
+    spawn_expiry_task(&db);
+    let primary = config
+        .replication
+        .primary_address
+        .clone()
+        .map(|primary_address| {
+            println!("Replicating the primary at {primary_address}");
+            start_replica(primary_address, Arc::clone(&db))
+        });
+    let server = Arc::new(Server::new(db, config, primary));
+
+    if let Some(tcp_port) = server.config.tcp_port {
+        let tcp_listener = TcpListener::bind(("0.0.0.0", tcp_port)).await?;
+        println!("Accepting newline delimited requests on TCP port {tcp_port}");
+        let server = Arc::clone(&server);
+        tokio::spawn(async move {
+            if let Err(e) = run_tcp_server(tcp_listener, server).await {
+                println!("Stopped accepting TCP clients: {e}");
+            }
+        });
+    }
+
+    if let Some(replication_port) = server.config.replication.listen_port {
+        let replication_listener = TcpListener::bind(("0.0.0.0", replication_port)).await?;
+        println!("Accepting replicas on TCP port {replication_port}");
+        let server = Arc::clone(&server);
+        tokio::spawn(async move {
+            if let Err(e) = run_primary(replication_listener, server).await {
+                println!("Stopped accepting replicas: {e}");
+            }
+        });
+    }
+
+    run_server(udp_socket, server).await
+}
+
+/// What requests of all transports are handled with
+struct Server {
+    db: Arc<Database>,
+    config: DatabaseConfig,
+    /// Only set on replicas, which forward writes to their primary
+    primary: Option<PrimaryLink>,
+    /// Permits for the requests that are handled at the same time
+    in_flight: Arc<Semaphore>,
+    /// Whether the last request found all permits taken
+    is_overloaded: AtomicBool,
+    /// Slots for the datagrams that wait for their turn or are handled, so that spoofed sources
+    /// cannot make the server queue requests without limit
+    queue_slots: Arc<Semaphore>,
+    /// Whether the last datagram found all queue slots taken
+    is_queue_full: AtomicBool,
+    /// Only set with a rate limit
+    rate_limiter: Option<Mutex<RateLimiter>>,
+}
+
+impl Server {
+    fn new(db: Arc<Database>, config: DatabaseConfig, primary: Option<PrimaryLink>) -> Self {
+        Server {
+            in_flight: Arc::new(Semaphore::new(config.limits.max_in_flight)),
+            is_overloaded: AtomicBool::new(false),
+            queue_slots: Arc::new(Semaphore::new(config.limits.max_queued)),
+            is_queue_full: AtomicBool::new(false),
+            rate_limiter: config
+                .limits
+                .rate_limit
+                .map(|rate_limit| Mutex::new(RateLimiter::new(rate_limit))),
+            db,
+            config,
+            primary,
+        }
+    }
+
+    /// Permit for a request, or `None` if too many requests are in flight. The request is then
+    /// dropped like a datagram in a full receive buffer.
+    fn try_start_request(&self) -> Option<OwnedSemaphorePermit> {
+        try_acquire(&self.in_flight, &self.is_overloaded, || {
+            format!(
+                "Dropping requests while {} requests are in flight",
+                self.config.limits.max_in_flight
+            )
+        })
+    }
+
+    /// Slot for a datagram until its request is answered, or `None` if too many are queued
+    fn try_queue_request(&self) -> Option<OwnedSemaphorePermit> {
+        try_acquire(&self.queue_slots, &self.is_queue_full, || {
+            format!(
+                "Dropping requests while {} requests are queued",
+                self.config.limits.max_queued
+            )
+        })
+    }
+
+    /// Whether the source sent more requests than the rate limit allows, which are dropped
+    fn exceeds_rate_limit(&self, source: SocketAddr) -> bool {
+        let Some(rate_limiter) = &self.rate_limiter else {
+            return false;
+        };
+        let rate_limit = rate_limiter
+            .lock()
+            .unwrap()
+            .check(source.ip(), Instant::now());
+        match rate_limit {
+            RateLimit::Allowed => false,
+            RateLimit::Exceeded { is_first } => {
+                if is_first {
+                    println!("[{source}] Dropping requests over the rate limit");
+                }
+                true
+            }
+        }
+    }
+}
+
+/// Permit of the semaphore, or `None` if all are taken. The message is logged when the permits run
+/// out, and again only after a permit was available in between.
+fn try_acquire(
+    semaphore: &Arc<Semaphore>,
+    is_exhausted: &AtomicBool,
+    message: impl FnOnce() -> String,
+) -> Option<OwnedSemaphorePermit> {
+    match Arc::clone(semaphore).try_acquire_owned() {
+        Ok(permit) => {
+            is_exhausted.store(false, Ordering::Relaxed);
+            Some(permit)
+        }
+        Err(_) => {
+            if !is_exhausted.swap(true, Ordering::Relaxed) {
+                println!("{}", message());
+            }
+            None
+        }
+    }
+}
+
+async fn run_server(udp_socket: UdpSocket, server: Arc<Server>) -> IO_Result<()> {
+    let udp_socket = Arc::new(udp_socket);
+    let mut udp_framed = UdpFramed::new(Arc::clone(&udp_socket), DatagramCodec::new());
+    let mut source_queues = SourceQueues::new();
+
+    while let Some(client_request) = udp_framed.next().await {
+        match client_request {
+            Ok((Datagram::Request(request_query), client_address)) => {
+                if server.exceeds_rate_limit(client_address) {
+                    continue;
+                }
+                // Taken before the request is queued, so that the spawned tasks are bounded no
+                // matter how many sources send
+                let Some(queue_slot) = server.try_queue_request() else {
+                    continue;
+                };
+                // Too many requests of the client waiting -> Further datagrams are lost, as they
+                // would be in a full receive buffer
+                let Some(mut queued_request) = source_queues.enqueue(client_address) else {
+                    continue;
+                };
+
+                let socket = Arc::clone(&udp_socket);
+                let server = Arc::clone(&server);
+                tokio::spawn(async move {
+                    // Requests of the same client are answered in order, e.g. a retrieve sees the
+                    // insert that was sent before it
+                    queued_request.wait_for_turn().await;
+                    // Requests only take a permit once it is their turn, so that a client with a
+                    // burst of requests cannot hold all permits while they wait
+                    let Some(in_flight_permit) = server.try_start_request() else {
+                        queued_request.finish();
+                        return;
+                    };
+                    let responses = handle_request(&request_query, client_address, &server).await;
+                    if exceeds_response_ratio(&request_query, &responses, &server.config.limits) {
+                        println!(
+                            "[{client_address}] Dropping {} bytes of responses to a request of {} \
+                             bytes",
+                            responses.iter().map(Vec::len).sum::<usize>(),
+                            request_query.len()
+                        );
+                    } else {
+                        for response in responses {
+                            send_response(&socket, &response, client_address).await;
+                        }
+                    }
+                    queued_request.finish();
+                    drop(in_flight_permit);
+                    drop(queue_slot);
+                });
+            }
+            Ok((Datagram::Oversized(request_size), client_address)) => {
+                println!("[{client_address}] Dropping request of {request_size} bytes");
+            }
+            Err(e) => println!("Failed to receive request: {e}"),
+        }
+    }
+
+    Ok(())
+}
+
+/// Sources of datagrams can be spoofed, so large responses to small requests could flood someone
+/// else
+fn exceeds_response_ratio(request: &[u8], responses: &[Vec<u8>], limits: &LimitsConfig) -> bool {
+    let Some(max_response_ratio) = limits.max_response_ratio else {
+        return false;
+    };
+    let response_size: usize = responses.iter().map(Vec::len).sum();
+    response_size > max_response_ratio as usize * request.len().max(1)
+}
+
+async fn send_response(socket: &UdpSocket, response: &[u8], client_address: SocketAddr) {
+    // Responses to spec requests are never longer than the request, except for the version
+    if response.len() >= MAX_DATAGRAM_SIZE {
+        println!(
+            "[{client_address}] Dropping response of {} bytes",
+            response.len()
+        );
+        return;
+    }
+
+    if let Err(e) = socket.send_to(response, client_address).await {
+        println!("[{client_address}] Unable to send response to client: {e}");
+    }
+}
+
+/// Requests over all transports run against the same database. Replicas only execute reads
+/// themselves.
+async fn handle_request(
+    request: &[u8],
+    client_address: SocketAddr,
+    server: &Server,
+) -> Vec<Vec<u8>> {
+    let config = &server.config;
+    if config.log_requests {
+        println!("[{client_address}] Request: {}", request.escape_ascii());
+    }
+    let query = parse_extended_query(request, &config.extensions);
+    let responses = match &server.primary {
+        Some(primary) if query.is_write() => match primary.forward(request).await {
+            Some(responses) => responses,
+            None => {
+                println!("[{client_address}] Rejecting write while the primary is unreachable");
+                rejection_responses(query)
+            }
+        },
+        _ => {
+            let (responses, log_ticket) = execute_query(query, &server.db);
+            // Changes are only confirmed once they are logged
+            if server.db.wait_for_log(log_ticket).await {
+                responses
+            } else {
+                println!("[{client_address}] Not answering a change that could not be logged");
+                Vec::new()
+            }
+        }
+    };
+    if config.log_requests {
+        for response in &responses {
+            println!("[{client_address}] Response: {}", response.escape_ascii());
+        }
+    }
+    responses
+}
+
+/// Most queries have at most one response, listings can be split over several. Queries that
+/// changed an entry return the ticket of their change in the write-ahead log.
+fn execute_query(query: QueryType, db: &Database) -> (Vec<Vec<u8>>, LogTicket) {
+    match query {
+        QueryType::Version => (
+            vec![b"version=Key-Value Store API v1".to_vec()],
+            LogTicket::default(),
+        ),
+        QueryType::Retrieve(key) => {
+            let value = db.get(&key);
+            let responses = value.map(|value| entry_response(&key, &value));
+            (responses.into_iter().collect(), LogTicket::default())
+        }
+        QueryType::Insert(key, value) => (Vec::new(), db.insert(key, Entry::permanent(value))),
+        QueryType::InsertExpiring(key, value, ttl) => {
+            // TTLs too long for the clock never expire
+            let expires_at = SystemTime::now().checked_add(ttl);
+            (Vec::new(), db.insert(key, Entry { value, expires_at }))
+        }
+        QueryType::Delete(key) => (Vec::new(), db.delete(&key).1),
+        QueryType::List(prefix) => (
+            list_responses(db.keys_with_prefix(&prefix)),
+            LogTicket::default(),
+        ),
+        QueryType::CompareAndSet(key, expected_value, new_value) => {
+            let (value, log_ticket) = db.update(&key, &mut |value| {
+                (value == Some(expected_value.as_slice())).then(|| new_value.clone())
+            });
+            let response = match value {
+                Some(value) => entry_response(&key, &value),
+                None => failure_response("cas", &key),
+            };
+            (vec![response], log_ticket)
+        }
+        QueryType::Increment(key, amount) => {
+            // Missing keys count as 0, other values have to be decimal numbers
+            let (value, log_ticket) = db.update(&key, &mut |value| {
+                let number: i64 = match value {
+                    Some(value) => str::from_utf8(value).ok()?.parse().ok()?,
+                    None => 0,
+                };
+                Some(number.checked_add(amount)?.to_string().into_bytes())
+            });
+            let response = match value {
+                Some(value) => entry_response(&key, &value),
+                None => failure_response("incr", &key),
+            };
+            (vec![response], log_ticket)
+        }
+    }
+}
+
+/// Only writes with a response are answered, with a failure
+fn rejection_responses(query: QueryType) -> Vec<Vec<u8>> {
+    match query {
+        QueryType::CompareAndSet(key, ..) => vec![failure_response("cas", &key)],
+        QueryType::Increment(key, _) => vec![failure_response("incr", &key)],
+        _ => Vec::new(),
+    }
+}
+
+/// `<key>=<value>`, as the response to a retrieve
+fn entry_response(key: &[u8], value: &[u8]) -> Vec<u8> {
+    [key, b"=", value].concat()
+}
+
+/// `!<command> failed <key>`
+fn failure_response(command: &str, key: &[u8]) -> Vec<u8> {
+    [b"!", command.as_bytes(), b" failed ", key].concat()
+}
+
+/// `!list <part>/<part count>` followed by one key per line, with as many keys per datagram as
+/// fit. Keys too long to fit next to the header are left out.
+fn list_responses(keys: Vec<Vec<u8>>) -> Vec<Vec<u8>> {
+    // Room for the longest header
+    let count_digits = keys.len().max(1).to_string().len();
+    let max_keys_size = MAX_DATAGRAM_SIZE - 1 - "!list /".len() - 2 * count_digits;
+
+    let mut parts = vec![Vec::new()];
+    for key in keys {
+        let line_size = 1 + key.len();
+        if line_size > max_keys_size {
+            println!("Leaving out key of {} bytes from listing", key.len());
+            continue;
+        }
+        if parts.last().unwrap().len() + line_size > max_keys_size {
+            parts.push(Vec::new());
+        }
+        let part = parts.last_mut().unwrap();
+        part.push(b'\n');
+        part.extend(key);
+    }
+
+    let part_count = parts.len();
+    parts
+        .into_iter()
+        .enumerate()
+        .map(|(index, keys)| {
+            let header = format!("!list {}/{part_count}", index + 1);
+            [header.as_bytes(), &keys].concat()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::{HashMap, HashSet};
+    use std::fs;
+    use std::time::{Duration, Instant};
+
+    use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+    use tokio::net::TcpStream;
+    use tokio::time::timeout;
+
+    use super::*;
+    use crate::config::PersistenceConfig;
+    use crate::query::{parse_query, Extension};
+    use crate::store::{StoreConfig, StoreKind};
+    use crate::wal::SyncPolicy;
+
+    fn query_responses(query: QueryType, db: &Database) -> Vec<Vec<u8>> {
+        execute_query(query, db).0
+    }
+
+    fn db_with(entries: &[(&[u8], &[u8])]) -> Database {
+        Database::in_memory(
+            &StoreConfig::default(),
+            entries
+                .iter()
+                .map(|(key, value)| (key.to_vec(), Entry::permanent(value.to_vec())))
+                .collect(),
+        )
+    }
+
+    /// Small xorshift generator, so fuzzed datagrams are the same on every run
+    struct Fuzzer(u64);
+
+    impl Fuzzer {
+        fn next(&mut self) -> u64 {
+            self.0 ^= self.0 << 13;
+            self.0 ^= self.0 >> 7;
+            self.0 ^= self.0 << 17;
+            self.0
+        }
+
+        /// Random bytes with a bias towards `=`, so that inserts are common
+        fn datagram(&mut self, max_size: usize) -> Vec<u8> {
+            let size = self.next() as usize % max_size;
+            (0..size)
+                .map(|_| match self.next() % 8 {
+                    0 => b'=',
+                    _ => self.next() as u8,
+                })
+                .collect()
+        }
+    }
+
+    async fn start_server(config: DatabaseConfig) -> (UdpSocket, SocketAddr) {
+        let server_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_address = server_socket.local_addr().unwrap();
+        let server = Arc::new(Server::new(
+            Arc::new(Database::open(&config).unwrap()),
+            config,
+            None,
+        ));
+        tokio::spawn(run_server(server_socket, server));
+        let client_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        (client_socket, server_address)
+    }
+
+    async fn receive(client_socket: &UdpSocket) -> Option<Vec<u8>> {
+        let mut buffer = [0; 2048];
+        let (size, _) = timeout(
+            Duration::from_millis(300),
+            client_socket.recv_from(&mut buffer),
+        )
+        .await
+        .ok()?
+        .unwrap();
+        Some(buffer[..size].to_vec())
+    }
+
+    #[test]
+    fn test_datagram_decoding() {
+        let mut codec = DatagramCodec::new();
+
+        let mut buffer = BytesMut::from(&b"\xff=\xfe"[..]);
+        assert_eq!(
+            codec.decode_eof(&mut buffer).unwrap(),
+            Some(Datagram::Request(b"\xff=\xfe".to_vec()))
+        );
+        assert_eq!(codec.decode_eof(&mut buffer).unwrap(), None);
+
+        // Empty datagrams are requests for the empty key
+        assert_eq!(
+            codec.decode_eof(&mut buffer).unwrap(),
+            Some(Datagram::Request(Vec::new()))
+        );
+        assert_eq!(codec.decode_eof(&mut buffer).unwrap(), None);
+
+        let mut buffer = BytesMut::from(&[b'a'; 999][..]);
+        assert_eq!(
+            codec.decode_eof(&mut buffer).unwrap(),
+            Some(Datagram::Request(vec![b'a'; 999]))
+        );
+        assert_eq!(codec.decode_eof(&mut buffer).unwrap(), None);
+        let mut buffer = BytesMut::from(&[b'a'; 1000][..]);
+        assert_eq!(
+            codec.decode_eof(&mut buffer).unwrap(),
+            Some(Datagram::Oversized(1000))
+        );
+        assert_eq!(codec.decode_eof(&mut buffer).unwrap(), None);
+    }
+
+    #[test]
+    fn test_version_query_execution() {
+        let db = db_with(&[]);
+        let version_query = QueryType::Version;
+
+        assert_eq!(
+            query_responses(version_query, &db),
+            [b"version=Key-Value Store API v1".to_vec()]
+        );
+        assert!(db.entries().is_empty());
+    }
+
+    #[test]
+    fn test_retrieve_query_execution() {
+        let db = db_with(&[(b"abc", b"42")]);
+        let retrieve_abc_query = QueryType::Retrieve(b"abc".to_vec());
+        let retrieve_def_query = QueryType::Retrieve(b"def".to_vec());
+
+        assert_eq!(
+            query_responses(retrieve_abc_query, &db),
+            [b"abc=42".to_vec()]
+        );
+        assert!(query_responses(retrieve_def_query, &db).is_empty());
+        assert_eq!(
+            db.entries(),
+            HashMap::from([(b"abc".to_vec(), b"42".to_vec())])
+        );
+    }
+
+    #[test]
+    fn test_insert_query_execution() {
+        let db = db_with(&[]);
+        let insert_value_query = QueryType::Insert(b"abc".to_vec(), b"42".to_vec());
+
+        assert!(query_responses(insert_value_query, &db).is_empty());
+        assert_eq!(
+            db.entries(),
+            HashMap::from([(b"abc".to_vec(), b"42".to_vec())])
+        );
+
+        let update_value_query = QueryType::Insert(b"abc".to_vec(), b"123".to_vec());
+
+        assert!(query_responses(update_value_query, &db).is_empty());
+        assert_eq!(
+            db.entries(),
+            HashMap::from([(b"abc".to_vec(), b"123".to_vec())])
+        );
+    }
+
+    #[test]
+    fn test_fuzzed_queries() {
+        let mut fuzzer = Fuzzer(0x2545_f491_4f6c_dd1d);
+        let db = db_with(&[]);
+
+        for _ in 0..10_000 {
+            let datagram = fuzzer.datagram(MAX_DATAGRAM_SIZE);
+            match parse_query(&datagram) {
+                QueryType::Insert(key, value) => {
+                    assert_eq!([key.as_slice(), b"=", &value].concat(), datagram);
+                    query_responses(QueryType::Insert(key.clone(), value), &db);
+
+                    // Inserted values come back byte for byte, in a response no longer than the
+                    // insert
+                    if key != b"version" {
+                        assert_eq!(query_responses(QueryType::Retrieve(key), &db), [datagram]);
+                    }
+                }
+                QueryType::Retrieve(key) => {
+                    assert_eq!(key, datagram);
+                    assert!(!key.contains(&b'='));
+                }
+                QueryType::Version => assert_eq!(datagram, b"version"),
+                QueryType::InsertExpiring(..)
+                | QueryType::Delete(_)
+                | QueryType::List(_)
+                | QueryType::CompareAndSet(..)
+                | QueryType::Increment(..) => unreachable!("Extensions are disabled"),
+            }
+        }
+    }
+
+    #[test]
+    fn test_command_execution() {
+        let db = db_with(&[(b"foo", b"bar"), (b"count", b"41"), (b"text", b"abc")]);
+
+        assert!(query_responses(QueryType::Delete(b"foo".to_vec()), &db).is_empty());
+        assert!(query_responses(QueryType::Retrieve(b"foo".to_vec()), &db).is_empty());
+
+        let compare_and_set = |expected_value: &[u8], new_value: &[u8]| {
+            QueryType::CompareAndSet(
+                b"text".to_vec(),
+                expected_value.to_vec(),
+                new_value.to_vec(),
+            )
+        };
+        assert_eq!(
+            query_responses(compare_and_set(b"xyz", b"def"), &db),
+            [b"!cas failed text".to_vec()]
+        );
+        assert_eq!(
+            query_responses(compare_and_set(b"abc", b"def"), &db),
+            [b"text=def".to_vec()]
+        );
+        assert_eq!(
+            query_responses(
+                QueryType::CompareAndSet(b"foo".to_vec(), Vec::new(), b"1".to_vec()),
+                &db
+            ),
+            [b"!cas failed foo".to_vec()]
+        );
+
+        let increment = |key: &[u8], amount| QueryType::Increment(key.to_vec(), amount);
+        assert_eq!(
+            query_responses(increment(b"count", 1), &db),
+            [b"count=42".to_vec()]
+        );
+        assert_eq!(
+            query_responses(increment(b"new", -5), &db),
+            [b"new=-5".to_vec()]
+        );
+        assert_eq!(
+            query_responses(increment(b"text", 1), &db),
+            [b"!incr failed text".to_vec()]
+        );
+        assert_eq!(
+            query_responses(increment(b"count", i64::MAX), &db),
+            [b"!incr failed count".to_vec()]
+        );
+
+        assert_eq!(
+            query_responses(QueryType::List(b"c".to_vec()), &db),
+            [b"!list 1/1\ncount".to_vec()]
+        );
+        assert_eq!(
+            query_responses(QueryType::List(b"missing".to_vec()), &db),
+            [b"!list 1/1".to_vec()]
+        );
+    }
+
+    #[test]
+    fn test_split_listing() {
+        let keys: Vec<Vec<u8>> = (0..300)
+            .map(|number| format!("key{number:03}").into_bytes())
+            .collect();
+        let db = db_with(&[]);
+        for key in &keys {
+            db.insert(key.clone(), Entry::permanent(b"1".to_vec()));
+        }
+        // Too long for a datagram with the header
+        db.insert(vec![b'k'; 990], Entry::permanent(Vec::new()));
+
+        let responses = query_responses(QueryType::List(b"key".to_vec()), &db);
+        assert_eq!(responses.len(), 3);
+        let mut listed_keys = Vec::new();
+        for (index, response) in responses.iter().enumerate() {
+            assert!(response.len() < MAX_DATAGRAM_SIZE);
+            let mut lines = response.split(|byte| *byte == b'\n');
+            let header = format!("!list {}/3", index + 1);
+            assert_eq!(lines.next(), Some(header.as_bytes()));
+            listed_keys.extend(lines.map(<[u8]>::to_vec));
+        }
+        assert_eq!(listed_keys, keys);
+
+        let responses = query_responses(QueryType::List(b"k".to_vec()), &db);
+        assert_eq!(responses.len(), 3);
+    }
+
+    #[test]
+    fn test_fuzzed_commands() {
+        let mut fuzzer = Fuzzer(0x1234_5678_9abc_def0);
+        let extensions = HashSet::from([
+            Extension::Ttl,
+            Extension::Delete,
+            Extension::List,
+            Extension::Cas,
+            Extension::Incr,
+        ]);
+        let db = db_with(&[]);
+        let commands = [
+            &b"!ttl "[..],
+            b"!delete ",
+            b"!list ",
+            b"!cas ",
+            b"!incr ",
+            b"",
+        ];
+
+        for _ in 0..10_000 {
+            let command = commands[fuzzer.next() as usize % commands.len()];
+            let datagram = [command, &fuzzer.datagram(20)].concat();
+            for response in query_responses(parse_extended_query(&datagram, &extensions), &db) {
+                assert!(response.len() < MAX_DATAGRAM_SIZE);
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_fuzzed_datagrams() {
+        let (client_socket, server_address) = start_server(DatabaseConfig::default()).await;
+        let mut fuzzer = Fuzzer(0x9e37_79b9_7f4a_7c15);
+
+        // Nothing the server receives stops it from answering
+        for number in 0..500 {
+            let datagram = fuzzer.datagram(2 * MAX_DATAGRAM_SIZE);
+            client_socket
+                .send_to(&datagram, server_address)
+                .await
+                .unwrap();
+            // Bursts that overflow the socket's receive buffer would be lost
+            if number % 20 == 0 {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        }
+        // Responses to fuzzed retrieves of fuzzed inserts
+        while receive(&client_socket).await.is_some() {}
+
+        client_socket
+            .send_to(b"\xff\x00=\xc3\x28", server_address)
+            .await
+            .unwrap();
+        client_socket
+            .send_to(b"\xff\x00", server_address)
+            .await
+            .unwrap();
+        assert_eq!(
+            receive(&client_socket).await,
+            Some(b"\xff\x00=\xc3\x28".to_vec())
+        );
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 4)]
+    async fn test_interleaved_requests() {
+        const CLIENT_COUNT: usize = 8;
+        const BATCH_COUNT: usize = 20;
+        const BATCH_SIZE: usize = 10;
+
+        let (_, server_address) = start_server(DatabaseConfig::default()).await;
+        let mut clients = Vec::new();
+        for client_number in 0..CLIENT_COUNT {
+            clients.push(tokio::spawn(async move {
+                let client_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+                // Every retrieve has to see the insert that was sent right before it
+                for batch in 0..BATCH_COUNT {
+                    let values: Vec<String> = (0..BATCH_SIZE)
+                        .map(|index| format!("{client_number}-{}", batch * BATCH_SIZE + index))
+                        .collect();
+                    for value in &values {
+                        let insert = format!("shared{client_number}={value}");
+                        let retrieve = format!("shared{client_number}");
+                        client_socket
+                            .send_to(insert.as_bytes(), server_address)
+                            .await
+                            .unwrap();
+                        client_socket
+                            .send_to(retrieve.as_bytes(), server_address)
+                            .await
+                            .unwrap();
+                    }
+                    for value in &values {
+                        let expected_response = format!("shared{client_number}={value}");
+                        assert_eq!(
+                            receive(&client_socket).await,
+                            Some(expected_response.into_bytes())
+                        );
+                    }
+                }
+            }));
+        }
+        for client in clients {
+            client.await.unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn test_oversized_datagrams() {
+        let (client_socket, server_address) = start_server(DatabaseConfig::default()).await;
+
+        let mut insert = b"big=".to_vec();
+        insert.resize(MAX_DATAGRAM_SIZE, b'x');
+        client_socket
+            .send_to(&insert, server_address)
+            .await
+            .unwrap();
+        client_socket.send_to(b"big", server_address).await.unwrap();
+        assert_eq!(receive(&client_socket).await, None);
+
+        // The largest request that is accepted
+        insert.truncate(MAX_DATAGRAM_SIZE - 1);
+        client_socket
+            .send_to(&insert, server_address)
+            .await
+            .unwrap();
+        client_socket.send_to(b"big", server_address).await.unwrap();
+        assert_eq!(receive(&client_socket).await, Some(insert));
+    }
+
+    #[tokio::test]
+    async fn test_expiring_inserts() {
+        let (client_socket, server_address) = start_server(DatabaseConfig {
+            extensions: HashSet::from([Extension::Ttl]),
+            ..DatabaseConfig::default()
+        })
+        .await;
+
+        for request in [&b"!ttl 200ms foo=bar"[..], b"abc=42", b"foo"] {
+            client_socket
+                .send_to(request, server_address)
+                .await
+                .unwrap();
+        }
+        assert_eq!(receive(&client_socket).await, Some(b"foo=bar".to_vec()));
+
+        // Expired keys are missing
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        client_socket.send_to(b"foo", server_address).await.unwrap();
+        assert_eq!(receive(&client_socket).await, None);
+        client_socket.send_to(b"abc", server_address).await.unwrap();
+        assert_eq!(receive(&client_socket).await, Some(b"abc=42".to_vec()));
+    }
+
+    #[tokio::test]
+    async fn test_tcp_and_udp_clients() {
+        let server = Arc::new(Server::new(
+            Arc::new(Database::in_memory(&StoreConfig::default(), HashMap::new())),
+            DatabaseConfig::default(),
+            None,
+        ));
+        let udp_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let udp_address = udp_socket.local_addr().unwrap();
+        let tcp_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let tcp_address = tcp_listener.local_addr().unwrap();
+        tokio::spawn(run_server(udp_socket, Arc::clone(&server)));
+        tokio::spawn(run_tcp_server(tcp_listener, server));
+
+        let udp_client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let (tcp_reader, mut tcp_writer) =
+            TcpStream::connect(tcp_address).await.unwrap().into_split();
+        let mut tcp_reader = BufReader::new(tcp_reader);
+        let mut tcp_response = Vec::new();
+
+        // The TCP retrieve is answered after the insert before it
+        tcp_writer.write_all(b"foo=bar\nfoo\n").await.unwrap();
+        tcp_reader
+            .read_until(b'\n', &mut tcp_response)
+            .await
+            .unwrap();
+        assert_eq!(tcp_response, b"foo=bar\n");
+        udp_client.send_to(b"foo", udp_address).await.unwrap();
+        assert_eq!(receive(&udp_client).await, Some(b"foo=bar".to_vec()));
+
+        for request in [&b"foo=baz"[..], b"foo"] {
+            udp_client.send_to(request, udp_address).await.unwrap();
+        }
+        assert_eq!(receive(&udp_client).await, Some(b"foo=baz".to_vec()));
+        tcp_response.clear();
+        tcp_writer.write_all(b"foo\n").await.unwrap();
+        tcp_reader
+            .read_until(b'\n', &mut tcp_response)
+            .await
+            .unwrap();
+        assert_eq!(tcp_response, b"foo=baz\n");
+    }
+
+    #[tokio::test]
+    async fn test_tcp_client_not_reading() {
+        let config = DatabaseConfig {
+            limits: LimitsConfig {
+                max_in_flight: 1,
+                ..LimitsConfig::default()
+            },
+            log_requests: false,
+            ..DatabaseConfig::default()
+        };
+        let value = vec![b'x'; 900];
+        let server = Arc::new(Server::new(
+            Arc::new(db_with(&[(b"big", &value)])),
+            config,
+            None,
+        ));
+        let udp_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let udp_address = udp_socket.local_addr().unwrap();
+        let tcp_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let tcp_address = tcp_listener.local_addr().unwrap();
+        tokio::spawn(run_server(udp_socket, Arc::clone(&server)));
+        tokio::spawn(run_tcp_server(tcp_listener, server));
+
+        // Far more responses than fit into the socket buffers, which are never read
+        let mut tcp_stream = TcpStream::connect(tcp_address).await.unwrap();
+        tokio::spawn(async move {
+            let requests = b"big\n".repeat(50_000);
+            let _ = tcp_stream.write_all(&requests).await;
+            std::future::pending::<()>().await;
+        });
+        tokio::time::sleep(Duration::from_millis(300)).await;
+
+        let udp_client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        udp_client.send_to(b"version", udp_address).await.unwrap();
+        assert!(receive(&udp_client).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit() {
+        let (client_socket, server_address) = start_server(DatabaseConfig {
+            limits: LimitsConfig {
+                rate_limit: Some(5),
+                ..LimitsConfig::default()
+            },
+            ..DatabaseConfig::default()
+        })
+        .await;
+
+        for _ in 0..10 {
+            client_socket
+                .send_to(b"version", server_address)
+                .await
+                .unwrap();
+        }
+        let mut response_count = 0;
+        while receive(&client_socket).await.is_some() {
+            response_count += 1;
+        }
+        assert_eq!(response_count, 5);
+
+        // Requests are allowed again as the limit refills
+        tokio::time::sleep(Duration::from_millis(250)).await;
+        client_socket
+            .send_to(b"version", server_address)
+            .await
+            .unwrap();
+        assert!(receive(&client_socket).await.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_in_flight_limit() {
+        let server = Arc::new(Server::new(
+            Arc::new(Database::in_memory(&StoreConfig::default(), HashMap::new())),
+            DatabaseConfig {
+                limits: LimitsConfig {
+                    max_in_flight: 2,
+                    ..LimitsConfig::default()
+                },
+                ..DatabaseConfig::default()
+            },
+            None,
+        ));
+        let server_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_address = server_socket.local_addr().unwrap();
+        tokio::spawn(run_server(server_socket, Arc::clone(&server)));
+        let client_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let other_client_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        // Requests are dropped while all permits are taken
+        let busy_permits = server.in_flight.acquire_many(2).await.unwrap();
+        client_socket
+            .send_to(b"version", server_address)
+            .await
+            .unwrap();
+        assert_eq!(receive(&client_socket).await, None);
+
+        // Permits are returned once the requests are answered
+        drop(busy_permits);
+        client_socket
+            .send_to(b"version", server_address)
+            .await
+            .unwrap();
+        assert!(receive(&client_socket).await.is_some());
+
+        // A burst of one client only takes a permit at a time, so other clients still get theirs
+        for _ in 0..50 {
+            client_socket
+                .send_to(b"version", server_address)
+                .await
+                .unwrap();
+        }
+        other_client_socket
+            .send_to(b"version", server_address)
+            .await
+            .unwrap();
+        assert!(receive(&other_client_socket).await.is_some());
+        let mut response_count = 0;
+        while receive(&client_socket).await.is_some() {
+            response_count += 1;
+        }
+        assert_eq!(response_count, 50);
+    }
+
+    #[tokio::test]
+    async fn test_queue_limit() {
+        // Primary that never answers, so that forwarded writes stay queued
+        let primary_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let primary_address = primary_listener.local_addr().unwrap();
+        tokio::spawn(async move {
+            let _connection = primary_listener.accept().await.unwrap();
+            std::future::pending::<()>().await;
+        });
+        let config = DatabaseConfig {
+            limits: LimitsConfig {
+                max_queued: 8,
+                ..LimitsConfig::default()
+            },
+            ..DatabaseConfig::default()
+        };
+        let db = Arc::new(Database::open(&config).unwrap());
+        let primary = start_replica(primary_address.to_string(), Arc::clone(&db));
+        let server = Arc::new(Server::new(db, config, Some(primary)));
+        let server_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let server_address = server_socket.local_addr().unwrap();
+        tokio::spawn(run_server(server_socket, Arc::clone(&server)));
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // Every source has its own queue, but the queued requests of all sources are limited
+        let mut clients = Vec::new();
+        for _ in 0..20 {
+            let client_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+            client_socket
+                .send_to(b"foo=bar", server_address)
+                .await
+                .unwrap();
+            clients.push(client_socket);
+        }
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert_eq!(server.queue_slots.available_permits(), 0);
+        assert_eq!(
+            server.in_flight.available_permits(),
+            LimitsConfig::default().max_in_flight - 8
+        );
+
+        let client_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        client_socket
+            .send_to(b"version", server_address)
+            .await
+            .unwrap();
+        assert_eq!(receive(&client_socket).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_response_ratio() {
+        let (client_socket, server_address) = start_server(DatabaseConfig {
+            extensions: HashSet::from([Extension::List]),
+            limits: LimitsConfig {
+                max_response_ratio: Some(3),
+                ..LimitsConfig::default()
+            },
+            ..DatabaseConfig::default()
+        })
+        .await;
+
+        for request in [&b"k=123456789"[..], b"key=1", b"k", b"key"] {
+            client_socket
+                .send_to(request, server_address)
+                .await
+                .unwrap();
+        }
+        // `k=123456789` is too large for a request of a single byte
+        assert_eq!(receive(&client_socket).await, Some(b"key=1".to_vec()));
+        assert_eq!(receive(&client_socket).await, None);
+
+        // Responses of a listing count together
+        client_socket
+            .send_to(b"long_key=", server_address)
+            .await
+            .unwrap();
+        client_socket
+            .send_to(b"!list ", server_address)
+            .await
+            .unwrap();
+        assert_eq!(receive(&client_socket).await, None);
+    }
+
+    /// Every client inserts and retrieves its own keys, waiting for each response before sending
+    /// the next pair. Returns the requests per second and the number of lost or late responses.
+    /// Without a sync policy, the changes are not logged
+    async fn run_store_benchmark(
+        store: StoreConfig,
+        sync_policy: Option<SyncPolicy>,
+        client_count: usize,
+        pair_count: usize,
+    ) -> (f64, usize) {
+        let directory = sync_policy.map(|_| {
+            std::env::temp_dir().join(format!("unusualdb-benchmark-{}", std::process::id()))
+        });
+        if let Some(directory) = &directory {
+            let _ = fs::remove_dir_all(directory);
+        }
+        let (_, server_address) = start_server(DatabaseConfig {
+            store,
+            persistence: PersistenceConfig {
+                directory: directory.clone(),
+                sync_policy: sync_policy.unwrap_or(SyncPolicy::Never),
+                ..PersistenceConfig::default()
+            },
+            log_requests: false,
+            ..DatabaseConfig::default()
+        })
+        .await;
+
+        let start = Instant::now();
+        let clients = (0..client_count).map(|client_number| {
+            tokio::spawn(async move {
+                let client_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+                let mut lost_count = 0;
+                for pair_number in 0..pair_count {
+                    let key = format!("client{client_number}-{}", pair_number % 10);
+                    let insert = format!("{key}={pair_number}");
+                    client_socket
+                        .send_to(insert.as_bytes(), server_address)
+                        .await
+                        .unwrap();
+                    client_socket
+                        .send_to(key.as_bytes(), server_address)
+                        .await
+                        .unwrap();
+                    // A response that is too late counts as lost, as does the one it is taken for
+                    if receive(&client_socket).await != Some(insert.into_bytes()) {
+                        lost_count += 1;
+                    }
+                }
+                lost_count
+            })
+        });
+        let mut lost_count = 0;
+        for client in clients.collect::<Vec<_>>() {
+            lost_count += client.await.unwrap();
+        }
+
+        if let Some(directory) = directory {
+            fs::remove_dir_all(directory).unwrap();
+        }
+
+        let request_count = 2 * client_count * pair_count;
+        (
+            request_count as f64 / start.elapsed().as_secs_f64(),
+            lost_count,
+        )
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_store_benchmark() {
+        for (kind, sync_policy) in [
+            (StoreKind::Mutex, None),
+            (StoreKind::Sharded, None),
+            (StoreKind::Sharded, Some(SyncPolicy::Always)),
+        ] {
+            let store = StoreConfig {
+                kind,
+                ..StoreConfig::default()
+            };
+            let (_, lost_count) = run_store_benchmark(store, sync_policy, 20, 20).await;
+            assert_eq!(lost_count, 0);
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    #[ignore = "benchmark, run with --release --ignored --nocapture"]
+    async fn test_store_benchmark_with_many_clients() {
+        let sync_policies = [
+            None,
+            Some(SyncPolicy::Interval(Duration::from_secs(1))),
+            Some(SyncPolicy::Always),
+        ];
+        for client_count in [10, 100, 500] {
+            for kind in [StoreKind::Mutex, StoreKind::Sharded] {
+                for sync_policy in sync_policies {
+                    let store = StoreConfig {
+                        kind,
+                        ..StoreConfig::default()
+                    };
+                    let (requests_per_second, lost_count) =
+                        run_store_benchmark(store, sync_policy, client_count, 200).await;
+                    let log = match sync_policy {
+                        Some(sync_policy) => format!("log synced {sync_policy:?}"),
+                        None => String::from("no log"),
+                    };
+                    println!(
+                        "{kind:?} store, {log}, {client_count} clients: \
+                         {requests_per_second:.0} requests/s, {lost_count} lost or late responses"
+                    );
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,295 @@
+use std::collections::HashSet;
+use std::str::{self, FromStr};
+use std::time::Duration;
+
+use crate::config::parse_duration;
+
+/// Keys and values are arbitrary bytes, they are not required to be UTF-8
+#[derive(Debug, PartialEq)]
+pub enum QueryType {
+    Insert(Vec<u8>, Vec<u8>),
+    /// Insert of a value that expires after the given time
+    InsertExpiring(Vec<u8>, Vec<u8>, Duration),
+    Retrieve(Vec<u8>),
+    Version,
+    Delete(Vec<u8>),
+    /// Keys that start with the prefix
+    List(Vec<u8>),
+    /// Key, expected value and new value, which only replaces the expected one
+    CompareAndSet(Vec<u8>, Vec<u8>, Vec<u8>),
+    /// Adds the amount to the key's value as a decimal number
+    Increment(Vec<u8>, i64),
+}
+
+impl QueryType {
+    /// Queries that may change the entries
+    pub fn is_write(&self) -> bool {
+        !matches!(
+            self,
+            QueryType::Retrieve(_) | QueryType::List(_) | QueryType::Version
+        )
+    }
+}
+
+/// Commands beyond the spec, which have to be enabled
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum Extension {
+    /// `!ttl <duration> <key>=<value>`
+    Ttl,
+    /// `!delete <key>`
+    Delete,
+    /// `!list <prefix>`
+    List,
+    /// `!cas <key>=<expected value>=<new value>`
+    Cas,
+    /// `!incr <key>` or `!incr <key>=<amount>`
+    Incr,
+}
+
+impl FromStr for Extension {
+    type Err = String;
+
+    fn from_str(extension: &str) -> Result<Self, Self::Err> {
+        match extension.trim().to_lowercase().as_str() {
+            "ttl" => Ok(Extension::Ttl),
+            "delete" => Ok(Extension::Delete),
+            "list" => Ok(Extension::List),
+            "cas" => Ok(Extension::Cas),
+            "incr" => Ok(Extension::Incr),
+            _ => Err(format!("Unknown extension {extension}")),
+        }
+    }
+}
+
+pub fn parse_query(query: &[u8]) -> QueryType {
+    if query == b"version" {
+        QueryType::Version
+    } else if let Some((key, value)) = split_once(query, b'=') {
+        QueryType::Insert(key.to_vec(), value.to_vec())
+    } else {
+        QueryType::Retrieve(query.to_vec())
+    }
+}
+
+/// Extension commands start with `!` and the command's name. Queries that are not a complete
+/// command of an enabled extension are parsed as in the spec.
+pub fn parse_extended_query(query: &[u8], extensions: &HashSet<Extension>) -> QueryType {
+    parse_command(query, extensions).unwrap_or_else(|| parse_query(query))
+}
+
+fn parse_command(query: &[u8], extensions: &HashSet<Extension>) -> Option<QueryType> {
+    let (name, arguments) = split_once(query.strip_prefix(b"!")?, b' ')?;
+    let extension = match name {
+        b"ttl" => Extension::Ttl,
+        b"delete" => Extension::Delete,
+        b"list" => Extension::List,
+        b"cas" => Extension::Cas,
+        b"incr" => Extension::Incr,
+        _ => return None,
+    };
+    if !extensions.contains(&extension) {
+        return None;
+    }
+
+    match extension {
+        Extension::Ttl => parse_expiring_insert(arguments),
+        Extension::Delete => Some(QueryType::Delete(arguments.to_vec())),
+        Extension::List => Some(QueryType::List(arguments.to_vec())),
+        Extension::Cas => {
+            // Expected values cannot contain `=`, new values can
+            let (key, values) = split_once(arguments, b'=')?;
+            let (expected_value, new_value) = split_once(values, b'=')?;
+            Some(QueryType::CompareAndSet(
+                key.to_vec(),
+                expected_value.to_vec(),
+                new_value.to_vec(),
+            ))
+        }
+        Extension::Incr => parse_increment(arguments),
+    }
+}
+
+fn parse_expiring_insert(arguments: &[u8]) -> Option<QueryType> {
+    let (ttl, insert) = split_once(arguments, b' ')?;
+    let ttl = parse_duration(str::from_utf8(ttl).ok()?).filter(|ttl| !ttl.is_zero())?;
+    let (key, value) = split_once(insert, b'=')?;
+    Some(QueryType::InsertExpiring(key.to_vec(), value.to_vec(), ttl))
+}
+
+fn parse_increment(arguments: &[u8]) -> Option<QueryType> {
+    let (key, amount) = match split_once(arguments, b'=') {
+        Some((key, amount)) => (key, str::from_utf8(amount).ok()?.parse().ok()?),
+        None => (arguments, 1),
+    };
+    Some(QueryType::Increment(key.to_vec(), amount))
+}
+
+fn split_once(bytes: &[u8], separator: u8) -> Option<(&[u8], &[u8])> {
+    let separator_index = bytes.iter().position(|byte| *byte == separator)?;
+    Some((&bytes[..separator_index], &bytes[separator_index + 1..]))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_query_parsing() {
+        // Version query
+        assert_eq!(parse_query(b"version"), QueryType::Version);
+
+        // Retrieve query
+        assert_eq!(parse_query(b""), QueryType::Retrieve(Vec::new()));
+        assert_eq!(parse_query(b"foo"), QueryType::Retrieve(b"foo".to_vec()));
+        assert_eq!(
+            parse_query(b"foo_bar"),
+            QueryType::Retrieve(b"foo_bar".to_vec())
+        );
+
+        // Insert Query
+        assert_eq!(
+            parse_query(b"foo=bar"),
+            QueryType::Insert(b"foo".to_vec(), b"bar".to_vec())
+        );
+        assert_eq!(
+            parse_query(b"foo=bar=baz"),
+            QueryType::Insert(b"foo".to_vec(), b"bar=baz".to_vec())
+        );
+        assert_eq!(
+            parse_query(b"foo="),
+            QueryType::Insert(b"foo".to_vec(), Vec::new())
+        );
+        assert_eq!(
+            parse_query(b"foo==="),
+            QueryType::Insert(b"foo".to_vec(), b"==".to_vec())
+        );
+        assert_eq!(
+            parse_query(b"=foo"),
+            QueryType::Insert(Vec::new(), b"foo".to_vec())
+        );
+
+        // Non-UTF-8 bytes are kept as they are
+        assert_eq!(
+            parse_query(b"\xff\xfe=\x00\xc3"),
+            QueryType::Insert(b"\xff\xfe".to_vec(), b"\x00\xc3".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_expiring_insert_parsing() {
+        let ttl = HashSet::from([Extension::Ttl]);
+        assert_eq!(
+            parse_extended_query(b"!ttl 30s foo=bar", &ttl),
+            QueryType::InsertExpiring(b"foo".to_vec(), b"bar".to_vec(), Duration::from_secs(30))
+        );
+        assert_eq!(
+            parse_extended_query(b"!ttl 250ms foo=bar baz=", &ttl),
+            QueryType::InsertExpiring(
+                b"foo".to_vec(),
+                b"bar baz=".to_vec(),
+                Duration::from_millis(250)
+            )
+        );
+
+        // Plain queries are unchanged
+        assert_eq!(
+            parse_extended_query(b"foo=bar", &ttl),
+            QueryType::Insert(b"foo".to_vec(), b"bar".to_vec())
+        );
+        assert_eq!(
+            parse_extended_query(b"!ttl", &ttl),
+            QueryType::Retrieve(b"!ttl".to_vec())
+        );
+        // Incomplete commands as well
+        assert_eq!(
+            parse_extended_query(b"!ttl soon foo=bar", &ttl),
+            QueryType::Insert(b"!ttl soon foo".to_vec(), b"bar".to_vec())
+        );
+        assert_eq!(
+            parse_extended_query(b"!ttl 0s foo=bar", &ttl),
+            QueryType::Insert(b"!ttl 0s foo".to_vec(), b"bar".to_vec())
+        );
+        assert_eq!(
+            parse_extended_query(b"!ttl 30s foo", &ttl),
+            QueryType::Retrieve(b"!ttl 30s foo".to_vec())
+        );
+
+        // Disabled extension
+        assert_eq!(
+            parse_extended_query(b"!ttl 30s foo=bar", &HashSet::new()),
+            QueryType::Insert(b"!ttl 30s foo".to_vec(), b"bar".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_command_parsing() {
+        let extensions = HashSet::from([
+            Extension::Delete,
+            Extension::List,
+            Extension::Cas,
+            Extension::Incr,
+        ]);
+        let parse = |query: &[u8]| parse_extended_query(query, &extensions);
+
+        assert_eq!(parse(b"!delete foo"), QueryType::Delete(b"foo".to_vec()));
+        assert_eq!(parse(b"!list "), QueryType::List(Vec::new()));
+        assert_eq!(parse(b"!list ab c"), QueryType::List(b"ab c".to_vec()));
+        assert_eq!(
+            parse(b"!cas foo=bar=baz=qux"),
+            QueryType::CompareAndSet(b"foo".to_vec(), b"bar".to_vec(), b"baz=qux".to_vec())
+        );
+        assert_eq!(
+            parse(b"!cas foo==bar"),
+            QueryType::CompareAndSet(b"foo".to_vec(), Vec::new(), b"bar".to_vec())
+        );
+        assert_eq!(
+            parse(b"!incr count"),
+            QueryType::Increment(b"count".to_vec(), 1)
+        );
+        assert_eq!(
+            parse(b"!incr count=-20"),
+            QueryType::Increment(b"count".to_vec(), -20)
+        );
+
+        // Incomplete commands are parsed as in the spec
+        assert_eq!(parse(b"!delete"), QueryType::Retrieve(b"!delete".to_vec()));
+        assert_eq!(
+            parse(b"!cas foo=bar"),
+            QueryType::Insert(b"!cas foo".to_vec(), b"bar".to_vec())
+        );
+        assert_eq!(
+            parse(b"!incr count=many"),
+            QueryType::Insert(b"!incr count".to_vec(), b"many".to_vec())
+        );
+        assert_eq!(
+            parse(b"!ttl 30s foo=bar"),
+            QueryType::Insert(b"!ttl 30s foo".to_vec(), b"bar".to_vec())
+        );
+        assert_eq!(parse(b"foo=bar=baz"), parse_query(b"foo=bar=baz"));
+
+        // Disabled extensions leave every query as in the spec
+        for query in [
+            &b"!delete foo"[..],
+            b"!list ",
+            b"!cas foo=bar=baz",
+            b"!incr a=1",
+        ] {
+            assert_eq!(
+                parse_extended_query(query, &HashSet::new()),
+                parse_query(query)
+            );
+        }
+        assert_eq!(
+            parse_query(b"!cas foo=bar=baz"),
+            QueryType::Insert(b"!cas foo".to_vec(), b"bar=baz".to_vec())
+        );
+    }
+
+    #[test]
+    fn test_extension_parsing() {
+        assert_eq!(" TTL ".parse(), Ok(Extension::Ttl));
+        assert_eq!("cas".parse(), Ok(Extension::Cas));
+        assert_eq!("Incr".parse(), Ok(Extension::Incr));
+        assert!("teleport".parse::<Extension>().is_err());
+    }
+}
@@ -0,0 +1,154 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::time::Instant;
+
+/// Sources are only forgotten once there are at least this many
+const MIN_CLEANUP_SIZE: usize = 1024;
+
+#[derive(Debug, PartialEq)]
+pub enum RateLimit {
+    Allowed,
+    /// The request is over the limit, `is_first` if the source's previous request was allowed
+    Exceeded {
+        is_first: bool,
+    },
+}
+
+/// Token bucket per source IP address, which allows bursts of up to one second of requests.
+/// Source ports are ignored, as a flood can easily use many of them.
+pub struct RateLimiter {
+    requests_per_second: f64,
+    buckets: HashMap<IpAddr, Bucket>,
+    cleanup_size: usize,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+    is_exceeded: bool,
+}
+
+impl RateLimiter {
+    pub fn new(requests_per_second: u32) -> Self {
+        RateLimiter {
+            requests_per_second: f64::from(requests_per_second),
+            buckets: HashMap::new(),
+            cleanup_size: MIN_CLEANUP_SIZE,
+        }
+    }
+
+    pub fn check(&mut self, source: IpAddr, now: Instant) -> RateLimit {
+        if self.buckets.len() >= self.cleanup_size {
+            self.remove_idle_sources(now);
+        }
+
+        let requests_per_second = self.requests_per_second;
+        let bucket = self.buckets.entry(source).or_insert(Bucket {
+            tokens: requests_per_second,
+            updated_at: now,
+            is_exceeded: false,
+        });
+        bucket.refill(requests_per_second, now);
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            bucket.is_exceeded = false;
+            RateLimit::Allowed
+        } else {
+            let is_first = !bucket.is_exceeded;
+            bucket.is_exceeded = true;
+            RateLimit::Exceeded { is_first }
+        }
+    }
+
+    /// Forgets the sources whose buckets are full again, they are no different from new sources
+    fn remove_idle_sources(&mut self, now: Instant) {
+        let requests_per_second = self.requests_per_second;
+        self.buckets.retain(|_, bucket| {
+            bucket.refill(requests_per_second, now);
+            bucket.tokens < requests_per_second
+        });
+        self.cleanup_size = (2 * self.buckets.len()).max(MIN_CLEANUP_SIZE);
+    }
+}
+
+impl Bucket {
+    fn refill(&mut self, requests_per_second: f64, now: Instant) {
+        let elapsed = now.saturating_duration_since(self.updated_at).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * requests_per_second).min(requests_per_second);
+        self.updated_at = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn source(number: u8) -> IpAddr {
+        IpAddr::from([10, 0, 0, number])
+    }
+
+    #[test]
+    fn test_rate_limit() {
+        let mut rate_limiter = RateLimiter::new(10);
+        let now = Instant::now();
+
+        // Bursts of one second of requests
+        for _ in 0..10 {
+            assert_eq!(rate_limiter.check(source(1), now), RateLimit::Allowed);
+        }
+        assert_eq!(
+            rate_limiter.check(source(1), now),
+            RateLimit::Exceeded { is_first: true }
+        );
+        assert_eq!(
+            rate_limiter.check(source(1), now),
+            RateLimit::Exceeded { is_first: false }
+        );
+        // Other sources have their own limit
+        assert_eq!(rate_limiter.check(source(2), now), RateLimit::Allowed);
+
+        // One request every 100ms
+        let later = now + Duration::from_millis(100);
+        assert_eq!(rate_limiter.check(source(1), later), RateLimit::Allowed);
+        assert_eq!(
+            rate_limiter.check(source(1), later),
+            RateLimit::Exceeded { is_first: true }
+        );
+
+        // The burst does not grow while the source is idle
+        let much_later = now + Duration::from_secs(60);
+        for _ in 0..10 {
+            assert_eq!(
+                rate_limiter.check(source(1), much_later),
+                RateLimit::Allowed
+            );
+        }
+        assert_eq!(
+            rate_limiter.check(source(1), much_later),
+            RateLimit::Exceeded { is_first: true }
+        );
+    }
+
+    #[test]
+    fn test_idle_sources() {
+        let mut rate_limiter = RateLimiter::new(10);
+        let now = Instant::now();
+        for _ in 0..5 {
+            rate_limiter.check(source(1), now);
+        }
+        for number in 0..4 {
+            for host in 0..=255 {
+                rate_limiter.check(IpAddr::from([10, 0, number, host]), now);
+            }
+        }
+        assert_eq!(rate_limiter.buckets.len(), MIN_CLEANUP_SIZE);
+
+        // Only sources whose buckets are not full again are kept
+        let later = now + Duration::from_millis(100);
+        rate_limiter.check(source(2), later);
+        assert_eq!(rate_limiter.buckets.len(), 2);
+        assert!(rate_limiter.buckets.contains_key(&source(1)));
+    }
+}
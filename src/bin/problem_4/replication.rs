@@ -0,0 +1,579 @@
+use std::collections::HashMap;
+use std::io::{Error as IO_Error, ErrorKind, Result as IO_Result};
+use std::mem;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bytes::Bytes;
+use futures::{SinkExt, StreamExt};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::broadcast::error::{RecvError, TryRecvError};
+use tokio::sync::{mpsc, oneshot};
+use tokio_util::codec::{Framed, LengthDelimitedCodec};
+
+use crate::database::Database;
+use crate::query::parse_extended_query;
+use crate::store::{Change, Entries};
+use crate::wal::{decode_record, encode_change};
+use crate::{handle_request, rejection_responses, Server};
+
+/// Entries per snapshot message
+const SNAPSHOT_PART_SIZE: usize = 1000;
+/// Time between attempts of a replica to connect to its primary
+const RECONNECT_DELAY: Duration = Duration::from_millis(500);
+/// Writes of replica clients that wait for being forwarded
+const FORWARD_QUEUE_SIZE: usize = 1024;
+
+/// Messages between a primary and a replica, changes use the record format of the log
+#[derive(Debug, PartialEq)]
+enum Message {
+    /// Part of the entries of the primary when the replica connected
+    Snapshot(Vec<Change>),
+    /// All entries were sent, the changes after them follow
+    SnapshotEnd,
+    Change(Change),
+    /// Write request of a replica's client, with an id for the responses
+    Forward(u64, Vec<u8>),
+    /// Responses to a forwarded request, sent after the request's changes
+    Forwarded(u64, Vec<Vec<u8>>),
+}
+
+// Message -> First byte
+const SNAPSHOT_TAG: u8 = b'S';
+const SNAPSHOT_END_TAG: u8 = b'E';
+const CHANGE_TAG: u8 = b'C';
+const FORWARD_TAG: u8 = b'F';
+const FORWARDED_TAG: u8 = b'R';
+
+fn encode_message(message: &Message) -> Bytes {
+    let mut encoded_message = Vec::new();
+    match message {
+        Message::Snapshot(changes) => {
+            encoded_message.push(SNAPSHOT_TAG);
+            for change in changes {
+                encoded_message.extend(encode_change(change));
+            }
+        }
+        Message::SnapshotEnd => encoded_message.push(SNAPSHOT_END_TAG),
+        Message::Change(change) => {
+            encoded_message.push(CHANGE_TAG);
+            encoded_message.extend(encode_change(change));
+        }
+        Message::Forward(id, request) => {
+            encoded_message.push(FORWARD_TAG);
+            encoded_message.extend_from_slice(&id.to_le_bytes());
+            encoded_message.extend_from_slice(request);
+        }
+        Message::Forwarded(id, responses) => {
+            encoded_message.push(FORWARDED_TAG);
+            encoded_message.extend_from_slice(&id.to_le_bytes());
+            for response in responses {
+                encoded_message.extend_from_slice(&(response.len() as u32).to_le_bytes());
+                encoded_message.extend_from_slice(response);
+            }
+        }
+    }
+    Bytes::from(encoded_message)
+}
+
+fn decode_message(encoded_message: &[u8]) -> Option<Message> {
+    let (tag, body) = encoded_message.split_first()?;
+    match *tag {
+        SNAPSHOT_TAG => {
+            let mut changes = Vec::new();
+            let mut records = body;
+            while !records.is_empty() {
+                let (change, record_size) = decode_record(records)?;
+                changes.push(change);
+                records = &records[record_size..];
+            }
+            Some(Message::Snapshot(changes))
+        }
+        SNAPSHOT_END_TAG if body.is_empty() => Some(Message::SnapshotEnd),
+        CHANGE_TAG => {
+            let (change, record_size) = decode_record(body)?;
+            (record_size == body.len()).then_some(Message::Change(change))
+        }
+        FORWARD_TAG => {
+            let (id, request) = split_id(body)?;
+            Some(Message::Forward(id, request.to_vec()))
+        }
+        FORWARDED_TAG => {
+            let (id, mut encoded_responses) = split_id(body)?;
+            let mut responses = Vec::new();
+            while !encoded_responses.is_empty() {
+                let (size, rest) = encoded_responses.split_first_chunk::<4>()?;
+                let size = u32::from_le_bytes(*size) as usize;
+                responses.push(rest.get(..size)?.to_vec());
+                encoded_responses = &rest[size..];
+            }
+            Some(Message::Forwarded(id, responses))
+        }
+        _ => None,
+    }
+}
+
+fn split_id(body: &[u8]) -> Option<(u64, &[u8])> {
+    let (id, rest) = body.split_first_chunk::<8>()?;
+    Some((u64::from_le_bytes(*id), rest))
+}
+
+type Link = Framed<TcpStream, LengthDelimitedCodec>;
+
+async fn send_message(link: &mut Link, message: &Message) -> IO_Result<()> {
+    link.send(encode_message(message)).await
+}
+
+/// `None` once the other side closed the link
+async fn receive_message(link: &mut Link) -> IO_Result<Option<Message>> {
+    let Some(encoded_message) = link.next().await.transpose()? else {
+        return Ok(None);
+    };
+    decode_message(&encoded_message)
+        .map(Some)
+        .ok_or_else(|| IO_Error::new(ErrorKind::InvalidData, "Invalid replication message"))
+}
+
+/// Streams the entries and every change after them to the replicas that connect
+pub async fn run_primary(replication_listener: TcpListener, server: Arc<Server>) -> IO_Result<()> {
+    loop {
+        let (tcp_stream, replica_address) = replication_listener.accept().await?;
+        let replica_ip = replica_address.ip().to_canonical();
+        if !server
+            .config
+            .replication
+            .replica_addresses
+            .contains(&replica_ip)
+        {
+            println!("[Replication] Refused replica {replica_address}");
+            continue;
+        }
+        let server = Arc::clone(&server);
+        tokio::spawn(async move {
+            println!("[Replication] Replica {replica_address} connected");
+            match serve_replica(tcp_stream, replica_address, &server).await {
+                Ok(()) => println!("[Replication] Replica {replica_address} disconnected"),
+                Err(e) => println!("[Replication] Lost replica {replica_address}: {e}"),
+            }
+        });
+    }
+}
+
+async fn serve_replica(
+    tcp_stream: TcpStream,
+    replica_address: SocketAddr,
+    server: &Server,
+) -> IO_Result<()> {
+    let Some((entries, mut changes)) = server.db.subscribe() else {
+        return Err(IO_Error::other("The database is not a primary"));
+    };
+    let mut link = Framed::new(tcp_stream, LengthDelimitedCodec::new());
+
+    let mut snapshot_part = Vec::new();
+    for (key, entry) in entries {
+        snapshot_part.push(Change::Insert(key, entry));
+        if snapshot_part.len() == SNAPSHOT_PART_SIZE {
+            send_message(&mut link, &Message::Snapshot(mem::take(&mut snapshot_part))).await?;
+        }
+    }
+    send_message(&mut link, &Message::Snapshot(snapshot_part)).await?;
+    send_message(&mut link, &Message::SnapshotEnd).await?;
+
+    loop {
+        tokio::select! {
+            change = changes.recv() => match change {
+                Ok(change) => send_message(&mut link, &Message::Change(change)).await?,
+                Err(RecvError::Lagged(_)) => return Err(lagged_error()),
+                Err(RecvError::Closed) => return Ok(()),
+            },
+            message = receive_message(&mut link) => {
+                let Some(message) = message? else {
+                    return Ok(());
+                };
+                let Message::Forward(id, request) = message else {
+                    return Err(IO_Error::new(ErrorKind::InvalidData, "Unexpected message"));
+                };
+                // Forwarded writes count against the limits like the replica's own requests,
+                // rejected ones are answered so that the replica's client is not left waiting
+                let responses = if server.exceeds_rate_limit(replica_address) {
+                    rejection_responses(parse_extended_query(&request, &server.config.extensions))
+                } else {
+                    let _in_flight_permit =
+                        server.in_flight.acquire().await.map_err(IO_Error::other)?;
+                    handle_request(&request, replica_address, server).await
+                };
+                // The replica applies the request's changes before it answers its client
+                loop {
+                    match changes.try_recv() {
+                        Ok(change) => send_message(&mut link, &Message::Change(change)).await?,
+                        Err(TryRecvError::Empty | TryRecvError::Closed) => break,
+                        Err(TryRecvError::Lagged(_)) => return Err(lagged_error()),
+                    }
+                }
+                send_message(&mut link, &Message::Forwarded(id, responses)).await?;
+            }
+        }
+    }
+}
+
+/// The replica resynchronises once it reconnects
+fn lagged_error() -> IO_Error {
+    IO_Error::other("Replica fell behind")
+}
+
+type ForwardedRequest = (Vec<u8>, oneshot::Sender<Vec<Vec<u8>>>);
+
+/// Connection of a replica to its primary, which writes of the replica's clients are forwarded to
+pub struct PrimaryLink {
+    forwards: mpsc::Sender<ForwardedRequest>,
+}
+
+impl PrimaryLink {
+    /// Responses of the primary to the request, `None` if the primary is unreachable
+    pub async fn forward(&self, request: &[u8]) -> Option<Vec<Vec<u8>>> {
+        let (responses, responses_receiver) = oneshot::channel();
+        self.forwards
+            .send((request.to_vec(), responses))
+            .await
+            .ok()?;
+        responses_receiver.await.ok()
+    }
+}
+
+/// Keeps the database in sync with the primary, reconnecting and resynchronising from a snapshot
+/// whenever the connection is lost
+pub fn start_replica(primary_address: String, db: Arc<Database>) -> PrimaryLink {
+    let (forwards, mut forward_receiver) = mpsc::channel(FORWARD_QUEUE_SIZE);
+    tokio::spawn(async move {
+        loop {
+            match TcpStream::connect(&primary_address).await {
+                Ok(tcp_stream) => {
+                    println!("[Replication] Connected to primary {primary_address}");
+                    if let Err(e) = follow_primary(tcp_stream, &db, &mut forward_receiver).await {
+                        println!("[Replication] Lost primary {primary_address}: {e}");
+                    }
+                }
+                Err(e) => {
+                    println!("[Replication] Failed to connect to primary {primary_address}: {e}")
+                }
+            }
+
+            // Writes are rejected until the primary is reachable again
+            let reconnect = tokio::time::sleep(RECONNECT_DELAY);
+            tokio::pin!(reconnect);
+            loop {
+                tokio::select! {
+                    _ = &mut reconnect => break,
+                    forwarded_request = forward_receiver.recv() => {
+                        if forwarded_request.is_none() {
+                            // The server is gone
+                            return;
+                        }
+                    }
+                }
+            }
+        }
+    });
+    PrimaryLink { forwards }
+}
+
+async fn follow_primary(
+    tcp_stream: TcpStream,
+    db: &Database,
+    forward_receiver: &mut mpsc::Receiver<ForwardedRequest>,
+) -> IO_Result<()> {
+    let mut link = Framed::new(tcp_stream, LengthDelimitedCodec::new());
+    let mut snapshot = Entries::new();
+    let mut pending_forwards: HashMap<u64, oneshot::Sender<Vec<Vec<u8>>>> = HashMap::new();
+    let mut next_forward_id = 0;
+
+    loop {
+        tokio::select! {
+            message = receive_message(&mut link) => {
+                let Some(message) = message? else {
+                    return Err(IO_Error::from(ErrorKind::ConnectionAborted));
+                };
+                match message {
+                    Message::Snapshot(changes) => {
+                        for change in changes {
+                            change.apply(&mut snapshot);
+                        }
+                    }
+                    Message::SnapshotEnd => {
+                        println!("[Replication] Synchronised {} entries", snapshot.len());
+                        db.replace_entries(mem::take(&mut snapshot));
+                    }
+                    Message::Change(change) => db.apply(change),
+                    Message::Forwarded(id, responses) => {
+                        if let Some(responses_sender) = pending_forwards.remove(&id) {
+                            // The client may have stopped waiting
+                            let _ = responses_sender.send(responses);
+                        }
+                    }
+                    Message::Forward(..) => {
+                        return Err(IO_Error::new(ErrorKind::InvalidData, "Unexpected message"));
+                    }
+                }
+            }
+            Some((request, responses_sender)) = forward_receiver.recv() => {
+                next_forward_id += 1;
+                pending_forwards.insert(next_forward_id, responses_sender);
+                send_message(&mut link, &Message::Forward(next_forward_id, request)).await?;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashSet;
+
+    use tokio::io::copy_bidirectional;
+    use tokio::net::UdpSocket;
+    use tokio::task::JoinHandle;
+    use tokio::time::{sleep, timeout, Instant};
+
+    use super::*;
+    use crate::config::{DatabaseConfig, ReplicationConfig};
+    use crate::query::Extension;
+    use crate::run_server;
+    use crate::store::Entry;
+
+    fn config(replication: ReplicationConfig) -> DatabaseConfig {
+        DatabaseConfig {
+            extensions: HashSet::from([Extension::Delete, Extension::Incr]),
+            replication,
+            ..DatabaseConfig::default()
+        }
+    }
+
+    async fn start_udp_server(server: Arc<Server>) -> SocketAddr {
+        let udp_socket = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        let udp_address = udp_socket.local_addr().unwrap();
+        tokio::spawn(run_server(udp_socket, server));
+        udp_address
+    }
+
+    fn primary_config() -> DatabaseConfig {
+        config(ReplicationConfig {
+            // Only enables replication, the listener is bound below
+            listen_port: Some(0),
+            ..ReplicationConfig::default()
+        })
+    }
+
+    /// Returns the UDP address and the replication address
+    async fn start_primary(
+        config: DatabaseConfig,
+        entries: &[(&[u8], &[u8])],
+    ) -> (SocketAddr, SocketAddr) {
+        let db = Arc::new(Database::open(&config).unwrap());
+        for (key, value) in entries {
+            db.insert(key.to_vec(), Entry::permanent(value.to_vec()));
+        }
+        let server = Arc::new(Server::new(db, config, None));
+
+        let replication_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let replication_address = replication_listener.local_addr().unwrap();
+        tokio::spawn(run_primary(replication_listener, Arc::clone(&server)));
+        (start_udp_server(server).await, replication_address)
+    }
+
+    /// Returns the UDP address
+    async fn start_replica_server(primary_address: SocketAddr) -> SocketAddr {
+        let config = config(ReplicationConfig {
+            primary_address: Some(primary_address.to_string()),
+            ..ReplicationConfig::default()
+        });
+        let db = Arc::new(Database::open(&config).unwrap());
+        let primary = start_replica(primary_address.to_string(), Arc::clone(&db));
+        start_udp_server(Arc::new(Server::new(db, config, Some(primary)))).await
+    }
+
+    /// Local link between a replica and the primary, which is cut by aborting the task
+    fn start_link(link_listener: TcpListener, primary_address: SocketAddr) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            loop {
+                let (mut replica_stream, _) = link_listener.accept().await.unwrap();
+                let mut primary_stream = TcpStream::connect(primary_address).await.unwrap();
+                let _ = copy_bidirectional(&mut replica_stream, &mut primary_stream).await;
+            }
+        })
+    }
+
+    async fn request(client: &UdpSocket, address: SocketAddr, request: &[u8]) -> Option<Vec<u8>> {
+        client.send_to(request, address).await.unwrap();
+        let mut buffer = [0; 1024];
+        let (size, _) = timeout(Duration::from_millis(300), client.recv_from(&mut buffer))
+            .await
+            .ok()?
+            .unwrap();
+        Some(buffer[..size].to_vec())
+    }
+
+    /// Retrieves the key until the replica has the expected value
+    async fn wait_for_value(client: &UdpSocket, address: SocketAddr, key: &[u8], value: &[u8]) {
+        let expected_response = [key, b"=", value].concat();
+        let start = Instant::now();
+        while request(client, address, key).await.as_ref() != Some(&expected_response) {
+            assert!(
+                start.elapsed() < Duration::from_secs(5),
+                "{} was not replicated",
+                expected_response.escape_ascii()
+            );
+            sleep(Duration::from_millis(20)).await;
+        }
+    }
+
+    #[test]
+    fn test_message_encoding() {
+        let messages = [
+            Message::Snapshot(vec![
+                Change::Insert(b"foo".to_vec(), Entry::permanent(b"bar".to_vec())),
+                Change::Insert(b"\xff".to_vec(), Entry::permanent(Vec::new())),
+            ]),
+            Message::Snapshot(Vec::new()),
+            Message::SnapshotEnd,
+            Message::Change(Change::Delete(b"foo".to_vec())),
+            Message::Forward(7, b"foo=bar".to_vec()),
+            Message::Forwarded(7, Vec::new()),
+            Message::Forwarded(u64::MAX, vec![b"a=1".to_vec(), Vec::new()]),
+        ];
+        for message in messages {
+            assert_eq!(decode_message(&encode_message(&message)), Some(message));
+        }
+
+        assert_eq!(decode_message(b""), None);
+        assert_eq!(decode_message(b"X"), None);
+        assert_eq!(decode_message(b"Efoo"), None);
+        assert_eq!(decode_message(b"F1234"), None);
+        let mut change = encode_message(&Message::Change(Change::Delete(b"foo".to_vec()))).to_vec();
+        change.push(0);
+        assert_eq!(decode_message(&change), None);
+    }
+
+    #[tokio::test]
+    async fn test_replication() {
+        let (primary_address, replication_address) =
+            start_primary(primary_config(), &[(b"foo", b"bar")]).await;
+        let replica_address = start_replica_server(replication_address).await;
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        // Entries from before the replica connected and inserts after it
+        wait_for_value(&client, replica_address, b"foo", b"bar").await;
+        client.send_to(b"abc=1", primary_address).await.unwrap();
+        wait_for_value(&client, replica_address, b"abc", b"1").await;
+
+        // Writes to the replica are forwarded, and the replica has them once they are answered
+        client.send_to(b"abc=2", replica_address).await.unwrap();
+        assert_eq!(
+            request(&client, replica_address, b"abc").await,
+            Some(b"abc=2".to_vec())
+        );
+        assert_eq!(
+            request(&client, replica_address, b"!incr count").await,
+            Some(b"count=1".to_vec())
+        );
+        client
+            .send_to(b"!delete foo", replica_address)
+            .await
+            .unwrap();
+        assert_eq!(request(&client, replica_address, b"foo").await, None);
+
+        assert_eq!(
+            request(&client, primary_address, b"abc").await,
+            Some(b"abc=2".to_vec())
+        );
+        assert_eq!(
+            request(&client, primary_address, b"count").await,
+            Some(b"count=1".to_vec())
+        );
+        assert_eq!(request(&client, primary_address, b"foo").await, None);
+    }
+
+    #[tokio::test]
+    async fn test_resynchronisation() {
+        let (primary_address, replication_address) =
+            start_primary(primary_config(), &[(b"foo", b"bar"), (b"abc", b"1")]).await;
+        let link_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let link_address = link_listener.local_addr().unwrap();
+        let link = start_link(link_listener, replication_address);
+        let replica_address = start_replica_server(link_address).await;
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+        wait_for_value(&client, replica_address, b"abc", b"1").await;
+
+        link.abort();
+        let _ = link.await;
+        // Changes the replica misses
+        for change in [&b"foo=baz"[..], b"new=1", b"!delete abc"] {
+            client.send_to(change, primary_address).await.unwrap();
+        }
+        // Writes are rejected without the primary
+        assert_eq!(
+            request(&client, replica_address, b"!incr count").await,
+            Some(b"!incr failed count".to_vec())
+        );
+        assert_eq!(
+            request(&client, replica_address, b"foo").await,
+            Some(b"foo=bar".to_vec())
+        );
+
+        let link = start_link(
+            TcpListener::bind(link_address).await.unwrap(),
+            replication_address,
+        );
+        wait_for_value(&client, replica_address, b"foo", b"baz").await;
+        assert_eq!(
+            request(&client, replica_address, b"new").await,
+            Some(b"new=1".to_vec())
+        );
+        assert_eq!(request(&client, replica_address, b"abc").await, None);
+        assert_eq!(request(&client, primary_address, b"count").await, None);
+        link.abort();
+    }
+
+    #[tokio::test]
+    async fn test_refused_replica() {
+        let mut config = primary_config();
+        config.replication.replica_addresses = vec!["192.0.2.1".parse().unwrap()];
+        let (primary_address, replication_address) =
+            start_primary(config, &[(b"foo", b"bar")]).await;
+        let replica_address = start_replica_server(replication_address).await;
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        sleep(Duration::from_millis(200)).await;
+        assert_eq!(request(&client, replica_address, b"foo").await, None);
+        assert_eq!(
+            request(&client, replica_address, b"!incr count").await,
+            Some(b"!incr failed count".to_vec())
+        );
+        assert_eq!(request(&client, primary_address, b"count").await, None);
+    }
+
+    #[tokio::test]
+    async fn test_forwarded_write_limits() {
+        let mut config = primary_config();
+        config.limits.rate_limit = Some(2);
+        let (_, replication_address) = start_primary(config, &[]).await;
+        let replica_address = start_replica_server(replication_address).await;
+        let client = UdpSocket::bind("127.0.0.1:0").await.unwrap();
+
+        // The writes of all the replica's clients count against the replica's address
+        for count in 1..=2 {
+            assert_eq!(
+                request(&client, replica_address, b"!incr count").await,
+                Some(format!("count={count}").into_bytes())
+            );
+        }
+        assert_eq!(
+            request(&client, replica_address, b"!incr count").await,
+            Some(b"!incr failed count".to_vec())
+        );
+        // Reads are answered by the replica
+        assert_eq!(
+            request(&client, replica_address, b"count").await,
+            Some(b"count=2".to_vec())
+        );
+    }
+}
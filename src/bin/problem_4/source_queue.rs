@@ -0,0 +1,170 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use tokio::sync::oneshot::{self, error::TryRecvError};
+
+/// Sources are only forgotten once there are at least this many
+const MIN_CLEANUP_SIZE: usize = 1024;
+/// Requests of a single source that may wait for their turn
+const MAX_QUEUED_REQUESTS: usize = 64;
+
+/// Keeps the requests of each source in their order of arrival, while requests of different
+/// sources run concurrently. Every request waits until the previous request of its source is
+/// finished.
+pub struct SourceQueues {
+    last_requests: HashMap<SocketAddr, LastRequest>,
+    cleanup_size: usize,
+}
+
+struct LastRequest {
+    /// Signal of the last request of the source that it is finished
+    finished: oneshot::Receiver<()>,
+    /// Shared by the unfinished requests of the source, to count them
+    queue: Arc<()>,
+}
+
+/// Place of a request in the queue of its source
+pub struct QueuedRequest {
+    previous_request: Option<oneshot::Receiver<()>>,
+    finished: oneshot::Sender<()>,
+    _queue: Arc<()>,
+}
+
+impl SourceQueues {
+    pub fn new() -> Self {
+        SourceQueues {
+            last_requests: HashMap::new(),
+            cleanup_size: MIN_CLEANUP_SIZE,
+        }
+    }
+
+    /// Returns `None` if too many requests of the source are unfinished already, so that a single
+    /// source cannot pile up waiting requests
+    pub fn enqueue(&mut self, source: SocketAddr) -> Option<QueuedRequest> {
+        if self.last_requests.len() >= self.cleanup_size {
+            self.remove_idle_sources();
+        }
+
+        let queue = match self.last_requests.get(&source) {
+            // The map holds a reference of its own
+            Some(last_request) if Arc::strong_count(&last_request.queue) > MAX_QUEUED_REQUESTS => {
+                return None;
+            }
+            Some(last_request) => Arc::clone(&last_request.queue),
+            None => Arc::new(()),
+        };
+        let (finished, finished_receiver) = oneshot::channel();
+        let last_request = LastRequest {
+            finished: finished_receiver,
+            queue: Arc::clone(&queue),
+        };
+        Some(QueuedRequest {
+            previous_request: self
+                .last_requests
+                .insert(source, last_request)
+                .map(|previous_request| previous_request.finished),
+            finished,
+            _queue: queue,
+        })
+    }
+
+    /// Forgets the sources without unfinished requests
+    fn remove_idle_sources(&mut self) {
+        self.last_requests.retain(|_, last_request| {
+            matches!(last_request.finished.try_recv(), Err(TryRecvError::Empty))
+        });
+        self.cleanup_size = (2 * self.last_requests.len()).max(MIN_CLEANUP_SIZE);
+    }
+}
+
+impl QueuedRequest {
+    /// Waits for the previous request of the source
+    pub async fn wait_for_turn(&mut self) {
+        if let Some(previous_request) = self.previous_request.take() {
+            // A failed previous request is finished as well
+            let _ = previous_request.await;
+        }
+    }
+
+    /// Lets the next request of the source run
+    pub fn finish(self) {
+        let _ = self.finished.send(());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+    use std::time::Duration;
+
+    use super::*;
+
+    fn source(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[tokio::test]
+    async fn test_request_order() {
+        let mut source_queues = SourceQueues::new();
+        let executed = Arc::new(Mutex::new(Vec::new()));
+
+        let mut handles = Vec::new();
+        for (number, port) in [(1, 1), (2, 2), (3, 1), (4, 1), (5, 2)] {
+            let mut queued_request = source_queues.enqueue(source(port)).unwrap();
+            let executed = Arc::clone(&executed);
+            handles.push(tokio::spawn(async move {
+                queued_request.wait_for_turn().await;
+                // Earlier requests take longer
+                tokio::time::sleep(Duration::from_millis(50 - 10 * number)).await;
+                executed.lock().unwrap().push((port, number));
+                queued_request.finish();
+            }));
+        }
+        for handle in handles {
+            handle.await.unwrap();
+        }
+
+        let executed = executed.lock().unwrap();
+        let source_order = |port| -> Vec<u64> {
+            executed
+                .iter()
+                .filter(|(source_port, _)| *source_port == port)
+                .map(|(_, number)| *number)
+                .collect()
+        };
+        assert_eq!(source_order(1), [1, 3, 4]);
+        assert_eq!(source_order(2), [2, 5]);
+    }
+
+    #[tokio::test]
+    async fn test_idle_sources() {
+        let mut source_queues = SourceQueues::new();
+        let busy_request = source_queues.enqueue(source(0)).unwrap();
+        for port in 1..MIN_CLEANUP_SIZE as u16 {
+            source_queues.enqueue(source(port)).unwrap().finish();
+        }
+        assert_eq!(source_queues.last_requests.len(), MIN_CLEANUP_SIZE);
+
+        // Only sources with unfinished requests are kept
+        source_queues.enqueue(source(5000)).unwrap().finish();
+        assert_eq!(source_queues.last_requests.len(), 2);
+        assert!(source_queues.last_requests.contains_key(&source(0)));
+        busy_request.finish();
+    }
+
+    #[test]
+    fn test_queue_limit() {
+        let mut source_queues = SourceQueues::new();
+        let mut queued_requests: Vec<QueuedRequest> = (0..MAX_QUEUED_REQUESTS)
+            .map(|_| source_queues.enqueue(source(1)).unwrap())
+            .collect();
+        assert!(source_queues.enqueue(source(1)).is_none());
+        // Other sources are not affected
+        assert!(source_queues.enqueue(source(2)).is_some());
+
+        // Finished requests make room again
+        queued_requests.remove(0).finish();
+        assert!(source_queues.enqueue(source(1)).is_some());
+    }
+}
@@ -0,0 +1,560 @@
+use std::collections::hash_map::RandomState;
+use std::collections::{BTreeSet, HashMap};
+use std::hash::BuildHasher;
+use std::mem;
+use std::str::FromStr;
+use std::sync::{Mutex, MutexGuard};
+use std::time::SystemTime;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Entry {
+    pub value: Vec<u8>,
+    /// Expired entries behave like missing ones, entries without an expiry time are kept forever
+    pub expires_at: Option<SystemTime>,
+}
+
+impl Entry {
+    pub fn permanent(value: Vec<u8>) -> Self {
+        Entry {
+            value,
+            expires_at: None,
+        }
+    }
+
+    fn is_expired(&self, now: SystemTime) -> bool {
+        self.expires_at.is_some_and(|expires_at| expires_at <= now)
+    }
+}
+
+pub type Entries = HashMap<Vec<u8>, Entry>;
+
+/// Change of a single key, as it is written to the log
+#[derive(Clone, Debug, PartialEq)]
+pub enum Change {
+    Insert(Vec<u8>, Entry),
+    Delete(Vec<u8>),
+}
+
+impl Change {
+    pub fn apply(self, entries: &mut Entries) {
+        match self {
+            Change::Insert(key, entry) => {
+                entries.insert(key, entry);
+            }
+            Change::Delete(key) => {
+                entries.remove(&key);
+            }
+        }
+    }
+}
+
+/// Computes the new value from the current value of a key, `None` leaves the key unchanged
+pub type Update<'a> = &'a mut dyn FnMut(Option<&[u8]>) -> Option<Vec<u8>>;
+
+/// Entries shared by all requests
+pub trait KeyValueStore: Send + Sync {
+    /// Value of the key, unless it is missing or expired
+    fn get(&self, key: &[u8], now: SystemTime) -> Option<Vec<u8>>;
+
+    /// Replaces the entry of the key, including its expiry time
+    fn insert(&self, key: Vec<u8>, entry: Entry);
+
+    /// Removes the entry of the key, returns whether it had a value
+    fn remove(&self, key: &[u8], now: SystemTime) -> bool;
+
+    /// Replaces the value of the key with the result of the update, which sees the current value
+    /// under the same lock. The expiry time is kept. Returns the new entry, if there is one.
+    fn update(&self, key: &[u8], now: SystemTime, update: Update) -> Option<Entry>;
+
+    /// Keys that start with the prefix, in no particular order
+    fn keys_with_prefix(&self, prefix: &[u8], now: SystemTime) -> Vec<Vec<u8>>;
+
+    /// Removes all expired entries and returns their number
+    fn remove_expired(&self, now: SystemTime) -> usize;
+
+    /// Copy of all entries
+    fn entries(&self) -> Entries;
+
+    /// Replaces all entries at once, readers see either the previous or the new entries. Returns
+    /// the previous entries.
+    fn replace_entries(&self, entries: Entries, now: SystemTime) -> Entries;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum StoreKind {
+    /// A single map behind a single lock
+    Mutex,
+    /// Keys are spread over several maps with their own locks
+    Sharded,
+}
+
+impl FromStr for StoreKind {
+    type Err = String;
+
+    fn from_str(store_kind: &str) -> Result<Self, Self::Err> {
+        match store_kind.trim().to_lowercase().as_str() {
+            "mutex" => Ok(StoreKind::Mutex),
+            "sharded" => Ok(StoreKind::Sharded),
+            _ => Err(format!("Unknown store {store_kind}")),
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct StoreConfig {
+    pub kind: StoreKind,
+    /// Number of maps of the sharded store
+    pub shard_count: usize,
+}
+
+impl Default for StoreConfig {
+    fn default() -> Self {
+        StoreConfig {
+            kind: StoreKind::Sharded,
+            shard_count: 16,
+        }
+    }
+}
+
+/// Leaves out the entries that expired already
+pub fn create_store(
+    config: &StoreConfig,
+    entries: Entries,
+    now: SystemTime,
+) -> Box<dyn KeyValueStore> {
+    match config.kind {
+        StoreKind::Mutex => Box::new(MutexStore(Mutex::new(Store::new(entries, now)))),
+        StoreKind::Sharded => Box::new(ShardedStore::new(config.shard_count, entries, now)),
+    }
+}
+
+pub struct MutexStore(Mutex<Store>);
+
+impl KeyValueStore for MutexStore {
+    fn get(&self, key: &[u8], now: SystemTime) -> Option<Vec<u8>> {
+        self.0.lock().unwrap().get(key, now).map(<[u8]>::to_vec)
+    }
+
+    fn insert(&self, key: Vec<u8>, entry: Entry) {
+        self.0.lock().unwrap().insert(key, entry);
+    }
+
+    fn remove(&self, key: &[u8], now: SystemTime) -> bool {
+        self.0.lock().unwrap().remove(key, now)
+    }
+
+    fn update(&self, key: &[u8], now: SystemTime, update: Update) -> Option<Entry> {
+        self.0.lock().unwrap().update(key, now, update)
+    }
+
+    fn keys_with_prefix(&self, prefix: &[u8], now: SystemTime) -> Vec<Vec<u8>> {
+        self.0.lock().unwrap().keys_with_prefix(prefix, now)
+    }
+
+    fn remove_expired(&self, now: SystemTime) -> usize {
+        self.0.lock().unwrap().remove_expired(now)
+    }
+
+    fn entries(&self) -> Entries {
+        self.0.lock().unwrap().entries().clone()
+    }
+
+    fn replace_entries(&self, entries: Entries, now: SystemTime) -> Entries {
+        let mut store = self.0.lock().unwrap();
+        mem::replace(&mut *store, Store::new(entries, now)).entries
+    }
+}
+
+/// Requests for keys in different shards do not wait for each other
+pub struct ShardedStore {
+    shards: Vec<Mutex<Store>>,
+    hasher: RandomState,
+}
+
+impl ShardedStore {
+    fn new(shard_count: usize, entries: Entries, now: SystemTime) -> Self {
+        let mut sharded_store = ShardedStore {
+            shards: Vec::new(),
+            hasher: RandomState::new(),
+        };
+        let mut shard_entries = vec![Entries::new(); shard_count.max(1)];
+        for (key, entry) in entries {
+            let shard_index = sharded_store.shard_index(&key, shard_entries.len());
+            shard_entries[shard_index].insert(key, entry);
+        }
+        sharded_store.shards = shard_entries
+            .into_iter()
+            .map(|entries| Mutex::new(Store::new(entries, now)))
+            .collect();
+        sharded_store
+    }
+
+    fn shard_index(&self, key: &[u8], shard_count: usize) -> usize {
+        (self.hasher.hash_one(key) % shard_count as u64) as usize
+    }
+
+    fn shard(&self, key: &[u8]) -> &Mutex<Store> {
+        &self.shards[self.shard_index(key, self.shards.len())]
+    }
+
+    /// Locks every shard, so that requests that look at all shards see no replacement halfway.
+    /// Other requests only ever lock a single shard -> No deadlock.
+    fn lock_all_shards(&self) -> Vec<MutexGuard<'_, Store>> {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap())
+            .collect()
+    }
+}
+
+impl KeyValueStore for ShardedStore {
+    fn get(&self, key: &[u8], now: SystemTime) -> Option<Vec<u8>> {
+        self.shard(key)
+            .lock()
+            .unwrap()
+            .get(key, now)
+            .map(<[u8]>::to_vec)
+    }
+
+    fn insert(&self, key: Vec<u8>, entry: Entry) {
+        self.shard(&key).lock().unwrap().insert(key, entry);
+    }
+
+    fn remove(&self, key: &[u8], now: SystemTime) -> bool {
+        self.shard(key).lock().unwrap().remove(key, now)
+    }
+
+    fn update(&self, key: &[u8], now: SystemTime, update: Update) -> Option<Entry> {
+        self.shard(key).lock().unwrap().update(key, now, update)
+    }
+
+    fn keys_with_prefix(&self, prefix: &[u8], now: SystemTime) -> Vec<Vec<u8>> {
+        self.lock_all_shards()
+            .iter()
+            .flat_map(|shard| shard.keys_with_prefix(prefix, now))
+            .collect()
+    }
+
+    fn remove_expired(&self, now: SystemTime) -> usize {
+        self.shards
+            .iter()
+            .map(|shard| shard.lock().unwrap().remove_expired(now))
+            .sum()
+    }
+
+    fn entries(&self) -> Entries {
+        let mut entries = Entries::new();
+        for shard in self.lock_all_shards() {
+            entries.extend(
+                shard
+                    .entries()
+                    .iter()
+                    .map(|(key, entry)| (key.clone(), entry.clone())),
+            );
+        }
+        entries
+    }
+
+    fn replace_entries(&self, entries: Entries, now: SystemTime) -> Entries {
+        let mut shard_entries = vec![Entries::new(); self.shards.len()];
+        for (key, entry) in entries {
+            let shard_index = self.shard_index(&key, self.shards.len());
+            shard_entries[shard_index].insert(key, entry);
+        }
+
+        let mut previous_entries = Entries::new();
+        let mut shards = self.lock_all_shards();
+        for (shard, entries) in shards.iter_mut().zip(shard_entries) {
+            previous_entries.extend(mem::replace(&mut **shard, Store::new(entries, now)).entries);
+        }
+        previous_entries
+    }
+}
+
+/// Entries with an index of their expiry times, so that expired entries can be removed without
+/// looking at all entries
+#[derive(Default)]
+struct Store {
+    entries: Entries,
+    expiries: BTreeSet<(SystemTime, Vec<u8>)>,
+}
+
+impl Store {
+    /// Leaves out the entries that expired already
+    pub fn new(entries: Entries, now: SystemTime) -> Self {
+        let mut store = Store::default();
+        for (key, entry) in entries {
+            if !entry.is_expired(now) {
+                store.insert(key, entry);
+            }
+        }
+        store
+    }
+
+    /// Expired entries are removed when they are retrieved
+    pub fn get(&mut self, key: &[u8], now: SystemTime) -> Option<&[u8]> {
+        self.remove_if_expired(key, now);
+        self.entries.get(key).map(|entry| entry.value.as_slice())
+    }
+
+    /// Replaces the entry of the key, including its expiry time
+    pub fn insert(&mut self, key: Vec<u8>, entry: Entry) {
+        let expires_at = entry.expires_at;
+        if let Some(Entry {
+            expires_at: Some(previous_expires_at),
+            ..
+        }) = self.entries.insert(key.clone(), entry)
+        {
+            self.expiries.remove(&(previous_expires_at, key.clone()));
+        }
+        if let Some(expires_at) = expires_at {
+            self.expiries.insert((expires_at, key));
+        }
+    }
+
+    /// Returns whether the key had a value that did not expire
+    pub fn remove(&mut self, key: &[u8], now: SystemTime) -> bool {
+        self.remove_entry(key)
+            .is_some_and(|entry| !entry.is_expired(now))
+    }
+
+    pub fn update(&mut self, key: &[u8], now: SystemTime, update: Update) -> Option<Entry> {
+        self.remove_if_expired(key, now);
+        let current_entry = self.entries.get(key);
+        let entry = Entry {
+            value: update(current_entry.map(|entry| entry.value.as_slice()))?,
+            expires_at: current_entry.and_then(|entry| entry.expires_at),
+        };
+        self.insert(key.to_vec(), entry.clone());
+        Some(entry)
+    }
+
+    pub fn keys_with_prefix(&self, prefix: &[u8], now: SystemTime) -> Vec<Vec<u8>> {
+        self.entries
+            .iter()
+            .filter(|(key, entry)| key.starts_with(prefix) && !entry.is_expired(now))
+            .map(|(key, _)| key.clone())
+            .collect()
+    }
+
+    fn remove_if_expired(&mut self, key: &[u8], now: SystemTime) {
+        if self
+            .entries
+            .get(key)
+            .is_some_and(|entry| entry.is_expired(now))
+        {
+            self.remove_entry(key);
+        }
+    }
+
+    fn remove_entry(&mut self, key: &[u8]) -> Option<Entry> {
+        let entry = self.entries.remove(key)?;
+        if let Some(expires_at) = entry.expires_at {
+            self.expiries.remove(&(expires_at, key.to_vec()));
+        }
+        Some(entry)
+    }
+
+    /// Removes all expired entries and returns their number
+    pub fn remove_expired(&mut self, now: SystemTime) -> usize {
+        let mut expired_count = 0;
+        while let Some((expires_at, _)) = self.expiries.first() {
+            if *expires_at > now {
+                break;
+            }
+            let (_, key) = self.expiries.pop_first().unwrap();
+            self.entries.remove(&key);
+            expired_count += 1;
+        }
+        expired_count
+    }
+
+    pub fn entries(&self) -> &Entries {
+        &self.entries
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn expiring(value: &[u8], expires_at: SystemTime) -> Entry {
+        Entry {
+            value: value.to_vec(),
+            expires_at: Some(expires_at),
+        }
+    }
+
+    #[test]
+    fn test_store_kinds() {
+        let now = SystemTime::now();
+        let later = now + Duration::from_secs(10);
+        let restored_entries = Entries::from([
+            (b"abc".to_vec(), Entry::permanent(b"42".to_vec())),
+            (b"old".to_vec(), expiring(b"1", now)),
+        ]);
+
+        for kind in [StoreKind::Mutex, StoreKind::Sharded] {
+            let config = StoreConfig {
+                kind,
+                shard_count: 4,
+            };
+            let store = create_store(&config, restored_entries.clone(), now);
+            assert_eq!(store.get(b"abc", now), Some(b"42".to_vec()));
+            assert_eq!(store.get(b"old", now), None);
+
+            for number in 0..100 {
+                let key = format!("key{number}").into_bytes();
+                store.insert(key, expiring(b"value", later));
+            }
+            store.insert(b"key7".to_vec(), Entry::permanent(b"seven".to_vec()));
+            assert_eq!(store.get(b"key7", later), Some(b"seven".to_vec()));
+            assert_eq!(store.get(b"key8", now), Some(b"value".to_vec()));
+            assert_eq!(store.entries().len(), 101);
+
+            assert_eq!(store.remove_expired(later), 99, "{kind:?}");
+            assert_eq!(
+                store.entries(),
+                Entries::from([
+                    (b"abc".to_vec(), Entry::permanent(b"42".to_vec())),
+                    (b"key7".to_vec(), Entry::permanent(b"seven".to_vec())),
+                ])
+            );
+
+            let previous_entries = store.replace_entries(restored_entries.clone(), now);
+            assert_eq!(previous_entries.len(), 2);
+            assert!(previous_entries.contains_key(&b"key7"[..]));
+            assert_eq!(store.get(b"key7", now), None);
+            assert_eq!(store.entries().len(), 1);
+        }
+    }
+
+    #[test]
+    fn test_atomic_replacement() {
+        let now = SystemTime::now();
+        let entries = |prefix: &str| -> Entries {
+            (0..100)
+                .map(|number| {
+                    let key = format!("{prefix}{number}").into_bytes();
+                    (key, Entry::permanent(b"value".to_vec()))
+                })
+                .collect()
+        };
+
+        for kind in [StoreKind::Mutex, StoreKind::Sharded] {
+            let config = StoreConfig {
+                kind,
+                shard_count: 4,
+            };
+            let store = create_store(&config, entries("a"), now);
+            std::thread::scope(|scope| {
+                scope.spawn(|| {
+                    for prefix in ["b", "a"].repeat(100) {
+                        store.replace_entries(entries(prefix), now);
+                    }
+                });
+                // Readers never see the keys of both replacements
+                for _ in 0..200 {
+                    let keys = store.keys_with_prefix(b"", now);
+                    assert_eq!(keys.len(), 100, "{kind:?}");
+                    assert!(keys.iter().all(|key| key[0] == keys[0][0]), "{kind:?}");
+                }
+            });
+        }
+    }
+
+    #[test]
+    fn test_store_kind_parsing() {
+        assert_eq!("mutex".parse(), Ok(StoreKind::Mutex));
+        assert_eq!(" Sharded".parse(), Ok(StoreKind::Sharded));
+        assert!("btree".parse::<StoreKind>().is_err());
+    }
+
+    #[test]
+    fn test_lazy_expiry() {
+        let now = SystemTime::now();
+        let later = now + Duration::from_secs(10);
+        let mut store = Store::new(Entries::new(), now);
+        store.insert(b"foo".to_vec(), expiring(b"bar", later));
+        store.insert(b"abc".to_vec(), Entry::permanent(b"42".to_vec()));
+
+        assert_eq!(store.get(b"foo", now), Some(&b"bar"[..]));
+        assert_eq!(store.get(b"foo", later), None);
+        assert_eq!(store.get(b"abc", later), Some(&b"42"[..]));
+        assert!(!store.entries().contains_key(&b"foo"[..]));
+        assert!(store.expiries.is_empty());
+    }
+
+    #[test]
+    fn test_updates() {
+        let now = SystemTime::now();
+        let later = now + Duration::from_secs(10);
+        let mut store = Store::new(Entries::new(), now);
+        store.insert(b"foo".to_vec(), expiring(b"1", later));
+        store.insert(b"food".to_vec(), Entry::permanent(b"2".to_vec()));
+        store.insert(b"bar".to_vec(), Entry::permanent(b"3".to_vec()));
+
+        // Updates keep the expiry time
+        let mut append_zero = |value: Option<&[u8]>| Some([value.unwrap_or(b""), b"0"].concat());
+        assert_eq!(
+            store.update(b"foo", now, &mut append_zero),
+            Some(expiring(b"10", later))
+        );
+        assert_eq!(store.update(b"bar", now, &mut |_| None), None);
+        assert_eq!(store.get(b"bar", now), Some(&b"3"[..]));
+
+        let mut keys = store.keys_with_prefix(b"fo", now);
+        keys.sort();
+        assert_eq!(keys, [b"foo".to_vec(), b"food".to_vec()]);
+        assert_eq!(store.keys_with_prefix(b"fo", later), [b"food".to_vec()]);
+
+        // Expired values are missing for updates and removals
+        assert_eq!(
+            store.update(b"foo", later, &mut append_zero),
+            Some(Entry::permanent(b"0".to_vec()))
+        );
+        store.insert(b"old".to_vec(), expiring(b"1", now));
+        assert!(!store.remove(b"old", later));
+        assert!(store.remove(b"food", later));
+        assert!(!store.remove(b"food", later));
+        assert!(store.expiries.is_empty());
+    }
+
+    #[test]
+    fn test_expiry_sweep() {
+        let now = SystemTime::now();
+        let mut store = Store::new(Entries::new(), now);
+        for (index, key) in [b"a", b"b", b"c"].into_iter().enumerate() {
+            let expires_at = now + Duration::from_secs(index as u64 + 1);
+            store.insert(key.to_vec(), expiring(b"1", expires_at));
+        }
+        // A plain insert makes the entry permanent again
+        store.insert(b"b".to_vec(), Entry::permanent(b"2".to_vec()));
+        // A new expiry time replaces the previous one
+        store.insert(b"c".to_vec(), expiring(b"3", now + Duration::from_secs(60)));
+
+        assert_eq!(store.remove_expired(now + Duration::from_secs(5)), 1);
+        assert_eq!(store.entries().len(), 2);
+        assert_eq!(
+            store.get(b"b", now + Duration::from_secs(5)),
+            Some(&b"2"[..])
+        );
+        assert_eq!(store.remove_expired(now + Duration::from_secs(60)), 1);
+        assert_eq!(store.entries().len(), 1);
+        assert!(store.expiries.is_empty());
+
+        // Entries that expired while the server was not running are not restored
+        let entries = Entries::from([
+            (b"old".to_vec(), expiring(b"1", now)),
+            (
+                b"new".to_vec(),
+                expiring(b"2", now + Duration::from_secs(1)),
+            ),
+        ]);
+        let store = Store::new(entries, now);
+        assert_eq!(store.entries().len(), 1);
+        assert_eq!(store.expiries.len(), 1);
+    }
+}
@@ -0,0 +1,249 @@
+use std::io::{Error as IO_Error, Result as IO_Result};
+use std::net::SocketAddr;
+use std::sync::Arc;
+
+use bytes::{Bytes, BytesMut};
+use futures::StreamExt;
+use tokio::io::{AsyncWriteExt, BufWriter};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::Semaphore;
+use tokio_util::codec::{AnyDelimiterCodec, AnyDelimiterCodecError, Decoder, FramedRead};
+
+use crate::{handle_request, Server, MAX_DATAGRAM_SIZE};
+
+#[derive(Debug, PartialEq)]
+enum RequestLine {
+    Request(Bytes),
+    /// Line that would not fit into a datagram, it is skipped up to the next newline
+    Oversized,
+}
+
+/// Newline delimited requests of raw bytes, with the size limit of datagrams
+struct RequestLineCodec(AnyDelimiterCodec);
+
+impl RequestLineCodec {
+    fn new() -> Self {
+        RequestLineCodec(AnyDelimiterCodec::new_with_max_length(
+            b"\n".to_vec(),
+            b"\n".to_vec(),
+            MAX_DATAGRAM_SIZE - 1,
+        ))
+    }
+}
+
+impl Decoder for RequestLineCodec {
+    type Item = RequestLine;
+    type Error = IO_Error;
+
+    fn decode(&mut self, buffer: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        to_request_line(self.0.decode(buffer))
+    }
+
+    fn decode_eof(&mut self, buffer: &mut BytesMut) -> Result<Option<Self::Item>, Self::Error> {
+        to_request_line(self.0.decode_eof(buffer))
+    }
+}
+
+/// Too long lines are not an error of the connection, the codec discards them by itself
+fn to_request_line(
+    line: Result<Option<Bytes>, AnyDelimiterCodecError>,
+) -> IO_Result<Option<RequestLine>> {
+    match line {
+        Ok(line) => Ok(line.map(RequestLine::Request)),
+        Err(AnyDelimiterCodecError::MaxChunkLengthExceeded) => Ok(Some(RequestLine::Oversized)),
+        Err(AnyDelimiterCodecError::Io(e)) => Err(e),
+    }
+}
+
+/// Accepts clients that send one request per line instead of one per datagram. Requests of a
+/// connection are answered in order, every response ends with a newline.
+pub async fn run_tcp_server(tcp_listener: TcpListener, server: Arc<Server>) -> IO_Result<()> {
+    let max_connections = server.config.limits.max_tcp_connections;
+    let connections = Arc::new(Semaphore::new(max_connections));
+    loop {
+        let (tcp_stream, client_address) = tcp_listener.accept().await?;
+        let Ok(connection_permit) = Arc::clone(&connections).try_acquire_owned() else {
+            println!("[{client_address}] Refusing TCP connection, {max_connections} are open");
+            continue;
+        };
+        let server = Arc::clone(&server);
+        tokio::spawn(async move {
+            if let Err(e) = handle_client(tcp_stream, client_address, &server).await {
+                println!("[{client_address}] TCP connection failed: {e}");
+            }
+            drop(connection_permit);
+        });
+    }
+}
+
+async fn handle_client(
+    tcp_stream: TcpStream,
+    client_address: SocketAddr,
+    server: &Server,
+) -> IO_Result<()> {
+    let (tcp_socket_reader, tcp_socket_writer) = tcp_stream.into_split();
+    let mut request_lines = FramedRead::new(tcp_socket_reader, RequestLineCodec::new());
+    let mut tcp_socket_writer = BufWriter::new(tcp_socket_writer);
+
+    while let Some(request_line) = request_lines.next().await {
+        match request_line? {
+            RequestLine::Request(request) => {
+                // TCP clients are slowed down instead of losing requests. The permit is returned
+                // before writing, a client that does not read its responses only blocks itself.
+                let in_flight_permit = server.in_flight.acquire().await.map_err(IO_Error::other)?;
+                let responses = handle_request(&request, client_address, server).await;
+                drop(in_flight_permit);
+                for response in responses {
+                    tcp_socket_writer.write_all(&response).await?;
+                    tcp_socket_writer.write_all(b"\n").await?;
+                }
+                tcp_socket_writer.flush().await?;
+            }
+            RequestLine::Oversized => {
+                println!(
+                    "[{client_address}] Dropping request of {MAX_DATAGRAM_SIZE} bytes or more"
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::io::{AsyncBufReadExt, AsyncReadExt, BufReader};
+    use tokio::time::timeout;
+
+    use super::*;
+    use crate::config::{DatabaseConfig, LimitsConfig};
+    use crate::database::Database;
+
+    async fn start_tcp_server(config: DatabaseConfig) -> SocketAddr {
+        let tcp_listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let server_address = tcp_listener.local_addr().unwrap();
+        let server = Server::new(Arc::new(Database::open(&config).unwrap()), config, None);
+        tokio::spawn(run_tcp_server(tcp_listener, Arc::new(server)));
+        server_address
+    }
+
+    #[test]
+    fn test_request_line_decoding() {
+        let mut codec = RequestLineCodec::new();
+
+        let mut buffer = BytesMut::from(&b"\xff=\xfe\n\nfoo"[..]);
+        assert_eq!(
+            codec.decode(&mut buffer).unwrap(),
+            Some(RequestLine::Request(Bytes::from_static(b"\xff=\xfe")))
+        );
+        // Empty lines are requests for the empty key
+        assert_eq!(
+            codec.decode(&mut buffer).unwrap(),
+            Some(RequestLine::Request(Bytes::new()))
+        );
+        assert_eq!(codec.decode(&mut buffer).unwrap(), None);
+        assert_eq!(
+            codec.decode_eof(&mut buffer).unwrap(),
+            Some(RequestLine::Request(Bytes::from_static(b"foo")))
+        );
+
+        // Oversized lines are skipped, the following lines are still decoded
+        let mut buffer = BytesMut::from(&[b'a'; MAX_DATAGRAM_SIZE][..]);
+        assert_eq!(
+            codec.decode(&mut buffer).unwrap(),
+            Some(RequestLine::Oversized)
+        );
+        buffer.extend_from_slice(b"aaa\nabc\n");
+        assert_eq!(
+            codec.decode(&mut buffer).unwrap(),
+            Some(RequestLine::Request(Bytes::from_static(b"abc")))
+        );
+
+        let mut buffer = BytesMut::from(&[b'a'; MAX_DATAGRAM_SIZE - 1][..]);
+        buffer.extend_from_slice(b"\n");
+        assert_eq!(
+            codec.decode(&mut buffer).unwrap(),
+            Some(RequestLine::Request(Bytes::from(vec![
+                b'a';
+                MAX_DATAGRAM_SIZE - 1
+            ])))
+        );
+    }
+
+    #[tokio::test]
+    async fn test_tcp_requests() {
+        let server_address = start_tcp_server(DatabaseConfig::default()).await;
+
+        let tcp_stream = TcpStream::connect(server_address).await.unwrap();
+        let (reader, mut writer) = tcp_stream.into_split();
+        let mut reader = BufReader::new(reader);
+        let mut oversized_insert = b"big=".to_vec();
+        oversized_insert.resize(MAX_DATAGRAM_SIZE, b'x');
+        writer.write_all(&oversized_insert).await.unwrap();
+        writer
+            .write_all(b"\nfoo=bar=baz\nbig\nversion\nfoo\nmissing\n\xff=\x00")
+            .await
+            .unwrap();
+        writer.shutdown().await.unwrap();
+
+        let mut responses = Vec::new();
+        loop {
+            let mut response = Vec::new();
+            let size = timeout(
+                Duration::from_millis(300),
+                reader.read_until(b'\n', &mut response),
+            )
+            .await
+            .unwrap()
+            .unwrap();
+            if size == 0 {
+                break;
+            }
+            responses.push(response);
+        }
+        assert_eq!(
+            responses,
+            [
+                b"version=Key-Value Store API v1\n".to_vec(),
+                b"foo=bar=baz\n".to_vec()
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_connection_limit() {
+        let server_address = start_tcp_server(DatabaseConfig {
+            limits: LimitsConfig {
+                max_tcp_connections: 1,
+                ..LimitsConfig::default()
+            },
+            ..DatabaseConfig::default()
+        })
+        .await;
+
+        let mut tcp_stream = BufReader::new(TcpStream::connect(server_address).await.unwrap());
+        tcp_stream.write_all(b"version\n").await.unwrap();
+        let mut response = Vec::new();
+        tcp_stream.read_until(b'\n', &mut response).await.unwrap();
+        assert_eq!(response, b"version=Key-Value Store API v1\n");
+
+        // Closed without an answer while the first client is connected
+        let mut refused_stream = TcpStream::connect(server_address).await.unwrap();
+        let mut buffer = [0; 64];
+        let size = timeout(Duration::from_millis(300), refused_stream.read(&mut buffer))
+            .await
+            .unwrap()
+            .unwrap_or(0);
+        assert_eq!(size, 0);
+
+        // The connection can be used once the first client is gone
+        drop(tcp_stream);
+        tokio::time::sleep(Duration::from_millis(50)).await;
+        let mut tcp_stream = BufReader::new(TcpStream::connect(server_address).await.unwrap());
+        tcp_stream.write_all(b"version\n").await.unwrap();
+        let mut response = Vec::new();
+        tcp_stream.read_until(b'\n', &mut response).await.unwrap();
+        assert_eq!(response, b"version=Key-Value Store API v1\n");
+    }
+}
@@ -0,0 +1,877 @@
+use std::fs::{self, File, OpenOptions};
+use std::future::Future;
+use std::io::{BufWriter, Error as IO_Error, ErrorKind, Result as IO_Result, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Mutex;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant, UNIX_EPOCH};
+
+use tokio::sync::watch;
+
+use crate::config::parse_duration;
+use crate::store::{Change, Entries, Entry};
+
+/// Record header: key length and value length, both as little endian `u32`
+const HEADER_SIZE: usize = 8;
+/// Set in the key length of records with an expiry time, which follows the header as
+/// milliseconds since the Unix epoch
+const EXPIRY_FLAG: u32 = 1 << 31;
+const EXPIRY_SIZE: usize = 8;
+/// Set in the key length of records that delete the key, they have no value
+const DELETE_FLAG: u32 = 1 << 30;
+/// Records end with the CRC-32 of everything before it
+const CHECKSUM_SIZE: usize = 4;
+
+/// When appended changes are flushed to the disk
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SyncPolicy {
+    /// After every batch of changes, before the requests are answered
+    Always,
+    /// Periodically, changes since the last flush may be lost in a crash
+    Interval(Duration),
+    /// Whenever the operating system decides to
+    Never,
+}
+
+impl FromStr for SyncPolicy {
+    type Err = String;
+
+    fn from_str(sync_policy: &str) -> Result<Self, Self::Err> {
+        match sync_policy.trim().to_lowercase().as_str() {
+            "always" => Ok(SyncPolicy::Always),
+            "never" => Ok(SyncPolicy::Never),
+            interval => parse_duration(interval)
+                .filter(|interval| !interval.is_zero())
+                .map(SyncPolicy::Interval)
+                .ok_or_else(|| format!("Unknown sync policy {sync_policy}")),
+        }
+    }
+}
+
+/// Append-only log of inserts and deletes. The data directory holds numbered generations:
+/// `snapshot-<n>.db` contains all entries at the start of `wal-<n>.log`, which contains the
+/// changes after it. Both files use the same record format.
+pub struct WriteAheadLog {
+    directory: PathBuf,
+    generation: u64,
+    file: File,
+    /// End of the last complete record in the file
+    size: u64,
+    sync_policy: SyncPolicy,
+    /// Changes were appended since the last flush
+    is_dirty: bool,
+}
+
+impl WriteAheadLog {
+    /// Restores the entries from the newest snapshot and the logs written after it and continues
+    /// the newest log. Records at the end of a log that were only partially written before a
+    /// crash are discarded, together with all later logs.
+    pub fn open(directory: &Path, sync_policy: SyncPolicy) -> IO_Result<(Self, Entries)> {
+        fs::create_dir_all(directory)?;
+
+        let mut snapshot_generations = Vec::new();
+        let mut log_generations = Vec::new();
+        for dir_entry in fs::read_dir(directory)? {
+            let file_name = dir_entry?.file_name();
+            let file_name = file_name.to_string_lossy();
+            if let Some(generation) = parse_generation(&file_name, "snapshot-", ".db") {
+                snapshot_generations.push(generation);
+            } else if let Some(generation) = parse_generation(&file_name, "wal-", ".log") {
+                log_generations.push(generation);
+            } else if file_name.ends_with(".tmp") {
+                // Snapshot that was not completely written
+                fs::remove_file(directory.join(file_name.as_ref()))?;
+            }
+        }
+        log_generations.sort_unstable();
+
+        let snapshot_generation = snapshot_generations.iter().max().copied().unwrap_or(0);
+        let mut entries = Entries::new();
+        if !snapshot_generations.is_empty() {
+            let snapshot_path = snapshot_path(directory, snapshot_generation);
+            let snapshot = fs::read(&snapshot_path)?;
+            // Snapshots are only renamed into place once they are complete
+            if replay(&snapshot, &mut entries) != snapshot.len() {
+                return Err(IO_Error::new(
+                    ErrorKind::InvalidData,
+                    format!("Corrupt snapshot {}", snapshot_path.display()),
+                ));
+            }
+        }
+
+        let mut generation = snapshot_generation;
+        let mut is_truncated = false;
+        for log_generation in log_generations
+            .into_iter()
+            .filter(|generation| *generation >= snapshot_generation)
+        {
+            let log_path = log_path(directory, log_generation);
+            if is_truncated {
+                // The changes of later logs may depend on the discarded ones
+                println!(
+                    "[WAL] Discarding {} after incomplete records",
+                    log_path.display()
+                );
+                fs::remove_file(&log_path)?;
+                continue;
+            }
+
+            let log = fs::read(&log_path)?;
+            let valid_size = replay(&log, &mut entries);
+            if valid_size < log.len() {
+                println!(
+                    "[WAL] Discarding {} bytes of incomplete records at the end of {}",
+                    log.len() - valid_size,
+                    log_path.display()
+                );
+                OpenOptions::new()
+                    .write(true)
+                    .open(&log_path)?
+                    .set_len(valid_size as u64)?;
+                is_truncated = true;
+            }
+            generation = log_generation;
+        }
+        remove_generations_before(directory, snapshot_generation)?;
+
+        let file = open_log(directory, generation)?;
+        let write_ahead_log = WriteAheadLog {
+            directory: directory.to_path_buf(),
+            generation,
+            size: file.metadata()?.len(),
+            file,
+            sync_policy,
+            is_dirty: false,
+        };
+        Ok((write_ahead_log, entries))
+    }
+
+    /// Appends a record from [`encode_change`]. It is only flushed to the disk by [`Self::sync`].
+    /// A record that could not be written completely is cut off again, so that the records after
+    /// it are not discarded on the next start.
+    pub fn append(&mut self, record: &[u8]) -> IO_Result<()> {
+        if let Err(e) = self.file.write_all(record) {
+            if let Err(truncate_error) = self.file.set_len(self.size) {
+                println!("[WAL] Failed to remove incomplete record: {truncate_error}");
+            }
+            return Err(e);
+        }
+        self.size += record.len() as u64;
+        self.is_dirty = true;
+        Ok(())
+    }
+
+    /// Flushes the changes that were appended since the last flush
+    pub fn sync(&mut self) -> IO_Result<()> {
+        if self.is_dirty {
+            self.file.sync_data()?;
+            self.is_dirty = false;
+        }
+        Ok(())
+    }
+
+    /// Continues in a new log, whose snapshot has to be written with `write_snapshot`. Returns
+    /// the generation of the new log.
+    pub fn start_generation(&mut self) -> IO_Result<u64> {
+        self.file.sync_data()?;
+        self.is_dirty = false;
+        let generation = self.generation + 1;
+        self.file = open_log(&self.directory, generation)?;
+        self.size = 0;
+        sync_directory(&self.directory)?;
+        self.generation = generation;
+        Ok(generation)
+    }
+}
+
+/// Requests to the log writer thread, in the order of the store
+enum LogCommand {
+    Append(Vec<u8>),
+    /// Continues in a new log and replies with its generation
+    StartGeneration(mpsc::Sender<IO_Result<u64>>),
+}
+
+/// Number of changes the log writer has written, and flushed to the disk
+#[derive(Clone, Copy, Debug, Default)]
+struct LogPosition {
+    written: u64,
+    synced: u64,
+    /// A write or flush failed, no further changes are written
+    has_failed: bool,
+}
+
+/// Queue of the writer thread, with the number of the last change that was queued
+struct LogQueue {
+    command_sender: mpsc::Sender<LogCommand>,
+    appended: u64,
+}
+
+/// Appends changes to the write-ahead log on a thread of its own, so that neither the database
+/// nor the runtime wait for the disk. Changes that arrive while the disk is busy are written and
+/// flushed together. Dropping the writer waits for the changes that are still queued.
+pub struct LogWriter {
+    directory: PathBuf,
+    sync_policy: SyncPolicy,
+    queue: Mutex<Option<LogQueue>>,
+    position: watch::Receiver<LogPosition>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl LogWriter {
+    pub fn start(write_ahead_log: WriteAheadLog) -> Self {
+        let directory = write_ahead_log.directory.clone();
+        let sync_policy = write_ahead_log.sync_policy;
+        let (command_sender, command_receiver) = mpsc::channel();
+        let (position_sender, position) = watch::channel(LogPosition::default());
+        let thread = thread::Builder::new()
+            .name(String::from("log writer"))
+            .spawn(move || run_log_writer(write_ahead_log, command_receiver, position_sender))
+            .expect("Failed to start log writer thread");
+        LogWriter {
+            directory,
+            sync_policy,
+            queue: Mutex::new(Some(LogQueue {
+                command_sender,
+                appended: 0,
+            })),
+            position,
+            thread: Some(thread),
+        }
+    }
+
+    /// Changes of the same key have to be appended one after another, in the order of the store.
+    /// Returns the number of the change for [`Self::logged`].
+    pub fn append(&self, change: &Change) -> u64 {
+        let record = encode_change(change);
+        let mut queue = self.queue.lock().unwrap();
+        let queue = queue.as_mut().unwrap();
+        // Numbered while queued, so that the numbers follow the order in which they are written
+        queue.appended += 1;
+        // The thread only stops once the sender is dropped
+        let _ = queue.command_sender.send(LogCommand::Append(record));
+        queue.appended
+    }
+
+    /// Continues in a new log once the changes before are written, see
+    /// [`WriteAheadLog::start_generation`]. Receives the generation of the new log.
+    pub fn start_generation(&self) -> mpsc::Receiver<IO_Result<u64>> {
+        let (generation_sender, generation) = mpsc::channel();
+        let queue = self.queue.lock().unwrap();
+        let _ = queue
+            .as_ref()
+            .unwrap()
+            .command_sender
+            .send(LogCommand::StartGeneration(generation_sender));
+        generation
+    }
+
+    /// Waits until the change with the number from [`Self::append`] and all changes before it are
+    /// written, and flushed to the disk if the sync policy asks for it. Resolves to `false` if the
+    /// writer failed before, so that the change is not confirmed.
+    pub fn logged(&self, change_number: u64) -> impl Future<Output = bool> {
+        let sync_policy = self.sync_policy;
+        let mut position = self.position.clone();
+        async move {
+            loop {
+                let LogPosition {
+                    written,
+                    synced,
+                    has_failed,
+                } = *position.borrow_and_update();
+                let logged = match sync_policy {
+                    SyncPolicy::Always => synced,
+                    SyncPolicy::Interval(_) | SyncPolicy::Never => written,
+                };
+                if logged >= change_number {
+                    return true;
+                }
+                if has_failed || position.changed().await.is_err() {
+                    return false;
+                }
+            }
+        }
+    }
+
+    pub fn directory(&self) -> &Path {
+        &self.directory
+    }
+}
+
+impl Drop for LogWriter {
+    fn drop(&mut self) {
+        // Closing the queue ends the thread once it is empty
+        self.queue.lock().unwrap().take();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+/// Executes the commands in batches of everything that is queued. After a failed write or flush,
+/// later changes could be replayed without the ones before them -> Stop writing, the changes are
+/// no longer confirmed.
+fn run_log_writer(
+    mut write_ahead_log: WriteAheadLog,
+    command_receiver: mpsc::Receiver<LogCommand>,
+    position_sender: watch::Sender<LogPosition>,
+) {
+    let mut position = LogPosition::default();
+    let sync_interval = match write_ahead_log.sync_policy {
+        SyncPolicy::Interval(sync_interval) => Some(sync_interval),
+        SyncPolicy::Always | SyncPolicy::Never => None,
+    };
+    let mut next_sync = sync_interval.map(|sync_interval| Instant::now() + sync_interval);
+    loop {
+        let first_command = match next_sync {
+            Some(sync_at) => {
+                command_receiver.recv_timeout(sync_at.saturating_duration_since(Instant::now()))
+            }
+            None => command_receiver
+                .recv()
+                .map_err(|_| RecvTimeoutError::Disconnected),
+        };
+        let first_command = match first_command {
+            Ok(command) => command,
+            Err(RecvTimeoutError::Timeout) => {
+                sync_log(&mut write_ahead_log, &mut position);
+                position_sender.send_replace(position);
+                next_sync = sync_interval.map(|sync_interval| Instant::now() + sync_interval);
+                continue;
+            }
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+
+        for command in std::iter::once(first_command).chain(command_receiver.try_iter()) {
+            match command {
+                LogCommand::Append(_) if position.has_failed => {}
+                LogCommand::Append(record) => match write_ahead_log.append(&record) {
+                    Ok(()) => position.written += 1,
+                    Err(e) => {
+                        println!("[WAL] Failed to log change, no longer logging changes: {e}");
+                        position.has_failed = true;
+                    }
+                },
+                LogCommand::StartGeneration(generation_sender) => {
+                    let generation = if position.has_failed {
+                        Err(IO_Error::other("The log writer failed"))
+                    } else {
+                        write_ahead_log.start_generation()
+                    };
+                    let _ = generation_sender.send(generation);
+                }
+            }
+        }
+        if write_ahead_log.sync_policy == SyncPolicy::Always {
+            sync_log(&mut write_ahead_log, &mut position);
+        }
+        position_sender.send_replace(position);
+    }
+
+    // Changes of the last interval
+    if sync_interval.is_some() {
+        sync_log(&mut write_ahead_log, &mut position);
+    }
+}
+
+fn sync_log(write_ahead_log: &mut WriteAheadLog, position: &mut LogPosition) {
+    if position.has_failed {
+        return;
+    }
+    match write_ahead_log.sync() {
+        Ok(()) => position.synced = position.written,
+        Err(e) => {
+            println!("[WAL] Failed to flush log, no longer logging changes: {e}");
+            position.has_failed = true;
+        }
+    }
+}
+
+/// Writes the entries at the start of the generation's log as its snapshot. The snapshot replaces
+/// all earlier snapshots and logs once it is complete.
+pub fn write_snapshot(directory: &Path, generation: u64, entries: &Entries) -> IO_Result<()> {
+    let temporary_path = directory.join(format!("snapshot-{generation}.tmp"));
+    let mut snapshot = BufWriter::new(File::create(&temporary_path)?);
+    for (key, entry) in entries {
+        snapshot.write_all(&encode_record(key, entry))?;
+    }
+    snapshot.into_inner()?.sync_all()?;
+
+    fs::rename(&temporary_path, snapshot_path(directory, generation))?;
+    sync_directory(directory)?;
+    remove_generations_before(directory, generation)
+}
+
+fn snapshot_path(directory: &Path, generation: u64) -> PathBuf {
+    directory.join(format!("snapshot-{generation}.db"))
+}
+
+fn log_path(directory: &Path, generation: u64) -> PathBuf {
+    directory.join(format!("wal-{generation}.log"))
+}
+
+fn parse_generation(file_name: &str, prefix: &str, suffix: &str) -> Option<u64> {
+    file_name
+        .strip_prefix(prefix)?
+        .strip_suffix(suffix)?
+        .parse()
+        .ok()
+}
+
+fn open_log(directory: &Path, generation: u64) -> IO_Result<File> {
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(log_path(directory, generation))
+}
+
+/// Renames and new files only survive a crash once their directory is flushed
+fn sync_directory(directory: &Path) -> IO_Result<()> {
+    File::open(directory)?.sync_all()
+}
+
+fn remove_generations_before(directory: &Path, generation: u64) -> IO_Result<()> {
+    for dir_entry in fs::read_dir(directory)? {
+        let file_name = dir_entry?.file_name();
+        let file_name = file_name.to_string_lossy();
+        let file_generation = parse_generation(&file_name, "snapshot-", ".db")
+            .or_else(|| parse_generation(&file_name, "wal-", ".log"));
+        if file_generation.is_some_and(|file_generation| file_generation < generation) {
+            fs::remove_file(directory.join(file_name.as_ref()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Record of the change, as it is written to the log
+pub fn encode_change(change: &Change) -> Vec<u8> {
+    match change {
+        Change::Insert(key, entry) => encode_record(key, entry),
+        Change::Delete(key) => encode_delete_record(key),
+    }
+}
+
+fn encode_delete_record(key: &[u8]) -> Vec<u8> {
+    let mut record = Vec::with_capacity(HEADER_SIZE + key.len() + CHECKSUM_SIZE);
+    record.extend_from_slice(&(key.len() as u32 | DELETE_FLAG).to_le_bytes());
+    record.extend_from_slice(&0u32.to_le_bytes());
+    record.extend_from_slice(key);
+    record.extend_from_slice(&crc32(&record).to_le_bytes());
+    record
+}
+
+fn encode_record(key: &[u8], entry: &Entry) -> Vec<u8> {
+    let mut record = Vec::with_capacity(
+        HEADER_SIZE + EXPIRY_SIZE + key.len() + entry.value.len() + CHECKSUM_SIZE,
+    );
+    let key_size = key.len() as u32;
+    match entry.expires_at {
+        Some(expires_at) => {
+            let expires_at = expires_at
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |expires_at| expires_at.as_millis() as u64);
+            record.extend_from_slice(&(key_size | EXPIRY_FLAG).to_le_bytes());
+            record.extend_from_slice(&(entry.value.len() as u32).to_le_bytes());
+            record.extend_from_slice(&expires_at.to_le_bytes());
+        }
+        None => {
+            record.extend_from_slice(&key_size.to_le_bytes());
+            record.extend_from_slice(&(entry.value.len() as u32).to_le_bytes());
+        }
+    }
+    record.extend_from_slice(key);
+    record.extend_from_slice(&entry.value);
+    record.extend_from_slice(&crc32(&record).to_le_bytes());
+    record
+}
+
+/// Applies the records to the entries, up to the first record that is incomplete or does not
+/// match its checksum. Returns the size of the valid records.
+fn replay(records: &[u8], entries: &mut Entries) -> usize {
+    let mut valid_size = 0;
+    while let Some((change, record_size)) = decode_record(&records[valid_size..]) {
+        change.apply(entries);
+        valid_size += record_size;
+    }
+    valid_size
+}
+
+/// First record and its size, unless it is incomplete or does not match its checksum
+pub fn decode_record(records: &[u8]) -> Option<(Change, usize)> {
+    let header = records.get(..HEADER_SIZE)?;
+    let key_size = u32::from_le_bytes(header[..4].try_into().unwrap());
+    let value_size = u32::from_le_bytes(header[4..].try_into().unwrap()) as usize;
+    let key_start = match key_size & EXPIRY_FLAG {
+        0 => HEADER_SIZE,
+        _ => HEADER_SIZE + EXPIRY_SIZE,
+    };
+    let is_delete = key_size & DELETE_FLAG != 0;
+    let key_size = (key_size & !(EXPIRY_FLAG | DELETE_FLAG)) as usize;
+    let checksum_start = key_start.checked_add(key_size)?.checked_add(value_size)?;
+    let checksum = records.get(checksum_start..checksum_start.checked_add(CHECKSUM_SIZE)?)?;
+    if crc32(&records[..checksum_start]) != u32::from_le_bytes(checksum.try_into().unwrap()) {
+        return None;
+    }
+
+    let expires_at = (key_start > HEADER_SIZE).then(|| {
+        let expires_at = u64::from_le_bytes(records[HEADER_SIZE..key_start].try_into().unwrap());
+        UNIX_EPOCH + Duration::from_millis(expires_at)
+    });
+    let key = records[key_start..key_start + key_size].to_vec();
+    let change = if is_delete {
+        Change::Delete(key)
+    } else {
+        let entry = Entry {
+            value: records[key_start + key_size..checksum_start].to_vec(),
+            expires_at,
+        };
+        Change::Insert(key, entry)
+    };
+    Some((change, checksum_start + CHECKSUM_SIZE))
+}
+
+/// CRC-32 as used by zlib and PNG
+fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in bytes {
+        crc ^= u32::from(*byte);
+        for _ in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (0xEDB8_8320 & mask);
+        }
+    }
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::time::timeout;
+
+    use super::*;
+
+    fn temporary_directory(name: &str) -> PathBuf {
+        let directory =
+            std::env::temp_dir().join(format!("unusualdb-wal-{name}-{}", std::process::id()));
+        let _ = fs::remove_dir_all(&directory);
+        directory
+    }
+
+    fn file_names(directory: &Path) -> Vec<String> {
+        let mut file_names: Vec<String> = fs::read_dir(directory)
+            .unwrap()
+            .map(|dir_entry| {
+                dir_entry
+                    .unwrap()
+                    .file_name()
+                    .to_string_lossy()
+                    .into_owned()
+            })
+            .collect();
+        file_names.sort();
+        file_names
+    }
+
+    fn entries(pairs: &[(&[u8], &[u8])]) -> Entries {
+        pairs
+            .iter()
+            .map(|(key, value)| (key.to_vec(), Entry::permanent(value.to_vec())))
+            .collect()
+    }
+
+    fn record(key: &[u8], value: &[u8]) -> Vec<u8> {
+        encode_record(key, &Entry::permanent(value.to_vec()))
+    }
+
+    fn append(write_ahead_log: &mut WriteAheadLog, key: &[u8], value: &[u8]) {
+        write_ahead_log.append(&record(key, value)).unwrap();
+    }
+
+    #[test]
+    fn test_sync_policy_parsing() {
+        assert_eq!("always".parse(), Ok(SyncPolicy::Always));
+        assert_eq!(" Never ".parse(), Ok(SyncPolicy::Never));
+        assert_eq!(
+            "500ms".parse(),
+            Ok(SyncPolicy::Interval(Duration::from_millis(500)))
+        );
+        assert_eq!(
+            "2s".parse(),
+            Ok(SyncPolicy::Interval(Duration::from_secs(2)))
+        );
+        assert!("0s".parse::<SyncPolicy>().is_err());
+        assert!("sometimes".parse::<SyncPolicy>().is_err());
+    }
+
+    #[test]
+    fn test_records() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+
+        let mut records = record(b"foo", b"bar");
+        records.extend(record(b"\xff\x00", b""));
+        records.extend(record(b"foo", b"baz=\xfe"));
+        let mut replayed = Entries::new();
+        assert_eq!(replay(&records, &mut replayed), records.len());
+        assert_eq!(
+            replayed,
+            entries(&[(b"foo", b"baz=\xfe"), (b"\xff\x00", b"")])
+        );
+
+        // Incomplete or damaged records end the replay
+        let first_record_size = record(b"foo", b"bar").len();
+        for damaged_size in 1..first_record_size {
+            let mut replayed = Entries::new();
+            assert_eq!(replay(&records[..damaged_size], &mut replayed), 0);
+            assert!(replayed.is_empty());
+        }
+        records[first_record_size + HEADER_SIZE] ^= 1;
+        let mut replayed = Entries::new();
+        assert_eq!(replay(&records, &mut replayed), first_record_size);
+        assert_eq!(replayed, entries(&[(b"foo", b"bar")]));
+    }
+
+    #[test]
+    fn test_expiring_records() {
+        let expires_at = UNIX_EPOCH + Duration::from_millis(1_700_000_000_123);
+        let expiring_entry = Entry {
+            value: b"bar".to_vec(),
+            expires_at: Some(expires_at),
+        };
+        let mut records = encode_record(b"foo", &expiring_entry);
+        records.extend(record(b"abc", b"42"));
+
+        let mut replayed = Entries::new();
+        assert_eq!(replay(&records, &mut replayed), records.len());
+        assert_eq!(replayed[&b"foo"[..]], expiring_entry);
+        assert_eq!(replayed[&b"abc"[..]].expires_at, None);
+
+        // A later plain insert makes the entry permanent
+        records.extend(record(b"foo", b"baz"));
+        let mut replayed = Entries::new();
+        replay(&records, &mut replayed);
+        assert_eq!(replayed[&b"foo"[..]], Entry::permanent(b"baz".to_vec()));
+    }
+
+    #[test]
+    fn test_delete_records() {
+        let mut records = record(b"foo", b"bar");
+        records.extend(record(b"abc", b"42"));
+        records.extend(encode_delete_record(b"foo"));
+        // Deletes of missing keys are harmless
+        records.extend(encode_delete_record(b"missing"));
+        let mut replayed = Entries::new();
+        assert_eq!(replay(&records, &mut replayed), records.len());
+        assert_eq!(replayed, entries(&[(b"abc", b"42")]));
+
+        // A later insert restores the key
+        records.extend(record(b"foo", b"baz"));
+        let mut replayed = Entries::new();
+        replay(&records, &mut replayed);
+        assert_eq!(replayed, entries(&[(b"foo", b"baz"), (b"abc", b"42")]));
+    }
+
+    #[test]
+    fn test_replay_after_crash() {
+        let directory = temporary_directory("crash");
+        let (mut write_ahead_log, restored) =
+            WriteAheadLog::open(&directory, SyncPolicy::Always).unwrap();
+        assert!(restored.is_empty());
+        append(&mut write_ahead_log, b"foo", b"bar");
+        append(&mut write_ahead_log, b"abc", b"42");
+        append(&mut write_ahead_log, b"abc", b"123");
+        drop(write_ahead_log);
+
+        // Crash while appending the last insert
+        let log_path = log_path(&directory, 0);
+        let log_size = fs::metadata(&log_path).unwrap().len();
+        OpenOptions::new()
+            .write(true)
+            .open(&log_path)
+            .unwrap()
+            .set_len(log_size - 3)
+            .unwrap();
+
+        let (mut write_ahead_log, restored) =
+            WriteAheadLog::open(&directory, SyncPolicy::Always).unwrap();
+        assert_eq!(restored, entries(&[(b"foo", b"bar"), (b"abc", b"42")]));
+
+        // Inserts after the recovery follow the last complete record
+        append(&mut write_ahead_log, b"def", b"7");
+        drop(write_ahead_log);
+        let mut log = OpenOptions::new().append(true).open(&log_path).unwrap();
+        log.write_all(b"\x03\x00\x00\x00garbage").unwrap();
+        drop(log);
+
+        let (_, restored) = WriteAheadLog::open(&directory, SyncPolicy::Never).unwrap();
+        assert_eq!(
+            restored,
+            entries(&[(b"foo", b"bar"), (b"abc", b"42"), (b"def", b"7")])
+        );
+        assert_eq!(file_names(&directory), ["wal-0.log"]);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_corrupted_tail() {
+        let directory = temporary_directory("corrupted");
+        let (mut write_ahead_log, _) = WriteAheadLog::open(&directory, SyncPolicy::Always).unwrap();
+        append(&mut write_ahead_log, b"foo", b"bar");
+        append(&mut write_ahead_log, b"foo", b"baz");
+        drop(write_ahead_log);
+
+        // Flipped bit in the value of the last insert
+        let log_path = log_path(&directory, 0);
+        let mut log = fs::read(&log_path).unwrap();
+        let value_index = log.len() - CHECKSUM_SIZE - 1;
+        log[value_index] ^= 0x10;
+        fs::write(&log_path, &log).unwrap();
+
+        let (_, restored) = WriteAheadLog::open(&directory, SyncPolicy::Always).unwrap();
+        assert_eq!(restored, entries(&[(b"foo", b"bar")]));
+        assert_eq!(
+            fs::metadata(&log_path).unwrap().len(),
+            record(b"foo", b"bar").len() as u64
+        );
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_corrupted_generation() {
+        let directory = temporary_directory("generation");
+        let (mut write_ahead_log, _) = WriteAheadLog::open(&directory, SyncPolicy::Always).unwrap();
+        append(&mut write_ahead_log, b"foo", b"bar");
+        write_ahead_log.start_generation().unwrap();
+        append(&mut write_ahead_log, b"foo", b"baz");
+        append(&mut write_ahead_log, b"abc", b"42");
+        write_ahead_log.start_generation().unwrap();
+        append(&mut write_ahead_log, b"abc", b"43");
+        drop(write_ahead_log);
+
+        // Flipped bit in the first insert of the middle generation
+        let log_path = log_path(&directory, 1);
+        let mut log = fs::read(&log_path).unwrap();
+        log[HEADER_SIZE] ^= 0x10;
+        fs::write(&log_path, &log).unwrap();
+
+        // Nothing after the damaged record is replayed, not even later generations
+        let (mut write_ahead_log, restored) =
+            WriteAheadLog::open(&directory, SyncPolicy::Always).unwrap();
+        assert_eq!(restored, entries(&[(b"foo", b"bar")]));
+        assert_eq!(file_names(&directory), ["wal-0.log", "wal-1.log"]);
+        assert_eq!(fs::metadata(&log_path).unwrap().len(), 0);
+
+        // Changes continue in the damaged generation
+        append(&mut write_ahead_log, b"def", b"7");
+        drop(write_ahead_log);
+        let (_, restored) = WriteAheadLog::open(&directory, SyncPolicy::Always).unwrap();
+        assert_eq!(restored, entries(&[(b"foo", b"bar"), (b"def", b"7")]));
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn test_snapshots() {
+        let directory = temporary_directory("snapshot");
+        let (mut write_ahead_log, _) = WriteAheadLog::open(&directory, SyncPolicy::Always).unwrap();
+        append(&mut write_ahead_log, b"foo", b"bar");
+        append(&mut write_ahead_log, b"foo", b"baz");
+
+        let generation = write_ahead_log.start_generation().unwrap();
+        assert_eq!(generation, 1);
+        append(&mut write_ahead_log, b"abc", b"42");
+        write_snapshot(&directory, generation, &entries(&[(b"foo", b"baz")])).unwrap();
+        // Older generations are compacted into the snapshot
+        assert_eq!(file_names(&directory), ["snapshot-1.db", "wal-1.log"]);
+        drop(write_ahead_log);
+
+        let (mut write_ahead_log, restored) =
+            WriteAheadLog::open(&directory, SyncPolicy::Always).unwrap();
+        assert_eq!(restored, entries(&[(b"foo", b"baz"), (b"abc", b"42")]));
+
+        // Crash before the snapshot of the next generation was complete
+        let generation = write_ahead_log.start_generation().unwrap();
+        append(&mut write_ahead_log, b"foo", b"qux");
+        drop(write_ahead_log);
+        fs::write(
+            directory.join(format!("snapshot-{generation}.tmp")),
+            b"incomplete",
+        )
+        .unwrap();
+
+        let (_, restored) = WriteAheadLog::open(&directory, SyncPolicy::Always).unwrap();
+        assert_eq!(restored, entries(&[(b"foo", b"qux"), (b"abc", b"42")]));
+        assert_eq!(
+            file_names(&directory),
+            ["snapshot-1.db", "wal-1.log", "wal-2.log"]
+        );
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_log_writer() {
+        let directory = temporary_directory("writer");
+        let (write_ahead_log, _) = WriteAheadLog::open(&directory, SyncPolicy::Always).unwrap();
+        let log_writer = LogWriter::start(write_ahead_log);
+        let insert = |key: &[u8], value: &[u8]| {
+            Change::Insert(key.to_vec(), Entry::permanent(value.to_vec()))
+        };
+        assert_eq!(log_writer.append(&insert(b"foo", b"bar")), 1);
+        assert_eq!(log_writer.append(&Change::Delete(b"foo".to_vec())), 2);
+        assert_eq!(log_writer.append(&insert(b"abc", b"42")), 3);
+
+        // Logged changes are on the disk while the writer keeps running
+        assert!(timeout(Duration::from_secs(1), log_writer.logged(3))
+            .await
+            .unwrap());
+        let mut records = record(b"foo", b"bar");
+        records.extend(encode_change(&Change::Delete(b"foo".to_vec())));
+        records.extend(record(b"abc", b"42"));
+        assert_eq!(fs::read(log_path(&directory, 0)).unwrap(), records);
+
+        let generation = log_writer.start_generation().recv().unwrap().unwrap();
+        assert_eq!(generation, 1);
+        log_writer.append(&insert(b"abc", b"43"));
+        // Queued changes are written before the writer stops
+        drop(log_writer);
+
+        let (_, restored) = WriteAheadLog::open(&directory, SyncPolicy::Always).unwrap();
+        assert_eq!(restored, entries(&[(b"abc", b"43")]));
+        assert_eq!(file_names(&directory), ["wal-0.log", "wal-1.log"]);
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_failing_log_writer() {
+        let directory = temporary_directory("failing-writer");
+        let (mut write_ahead_log, _) = WriteAheadLog::open(&directory, SyncPolicy::Always).unwrap();
+        write_ahead_log.append(&record(b"foo", b"bar")).unwrap();
+        // Writes to a file that is only open for reading fail
+        write_ahead_log.file = File::open(log_path(&directory, 0)).unwrap();
+        let log_writer = LogWriter::start(write_ahead_log);
+
+        let change_number = log_writer.append(&Change::Delete(b"foo".to_vec()));
+        assert!(
+            !timeout(Duration::from_secs(1), log_writer.logged(change_number))
+                .await
+                .unwrap()
+        );
+        // Later changes are not confirmed either, and the log is not compacted
+        let change_number = log_writer.append(&Change::Delete(b"abc".to_vec()));
+        assert!(
+            !timeout(Duration::from_secs(1), log_writer.logged(change_number))
+                .await
+                .unwrap()
+        );
+        assert!(log_writer.start_generation().recv().unwrap().is_err());
+        drop(log_writer);
+
+        let (_, restored) = WriteAheadLog::open(&directory, SyncPolicy::Always).unwrap();
+        assert_eq!(restored, entries(&[(b"foo", b"bar")]));
+
+        fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -96,7 +96,7 @@ async fn forward_message(
     Ok(())
 }
 
-fn replace_boguscoin_address(message: &str) -> Cow<str> {
+fn replace_boguscoin_address(message: &str) -> Cow<'_, str> {
     let address_regex = Regex::new(r"(?<=^|\s)7[a-zA-Z0-9]{25,34}(?=$|\s)").unwrap();
     address_regex.replace_all(message, "${1}7YWHMfk9JZe0LM0g1ZauHuiSxhI${3}")
 }